@@ -105,7 +105,7 @@ use clap::{App, AppSettings, Arg, SubCommand};
 
 use crust::{read_config_file, ConnectionInfoResult, PeerId, PrivConnectionInfo, Service};
 use rand::Rng;
-use safe_crypto::{gen_encrypt_keypair, gen_sign_keypair, SecretEncryptKey};
+use safe_crypto::{gen_encrypt_keypair, gen_sign_keypair, SecretEncryptKey, SecretSignKey};
 use std::cmp;
 use std::collections::{BTreeMap, HashMap};
 use std::io;
@@ -299,8 +299,14 @@ fn main() {
         None
     };
 
-    let (peer_id, peer_sk) = new_peer_id();
-    let mut service = unwrap!(Service::with_config(event_sender, config, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = new_peer_id();
+    let mut service = unwrap!(Service::with_config(
+        event_sender,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
     unwrap!(service.start_listening_tcp());
     service.start_service_discovery();
     let service = Arc::new(Mutex::new(service));
@@ -399,6 +405,17 @@ fn main() {
                                     peer_id,
                                 );
                             }
+                            crust::Event::RelayConnectSuccess(peer_id, relay_id) => {
+                                println!(
+                                    "\nConnected to peer {:?} through relay {:?}",
+                                    peer_id, relay_id
+                                );
+                                let _ = handle_new_peer(
+                                    &unwrap!(service.lock()),
+                                    network2.clone(),
+                                    peer_id,
+                                );
+                            }
                             crust::Event::ListenerStarted(port) => {
                                 println!("\nListener started on port {}", port);
                                 if !test_extreach {
@@ -684,12 +701,12 @@ fn parse_user_command(cmd: &str) -> Option<UserCommand> {
     }
 }
 
-fn new_peer_id() -> (PeerId, SecretEncryptKey) {
+fn new_peer_id() -> (PeerId, SecretEncryptKey, SecretSignKey) {
     let (enc_pk, enc_sk) = gen_encrypt_keypair();
-    let (sign_pk, _sign_sk) = gen_sign_keypair();
+    let (sign_pk, sign_sk) = gen_sign_keypair();
     let id = PeerId {
         pub_sign_key: sign_pk,
         pub_enc_key: enc_pk,
     };
-    (id, enc_sk)
+    (id, enc_sk, sign_sk)
 }
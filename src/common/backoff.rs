@@ -0,0 +1,78 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use rand::{self, Rng};
+use std::cmp;
+use std::time::Duration;
+
+/// The delay stops doubling after this many attempts to avoid overflows.
+const MAX_DOUBLINGS: u32 = 16;
+
+/// Exponential backoff with random jitter.
+///
+/// Each delay is twice as long as the previous one, capped at the given maximum. The actual
+/// delay is picked randomly from the upper half of the computed one, so that peers which lost
+/// connection at the same time don't retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial_ms: u64,
+    max_ms: u64,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial_ms: u64, max_ms: u64) -> Self {
+        Self {
+            initial_ms: cmp::max(initial_ms, 1),
+            max_ms: cmp::max(max_ms, 1),
+            attempt: 0,
+        }
+    }
+
+    /// Number of delays handed out so far.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns the delay to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = cmp::min(self.attempt, MAX_DOUBLINGS);
+        let delay_ms = cmp::min(self.initial_ms.saturating_mul(1 << exp), self.max_ms);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let jittered_ms = rand::thread_rng().gen_range(delay_ms / 2, delay_ms + 1);
+        Duration::from_millis(jittered_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_exponentially_within_jitter_bounds() {
+        let mut backoff = Backoff::new(100, 60_000);
+
+        for expected_ms in &[100, 200, 400, 800, 1600] {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(expected_ms / 2));
+            assert!(delay <= Duration::from_millis(*expected_ms));
+        }
+        assert_eq!(backoff.attempt(), 5);
+    }
+
+    #[test]
+    fn delays_are_capped() {
+        let mut backoff = Backoff::new(100, 1000);
+
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= Duration::from_millis(1000));
+        }
+    }
+}
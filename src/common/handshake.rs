@@ -0,0 +1,193 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{NameHash, Result, Socket};
+use crate::PeerId;
+use maidsafe_utilities::serialisation::serialise;
+use rand;
+use safe_crypto::{PublicEncryptKey, SecretSignKey, SharedSecretKey, Signature};
+use socket_collection::{DecryptContext, EncryptContext, SocketError};
+
+/// Random value picked by the peer initiating a handshake. The responder signs it too, so that
+/// its signature can't be replayed in another session.
+pub type HandshakeNonce = [u8; 32];
+
+/// Proves the sender of a handshake message owns the signing key of its `PeerId` and
+/// authenticates the ephemeral key the session is going to be encrypted with.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct HandshakeAuth {
+    /// Nonce of this session.
+    pub nonce: HandshakeNonce,
+    /// Ephemeral public key of the sender. The session key is derived from both peers' ephemeral
+    /// keys, so recorded traffic stays secret even if the identity keys leak later.
+    pub session_pk: PublicEncryptKey,
+    /// Signature over the session made with the sender's secret signing key.
+    pub signature: Signature,
+}
+
+impl HandshakeAuth {
+    /// Starts a new session with the peer owning `their_pk`.
+    pub fn new(
+        our_sign_sk: &SecretSignKey,
+        name_hash: &NameHash,
+        our_pk: &PublicEncryptKey,
+        their_pk: &PublicEncryptKey,
+        session_pk: PublicEncryptKey,
+    ) -> Result<Self> {
+        let nonce = rand::random();
+        Self::sign(our_sign_sk, name_hash, our_pk, their_pk, session_pk, nonce)
+    }
+
+    /// Signs the session identified by `nonce` with the peer owning `their_pk`. Used to respond
+    /// to the peer which started the session.
+    pub fn sign(
+        our_sign_sk: &SecretSignKey,
+        name_hash: &NameHash,
+        our_pk: &PublicEncryptKey,
+        their_pk: &PublicEncryptKey,
+        session_pk: PublicEncryptKey,
+        nonce: HandshakeNonce,
+    ) -> Result<Self> {
+        let session = serialise(&Session {
+            name_hash,
+            signer_pk: our_pk,
+            peer_pk: their_pk,
+            session_pk: &session_pk,
+            nonce: &nonce,
+        })?;
+        Ok(Self {
+            nonce,
+            session_pk,
+            signature: our_sign_sk.sign_detached(&session),
+        })
+    }
+
+    /// Returns true if the owner of `their_id` signed this session with us.
+    pub fn verify(
+        &self,
+        their_id: &PeerId,
+        name_hash: &NameHash,
+        our_pk: &PublicEncryptKey,
+    ) -> bool {
+        let session = serialise(&Session {
+            name_hash,
+            signer_pk: &their_id.pub_enc_key,
+            peer_pk: our_pk,
+            session_pk: &self.session_pk,
+            nonce: &self.nonce,
+        });
+        match session {
+            Ok(session) => their_id
+                .pub_sign_key
+                .verify_detached(&self.signature, &session),
+            Err(e) => {
+                debug!("Failed to serialise handshake session: {:?}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Everything a handshake signature covers. Including both encryption keys binds the signer's
+/// signing key to its own encryption key, so nobody can present someone else's signing key.
+#[derive(Serialize)]
+struct Session<'a> {
+    name_hash: &'a NameHash,
+    signer_pk: &'a PublicEncryptKey,
+    peer_pk: &'a PublicEncryptKey,
+    session_pk: &'a PublicEncryptKey,
+    nonce: &'a HandshakeNonce,
+}
+
+/// Encrypts all further traffic on `socket` with the key agreed during the handshake.
+pub fn set_session_key(
+    socket: &mut Socket,
+    session_key: SharedSecretKey,
+) -> ::std::result::Result<(), SocketError> {
+    socket.set_encrypt_ctx(EncryptContext::authenticated(session_key.clone()))?;
+    socket.set_decrypt_ctx(DecryptContext::authenticated(session_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::rand_peer_id_and_keys;
+    use safe_crypto::gen_encrypt_keypair;
+
+    const NAME_HASH: NameHash = [1; 32];
+
+    #[test]
+    fn signed_session_is_verified_against_signers_id() {
+        let (our_id, _, _) = rand_peer_id_and_keys();
+        let (their_id, _, their_sign_sk) = rand_peer_id_and_keys();
+
+        let auth = unwrap!(HandshakeAuth::new(
+            &their_sign_sk,
+            &NAME_HASH,
+            &their_id.pub_enc_key,
+            &our_id.pub_enc_key,
+            gen_encrypt_keypair().0,
+        ));
+        assert!(auth.verify(&their_id, &NAME_HASH, &our_id.pub_enc_key));
+        assert!(!auth.verify(&their_id, &[2; 32], &our_id.pub_enc_key));
+    }
+
+    #[test]
+    fn signature_with_someone_elses_sign_key_is_rejected() {
+        let (our_id, _, _) = rand_peer_id_and_keys();
+        let (their_id, _, _) = rand_peer_id_and_keys();
+        let (_, _, other_sign_sk) = rand_peer_id_and_keys();
+
+        let auth = unwrap!(HandshakeAuth::new(
+            &other_sign_sk,
+            &NAME_HASH,
+            &their_id.pub_enc_key,
+            &our_id.pub_enc_key,
+            gen_encrypt_keypair().0,
+        ));
+        assert!(!auth.verify(&their_id, &NAME_HASH, &our_id.pub_enc_key));
+    }
+
+    #[test]
+    fn signature_can_not_be_reflected_back_to_signer() {
+        let (our_id, _, our_sign_sk) = rand_peer_id_and_keys();
+        let (their_id, _, _) = rand_peer_id_and_keys();
+
+        let auth = unwrap!(HandshakeAuth::new(
+            &our_sign_sk,
+            &NAME_HASH,
+            &our_id.pub_enc_key,
+            &their_id.pub_enc_key,
+            gen_encrypt_keypair().0,
+        ));
+        // Peer responds with our own signature, claiming our signing key.
+        let their_id = PeerId {
+            pub_sign_key: our_id.pub_sign_key,
+            pub_enc_key: their_id.pub_enc_key,
+        };
+        assert!(!auth.verify(&their_id, &NAME_HASH, &our_id.pub_enc_key));
+    }
+
+    #[test]
+    fn substituted_session_key_is_rejected() {
+        let (our_id, _, _) = rand_peer_id_and_keys();
+        let (their_id, _, their_sign_sk) = rand_peer_id_and_keys();
+
+        let mut auth = unwrap!(HandshakeAuth::new(
+            &their_sign_sk,
+            &NAME_HASH,
+            &their_id.pub_enc_key,
+            &our_id.pub_enc_key,
+            gen_encrypt_keypair().0,
+        ));
+        // Man in the middle swaps in its own ephemeral key.
+        auth.session_pk = gen_encrypt_keypair().0;
+        assert!(!auth.verify(&their_id, &NAME_HASH, &our_id.pub_enc_key));
+    }
+}
@@ -7,7 +7,7 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{BootstrapperRole, NameHash};
+use crate::common::{BootstrapperRole, HandshakeAuth, NameHash, PeerInfo, ProtocolInfo};
 use crate::PeerId;
 use safe_crypto::PublicEncryptKey;
 use std::collections::HashSet;
@@ -16,28 +16,92 @@ use std::net::SocketAddr;
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Message {
     Heartbeat,
-    /// Carries a list of our listener addresses in case remote peer wants to check our
-    /// external reachability.
-    BootstrapRequest(PeerId, NameHash, BootstrapperRole),
-    /// Connection listener sends this message to the bootstrapee together with the peer ID that
-    /// runs connection listener.
-    BootstrapGranted(PeerId),
+    /// Bootstrap request of protocol version 1, whose handshake wasn't signed. Only kept so
+    /// that such peers can be told their version is incompatible.
+    BootstrapRequestV1(PeerId, NameHash, BootstrapperRole, ProtocolInfo),
+    /// Bootstrap grant of protocol version 1.
+    BootstrapGrantedV1(PeerId, ProtocolInfo),
     BootstrapDenied(BootstrapDenyReason),
     EchoAddrReq(PublicEncryptKey),
     EchoAddrResp(SocketAddr),
     ChooseConnection,
-    /// Send this message to initiate connection with remote peer. This message carries our ID,
-    /// network name hash ad list of public IP:port pairs.
-    ConnectRequest(PeerId, NameHash, HashSet<SocketAddr>),
-    /// Response of accepted connection that carries remote peer's ID and network name hash.
-    ConnectResponse(PeerId, NameHash),
+    /// Connect request of protocol version 1, whose handshake wasn't signed. Only kept so that
+    /// such peers can be told their version is incompatible.
+    ConnectRequestV1(PeerId, NameHash, HashSet<SocketAddr>, ProtocolInfo),
+    /// Connect response of protocol version 1.
+    ConnectResponseV1(PeerId, NameHash, ProtocolInfo),
     Data(Vec<u8>),
+    /// Asks a relay node to forward the end-to-end encrypted payload to the given peer.
+    RelayRequest(PeerId, Vec<u8>),
+    /// Payload forwarded by a relay node from the given peer.
+    Relayed(PeerId, Vec<u8>),
+    /// Relay node couldn't forward our payload to the given peer.
+    RelayFailed(PeerId),
+    /// Part of a serialised `Data` or `TrackedData` message too large to be sent at once:
+    /// message ID, fragment index, fragment count and the fragment itself.
+    DataFragment(u64, u32, u32, Vec<u8>),
+    /// User data which the receiver acknowledges with `Ack` carrying the same ID.
+    TrackedData(u64, Vec<u8>),
+    Ack(u64),
+    /// Heartbeat which the receiver answers with `HeartbeatPong` carrying the same nonce, so that
+    /// the sender can measure the round trip time.
+    HeartbeatPing(u64),
+    HeartbeatPong(u64),
+    /// Asks a connected node for a sample of the contacts it knows.
+    PeerExchangeRequest,
+    /// Our listeners and contacts from our bootstrap cache.
+    PeerExchangeResponse(Vec<PeerInfo>),
+    /// Starts replacing the session key. Carries the sender's new ephemeral public key.
+    Rekey(PublicEncryptKey),
+    /// Answers `Rekey` with the responder's new ephemeral public key. Anything the responder
+    /// sends after it is encrypted with the new session key.
+    RekeyAck(PublicEncryptKey),
+    /// Last message the rekey initiator encrypts with the old session key.
+    RekeyDone,
+    /// Carries a list of our listener addresses in case remote peer wants to check our
+    /// external reachability, the protocol version and features we support and our signed
+    /// ephemeral key for the session.
+    BootstrapRequest(
+        PeerId,
+        NameHash,
+        BootstrapperRole,
+        ProtocolInfo,
+        HandshakeAuth,
+    ),
+    /// Connection listener sends this message to the bootstrapee together with the peer ID that
+    /// runs connection listener, its protocol info and its signed ephemeral key for the
+    /// bootstrapee's session.
+    BootstrapGranted(PeerId, ProtocolInfo, HandshakeAuth),
+    /// Send this message to initiate connection with remote peer. This message carries our ID,
+    /// network name hash ad list of public IP:port pairs, our protocol info and our signed
+    /// ephemeral key for the session.
+    ConnectRequest(
+        PeerId,
+        NameHash,
+        HashSet<SocketAddr>,
+        ProtocolInfo,
+        HandshakeAuth,
+    ),
+    /// Response of accepted connection that carries remote peer's ID, network name hash, protocol
+    /// info and its signed ephemeral key for the requester's session.
+    ConnectResponse(PeerId, NameHash, ProtocolInfo, HandshakeAuth),
 }
 
+/// Why a peer refused our bootstrap request.
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum BootstrapDenyReason {
+    /// The peer is on a network with a different name.
     InvalidNameHash,
+    /// We asked to bootstrap as a node, but the peer couldn't connect back to us.
     FailedExternalReachability,
+    /// Our IP address is not in the peer's `whitelisted_node_ips`.
     NodeNotWhitelisted,
+    /// Our IP address is not in the peer's `whitelisted_client_ips`.
     ClientNotWhitelisted,
+    /// The peer doesn't speak our protocol version any more. Carries the peer's own version.
+    IncompatibleVersion(u32),
+    /// Our handshake signature didn't match the signing key in our `PeerId`.
+    InvalidSignature,
+    /// The peer has reached its `connection_limits` and won't take another connection.
+    AtCapacity,
 }
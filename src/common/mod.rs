@@ -7,13 +7,19 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+pub use self::backoff::Backoff;
 pub use self::core::{spawn_event_loop, Core, CoreMessage, CoreTimer, EventLoop};
 pub use self::error::CommonError;
+pub use self::handshake::{set_session_key, HandshakeAuth, HandshakeNonce};
 pub use self::message::{BootstrapDenyReason, Message};
+pub use self::protocol::{Features, ProtocolInfo};
+pub use self::rtt::RttEstimator;
+pub use self::socket::{Socket, Transport};
 pub use self::state::State;
+pub use self::udp::{UdpEndpoint, UdpListener, UdpPacket, UdpStream};
 use safe_crypto::PublicEncryptKey;
 use std::collections::HashSet;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 
 pub const HASH_SIZE: usize = 32;
 pub type NameHash = [u8; HASH_SIZE];
@@ -74,7 +80,23 @@ pub fn ipv4_addr(a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddr {
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port))
 }
 
+/// Unspecified address with port 0 of the same IP family as the given address. Sockets bound to
+/// it are able to connect to the given address.
+pub fn unspecified_addr_like(addr: &SocketAddr) -> SocketAddr {
+    let ip = match *addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+    };
+    SocketAddr::new(ip, 0)
+}
+
+mod backoff;
 mod core;
 mod error;
+mod handshake;
 mod message;
+mod protocol;
+mod rtt;
+mod socket;
 mod state;
+mod udp;
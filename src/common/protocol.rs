@@ -0,0 +1,109 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+/// Version of the wire protocol we speak. Bump it when the handshake or message format changes
+/// in a way older peers can't cope with.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// The oldest protocol version we still accept connections from. Version 2 signs the handshake
+/// and derives the session key from ephemeral keys, which version 1 peers don't understand.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Set of optional protocol features. Messages belonging to a feature are only sent to peers
+/// which advertised it during the handshake, so new message types can be rolled out without
+/// breaking peers that don't know about them yet.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Features(u64);
+
+impl Features {
+    /// `HeartbeatPing` and `HeartbeatPong` messages used to measure round trip time.
+    pub const HEARTBEAT_PING: Features = Features(1);
+    /// `PeerExchangeRequest` and `PeerExchangeResponse` messages.
+    pub const PEER_EXCHANGE: Features = Features(1 << 1);
+    /// `Rekey`, `RekeyAck` and `RekeyDone` messages used to replace the session key.
+    pub const REKEY: Features = Features(1 << 2);
+
+    /// Features this version of crust supports.
+    pub fn supported() -> Self {
+        Features(Self::HEARTBEAT_PING.0 | Self::PEER_EXCHANGE.0 | Self::REKEY.0)
+    }
+
+    /// Returns true if all features in `other` are set in `self`.
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Features set in both `self` and `other`. Bits we don't know about are dropped.
+    pub fn intersection(self, other: Features) -> Self {
+        Features(self.0 & other.0 & Self::supported().0)
+    }
+}
+
+/// Protocol version and features a peer advertises during the handshake.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ProtocolInfo {
+    /// Protocol version the peer speaks.
+    pub version: u32,
+    /// Optional features the peer supports.
+    pub features: Features,
+}
+
+impl ProtocolInfo {
+    /// What we advertise to remote peers.
+    pub fn ours() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            features: Features::supported(),
+        }
+    }
+
+    /// Returns true if we can talk to a peer advertising this protocol info.
+    pub fn is_compatible(&self) -> bool {
+        self.version >= MIN_PROTOCOL_VERSION
+    }
+
+    /// Features both we and the peer advertising this protocol info support.
+    pub fn negotiated_features(&self) -> Features {
+        Features::supported().intersection(self.features)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiated_features_are_common_to_both_peers() {
+        let theirs = ProtocolInfo {
+            version: PROTOCOL_VERSION,
+            features: Features::HEARTBEAT_PING,
+        };
+        let features = theirs.negotiated_features();
+        assert!(features.contains(Features::HEARTBEAT_PING));
+        assert!(!features.contains(Features::PEER_EXCHANGE));
+    }
+
+    #[test]
+    fn unknown_features_are_ignored() {
+        let theirs = ProtocolInfo {
+            version: PROTOCOL_VERSION + 1,
+            features: Features(u64::max_value()),
+        };
+        assert!(theirs.is_compatible());
+        assert_eq!(theirs.negotiated_features(), Features::supported());
+    }
+
+    #[test]
+    fn versions_below_minimum_are_incompatible() {
+        let theirs = ProtocolInfo {
+            version: MIN_PROTOCOL_VERSION - 1,
+            features: Features::supported(),
+        };
+        assert!(!theirs.is_compatible());
+    }
+}
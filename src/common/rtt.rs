@@ -0,0 +1,80 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use std::time::Duration;
+
+/// Smoothed round trip time and its variation, as calculated in RFC 6298.
+#[derive(Default)]
+pub struct RttEstimator {
+    /// `None` until the first sample.
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+}
+
+impl RttEstimator {
+    /// Adds a sample and returns the smoothed round trip time.
+    pub fn update(&mut self, sample: Duration) -> Duration {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = sample / 2;
+                sample
+            }
+            Some(srtt) => {
+                let deviation = if srtt > sample {
+                    srtt - sample
+                } else {
+                    sample - srtt
+                };
+                self.rttvar = self.rttvar * 3 / 4 + deviation / 4;
+                srtt * 7 / 8 + sample / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        srtt
+    }
+
+    /// Retransmission timeout, i.e. how long to wait for an acknowledgement before assuming the
+    /// packet was lost. `None` until the first sample.
+    pub fn rto(&self) -> Option<Duration> {
+        self.srtt.map(|srtt| srtt + self.rttvar * 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.rto(), None);
+
+        assert_eq!(
+            rtt.update(Duration::from_millis(100)),
+            Duration::from_millis(100)
+        );
+        assert_eq!(rtt.rttvar, Duration::from_millis(50));
+        assert_eq!(rtt.rto(), Some(Duration::from_millis(300)));
+
+        assert_eq!(
+            rtt.update(Duration::from_millis(180)),
+            Duration::from_millis(110)
+        );
+        assert_eq!(
+            rtt.rttvar,
+            Duration::from_millis(57) + Duration::from_micros(500)
+        );
+
+        assert_eq!(
+            rtt.update(Duration::from_millis(110)),
+            Duration::from_millis(110)
+        );
+        assert_eq!(rtt.rttvar, Duration::from_micros(43_125));
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{Core, UdpEndpoint, UdpStream};
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use socket_collection::{DecryptContext, EncryptContext, Priority, SocketError, TcpSock};
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+/// Transport protocol of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Transport {
+    /// TCP.
+    Tcp,
+    /// Reliable, congestion controlled stream over UDP. It gets through NATs which only let UDP
+    /// hole punching through.
+    Udp,
+}
+
+/// Connection over either transport. Both are read and written the same way.
+pub enum Socket {
+    Tcp(TcpSock),
+    Udp(UdpStream),
+}
+
+impl Socket {
+    /// Connects to `addr` over `transport`. UDP connections are opened from `udp_endpoint` if
+    /// given, from a new endpoint bound to an ephemeral port otherwise.
+    pub fn connect<T>(
+        core: &mut Core<T>,
+        poll: &Poll,
+        transport: Transport,
+        addr: &SocketAddr,
+        udp_endpoint: Option<Rc<RefCell<UdpEndpoint>>>,
+    ) -> Result<Self, SocketError> {
+        Ok(match transport {
+            Transport::Tcp => Socket::Tcp(TcpSock::connect(addr)?),
+            Transport::Udp => Socket::Udp(UdpStream::connect(core, poll, addr, udp_endpoint)?),
+        })
+    }
+
+    pub fn transport(&self) -> Transport {
+        match *self {
+            Socket::Tcp(_) => Transport::Tcp,
+            Socket::Udp(_) => Transport::Udp,
+        }
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, SocketError> {
+        match *self {
+            Socket::Tcp(ref sock) => sock.peer_addr(),
+            Socket::Udp(ref sock) => sock.peer_addr(),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, SocketError> {
+        match *self {
+            Socket::Tcp(ref sock) => sock.local_addr(),
+            Socket::Udp(ref sock) => sock.local_addr(),
+        }
+    }
+
+    pub fn set_encrypt_ctx(&mut self, enc_ctx: EncryptContext) -> Result<(), SocketError> {
+        match *self {
+            Socket::Tcp(ref mut sock) => sock.set_encrypt_ctx(enc_ctx),
+            Socket::Udp(ref mut sock) => sock.set_encrypt_ctx(enc_ctx),
+        }
+    }
+
+    pub fn set_decrypt_ctx(&mut self, dec_ctx: DecryptContext) -> Result<(), SocketError> {
+        match *self {
+            Socket::Tcp(ref mut sock) => sock.set_decrypt_ctx(dec_ctx),
+            Socket::Udp(ref mut sock) => sock.set_decrypt_ctx(dec_ctx),
+        }
+    }
+
+    pub fn read<T: Serialize + DeserializeOwned>(&mut self) -> Result<Option<T>, SocketError> {
+        match *self {
+            Socket::Tcp(ref mut sock) => sock.read(),
+            Socket::Udp(ref mut sock) => sock.read(),
+        }
+    }
+
+    pub fn write<T: Serialize>(&mut self, msg: Option<(T, Priority)>) -> Result<bool, SocketError> {
+        match *self {
+            Socket::Tcp(ref mut sock) => sock.write(msg),
+            Socket::Udp(ref mut sock) => sock.write(msg),
+        }
+    }
+}
+
+impl Default for Socket {
+    fn default() -> Self {
+        Socket::Tcp(Default::default())
+    }
+}
+
+impl From<TcpSock> for Socket {
+    fn from(sock: TcpSock) -> Self {
+        Socket::Tcp(sock)
+    }
+}
+
+impl From<UdpStream> for Socket {
+    fn from(sock: UdpStream) -> Self {
+        Socket::Udp(sock)
+    }
+}
+
+impl Evented for Socket {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref sock) => sock.register(poll, token, interest, opts),
+            Socket::Udp(ref sock) => sock.register(poll, token, interest, opts),
+        }
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref sock) => sock.reregister(poll, token, interest, opts),
+            Socket::Udp(ref sock) => sock.reregister(poll, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        match *self {
+            Socket::Tcp(ref sock) => sock.deregister(poll),
+            Socket::Udp(ref sock) => sock.deregister(poll),
+        }
+    }
+}
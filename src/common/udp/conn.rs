@@ -0,0 +1,705 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::packet::{Packet, MAX_PAYLOAD};
+use crate::common::RttEstimator;
+use mio::{Ready, SetReadiness};
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::ErrorKind;
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Packets we buffer on the receiving side, in order or not.
+const RECV_WINDOW: u32 = 256;
+/// Bytes queued for sending at which the stream stops handing over further messages.
+const SEND_BUFFER: usize = 256 * 1024;
+const INITIAL_CWND: u32 = 4;
+const INITIAL_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 200;
+const MAX_RTO_MS: u64 = 10_000;
+/// Duplicate acknowledgements after which the missing packet is retransmitted right away.
+const DUP_ACK_THRESHOLD: u32 = 3;
+/// The connection breaks if the peer doesn't respond for this long while we wait for it.
+const DEAD_PEER_TIMEOUT_SEC: u64 = 30;
+/// Connecting fails if the peer doesn't accept after this many retransmissions of `Syn`, i.e.
+/// within about 7 seconds.
+const MAX_SYN_RETRIES: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// We sent `Syn` and wait for the peer to accept.
+    Connecting,
+    Established,
+    /// Carries the error reads and writes fail with, `None` if closed gracefully.
+    Closed(Option<ErrorKind>),
+}
+
+struct Sent {
+    payload: Vec<u8>,
+    sent_at: Instant,
+    retransmitted: bool,
+}
+
+/// Reliable, ordered byte stream to a single peer. Retransmissions and congestion control follow
+/// TCP NewReno.
+///
+/// The connection never touches the socket: the endpoint hands it the packets it receives and
+/// sends whatever the connection queues in its outbox.
+pub struct Conn {
+    id: u32,
+    peer: SocketAddr,
+    initiator: bool,
+    phase: Phase,
+    /// Set once the stream is dropped. The connection lingers until its data is delivered.
+    closing: bool,
+    outbox: Vec<Packet>,
+    readiness: SetReadiness,
+    last_heard: Instant,
+    rtt: RttEstimator,
+    rto: Duration,
+    syn_sent_at: Instant,
+    syn_retries: u32,
+    /// Cookie the peer asked us to repeat in `Syn`.
+    cookie: u64,
+
+    next_seq: u64,
+    unsent: VecDeque<Vec<u8>>,
+    unsent_bytes: usize,
+    in_flight: BTreeMap<u64, Sent>,
+    /// Congestion window in packets.
+    cwnd: u32,
+    /// Packets acknowledged since `cwnd` last grew during congestion avoidance.
+    cwnd_acked: u32,
+    ssthresh: u32,
+    peer_window: u32,
+    dup_acks: u32,
+    /// While recovering from a loss, the sequence number everything below which has to be
+    /// acknowledged to leave the recovery.
+    recovery: Option<u64>,
+
+    recv_next: u64,
+    out_of_order: BTreeMap<u64, Vec<u8>>,
+    received: Vec<u8>,
+    ack_due: bool,
+    advertised_window: u32,
+}
+
+impl Conn {
+    /// Connection we open. Keeps sending `Syn` until the peer accepts.
+    pub fn connect(id: u32, peer: SocketAddr, readiness: SetReadiness, now: Instant) -> Self {
+        let mut conn = Self::new(id, peer, true, readiness, now);
+        conn.outbox.push(Packet::Syn { id, cookie: 0 });
+        conn
+    }
+
+    /// Connection the peer opened with `Syn`, carrying the cookie the endpoint asked for.
+    pub fn accept(id: u32, peer: SocketAddr, readiness: SetReadiness, now: Instant) -> Self {
+        let mut conn = Self::new(id, peer, false, readiness, now);
+        conn.phase = Phase::Established;
+        conn.outbox.push(Packet::SynAck { id });
+        conn
+    }
+
+    fn new(
+        id: u32,
+        peer: SocketAddr,
+        initiator: bool,
+        readiness: SetReadiness,
+        now: Instant,
+    ) -> Self {
+        Self {
+            id,
+            peer,
+            initiator,
+            phase: Phase::Connecting,
+            closing: false,
+            outbox: Vec::new(),
+            readiness,
+            last_heard: now,
+            rtt: RttEstimator::default(),
+            rto: Duration::from_millis(INITIAL_RTO_MS),
+            syn_sent_at: now,
+            syn_retries: 0,
+            cookie: 0,
+            next_seq: 0,
+            unsent: VecDeque::new(),
+            unsent_bytes: 0,
+            in_flight: BTreeMap::new(),
+            cwnd: INITIAL_CWND,
+            cwnd_acked: 0,
+            ssthresh: RECV_WINDOW,
+            peer_window: RECV_WINDOW,
+            dup_acks: 0,
+            recovery: None,
+            recv_next: 0,
+            out_of_order: BTreeMap::new(),
+            received: Vec::new(),
+            ack_due: false,
+            advertised_window: RECV_WINDOW,
+        }
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match self.phase {
+            Phase::Closed(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Error the connection broke with.
+    pub fn error(&self) -> Option<ErrorKind> {
+        match self.phase {
+            Phase::Closed(error) => error,
+            _ => None,
+        }
+    }
+
+    /// Whether the stream may queue more data.
+    pub fn has_room(&self) -> bool {
+        self.unsent_bytes < SEND_BUFFER
+    }
+
+    /// Queues data for sending.
+    pub fn write(&mut self, data: &[u8]) {
+        for chunk in data.chunks(MAX_PAYLOAD) {
+            self.unsent_bytes += chunk.len();
+            self.unsent.push_back(chunk.to_vec());
+        }
+    }
+
+    /// Moves the data received in order to `buf`.
+    pub fn read(&mut self, buf: &mut Vec<u8>) {
+        if self.received.is_empty() {
+            return;
+        }
+        buf.extend_from_slice(&self.received);
+        self.received.clear();
+        // Tell the peer it may send again, if we asked it to slow down.
+        if self.advertised_window < RECV_WINDOW / 2 {
+            self.ack_due = true;
+        }
+    }
+
+    /// Closes the connection once all queued data is acknowledged.
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    /// Drops all queued data and fails further reads and writes with `error`.
+    pub fn abort(&mut self, error: ErrorKind) {
+        self.phase = Phase::Closed(Some(error));
+        self.unsent.clear();
+        self.unsent_bytes = 0;
+        self.in_flight.clear();
+        self.out_of_order.clear();
+    }
+
+    pub fn handle(&mut self, packet: Packet, now: Instant) {
+        if self.is_closed() {
+            return;
+        }
+        self.last_heard = now;
+
+        match packet {
+            Packet::Syn { id, .. } => {
+                // The peer didn't get our `SynAck`.
+                if !self.initiator {
+                    self.outbox.push(Packet::SynAck { id });
+                }
+            }
+            Packet::Cookie { id, cookie } => {
+                // Repeating `Syn` right away doesn't count as a retry.
+                if self.phase == Phase::Connecting {
+                    self.cookie = cookie;
+                    self.syn_sent_at = now;
+                    self.outbox.push(Packet::Syn { id, cookie });
+                }
+            }
+            Packet::SynAck { .. } => self.establish(),
+            Packet::Data {
+                seq,
+                ack,
+                window,
+                payload,
+                ..
+            } => {
+                self.establish();
+                self.handle_ack(ack, window, now, false);
+                self.handle_data(seq, payload);
+            }
+            Packet::Ack { ack, window, .. } => {
+                self.establish();
+                self.handle_ack(ack, window, now, true);
+            }
+            Packet::Fin { .. } => self.phase = Phase::Closed(None),
+            Packet::Reset { .. } => {
+                let error = if self.phase == Phase::Connecting {
+                    ErrorKind::ConnectionRefused
+                } else {
+                    ErrorKind::ConnectionReset
+                };
+                self.abort(error);
+            }
+            // Endpoints answer echoes themselves.
+            Packet::Echo { .. } | Packet::EchoResp { .. } => (),
+        }
+    }
+
+    /// Queues as much data as the windows allow and an acknowledgement, if one is due.
+    pub fn transmit(&mut self, now: Instant) {
+        if self.phase == Phase::Established {
+            // With nothing in flight, a single packet probes whether the peer's window reopened.
+            let window = cmp::max(cmp::min(self.cwnd, self.peer_window), 1);
+            while (self.in_flight.len() as u32) < window {
+                let payload = match self.unsent.pop_front() {
+                    Some(payload) => payload,
+                    None => break,
+                };
+                self.unsent_bytes -= payload.len();
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                let packet = self.data_packet(seq, payload.clone());
+                self.outbox.push(packet);
+                let _ = self.in_flight.insert(
+                    seq,
+                    Sent {
+                        payload,
+                        sent_at: now,
+                        retransmitted: false,
+                    },
+                );
+            }
+        }
+        if self.ack_due {
+            let packet = self.ack_packet();
+            self.outbox.push(packet);
+        }
+        if self.closing && self.unsent.is_empty() && self.in_flight.is_empty() {
+            match self.phase {
+                Phase::Connecting => self.outbox.push(Packet::Reset { id: self.id }),
+                Phase::Established => self.outbox.push(Packet::Fin { id: self.id }),
+                Phase::Closed(_) => return,
+            }
+            self.phase = Phase::Closed(None);
+        }
+    }
+
+    /// Retransmits what wasn't acknowledged in time and gives up on unresponsive peers.
+    pub fn tick(&mut self, now: Instant) {
+        let waiting = match self.phase {
+            Phase::Connecting => {
+                if now - self.syn_sent_at >= self.rto {
+                    if self.syn_retries == MAX_SYN_RETRIES {
+                        return self.abort(ErrorKind::TimedOut);
+                    }
+                    self.syn_retries += 1;
+                    self.outbox.push(Packet::Syn {
+                        id: self.id,
+                        cookie: self.cookie,
+                    });
+                    self.syn_sent_at = now;
+                    self.back_off();
+                }
+                true
+            }
+            Phase::Established => {
+                let rto = self.rto;
+                let expired = self
+                    .in_flight
+                    .values()
+                    .next()
+                    .map_or(false, |sent| now - sent.sent_at >= rto);
+                if expired {
+                    self.ssthresh = self.halved_flight();
+                    self.cwnd = 1;
+                    self.cwnd_acked = 0;
+                    self.dup_acks = 0;
+                    // Further holes are retransmitted as partial acks arrive.
+                    self.recovery = Some(self.next_seq);
+                    self.back_off();
+                    self.retransmit_first(now);
+                }
+                !self.in_flight.is_empty()
+            }
+            Phase::Closed(_) => return,
+        };
+        if waiting && now - self.last_heard >= Duration::from_secs(DEAD_PEER_TIMEOUT_SEC) {
+            return self.abort(ErrorKind::TimedOut);
+        }
+        self.transmit(now);
+    }
+
+    pub fn drain_outbox(&mut self) -> Vec<Packet> {
+        mem::replace(&mut self.outbox, Vec::new())
+    }
+
+    /// Wakes the stream up if there's something to read or room to write.
+    pub fn update_readiness(&self) {
+        let closed = self.is_closed();
+        let mut ready = Ready::empty();
+        if closed || !self.received.is_empty() {
+            ready |= Ready::readable();
+        }
+        if closed || (self.phase == Phase::Established && self.has_room()) {
+            ready |= Ready::writable();
+        }
+        let _ = self.readiness.set_readiness(ready);
+    }
+
+    fn establish(&mut self) {
+        if self.phase == Phase::Connecting {
+            self.phase = Phase::Established;
+            self.rto = Duration::from_millis(INITIAL_RTO_MS);
+        }
+    }
+
+    fn handle_ack(&mut self, ack: u64, window: u32, now: Instant, pure_ack: bool) {
+        // Window updates aren't duplicate acknowledgements, even if they don't ack anything new.
+        let window_changed = window != self.peer_window;
+        self.peer_window = window;
+
+        let first_unacked = self.first_unacked();
+        if ack > first_unacked && ack <= self.next_seq {
+            let acked: Vec<u64> = self.in_flight.range(..ack).map(|(seq, _)| *seq).collect();
+            let mut sample = None;
+            let mut ambiguous = false;
+            for seq in &acked {
+                if let Some(sent) = self.in_flight.remove(seq) {
+                    ambiguous |= sent.retransmitted;
+                    sample = Some(now - sent.sent_at);
+                }
+            }
+            // The ack might be for either transmission of a retransmitted packet, so it's not
+            // sampled (Karn's algorithm).
+            if let (Some(sample), false) = (sample, ambiguous) {
+                let _ = self.rtt.update(sample);
+            }
+            self.rto = self.rtt_rto();
+            self.dup_acks = 0;
+            match self.recovery {
+                // Partial ack, the next packet was lost as well.
+                Some(end) if ack < end => self.retransmit_first(now),
+                Some(_) => self.recovery = None,
+                None => self.grow_cwnd(acked.len() as u32),
+            }
+        } else if pure_ack && !window_changed && ack == first_unacked && !self.in_flight.is_empty()
+        {
+            self.dup_acks += 1;
+            if self.dup_acks == DUP_ACK_THRESHOLD && self.recovery.is_none() {
+                self.ssthresh = self.halved_flight();
+                self.cwnd = self.ssthresh;
+                self.recovery = Some(self.next_seq);
+                self.retransmit_first(now);
+            }
+        }
+    }
+
+    fn handle_data(&mut self, seq: u64, payload: Vec<u8>) {
+        if seq < self.recv_next || self.out_of_order.contains_key(&seq) {
+            // Our acknowledgement got lost, repeat it.
+            let packet = self.ack_packet();
+            self.outbox.push(packet);
+        } else if seq >= self.recv_next + u64::from(RECV_WINDOW) {
+            trace!("Dropping packet {} beyond the receive window", seq);
+        } else if seq == self.recv_next {
+            // The reader fell behind. The peer probes again once the window reopens.
+            if self.received.len() >= RECV_WINDOW as usize * MAX_PAYLOAD {
+                let packet = self.ack_packet();
+                return self.outbox.push(packet);
+            }
+            self.received.extend_from_slice(&payload);
+            self.recv_next += 1;
+            while let Some(payload) = self.out_of_order.remove(&self.recv_next) {
+                self.received.extend_from_slice(&payload);
+                self.recv_next += 1;
+            }
+            self.ack_due = true;
+        } else {
+            let _ = self.out_of_order.insert(seq, payload);
+            // Duplicate acknowledgement tells the peer that a packet is missing.
+            let packet = self.ack_packet();
+            self.outbox.push(packet);
+        }
+    }
+
+    fn retransmit_first(&mut self, now: Instant) {
+        let (seq, payload) = match self.in_flight.iter_mut().next() {
+            Some((seq, sent)) => {
+                sent.sent_at = now;
+                sent.retransmitted = true;
+                (*seq, sent.payload.clone())
+            }
+            None => return,
+        };
+        let packet = self.data_packet(seq, payload);
+        self.outbox.push(packet);
+    }
+
+    fn data_packet(&mut self, seq: u64, payload: Vec<u8>) -> Packet {
+        self.ack_due = false;
+        self.advertised_window = self.free_window();
+        Packet::Data {
+            id: self.id,
+            seq,
+            ack: self.recv_next,
+            window: self.advertised_window,
+            payload,
+        }
+    }
+
+    fn ack_packet(&mut self) -> Packet {
+        self.ack_due = false;
+        self.advertised_window = self.free_window();
+        Packet::Ack {
+            id: self.id,
+            ack: self.recv_next,
+            window: self.advertised_window,
+        }
+    }
+
+    /// Only data the stream hasn't read yet shrinks the window. Packets received out of order
+    /// don't, so that the acknowledgements they trigger count as duplicates on the sender's side.
+    fn free_window(&self) -> u32 {
+        let unread = (self.received.len() + MAX_PAYLOAD - 1) / MAX_PAYLOAD;
+        RECV_WINDOW.saturating_sub(unread as u32)
+    }
+
+    fn first_unacked(&self) -> u64 {
+        self.in_flight
+            .keys()
+            .next()
+            .cloned()
+            .unwrap_or(self.next_seq)
+    }
+
+    fn halved_flight(&self) -> u32 {
+        cmp::max(self.in_flight.len() as u32 / 2, 2)
+    }
+
+    fn grow_cwnd(&mut self, acked: u32) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += acked;
+        } else {
+            self.cwnd_acked += acked;
+            if self.cwnd_acked >= self.cwnd {
+                self.cwnd_acked -= self.cwnd;
+                self.cwnd += 1;
+            }
+        }
+        self.cwnd = cmp::min(self.cwnd, RECV_WINDOW);
+    }
+
+    fn back_off(&mut self) {
+        self.rto = cmp::min(self.rto * 2, Duration::from_millis(MAX_RTO_MS));
+    }
+
+    fn rtt_rto(&self) -> Duration {
+        let rto = self
+            .rtt
+            .rto()
+            .unwrap_or_else(|| Duration::from_millis(INITIAL_RTO_MS));
+        cmp::max(
+            cmp::min(rto, Duration::from_millis(MAX_RTO_MS)),
+            Duration::from_millis(MIN_RTO_MS),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+    use mio::Registration;
+
+    const ID: u32 = 7;
+
+    /// Both ends of a connection. Registrations are kept alive, so that readiness can be set.
+    struct Link {
+        a: Conn,
+        b: Conn,
+        now: Instant,
+        _registrations: (Registration, Registration),
+    }
+
+    impl Link {
+        fn new() -> Self {
+            let now = Instant::now();
+            let (reg_a, readiness_a) = Registration::new2();
+            let (reg_b, readiness_b) = Registration::new2();
+            let mut a = Conn::connect(ID, ipv4_addr(127, 0, 0, 1, 2), readiness_a, now);
+            assert_eq!(a.drain_outbox(), vec![Packet::Syn { id: ID, cookie: 0 }]);
+            let b = Conn::accept(ID, ipv4_addr(127, 0, 0, 1, 1), readiness_b, now);
+            Self {
+                a,
+                b,
+                now,
+                _registrations: (reg_a, reg_b),
+            }
+        }
+
+        /// Advances the time by 10 ms and exchanges queued packets, dropping those `lose`
+        /// picks.
+        fn step<F: FnMut(&Packet) -> bool>(&mut self, mut lose: F) {
+            self.now += Duration::from_millis(10);
+            let now = self.now;
+            self.a.tick(now);
+            self.b.tick(now);
+            for packet in self.a.drain_outbox() {
+                if !lose(&packet) {
+                    self.b.handle(packet, now);
+                }
+            }
+            self.b.transmit(now);
+            for packet in self.b.drain_outbox() {
+                if !lose(&packet) {
+                    self.a.handle(packet, now);
+                }
+            }
+            self.a.transmit(now);
+        }
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn data_is_delivered_in_order() {
+        let mut link = Link::new();
+        let sent = data(100 * 1024);
+        link.a.write(&sent);
+        link.step(|_| false);
+        assert!(!link.a.is_closed());
+
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            link.step(|_| false);
+            link.b.read(&mut received);
+        }
+        assert_eq!(received, sent);
+        assert!(link.a.in_flight.is_empty());
+    }
+
+    #[test]
+    fn lost_packets_are_retransmitted() {
+        let mut link = Link::new();
+        let sent = data(200 * 1024);
+        link.a.write(&sent);
+
+        let mut received = Vec::new();
+        let mut count = 0;
+        for _ in 0..2000 {
+            link.step(|packet| match *packet {
+                Packet::Data { .. } | Packet::Ack { .. } => {
+                    count += 1;
+                    count % 10 == 0
+                }
+                _ => false,
+            });
+            link.b.read(&mut received);
+        }
+        assert_eq!(received, sent);
+        assert!(link.a.in_flight.is_empty());
+        assert!(!link.a.is_closed());
+    }
+
+    #[test]
+    fn slow_reader_throttles_sender() {
+        let mut link = Link::new();
+        let sent = data(RECV_WINDOW as usize * MAX_PAYLOAD * 2);
+        link.a.write(&sent);
+        for _ in 0..DEAD_PEER_TIMEOUT_SEC * 200 {
+            link.step(|_| false);
+        }
+        assert_eq!(link.b.received.len(), RECV_WINDOW as usize * MAX_PAYLOAD);
+        assert_eq!(link.b.advertised_window, 0);
+        assert!(!link.a.is_closed());
+
+        let mut received = Vec::new();
+        for _ in 0..1000 {
+            link.step(|_| false);
+            link.b.read(&mut received);
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn closing_waits_for_data_to_be_acknowledged() {
+        let mut link = Link::new();
+        let sent = data(10 * 1024);
+        link.a.write(&sent);
+        link.a.close();
+
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            link.step(|_| false);
+            link.b.read(&mut received);
+        }
+        assert_eq!(received, sent);
+        assert_eq!(link.a.error(), None);
+        assert!(link.a.is_closed());
+        assert!(link.b.is_closed());
+    }
+
+    #[test]
+    fn unresponsive_peer_breaks_connection() {
+        let mut link = Link::new();
+        link.step(|_| false);
+        link.a.write(&data(1000));
+        let steps = DEAD_PEER_TIMEOUT_SEC * 100 + 1;
+        for _ in 0..steps {
+            link.step(|_| true);
+        }
+        assert_eq!(link.a.error(), Some(ErrorKind::TimedOut));
+        // Nothing was expected from the peer, so its end is still fine.
+        assert!(!link.b.is_closed());
+    }
+
+    #[test]
+    fn connecting_times_out() {
+        let mut link = Link::new();
+        for _ in 0..800 {
+            link.step(|_| true);
+        }
+        assert_eq!(link.a.error(), Some(ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn syn_is_repeated_with_cookie() {
+        let mut link = Link::new();
+        link.a
+            .handle(Packet::Cookie { id: ID, cookie: 42 }, link.now);
+        assert_eq!(
+            link.a.drain_outbox(),
+            vec![Packet::Syn { id: ID, cookie: 42 }]
+        );
+
+        // Retransmissions carry the cookie as well.
+        link.now += Duration::from_millis(INITIAL_RTO_MS);
+        let now = link.now;
+        link.a.tick(now);
+        assert_eq!(
+            link.a.drain_outbox(),
+            vec![Packet::Syn { id: ID, cookie: 42 }]
+        );
+    }
+
+    #[test]
+    fn reset_refuses_connection() {
+        let mut link = Link::new();
+        link.a.handle(Packet::Reset { id: ID }, link.now);
+        assert_eq!(link.a.error(), Some(ErrorKind::ConnectionRefused));
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::conn::Conn;
+use super::packet::{Packet, ECHO_PADDING};
+use super::stream::UdpStream;
+use crate::common::{Core, CoreTimer, State};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use mio::net::UdpSocket;
+use mio::{Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio_extras::timer::Timeout;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+/// Longer datagrams are truncated and fail to deserialise.
+const MAX_DATAGRAM_SIZE: usize = 2048;
+/// Accepted connections waiting for `UdpListener::accept()`. Further peers are refused.
+///
+/// Peers get a connection only once they repeat the cookie we sent to their address, so spoofed
+/// `Syn`s don't take up any room.
+const MAX_BACKLOG: usize = 128;
+const TICK_INTERVAL_MS: u64 = 100;
+
+type ConnKey = (SocketAddr, u32);
+
+struct Incoming {
+    streams: VecDeque<UdpStream>,
+    readiness: SetReadiness,
+}
+
+/// UDP socket shared by all connections from its address. It's registered on the event loop to
+/// receive packets and to drive retransmissions, while connections are handed out as
+/// `UdpStream`s.
+pub struct UdpEndpoint {
+    token: Token,
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    conns: HashMap<ConnKey, Rc<RefCell<Conn>>>,
+    /// `Some` if we accept connections peers open to us.
+    incoming: Option<Incoming>,
+    tick: Option<Timeout>,
+    /// Makes the cookies we ask peers for unpredictable.
+    cookie_secret: [u8; 32],
+    self_weak: Weak<RefCell<UdpEndpoint>>,
+}
+
+impl UdpEndpoint {
+    /// Registers the socket on the event loop.
+    pub fn start<T>(
+        core: &mut Core<T>,
+        poll: &Poll,
+        socket: UdpSocket,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let token = core.get_new_token();
+        let local_addr = socket.local_addr()?;
+        poll.register(&socket, token, Ready::readable(), PollOpt::edge())?;
+
+        let endpoint = Rc::new(RefCell::new(UdpEndpoint {
+            token,
+            socket,
+            local_addr,
+            conns: HashMap::new(),
+            incoming: None,
+            tick: None,
+            cookie_secret: rand::random(),
+            self_weak: Weak::new(),
+        }));
+        endpoint.borrow_mut().self_weak = Rc::downgrade(&endpoint);
+        let _ = core.insert_state(token, endpoint.clone());
+        Ok(endpoint)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Opens a connection to `addr`.
+    pub fn connect<T>(&mut self, core: &mut Core<T>, addr: SocketAddr) -> UdpStream {
+        let mut id = rand::random();
+        while self.conns.contains_key(&(addr, id)) {
+            id = rand::random();
+        }
+        let (registration, readiness) = Registration::new2();
+        let conn = Rc::new(RefCell::new(Conn::connect(
+            id,
+            addr,
+            readiness,
+            Instant::now(),
+        )));
+        let _ = self.conns.insert((addr, id), conn.clone());
+        self.flush(&conn);
+        self.schedule_tick(core);
+        UdpStream::new(conn, self.self_weak.clone(), registration, self.local_addr)
+    }
+
+    /// Starts accepting connections. The returned registration turns readable whenever there's
+    /// a connection to accept.
+    pub fn listen(&mut self) -> Registration {
+        let (registration, readiness) = Registration::new2();
+        self.incoming = Some(Incoming {
+            streams: VecDeque::new(),
+            readiness,
+        });
+        registration
+    }
+
+    /// Returns the next connection a peer opened to us.
+    pub fn accept(&mut self) -> Option<UdpStream> {
+        let incoming = self.incoming.as_mut()?;
+        let stream = incoming.streams.pop_front();
+        if incoming.streams.is_empty() {
+            let _ = incoming.readiness.set_readiness(Ready::empty());
+        }
+        stream
+    }
+
+    /// Stops accepting connections. The endpoint keeps serving the connections it has and
+    /// terminates once they are closed.
+    pub fn stop_listening<T>(&mut self, core: &mut Core<T>, poll: &Poll) {
+        self.incoming = None;
+        if self.conns.is_empty() {
+            self.stop(core, poll);
+        }
+    }
+
+    /// Sends the data and the acknowledgements the connection has queued.
+    pub fn flush(&self, conn: &RefCell<Conn>) {
+        let mut conn = conn.borrow_mut();
+        conn.transmit(Instant::now());
+        let peer = conn.peer();
+        for packet in conn.drain_outbox() {
+            self.send(&packet, peer);
+        }
+        conn.update_readiness();
+    }
+
+    fn send(&self, packet: &Packet, addr: SocketAddr) {
+        let data = match serialise(packet) {
+            Ok(data) => data,
+            Err(e) => {
+                debug!("Failed to serialise UDP packet: {:?}", e);
+                return;
+            }
+        };
+        match self.socket.send_to(&data, &addr) {
+            Ok(_) => (),
+            // The packet is retransmitted, as if it got lost.
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => debug!("Failed to send UDP packet to {}: {}", addr, e),
+        }
+    }
+
+    /// Accepts a connection the peer opened, if we are listening and the backlog isn't full.
+    /// Peers which didn't repeat their cookie yet are asked for it.
+    fn admit(&mut self, addr: SocketAddr, id: u32, cookie: u64, now: Instant) -> bool {
+        if self.incoming.is_some() {
+            let expected = self.cookie(addr, id);
+            if cookie != expected {
+                self.send(
+                    &Packet::Cookie {
+                        id,
+                        cookie: expected,
+                    },
+                    addr,
+                );
+                return false;
+            }
+        }
+
+        let has_room = self
+            .incoming
+            .as_ref()
+            .map_or(false, |incoming| incoming.streams.len() < MAX_BACKLOG);
+        if !has_room {
+            self.send(&Packet::Reset { id }, addr);
+            return false;
+        }
+
+        let (registration, readiness) = Registration::new2();
+        let conn = Rc::new(RefCell::new(Conn::accept(id, addr, readiness, now)));
+        let _ = self.conns.insert((addr, id), conn.clone());
+        let stream = UdpStream::new(conn, self.self_weak.clone(), registration, self.local_addr);
+        if let Some(ref mut incoming) = self.incoming {
+            incoming.streams.push_back(stream);
+            let _ = incoming.readiness.set_readiness(Ready::readable());
+        }
+        true
+    }
+
+    /// Cookie the peer at `addr` has to repeat to open connection `id`. It's never zero, which
+    /// is what `Syn` carries before the peer got any cookie.
+    fn cookie(&self, addr: SocketAddr, id: u32) -> u64 {
+        let mut data = self.cookie_secret.to_vec();
+        data.extend_from_slice(format!("{}/{}", addr, id).as_bytes());
+        let hash = safe_crypto::hash(&data);
+        let cookie = hash[..8]
+            .iter()
+            .fold(0, |cookie, byte| cookie << 8 | u64::from(*byte));
+        cmp::max(cookie, 1)
+    }
+
+    /// Tells a peer which address its echo request came from, if we are listening.
+    fn answer_echo(&self, addr: SocketAddr, id: u32, padding: &[u8]) {
+        if self.incoming.is_none() || padding.len() < ECHO_PADDING {
+            return;
+        }
+        self.send(&Packet::EchoResp { id, addr }, addr);
+    }
+
+    fn schedule_tick<T>(&mut self, core: &mut Core<T>) {
+        if self.tick.is_none() {
+            self.tick = Some(core.set_timeout(
+                Duration::from_millis(TICK_INTERVAL_MS),
+                CoreTimer::new(self.token, 0),
+            ));
+        }
+    }
+
+    /// Fails all connections and tells their peers.
+    fn abort_all(&mut self) {
+        for (&(addr, id), conn) in &self.conns {
+            if let Ok(mut conn) = conn.try_borrow_mut() {
+                if !conn.is_closed() {
+                    conn.abort(ErrorKind::ConnectionAborted);
+                    conn.update_readiness();
+                    self.send(&Packet::Reset { id }, addr);
+                }
+            }
+        }
+        self.conns.clear();
+    }
+
+    fn stop<T>(&mut self, core: &mut Core<T>, poll: &Poll) {
+        if let Some(tick) = self.tick.take() {
+            let _ = core.cancel_timeout(&tick);
+        }
+        let _ = poll.deregister(&self.socket);
+        let _ = core.remove_state(self.token);
+    }
+}
+
+impl<T> State<T> for UdpEndpoint {
+    fn ready(&mut self, core: &mut Core<T>, _poll: &Poll, kind: Ready) {
+        if !kind.is_readable() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut touched = HashSet::new();
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                // Some platforms report ICMP port unreachable this way, other datagrams may follow.
+                Err(ref e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    debug!("Failed to receive UDP packet: {}", e);
+                    break;
+                }
+            };
+            let packet: Packet = match deserialise(&buf[..len]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    trace!("Invalid UDP packet from {}: {:?}", addr, e);
+                    continue;
+                }
+            };
+
+            let key = (addr, packet.id());
+            match self.conns.get(&key).cloned() {
+                Some(conn) => conn.borrow_mut().handle(packet, now),
+                None => match packet {
+                    Packet::Syn { id, cookie } => {
+                        if !self.admit(addr, id, cookie, now) {
+                            continue;
+                        }
+                    }
+                    Packet::Echo { id, ref padding } => {
+                        self.answer_echo(addr, id, padding);
+                        continue;
+                    }
+                    Packet::Reset { .. } | Packet::Cookie { .. } | Packet::EchoResp { .. } => {
+                        continue
+                    }
+                    _ => {
+                        self.send(&Packet::Reset { id: packet.id() }, addr);
+                        continue;
+                    }
+                },
+            }
+            let _ = touched.insert(key);
+        }
+
+        // Acknowledgements are only sent once all received packets are handled.
+        for key in touched {
+            if let Some(conn) = self.conns.get(&key) {
+                self.flush(conn);
+            }
+        }
+        if !self.conns.is_empty() {
+            self.schedule_tick(core);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core<T>, poll: &Poll, _timer_id: u8) {
+        self.tick = None;
+        let now = Instant::now();
+        for conn in self.conns.values() {
+            conn.borrow_mut().tick(now);
+            self.flush(conn);
+        }
+        self.conns.retain(|_, conn| !conn.borrow().is_closed());
+
+        if !self.conns.is_empty() {
+            self.schedule_tick(core);
+        } else if self.incoming.is_none() {
+            self.stop(core, poll);
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core<T>, poll: &Poll) {
+        self.abort_all();
+        self.incoming = None;
+        self.stop(core, poll);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+impl Drop for UdpEndpoint {
+    fn drop(&mut self) {
+        self.abort_all();
+    }
+}
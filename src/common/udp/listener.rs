@@ -0,0 +1,90 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::endpoint::UdpEndpoint;
+use super::stream::UdpStream;
+use crate::common::Core;
+use mio::net::UdpSocket;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, Token};
+use std::cell::RefCell;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+/// Accepts connections over UDP, used the same way as `TcpListener`. It's readable whenever
+/// there's a connection to accept.
+pub struct UdpListener {
+    endpoint: Rc<RefCell<UdpEndpoint>>,
+    registration: Registration,
+}
+
+impl UdpListener {
+    /// Listens on the given socket.
+    pub fn start<T>(core: &mut Core<T>, poll: &Poll, socket: UdpSocket) -> io::Result<Self> {
+        let endpoint = UdpEndpoint::start(core, poll, socket)?;
+        let registration = endpoint.borrow_mut().listen();
+        Ok(UdpListener {
+            endpoint,
+            registration,
+        })
+    }
+
+    pub fn accept(&self) -> io::Result<(UdpStream, SocketAddr)> {
+        let stream = self
+            .endpoint
+            .borrow_mut()
+            .accept()
+            .ok_or_else(|| io::Error::from(ErrorKind::WouldBlock))?;
+        let peer_addr = stream
+            .peer_addr()
+            .map_err(|e| io::Error::new(ErrorKind::Other, format!("{}", e)))?;
+        Ok((stream, peer_addr))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.endpoint.borrow().local_addr()
+    }
+
+    /// Endpoint the listener accepts connections on. Connections opened from it share the
+    /// listener's port, so that peers behind NATs are able to hole punch to each other.
+    pub fn endpoint(&self) -> &Rc<RefCell<UdpEndpoint>> {
+        &self.endpoint
+    }
+
+    /// Stops accepting connections. Those already accepted stay open.
+    pub fn close<T>(&self, core: &mut Core<T>, poll: &Poll) {
+        self.endpoint.borrow_mut().stop_listening(core, poll);
+    }
+}
+
+impl Evented for UdpListener {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        poll.register(&self.registration, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        poll.reregister(&self.registration, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Reliable, congestion controlled connections over UDP. A single `UdpEndpoint` multiplexes all
+//! connections from its socket, which appear as `UdpStream`s registered on the event loop like
+//! any other socket.
+
+pub use self::endpoint::UdpEndpoint;
+pub use self::listener::UdpListener;
+pub use self::packet::Packet as UdpPacket;
+pub use self::stream::UdpStream;
+
+mod conn;
+mod endpoint;
+mod listener;
+mod packet;
+mod stream;
@@ -0,0 +1,76 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use std::net::SocketAddr;
+
+/// Largest payload of a data packet. Together with the headers it stays below the minimum IPv6
+/// MTU, so packets are never fragmented on the way.
+pub const MAX_PAYLOAD: usize = 1200;
+/// Least padding of `Echo`, which makes it larger than `EchoResp`.
+pub const ECHO_PADDING: usize = 32;
+
+/// Datagram exchanged by UDP endpoints. Packets belong to the connection identified by the
+/// remote address and the ID picked by the peer which opened the connection.
+///
+/// Sequence numbers count data packets, not bytes. Acknowledgements are cumulative: `ack` is the
+/// sequence number of the first packet the sender hasn't received in order yet. `window` is the
+/// number of packets the sender is able to buffer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Packet {
+    /// Opens connection `id`. `cookie` is zero until the peer asks for it with `Cookie`.
+    Syn { id: u32, cookie: u64 },
+    /// Asks the sender of `Syn` to repeat it with `cookie`. That proves the sender receives
+    /// packets at its address before the peer sets up any state for the connection.
+    Cookie { id: u32, cookie: u64 },
+    /// Accepts connection `id`.
+    SynAck { id: u32 },
+    /// Carries a segment of the byte stream.
+    Data {
+        id: u32,
+        seq: u64,
+        ack: u64,
+        window: u32,
+        payload: Vec<u8>,
+    },
+    /// Acknowledges data without sending any.
+    Ack { id: u32, ack: u64, window: u32 },
+    /// The sender closed the connection once all its data was acknowledged.
+    Fin { id: u32 },
+    /// The connection is unknown to the sender or was aborted.
+    Reset { id: u32 },
+    /// Asks a listening endpoint which address the packet came from. The padding makes the
+    /// request larger than the response, so that spoofed requests don't amplify traffic.
+    Echo { id: u32, padding: Vec<u8> },
+    /// Answers `Echo` with the address it came from.
+    EchoResp { id: u32, addr: SocketAddr },
+}
+
+impl Packet {
+    /// Request for the address our socket is seen from.
+    pub fn echo(id: u32) -> Self {
+        Packet::Echo {
+            id,
+            padding: vec![0; ECHO_PADDING],
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match *self {
+            Packet::Syn { id, .. }
+            | Packet::Cookie { id, .. }
+            | Packet::SynAck { id }
+            | Packet::Data { id, .. }
+            | Packet::Ack { id, .. }
+            | Packet::Fin { id }
+            | Packet::Reset { id }
+            | Packet::Echo { id, .. }
+            | Packet::EchoResp { id, .. } => id,
+        }
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::conn::Conn;
+use super::endpoint::UdpEndpoint;
+use crate::common::{unspecified_addr_like, Core};
+use mio::net::UdpSocket;
+use mio::{Evented, Poll, PollOpt, Ready, Registration, Token};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use socket_collection::{DecryptContext, EncryptContext, Priority, SocketError};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+
+/// Every message is prefixed with its length as a big endian `u32`.
+const LEN_SIZE: usize = 4;
+/// Largest encrypted message. `ActiveConnection` splits larger ones into fragments.
+const MAX_MSG_SIZE: usize = 2 * 1024 * 1024;
+
+/// Connection over UDP, used the same way as `TcpSock`: messages are serialised, encrypted with
+/// the current context and sent in the order of their priority.
+pub struct UdpStream {
+    conn: Rc<RefCell<Conn>>,
+    endpoint: Weak<RefCell<UdpEndpoint>>,
+    registration: Registration,
+    local_addr: SocketAddr,
+    enc_ctx: EncryptContext,
+    dec_ctx: DecryptContext,
+    read_buf: Vec<u8>,
+    write_queue: BTreeMap<Priority, VecDeque<Vec<u8>>>,
+}
+
+impl UdpStream {
+    /// Connects to `addr` from the given endpoint, or from a new one bound to an ephemeral port.
+    pub fn connect<T>(
+        core: &mut Core<T>,
+        poll: &Poll,
+        addr: &SocketAddr,
+        endpoint: Option<Rc<RefCell<UdpEndpoint>>>,
+    ) -> io::Result<Self> {
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => {
+                let socket = UdpSocket::bind(&unspecified_addr_like(addr))?;
+                UdpEndpoint::start(core, poll, socket)?
+            }
+        };
+        let stream = endpoint.borrow_mut().connect(core, *addr);
+        Ok(stream)
+    }
+
+    pub fn new(
+        conn: Rc<RefCell<Conn>>,
+        endpoint: Weak<RefCell<UdpEndpoint>>,
+        registration: Registration,
+        local_addr: SocketAddr,
+    ) -> Self {
+        UdpStream {
+            conn,
+            endpoint,
+            registration,
+            local_addr,
+            enc_ctx: EncryptContext::null(),
+            dec_ctx: DecryptContext::null(),
+            read_buf: Vec::new(),
+            write_queue: BTreeMap::new(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, SocketError> {
+        Ok(self.conn.borrow().peer())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, SocketError> {
+        Ok(self.local_addr)
+    }
+
+    pub fn set_encrypt_ctx(&mut self, enc_ctx: EncryptContext) -> Result<(), SocketError> {
+        self.enc_ctx = enc_ctx;
+        Ok(())
+    }
+
+    pub fn set_decrypt_ctx(&mut self, dec_ctx: DecryptContext) -> Result<(), SocketError> {
+        self.dec_ctx = dec_ctx;
+        Ok(())
+    }
+
+    /// Returns the next message, or `None` if it hasn't fully arrived yet. Messages received
+    /// before the connection was closed are still returned.
+    pub fn read<T: Serialize + DeserializeOwned>(&mut self) -> Result<Option<T>, SocketError> {
+        let (closed, error) = {
+            let mut conn = self.conn.borrow_mut();
+            conn.read(&mut self.read_buf);
+            (conn.is_closed(), conn.error())
+        };
+        // Reading might have reopened our receive window.
+        self.flush();
+
+        if let Some(msg) = self.next_msg()? {
+            return Ok(Some(self.dec_ctx.decrypt(&msg)?));
+        }
+        match error {
+            Some(kind) => Err(io::Error::from(kind).into()),
+            None if closed => Err(SocketError::ZeroByteRead),
+            None => Ok(None),
+        }
+    }
+
+    /// Queues the message and sends as much of the queue as the connection takes. Returns
+    /// `true` if the queue is empty.
+    pub fn write<T: Serialize>(&mut self, msg: Option<(T, Priority)>) -> Result<bool, SocketError> {
+        {
+            let conn = self.conn.borrow();
+            if conn.is_closed() {
+                let kind = conn.error().unwrap_or(ErrorKind::BrokenPipe);
+                return Err(io::Error::from(kind).into());
+            }
+        }
+
+        if let Some((msg, priority)) = msg {
+            let data = self.enc_ctx.encrypt(&msg)?;
+            if data.len() > MAX_MSG_SIZE {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "Message too large").into());
+            }
+            let len = data.len() as u32;
+            let mut frame = Vec::with_capacity(LEN_SIZE + data.len());
+            frame.extend_from_slice(&[
+                (len >> 24) as u8,
+                (len >> 16) as u8,
+                (len >> 8) as u8,
+                len as u8,
+            ]);
+            frame.extend_from_slice(&data);
+            self.write_queue
+                .entry(priority)
+                .or_insert_with(VecDeque::new)
+                .push_back(frame);
+        }
+
+        let conn = self.conn.clone();
+        {
+            let mut conn = conn.borrow_mut();
+            while conn.has_room() {
+                match self.pop_frame() {
+                    Some(frame) => conn.write(&frame),
+                    None => break,
+                }
+            }
+        }
+        self.flush();
+        Ok(self.write_queue.is_empty())
+    }
+
+    fn pop_frame(&mut self) -> Option<Vec<u8>> {
+        let priority = *self.write_queue.keys().next()?;
+        let (frame, drained) = match self.write_queue.get_mut(&priority) {
+            Some(queue) => (queue.pop_front(), queue.is_empty()),
+            None => return None,
+        };
+        if drained {
+            let _ = self.write_queue.remove(&priority);
+        }
+        frame
+    }
+
+    fn next_msg(&mut self) -> Result<Option<Vec<u8>>, SocketError> {
+        if self.read_buf.len() < LEN_SIZE {
+            return Ok(None);
+        }
+        let len = self.read_buf[..LEN_SIZE]
+            .iter()
+            .fold(0, |len, byte| (len << 8) | *byte as usize);
+        if len > MAX_MSG_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Message too large").into());
+        }
+        if self.read_buf.len() < LEN_SIZE + len {
+            return Ok(None);
+        }
+        let msg = self.read_buf[LEN_SIZE..LEN_SIZE + len].to_vec();
+        let _ = self.read_buf.drain(..LEN_SIZE + len);
+        Ok(Some(msg))
+    }
+
+    fn flush(&self) {
+        if let Some(endpoint) = self.endpoint.upgrade() {
+            if let Ok(endpoint) = endpoint.try_borrow() {
+                endpoint.flush(&self.conn);
+            }
+        }
+    }
+}
+
+impl Evented for UdpStream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        poll.register(&self.registration, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        poll.reregister(&self.registration, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
+
+impl Drop for UdpStream {
+    fn drop(&mut self) {
+        self.conn.borrow_mut().close();
+        self.flush();
+    }
+}
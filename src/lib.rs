@@ -95,11 +95,18 @@ mod main;
 mod nat;
 mod service_discovery;
 
-pub use crate::common::{CrustUser, PeerInfo};
+pub use crate::common::{BootstrapDenyReason, CrustUser, PeerInfo, Transport};
 pub use crate::main::{
-    read_config_file, BootstrapCacheConfig, Config, ConnectionInfoResult, CrustError, Event,
-    PeerId, PrivConnectionInfo, PubConnectionInfo, Service,
+    read_config_file, BanConfig, BanTarget, BootstrapCacheConfig, BootstrapFailure,
+    BootstrapFailureReason, BootstrapRetryConfig, Config, ConnectAttemptError,
+    ConnectAttemptFailure, ConnectFailureReason, ConnectionInfoResult, ConnectionLimitsConfig,
+    CrustError, Event, HandshakeLimitsConfig, HandshakeStats, IpRange, MsgId, PeerExchangeConfig,
+    PeerId, PeerStats, PrivConnectionInfo, PubConnectionInfo, ReconnectConfig, RekeyConfig,
+    RelayConfig, Service, Timeouts, TrafficStats,
 };
+#[cfg(feature = "async-api")]
+pub use crate::main::{AsyncService, EventStream};
+pub use crate::nat::MappingMethod;
 pub use socket_collection::Priority;
 
 /// Used to receive events from a `Service`.
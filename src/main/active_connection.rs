@@ -7,37 +7,54 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{CoreTimer, CrustUser, Message, State};
-use crate::main::{ConnectionId, CrustData, Event, EventLoopCore};
+use crate::common::{CoreTimer, CrustUser, Features, Message, Socket, State};
+use crate::main::fragmentation::{
+    self, Fragmenter, Reassembler, MAX_FRAGMENT_SIZE, MAX_MESSAGE_OVERHEAD,
+};
+use crate::main::peer_stats::StatsRecorder;
+use crate::main::rekey::{KeySwitch, Rekey};
+use crate::main::{peer_exchange, reconnect, relay};
+use crate::main::{ConnectionId, CrustData, Event, EventLoopCore, MsgId, PeerStats};
 use crate::PeerId;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
 use mio::{Poll, Ready, Token};
 use mio_extras::timer::Timeout;
-use socket_collection::{Priority, TcpSock};
+use safe_crypto::{PublicEncryptKey, SharedSecretKey};
+use socket_collection::{DecryptContext, EncryptContext, Priority, SocketError};
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, VecDeque};
+use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Duration;
-
-#[cfg(not(test))]
-pub const INACTIVITY_TIMEOUT_MS: u64 = 120_000;
-#[cfg(not(test))]
-const HEARTBEAT_PERIOD_MS: u64 = 20_000;
-
-#[cfg(test)]
-pub const INACTIVITY_TIMEOUT_MS: u64 = 900;
-#[cfg(test)]
-const HEARTBEAT_PERIOD_MS: u64 = 300;
+use std::time::{Duration, Instant};
 
 pub struct ActiveConnection {
     token: Token,
-    socket: TcpSock,
+    socket: Socket,
     our_id: PeerId,
     their_id: PeerId,
     their_role: CrustUser,
     event_tx: crate::CrustEventSender,
     heartbeat: Heartbeat,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+    /// Tracked messages the peer hasn't acknowledged yet.
+    unacked: BTreeSet<MsgId>,
+    /// Tracked messages handed to the socket, but not yet flushed.
+    unflushed: Vec<MsgId>,
+    stats: StatsRecorder,
+    next_ping_nonce: u64,
+    /// Nonce of the heartbeat the peer is yet to answer and when we sent it.
+    pending_ping: Option<(u64, Instant)>,
+    /// Features negotiated during the handshake.
+    features: Features,
+    rekey: Rekey,
+    /// Pending switch of our encrypt context to a new session key.
+    key_switch: Option<KeySwitch>,
+    /// Messages sent while `key_switch` is pending. They are encrypted with the new key.
+    held: VecDeque<(Message, Priority, Option<MsgId>)>,
 }
 
 impl ActiveConnection {
@@ -45,12 +62,13 @@ impl ActiveConnection {
         core: &mut EventLoopCore,
         poll: &Poll,
         token: Token,
-        socket: TcpSock,
+        socket: Socket,
         our_id: PeerId,
         their_id: PeerId,
         their_role: CrustUser,
         event: Event,
         event_tx: crate::CrustEventSender,
+        features: Features,
     ) {
         trace!(
             "Entered state ActiveConnection: {:?} -> {:?}",
@@ -59,6 +77,15 @@ impl ActiveConnection {
         );
 
         let heartbeat = Heartbeat::new(core, token);
+        let rekey = Rekey::new(core, token, features);
+        let stats = StatsRecorder::new(
+            their_id,
+            their_role,
+            socket.peer_addr().ok(),
+            socket.local_addr().ok(),
+            Some(socket.transport()),
+            None,
+        );
         let state = Rc::new(RefCell::new(ActiveConnection {
             token,
             socket,
@@ -67,6 +94,17 @@ impl ActiveConnection {
             their_role,
             event_tx,
             heartbeat,
+            fragmenter: Default::default(),
+            reassembler: Default::default(),
+            unacked: Default::default(),
+            unflushed: Vec::new(),
+            stats,
+            next_ping_nonce: 0,
+            pending_ping: None,
+            features,
+            rekey,
+            key_switch: None,
+            held: VecDeque::new(),
         }));
         let _ = core.insert_state(token, state.clone());
 
@@ -91,15 +129,69 @@ impl ActiveConnection {
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         loop {
             match self.socket.read::<Message>() {
-                Ok(Some(Message::Data(data))) => {
-                    let _ =
-                        self.event_tx
-                            .send(Event::NewMessage(self.their_id, self.their_role, data));
+                Ok(Some(message @ Message::Data(..)))
+                | Ok(Some(message @ Message::TrackedData(..))) => {
+                    self.handle_data(core, poll, message);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::DataFragment(msg_id, index, count, fragment))) => {
+                    let max_size = fragmentation::max_message_size(core) + MAX_MESSAGE_OVERHEAD;
+                    if let Some(data) = self
+                        .reassembler
+                        .add(msg_id, index, count, fragment, max_size)
+                    {
+                        match deserialise(&data) {
+                            Ok(message) => self.handle_data(core, poll, message),
+                            Err(e) => debug!(
+                                "{:?} - Failed to deserialise fragmented message: {:?}",
+                                self.our_id, e
+                            ),
+                        }
+                    }
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::Ack(msg_id))) => {
+                    if self.unacked.remove(&msg_id) {
+                        let _ = self
+                            .event_tx
+                            .send(Event::MessageDelivered(self.their_id, msg_id));
+                    }
                     self.reset_receive_heartbeat(core, poll);
                 }
                 Ok(Some(Message::Heartbeat)) => {
                     self.reset_receive_heartbeat(core, poll);
                 }
+                Ok(Some(Message::HeartbeatPing(nonce))) => {
+                    self.write(core, poll, Some((Message::HeartbeatPong(nonce), 0)));
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::HeartbeatPong(nonce))) => {
+                    self.handle_pong(core, nonce);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(message @ Message::RelayRequest(..)))
+                | Ok(Some(message @ Message::Relayed(..)))
+                | Ok(Some(message @ Message::RelayFailed(..))) => {
+                    relay::handle_message(core, self.their_id, message);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(message @ Message::PeerExchangeRequest))
+                | Ok(Some(message @ Message::PeerExchangeResponse(..))) => {
+                    peer_exchange::handle_message(core, self.their_id, message);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::Rekey(their_pk))) => {
+                    self.handle_rekey_request(core, poll, &their_pk);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::RekeyAck(their_pk))) => {
+                    self.handle_rekey_ack(core, poll, &their_pk);
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::RekeyDone)) => {
+                    self.handle_rekey_done(core, poll);
+                    self.reset_receive_heartbeat(core, poll);
+                }
                 Ok(Some(message)) => {
                     debug!("{:?} - Unexpected message: {:?}", self.our_id, message);
                     self.reset_receive_heartbeat(core, poll);
@@ -113,6 +205,160 @@ impl ActiveConnection {
         }
     }
 
+    fn handle_data(&mut self, core: &mut EventLoopCore, poll: &Poll, message: Message) {
+        let data = match message {
+            Message::Data(data) => data,
+            Message::TrackedData(msg_id, data) => {
+                self.write(core, poll, Some((Message::Ack(msg_id), 0)));
+                data
+            }
+            message => {
+                debug!("{:?} - Unexpected message: {:?}", self.our_id, message);
+                return;
+            }
+        };
+        self.stats.record_received(data.len());
+        let rekey_due = self.rekey.record(data.len());
+        let _ = self
+            .event_tx
+            .send(Event::NewMessage(self.their_id, self.their_role, data));
+        if rekey_due {
+            self.start_rekey(core, poll);
+        }
+    }
+
+    fn start_rekey(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if let Some(msg) = self.rekey.start() {
+            self.write(core, poll, Some((msg, 0)));
+        }
+    }
+
+    fn handle_rekey_request(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_pk: &PublicEncryptKey,
+    ) {
+        let we_win = self.our_id > self.their_id;
+        if let Some((ack, key)) = self.rekey.handle_request(we_win, their_pk) {
+            self.switch_encrypt_key(core, poll, ack, key);
+        }
+    }
+
+    fn handle_rekey_ack(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_pk: &PublicEncryptKey,
+    ) {
+        let key = match self.rekey.handle_ack(their_pk) {
+            Some(key) => key,
+            None => return,
+        };
+        if let Err(e) = self
+            .socket
+            .set_decrypt_ctx(DecryptContext::authenticated(key.clone()))
+        {
+            debug!("{:?} - Failed to set decrypt context: {:?}", self.our_id, e);
+            return self.terminate(core, poll);
+        }
+        self.switch_encrypt_key(core, poll, Message::RekeyDone, key);
+        self.finish_rekey(core, poll);
+    }
+
+    fn handle_rekey_done(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let key = match self.rekey.handle_done() {
+            Some(key) => key,
+            None => return,
+        };
+        if let Err(e) = self
+            .socket
+            .set_decrypt_ctx(DecryptContext::authenticated(key))
+        {
+            debug!("{:?} - Failed to set decrypt context: {:?}", self.our_id, e);
+            return self.terminate(core, poll);
+        }
+        self.finish_rekey(core, poll);
+    }
+
+    fn finish_rekey(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.stats.record_rekey();
+        if self.rekey.finish(core) {
+            self.start_rekey(core, poll);
+        }
+    }
+
+    /// Sends `marker` as the last message encrypted with the current key and switches to `key`
+    /// afterwards.
+    fn switch_encrypt_key(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        marker: Message,
+        key: SharedSecretKey,
+    ) {
+        if self.key_switch.is_some() {
+            debug!("{:?} - Rekey while switching keys", self.our_id);
+            return self.terminate(core, poll);
+        }
+        self.key_switch = Some(KeySwitch {
+            marker: Some(marker),
+            key,
+        });
+        self.write(core, poll, None);
+    }
+
+    /// Called once the socket has flushed everything queued. Returns the marker to send if it
+    /// hasn't been sent yet. Otherwise the marker has been flushed, so the new key is installed
+    /// and held messages are handed to the socket.
+    fn advance_key_switch(&mut self) -> Result<Option<(Message, Priority)>, SocketError> {
+        let key = match self.key_switch.take() {
+            Some(KeySwitch {
+                marker: Some(marker),
+                key,
+            }) => {
+                self.key_switch = Some(KeySwitch { marker: None, key });
+                return Ok(Some((marker, 0)));
+            }
+            Some(KeySwitch { marker: None, key }) => key,
+            None => return Ok(None),
+        };
+        self.socket
+            .set_encrypt_ctx(EncryptContext::authenticated(key))?;
+        while let Some((msg, priority, tracked)) = self.held.pop_front() {
+            self.unflushed.extend(tracked);
+            let _ = self.socket.write(Some((msg, priority)))?;
+        }
+        Ok(None)
+    }
+
+    fn handle_pong(&mut self, core: &EventLoopCore, nonce: u64) {
+        let sent_at = match self.pending_ping {
+            Some((pending_nonce, sent_at)) if pending_nonce == nonce => sent_at,
+            _ => return,
+        };
+        self.pending_ping = None;
+        let rtt = self.stats.record_rtt(sent_at.elapsed());
+        if core.user_data().config.cfg.report_peer_latency {
+            let _ = self.event_tx.send(Event::PeerLatency(self.their_id, rtt));
+        }
+    }
+
+    /// Sends a heartbeat which the peer answers, so that we measure the round trip time. Unlike
+    /// relayed connections, we send heartbeats even when busy, to keep the estimate up to date.
+    /// Any heartbeat still unanswered is forgotten. Peers which don't support pings get a plain
+    /// heartbeat instead.
+    fn ping(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.stats.touch_sent();
+        if !self.features.contains(Features::HEARTBEAT_PING) {
+            return self.write(core, poll, Some((Message::Heartbeat, 0)));
+        }
+        let nonce = self.next_ping_nonce;
+        self.next_ping_nonce = nonce.wrapping_add(1);
+        self.pending_ping = Some((nonce, Instant::now()));
+        self.write(core, poll, Some((Message::HeartbeatPing(nonce), 0)));
+    }
+
     #[cfg(not(test))]
     /// Helper function that returns a socket address of the connection
     pub fn peer_addr(&self) -> crate::Res<SocketAddr> {
@@ -131,22 +377,143 @@ impl ActiveConnection {
         self.their_role
     }
 
-    fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, msg: Option<(Message, Priority)>) {
-        if let Err(e) = self.socket.write(msg) {
-            debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
-            self.terminate(core, poll);
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    pub fn stats(&self) -> PeerStats {
+        self.stats.snapshot(self.fragmenter.queued_bytes())
+    }
+
+    /// Sends a protocol message other than user data, e.g. relayed traffic.
+    pub fn send(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        msg: Message,
+        priority: Priority,
+    ) {
+        self.write(core, poll, Some((msg, priority)));
+        self.stats.touch_sent();
+    }
+
+    /// Sends user data, which the peer acknowledges if it's tracked.
+    pub fn write_tracked(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        data: Vec<u8>,
+        priority: Priority,
+        msg_id: MsgId,
+    ) {
+        self.write_data(core, poll, data, priority, Some(msg_id));
+    }
+
+    fn write_data(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        data: Vec<u8>,
+        priority: Priority,
+        tracked: Option<MsgId>,
+    ) {
+        if data.len() > fragmentation::max_message_size(core) {
+            let _ = self
+                .event_tx
+                .send(Event::WriteMsgSizeProhibitive(self.their_id, data));
+            if let Some(msg_id) = tracked {
+                let _ = self
+                    .event_tx
+                    .send(Event::MessageFailed(self.their_id, vec![msg_id]));
+            }
+            return;
+        }
+
+        self.stats.record_sent(priority, data.len());
+        let size = data.len();
+        let fragmented = data.len() > MAX_FRAGMENT_SIZE;
+        let msg = match tracked {
+            Some(msg_id) => {
+                let _ = self.unacked.insert(msg_id);
+                Message::TrackedData(msg_id, data)
+            }
+            None => Message::Data(data),
+        };
+        if fragmented {
+            match serialise(&msg) {
+                Ok(data) => self.fragmenter.push(data, priority, tracked),
+                Err(e) => {
+                    debug!("{:?} - Failed to serialise message: {:?}", self.our_id, e);
+                    return self.terminate(core, poll);
+                }
+            }
+            self.write(core, poll, None);
+        } else if self.key_switch.is_some() {
+            self.held.push_back((msg, priority, tracked));
+        } else {
+            self.unflushed.extend(tracked);
+            self.write(core, poll, Some((msg, priority)));
+        }
+        self.stats.touch_sent();
+        if self.rekey.record(size) {
+            self.start_rekey(core, poll);
         }
     }
 
-    fn reset_receive_heartbeat(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        if let Err(e) = self.heartbeat.reset_receive(core) {
-            debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
-            self.terminate(core, poll);
+    /// Hands the next fragment of large messages to the socket only once everything else has
+    /// been flushed, so that fragments don't delay heartbeats and small messages for long.
+    /// While switching keys, messages are held back and no fragments are handed out.
+    fn write(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        mut msg: Option<(Message, Priority)>,
+    ) {
+        if self.key_switch.is_some() {
+            if let Some((msg, priority)) = msg.take() {
+                self.held.push_back((msg, priority, None));
+            }
+        }
+        loop {
+            match self.socket.write(msg.take()) {
+                Ok(true) => {
+                    for msg_id in self.unflushed.drain(..) {
+                        let _ = self
+                            .event_tx
+                            .send(Event::MessageSent(self.their_id, msg_id));
+                    }
+                    if self.key_switch.is_some() {
+                        match self.advance_key_switch() {
+                            Ok(marker) => {
+                                msg = marker;
+                                continue;
+                            }
+                            Err(e) => {
+                                debug!("{:?} - Failed to switch keys: {:?}", self.our_id, e);
+                                return self.terminate(core, poll);
+                            }
+                        }
+                    }
+                    match self.fragmenter.next_fragment() {
+                        Some((fragment, priority, tracked)) => {
+                            self.unflushed.extend(tracked);
+                            msg = Some((fragment, priority));
+                        }
+                        None => return,
+                    }
+                }
+                Ok(false) => return,
+                Err(e) => {
+                    debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
+                    return self.terminate(core, poll);
+                }
+            }
         }
     }
 
-    fn reset_send_heartbeat(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        if let Err(e) = self.heartbeat.reset_send(core) {
+    fn reset_receive_heartbeat(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.stats.touch_received();
+        if let Err(e) = self.heartbeat.reset_receive(core) {
             debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
             self.terminate(core, poll);
         }
@@ -164,12 +531,12 @@ impl State<CrustData> for ActiveConnection {
     }
 
     fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, data: Vec<u8>, priority: Priority) {
-        self.write(core, poll, Some((Message::Data(data), priority)));
-        self.reset_send_heartbeat(core, poll);
+        self.write_data(core, poll, data, priority, None);
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         self.heartbeat.terminate(core);
+        self.rekey.terminate(core);
         let _ = poll.deregister(&self.socket);
         let _ = core.remove_state(self.token);
 
@@ -186,12 +553,24 @@ impl State<CrustData> for ActiveConnection {
             connections.get(&self.their_id)
         );
 
+        if !self.unacked.is_empty() {
+            let failed = mem::replace(&mut self.unacked, BTreeSet::new());
+            let _ = self.event_tx.send(Event::MessageFailed(
+                self.their_id,
+                failed.into_iter().collect(),
+            ));
+        }
         let _ = self.event_tx.send(Event::LostPeer(self.their_id));
+        reconnect::peer_lost(core, self.their_id);
+        relay::relay_lost(core, self.their_id);
     }
 
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, timer_id: u8) {
+        if self.rekey.is_timer(timer_id) {
+            return self.start_rekey(core, poll);
+        }
         match self.heartbeat.timeout(core, timer_id) {
-            HeartbeatAction::Send => self.write(core, poll, Some((Message::Heartbeat, 0))),
+            HeartbeatAction::Send => self.ping(core, poll),
             HeartbeatAction::Terminate => {
                 debug!(
                     "Dropping connection to {:?} due to peer inactivity",
@@ -207,63 +586,66 @@ impl State<CrustData> for ActiveConnection {
     }
 }
 
-struct Heartbeat {
+pub struct Heartbeat {
     recv_timeout: Timeout,
     recv_timer: CoreTimer,
     send_timeout: Timeout,
     send_timer: CoreTimer,
+    inactivity_timeout: Duration,
+    period: Duration,
 }
 
 impl Heartbeat {
-    fn new(core: &mut EventLoopCore, state_id: Token) -> Self {
+    /// Timings are taken from the current config, later changes don't affect this connection.
+    pub fn new(core: &mut EventLoopCore, state_id: Token) -> Self {
+        let timeouts = &core.user_data().config.cfg.timeouts;
+        let inactivity_timeout = Duration::from_millis(timeouts.inactivity_timeout_ms);
+        let period = Duration::from_millis(timeouts.heartbeat_period_ms);
+
         let recv_timer = CoreTimer::new(state_id, 0);
-        let recv_timeout =
-            core.set_timeout(Duration::from_millis(INACTIVITY_TIMEOUT_MS), recv_timer);
+        let recv_timeout = core.set_timeout(inactivity_timeout, recv_timer);
 
         let send_timer = CoreTimer::new(state_id, 1);
-        let send_timeout = core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), send_timer);
+        let send_timeout = core.set_timeout(period, send_timer);
 
         Self {
             recv_timeout,
             recv_timer,
             send_timeout,
             send_timer,
+            inactivity_timeout,
+            period,
         }
     }
 
-    fn timeout(&mut self, core: &mut EventLoopCore, timer_id: u8) -> HeartbeatAction {
+    pub fn timeout(&mut self, core: &mut EventLoopCore, timer_id: u8) -> HeartbeatAction {
         if timer_id == self.recv_timer.timer_id {
             HeartbeatAction::Terminate
         } else {
-            self.send_timeout =
-                core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), self.send_timer);
+            self.send_timeout = core.set_timeout(self.period, self.send_timer);
             HeartbeatAction::Send
         }
     }
 
-    fn reset_receive(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
+    pub fn reset_receive(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
         let _ = core.cancel_timeout(&self.recv_timeout);
-        self.recv_timeout = core.set_timeout(
-            Duration::from_millis(INACTIVITY_TIMEOUT_MS),
-            self.recv_timer,
-        );
+        self.recv_timeout = core.set_timeout(self.inactivity_timeout, self.recv_timer);
         Ok(())
     }
 
-    fn reset_send(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
+    pub fn reset_send(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
         let _ = core.cancel_timeout(&self.send_timeout);
-        self.send_timeout =
-            core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), self.send_timer);
+        self.send_timeout = core.set_timeout(self.period, self.send_timer);
         Ok(())
     }
 
-    fn terminate(&mut self, core: &mut EventLoopCore) {
+    pub fn terminate(&mut self, core: &mut EventLoopCore) {
         let _ = core.cancel_timeout(&self.recv_timeout);
         let _ = core.cancel_timeout(&self.send_timeout);
     }
 }
 
-enum HeartbeatAction {
+pub enum HeartbeatAction {
     Send,
     Terminate,
 }
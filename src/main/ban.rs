@@ -0,0 +1,293 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::PeerInfo;
+use crate::main::{peer_exchange, reconnect};
+use crate::main::{ActiveConnection, ConnectionId, EventLoopCore};
+use crate::PeerId;
+use config_file_handler::{self, FileHandler};
+use mio::{Poll, Token};
+use safe_crypto::PublicEncryptKey;
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+/// Longer bans are shortened to this, so that the expiry time can't overflow.
+const MAX_BAN_DURATION_SEC: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// Peer ban list specific configurable settings.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct BanConfig {
+    /// Write bans to disk, so that they outlive the `Service`.
+    pub persist: bool,
+    /// File path for the ban list. Defaults to executable file + '.bans' suffix.
+    pub file_name: Option<OsString>,
+}
+
+/// Peer or IP address banned with `Service::ban_peer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BanTarget {
+    /// Peer with the given ID, no matter which address it connects from.
+    Peer(PeerId),
+    /// Any peer connecting from or listening on the given IP address.
+    Ip(IpAddr),
+}
+
+impl From<PeerId> for BanTarget {
+    fn from(peer_id: PeerId) -> Self {
+        BanTarget::Peer(peer_id)
+    }
+}
+
+impl From<IpAddr> for BanTarget {
+    fn from(ip: IpAddr) -> Self {
+        BanTarget::Ip(ip)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Ban {
+    target: BanTarget,
+    expires_at: SystemTime,
+}
+
+/// Currently banned peers and IP addresses.
+#[derive(Default)]
+pub struct BanList {
+    cfg: BanConfig,
+    bans: HashMap<BanTarget, SystemTime>,
+}
+
+impl BanList {
+    pub fn new(cfg: BanConfig) -> Self {
+        Self {
+            cfg,
+            bans: HashMap::new(),
+        }
+    }
+
+    /// Bans the target for the given duration, replacing its previous ban, if any.
+    pub fn ban(&mut self, target: BanTarget, duration: Duration) {
+        let duration = cmp::min(duration, Duration::from_secs(MAX_BAN_DURATION_SEC));
+        let _ = self.bans.insert(target, SystemTime::now() + duration);
+        self.purge_expired();
+        self.try_commit();
+    }
+
+    /// Returns whether the target was banned.
+    pub fn unban(&mut self, target: &BanTarget) -> bool {
+        let was_banned = self.bans.remove(target).is_some();
+        self.purge_expired();
+        self.try_commit();
+        was_banned
+    }
+
+    pub fn is_banned(&self, target: &BanTarget) -> bool {
+        self.bans
+            .get(target)
+            .map_or(false, |expires_at| *expires_at > SystemTime::now())
+    }
+
+    /// Checks both the peer and the IP address it connects from.
+    pub fn is_peer_banned(&self, peer_id: &PeerId, ip: Option<IpAddr>) -> bool {
+        self.is_banned(&BanTarget::Peer(*peer_id))
+            || ip.map_or(false, |ip| self.is_banned(&BanTarget::Ip(ip)))
+    }
+
+    /// Checks contacts, e.g. from bootstrap cache, which only know the encryption key of the peer.
+    pub fn is_contact_banned(&self, peer_info: &PeerInfo) -> bool {
+        self.is_banned(&BanTarget::Ip(peer_info.addr.ip()))
+            || self.is_key_banned(&peer_info.pub_key)
+    }
+
+    fn is_key_banned(&self, pub_key: &PublicEncryptKey) -> bool {
+        let now = SystemTime::now();
+        self.bans.iter().any(|(target, expires_at)| match *target {
+            BanTarget::Peer(ref peer_id) => peer_id.pub_enc_key == *pub_key && *expires_at > now,
+            BanTarget::Ip(_) => false,
+        })
+    }
+
+    /// Loads bans from disk, if they are persisted.
+    pub fn read_file(&mut self) {
+        if !self.cfg.persist {
+            return;
+        }
+        match self.open_file() {
+            Ok(file_handler) => {
+                let bans: Vec<Ban> = file_handler.read_file().unwrap_or_else(|e| {
+                    info!("Failed to read ban list file: {}", e);
+                    Default::default()
+                });
+                self.bans
+                    .extend(bans.into_iter().map(|ban| (ban.target, ban.expires_at)));
+                self.purge_expired();
+            }
+            Err(e) => info!("Failed to open ban list file: {}", e),
+        }
+    }
+
+    fn try_commit(&self) {
+        if !self.cfg.persist {
+            return;
+        }
+        if let Err(e) = self.commit() {
+            info!("Failed to write ban list to disk: {}", e);
+        }
+    }
+
+    fn commit(&self) -> crate::Res<()> {
+        let bans: Vec<Ban> = self
+            .bans
+            .iter()
+            .map(|(target, expires_at)| Ban {
+                target: *target,
+                expires_at: *expires_at,
+            })
+            .collect();
+        self.open_file()?.write_file(&bans)?;
+        Ok(())
+    }
+
+    fn purge_expired(&mut self) {
+        let now = SystemTime::now();
+        self.bans.retain(|_, expires_at| *expires_at > now);
+    }
+
+    fn open_file(&self) -> crate::Res<FileHandler<Vec<Ban>>> {
+        let fname = match self.cfg.file_name {
+            Some(ref fname) => fname.clone(),
+            None => {
+                let mut fname = config_file_handler::exe_file_stem()?;
+                fname.push(".bans");
+                fname
+            }
+        };
+        Ok(FileHandler::new(&fname, true)?)
+    }
+}
+
+/// Bans the target and drops the connections to it, as well as its bootstrap cache entries and
+/// the contacts it shared with us through peer exchange.
+pub fn ban(core: &mut EventLoopCore, poll: &Poll, target: BanTarget, duration: Duration) {
+    core.user_data_mut().bans.ban(target, duration);
+
+    let banned_peers: Vec<(PeerId, Option<Token>)> = core
+        .user_data()
+        .connections
+        .iter()
+        .filter(|&(peer_id, conn_id)| match target {
+            BanTarget::Peer(banned_id) => *peer_id == banned_id,
+            BanTarget::Ip(ip) => connection_ip(core, conn_id) == Some(ip),
+        })
+        .map(|(peer_id, conn_id)| (*peer_id, conn_id.active_connection))
+        .collect();
+    for (peer_id, token) in banned_peers {
+        reconnect::forget_peer(core, poll, &peer_id);
+        if let Some(state) = token.and_then(|token| core.get_state(token)) {
+            state.borrow_mut().terminate(core, poll);
+        }
+    }
+
+    if let BanTarget::Peer(peer_id) = target {
+        peer_exchange::drop_contacts_from(core, &peer_id);
+    }
+
+    let user_data = core.user_data_mut();
+    let banned_contacts: Vec<PeerInfo> = user_data
+        .bootstrap_cache
+        .snapshot()
+        .into_iter()
+        .filter(|peer_info| user_data.bans.is_contact_banned(peer_info))
+        .collect();
+    if !banned_contacts.is_empty() {
+        for peer_info in &banned_contacts {
+            user_data.bootstrap_cache.remove(peer_info);
+        }
+        user_data.bootstrap_cache.try_commit();
+    }
+}
+
+/// IP address of the peer, if we're directly connected to it.
+pub fn connection_ip(core: &EventLoopCore, conn_id: &ConnectionId) -> Option<IpAddr> {
+    let state = core.get_state(conn_id.active_connection?)?;
+    let mut state = state.borrow_mut();
+    let active_connection = state.as_any().downcast_mut::<ActiveConnection>()?;
+    active_connection.peer_addr().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+    use crate::tests::rand_peer_id_and_enc_sk;
+    use std::env;
+    use std::thread;
+
+    #[test]
+    fn bans_expire() {
+        let mut bans = BanList::default();
+        let (peer_id, _) = rand_peer_id_and_enc_sk();
+        let ip = ipv4_addr(1, 2, 3, 4, 0).ip();
+
+        bans.ban(BanTarget::Peer(peer_id), Duration::from_millis(100));
+        bans.ban(BanTarget::Ip(ip), Duration::from_secs(60));
+        assert!(bans.is_peer_banned(&peer_id, None));
+        assert!(bans.is_banned(&BanTarget::Ip(ip)));
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(!bans.is_peer_banned(&peer_id, None));
+        assert!(bans.is_banned(&BanTarget::Ip(ip)));
+
+        assert!(bans.unban(&BanTarget::Ip(ip)));
+        assert!(!bans.is_banned(&BanTarget::Ip(ip)));
+    }
+
+    #[test]
+    fn contacts_are_matched_by_ip_or_key() {
+        let mut bans = BanList::default();
+        let (peer_id, _) = rand_peer_id_and_enc_sk();
+        let (other_id, _) = rand_peer_id_and_enc_sk();
+        bans.ban(BanTarget::Peer(peer_id), Duration::from_secs(60));
+        bans.ban(
+            ipv4_addr(1, 2, 3, 4, 0).ip().into(),
+            Duration::from_secs(60),
+        );
+
+        let addr = ipv4_addr(5, 6, 7, 8, 5000);
+        assert!(bans.is_contact_banned(&PeerInfo::new(addr, peer_id.pub_enc_key)));
+        assert!(!bans.is_contact_banned(&PeerInfo::new(addr, other_id.pub_enc_key)));
+
+        let banned_addr = ipv4_addr(1, 2, 3, 4, 5000);
+        assert!(bans.is_contact_banned(&PeerInfo::new(banned_addr, other_id.pub_enc_key)));
+        assert!(bans.is_peer_banned(&other_id, Some(banned_addr.ip())));
+    }
+
+    #[test]
+    fn persisted_bans_are_read_back() {
+        let mut path = env::temp_dir();
+        path.push(format!("{:016x}.bans", rand::random::<u64>()));
+        let cfg = BanConfig {
+            persist: true,
+            file_name: Some(path.into()),
+        };
+        let (peer_id, _) = rand_peer_id_and_enc_sk();
+
+        let mut bans = BanList::new(cfg.clone());
+        bans.ban(peer_id.into(), Duration::from_secs(60));
+
+        let mut bans = BanList::new(cfg);
+        assert!(!bans.is_peer_banned(&peer_id, None));
+        bans.read_file();
+        assert!(bans.is_peer_banned(&peer_id, None));
+    }
+}
@@ -148,7 +148,7 @@ mod tests {
     use crate::common::{spawn_event_loop, CoreMessage};
     use crate::main::{bootstrap, Event};
     use crate::tests::utils::test_service;
-    use safe_crypto::gen_encrypt_keypair;
+    use safe_crypto::{gen_encrypt_keypair, gen_sign_keypair};
     use std::sync::mpsc;
     use std::time::Duration;
 
@@ -165,7 +165,7 @@ mod tests {
 
             let init_crust_data = move || {
                 let bootstrap_cache = bootstrap::Cache::new(Default::default());
-                CrustData::new(bootstrap_cache)
+                CrustData::new(bootstrap_cache, gen_sign_keypair().1)
             };
             let el = unwrap!(spawn_event_loop(0, None, init_crust_data));
 
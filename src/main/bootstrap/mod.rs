@@ -14,10 +14,12 @@ mod try_peer;
 pub use self::cache::{Cache, CacheConfig};
 use self::cache_validator::test_inactive_cached_peers;
 pub use self::cache_validator::CacheValidator;
-use self::try_peer::TryPeer;
+pub use self::try_peer::TryPeer;
 use crate::common::{
-    BootstrapDenyReason, BootstrapperRole, CoreTimer, CrustUser, NameHash, PeerInfo, State,
+    Backoff, BootstrapDenyReason, BootstrapperRole, CoreTimer, CrustUser, Features, NameHash,
+    PeerInfo, Socket, State,
 };
+use crate::main::reconnect::{self, ReconnectTarget};
 use crate::main::{ActiveConnection, Config, CrustData, CrustError, Event, EventLoopCore};
 use crate::service_discovery::ServiceDiscovery;
 use crate::PeerId;
@@ -26,41 +28,119 @@ use mio_extras::timer::Timeout;
 use rand;
 use rand::seq::SliceRandom;
 use safe_crypto::SecretEncryptKey;
-use socket_collection::TcpSock;
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::rc::{Rc, Weak};
 use std::sync::mpsc::{self, Receiver};
 use std::time::Duration;
 
-const BOOTSTRAP_TIMEOUT_SEC: u64 = 10;
-const SERVICE_DISCOVERY_TIMEOUT_SEC: u64 = 1;
 const BOOTSTRAP_TIMER_ID: u8 = 0;
 const SERVICE_DISCOVERY_TIMER_ID: u8 = BOOTSTRAP_TIMER_ID + 1;
+const RETRY_TIMER_ID: u8 = SERVICE_DISCOVERY_TIMER_ID + 1;
 const MAX_CONTACTS_EXPECTED: usize = 1500;
 
+/// Bootstrap retry specific configurable settings.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Instead of failing, keep bootstrapping until we succeed or `Service::stop_bootstrap` is
+    /// called. Every round queries service discovery, the bootstrap cache and the hard coded
+    /// contacts of the current config again.
+    pub enabled: bool,
+    /// Delay before the second round in milliseconds. It doubles after every failed round.
+    pub initial_backoff_ms: u64,
+    /// Upper bound of the delay between rounds in milliseconds.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            enabled: false,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 5 * 60 * 1000,
+        }
+    }
+}
+
+/// Why bootstrapping off a contact failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootstrapFailureReason {
+    /// The contact refused our bootstrap request.
+    Denied(BootstrapDenyReason),
+    /// Connecting to, writing to or reading from the contact failed.
+    Io(String),
+    /// The contact doesn't own the public key we have for it.
+    InvalidKey,
+    /// The contact didn't respond before the bootstrap timeout.
+    Timeout,
+    /// The contact responded with something other than a bootstrap response.
+    UnexpectedMessage,
+    /// The contact is banned, so we didn't try it.
+    Banned,
+    /// The contact speaks a protocol version we no longer support. Carries its version.
+    IncompatibleVersion(u32),
+    /// The contact's handshake signature didn't match the signing key in its `PeerId`.
+    InvalidSignature,
+}
+
+/// Contact which we failed to bootstrap off and the reason, reported by `Event::BootstrapFailed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapFailure {
+    /// The contact we tried.
+    pub contact: PeerInfo,
+    /// Why it failed.
+    pub reason: BootstrapFailureReason,
+}
+
 /// Connection bootstrap state that
 ///
 /// 1. attempts service discovery,
 /// 2. if no peers are found, tries cached ones,
 /// 3. if no success again, tries peers hard coded in the config.
+///
+/// It keeps going until connected to `Config::target_bootstrap_connections` distinct peers. If
+/// `RetryConfig::enabled` is set, failed rounds are repeated after a delay.
 pub struct Bootstrap {
     token: Token,
     peers: Vec<PeerInfo>,
     name_hash: NameHash,
     our_uid: PeerId,
     our_role: BootstrapperRole,
+    blacklist: HashSet<SocketAddr>,
+    service_discovery_token: Token,
     event_tx: crate::CrustEventSender,
     sd_meta: Option<ServiceDiscMeta>,
     bs_timer: CoreTimer,
-    bs_timeout: Timeout,
-    children: HashSet<Token>,
+    bs_timeout: Option<Timeout>,
+    /// Ongoing attempts and the peers they try.
+    children: HashMap<Token, PeerInfo>,
     self_weak: Weak<RefCell<Bootstrap>>,
     our_sk: SecretEncryptKey,
+    target: usize,
+    connected: HashSet<PeerId>,
+    connected_ranges: HashSet<IpAddr>,
+    /// Peers which accepted us, but are in IP ranges we're already connected to.
+    deferred: Vec<Bootstrapped>,
+    /// Set in persistent mode.
+    backoff: Option<Backoff>,
+    retry_timeout: Option<Timeout>,
+    /// Contacts which failed in the current round.
+    failures: Vec<BootstrapFailure>,
+}
+
+/// Peer we completed the bootstrap handshake with.
+struct Bootstrapped {
+    token: Token,
+    socket: Socket,
+    peer_info: PeerInfo,
+    peer_id: PeerId,
+    features: Features,
 }
 
 impl Bootstrap {
@@ -80,52 +160,90 @@ impl Bootstrap {
         event_tx: crate::CrustEventSender,
         our_sk: &SecretEncryptKey,
     ) -> crate::Res<()> {
-        let bs_timer = CoreTimer::new(token, BOOTSTRAP_TIMER_ID);
-        let bs_timeout = core.set_timeout(Duration::from_secs(BOOTSTRAP_TIMEOUT_SEC), bs_timer);
-        let sd_meta = match seek_peers(core, service_discovery_token, token) {
-            Ok((rx, timeout)) => Some(ServiceDiscMeta { rx, timeout }),
-            Err(CrustError::ServiceDiscNotEnabled) => None,
-            Err(e) => {
-                debug!("Failed to seek peers using service discovery: {:?}", e);
-                return Err(e);
-            }
+        let config = &core.user_data().config.cfg;
+        let target = cmp::max(config.target_bootstrap_connections.unwrap_or(1), 1);
+        let retry = &config.bootstrap_retry;
+        let backoff = if retry.enabled {
+            Some(Backoff::new(retry.initial_backoff_ms, retry.max_backoff_ms))
+        } else {
+            None
         };
-
-        let (cached_peers, expired_peers) = core.user_data_mut().bootstrap_cache.peers();
-        test_inactive_cached_peers(core, poll, expired_peers);
-
-        let peers = bootstrap_peers(cached_peers, &core.user_data().config.cfg, blacklist);
         let state = Rc::new(RefCell::new(Self {
             token,
-            peers,
+            peers: Vec::new(),
             name_hash,
             our_uid,
             our_role,
+            blacklist,
+            service_discovery_token,
             event_tx,
-            sd_meta,
-            bs_timer,
-            bs_timeout,
-            children: HashSet::with_capacity(MAX_CONTACTS_EXPECTED),
+            sd_meta: None,
+            bs_timer: CoreTimer::new(token, BOOTSTRAP_TIMER_ID),
+            bs_timeout: None,
+            children: HashMap::with_capacity(MAX_CONTACTS_EXPECTED),
             self_weak: Weak::new(),
             our_sk: our_sk.clone(),
+            target,
+            connected: HashSet::new(),
+            connected_ranges: HashSet::new(),
+            deferred: Vec::new(),
+            backoff,
+            retry_timeout: None,
+            failures: Vec::new(),
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
 
         let _ = core.insert_state(token, state.clone());
 
-        if state.borrow().sd_meta.is_none() {
-            state.borrow_mut().begin_bootstrap(core, poll);
+        let mut state = state.borrow_mut();
+        if let Err(e) = state.start_round(core, poll) {
+            state.terminate(core, poll);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Collects peers from service discovery, the bootstrap cache and the config and starts
+    /// trying them.
+    fn start_round(&mut self, core: &mut EventLoopCore, poll: &Poll) -> crate::Res<()> {
+        let timeout_sec = core.user_data().config.cfg.timeouts.bootstrap_timeout_sec;
+        self.bs_timeout = Some(core.set_timeout(Duration::from_secs(timeout_sec), self.bs_timer));
+        self.failures.clear();
+        self.sd_meta = match seek_peers(core, self.service_discovery_token, self.token) {
+            Ok((rx, timeout)) => Some(ServiceDiscMeta { rx, timeout }),
+            Err(CrustError::ServiceDiscNotEnabled) => None,
+            Err(e) => {
+                debug!("Failed to seek peers using service discovery: {:?}", e);
+                return Err(e);
+            }
+        };
+
+        let (cached_peers, expired_peers) = core.user_data_mut().bootstrap_cache.peers();
+        test_inactive_cached_peers(core, poll, expired_peers);
+
+        self.peers = bootstrap_peers(cached_peers, &core.user_data().config.cfg, &self.blacklist);
+
+        if self.sd_meta.is_none() {
+            self.begin_bootstrap(core, poll);
         }
 
         Ok(())
     }
 
     fn begin_bootstrap(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        let peers = mem::replace(&mut self.peers, Vec::new());
+        let (banned, peers): (Vec<_>, Vec<_>) = mem::replace(&mut self.peers, Vec::new())
+            .into_iter()
+            .partition(|peer| core.user_data().bans.is_contact_banned(peer));
+        self.failures
+            .extend(banned.into_iter().map(|contact| BootstrapFailure {
+                contact,
+                reason: BootstrapFailureReason::Banned,
+            }));
         if peers.is_empty() {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
+            info!("No peers to bootstrap off");
+            return self.finish(core, poll);
         }
 
         for peer in peers {
@@ -136,7 +254,8 @@ impl Bootstrap {
                 }
             };
 
-            if let Ok(child) = TryPeer::start(
+            let transports = core.user_data().config.cfg.transports();
+            match TryPeer::start(
                 core,
                 poll,
                 peer,
@@ -144,47 +263,52 @@ impl Bootstrap {
                 self.name_hash,
                 self.our_role.clone(),
                 &self.our_sk,
+                transports,
                 Box::new(finish),
             ) {
-                let _ = self.children.insert(child);
+                Ok(child) => {
+                    let _ = self.children.insert(child, peer);
+                }
+                Err(e) => self.failures.push(BootstrapFailure {
+                    contact: peer,
+                    reason: BootstrapFailureReason::Io(e.to_string()),
+                }),
             }
         }
         self.maybe_terminate(core, poll);
     }
 
-    /// Spawns `ActiveConnection` state and terminates remaining bootstrap attempts.
+    /// Spawns `ActiveConnection` states until we're connected to enough peers, then terminates
+    /// remaining bootstrap attempts.
     fn handle_result(
         &mut self,
         core: &mut EventLoopCore,
         poll: &Poll,
         child: Token,
-        res: Result<(TcpSock, PeerInfo, PeerId), (PeerInfo, Option<BootstrapDenyReason>)>,
+        res: Result<(Socket, PeerInfo, PeerId, Features), (PeerInfo, BootstrapFailureReason)>,
     ) {
         let _ = self.children.remove(&child);
         match res {
-            Ok((socket, peer_info, peer_id)) => {
-                self.terminate(core, poll);
-                return ActiveConnection::start(
-                    core,
-                    poll,
-                    child,
-                    socket,
-                    self.our_uid,
-                    peer_id,
-                    // Note; We bootstrap only to Nodes
-                    CrustUser::Node,
-                    Event::BootstrapConnect(peer_id, peer_info.addr),
-                    self.event_tx.clone(),
-                );
-            }
-            Err((bad_peer, opt_reason)) => {
-                {
+            Ok((socket, peer_info, peer_id, features)) => self.deferred.push(Bootstrapped {
+                token: child,
+                socket,
+                peer_info,
+                peer_id,
+                features,
+            }),
+            Err((bad_peer, failure)) => {
+                // Peers at capacity are fine, just busy, so keep them for later.
+                if failure != BootstrapFailureReason::Denied(BootstrapDenyReason::AtCapacity) {
                     let bootstrap_cache = &mut core.user_data_mut().bootstrap_cache;
                     bootstrap_cache.remove(&bad_peer);
                     bootstrap_cache.try_commit();
                 }
+                self.failures.push(BootstrapFailure {
+                    contact: bad_peer,
+                    reason: failure.clone(),
+                });
 
-                if let Some(reason) = opt_reason {
+                if let BootstrapFailureReason::Denied(reason) = failure {
                     let (err_msg, is_err_fatal) = match reason {
                         BootstrapDenyReason::InvalidNameHash => ("Network name mismatch.", false),
                         BootstrapDenyReason::FailedExternalReachability => (
@@ -197,12 +321,22 @@ impl Bootstrap {
                         BootstrapDenyReason::ClientNotWhitelisted => {
                             ("Our Client is not whitelisted", false)
                         }
+                        BootstrapDenyReason::IncompatibleVersion(_) => (
+                            "Bootstrappee no longer supports our protocol version.",
+                            false,
+                        ),
+                        BootstrapDenyReason::InvalidSignature => {
+                            ("Bootstrappee rejected our handshake signature.", false)
+                        }
+                        BootstrapDenyReason::AtCapacity => {
+                            ("Bootstrappee has no room for more connections.", false)
+                        }
                     };
                     if is_err_fatal {
                         info!("Failed to Bootstrap: ({:?}) {}", reason, err_msg);
-                        self.terminate(core, poll);
-                        let _ = self.event_tx.send(Event::BootstrapFailed);
-                        return;
+                        self.terminate_children(core, poll);
+                        self.connect_deferred(core, poll);
+                        return self.finish(core, poll);
                     } else {
                         info!(
                             "Failed to Bootstrap with {:?}: ({:?}) {}",
@@ -212,19 +346,117 @@ impl Bootstrap {
                 }
             }
         }
+        self.connect_deferred(core, poll);
+        if self.connected.len() >= self.target {
+            return self.finish(core, poll);
+        }
         self.maybe_terminate(core, poll);
     }
 
+    /// Connects to the peers which accepted us, as long as we need more connections. Peers in IP
+    /// ranges we're already connected to wait until no attempt to a new range is in progress.
+    fn connect_deferred(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        while self.connected.len() < self.target {
+            let new_range = {
+                let connected_ranges = &self.connected_ranges;
+                self.deferred.iter().position(|peer| {
+                    !connected_ranges.contains(&ip_range(peer.peer_info.addr.ip()))
+                })
+            };
+            let next = match new_range {
+                Some(index) => index,
+                None if self.deferred.is_empty() || self.new_range_pending() => return,
+                None => 0,
+            };
+            let peer = self.deferred.remove(next);
+            self.connect(core, poll, peer);
+        }
+    }
+
+    fn new_range_pending(&self) -> bool {
+        self.children
+            .values()
+            .any(|peer| !self.connected_ranges.contains(&ip_range(peer.addr.ip())))
+    }
+
+    fn connect(&mut self, core: &mut EventLoopCore, poll: &Poll, peer: Bootstrapped) {
+        if !self.connected.insert(peer.peer_id) {
+            debug!("Already bootstrapped off {:?}", peer.peer_id);
+            let _ = poll.deregister(&peer.socket);
+            return;
+        }
+        let _ = self
+            .connected_ranges
+            .insert(ip_range(peer.peer_info.addr.ip()));
+        reconnect::remember_peer(
+            core,
+            peer.peer_id,
+            ReconnectTarget::Bootstrap(peer.peer_info, (&self.our_role).into()),
+        );
+        ActiveConnection::start(
+            core,
+            poll,
+            peer.token,
+            peer.socket,
+            self.our_uid,
+            peer.peer_id,
+            // Note; We bootstrap only to Nodes
+            CrustUser::Node,
+            Event::BootstrapConnect(peer.peer_id, peer.peer_info.addr),
+            self.event_tx.clone(),
+            peer.features,
+        );
+    }
+
     fn maybe_terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         if self.children.is_empty() {
-            info!("Bootstrapper has no active children left - bootstrap has failed");
-            self.terminate(core, poll);
-            let _ = self.event_tx.send(Event::BootstrapFailed);
+            info!("Bootstrapper has no active children left");
+            self.connect_deferred(core, poll);
+            self.finish(core, poll);
+        }
+    }
+
+    /// Stops bootstrapping, which failed unless we're connected to at least one peer. Failed
+    /// rounds are retried in persistent mode.
+    fn finish(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if !self.connected.is_empty() {
+            return self.terminate(core, poll);
+        }
+        self.end_round(core, poll);
+        let (attempt, delay) = match self.backoff {
+            Some(ref mut backoff) => (backoff.attempt() + 1, backoff.next_delay()),
+            None => {
+                info!("Bootstrap has failed");
+                self.terminate(core, poll);
+                let failures = mem::replace(&mut self.failures, Vec::new());
+                let _ = self.event_tx.send(Event::BootstrapFailed(failures));
+                return;
+            }
+        };
+        info!(
+            "Bootstrap round {} has failed, retrying in {:?}",
+            attempt, delay
+        );
+        self.retry_timeout =
+            Some(core.set_timeout(delay, CoreTimer::new(self.token, RETRY_TIMER_ID)));
+        let _ = self.event_tx.send(Event::BootstrapRetry(attempt, delay));
+    }
+
+    fn end_round(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.terminate_children(core, poll);
+        for peer in self.deferred.drain(..) {
+            let _ = poll.deregister(&peer.socket);
+        }
+        if let Some(sd_meta) = self.sd_meta.take() {
+            let _ = core.cancel_timeout(&sd_meta.timeout);
+        }
+        if let Some(bs_timeout) = self.bs_timeout.take() {
+            let _ = core.cancel_timeout(&bs_timeout);
         }
     }
 
     fn terminate_children(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        for child in self.children.drain() {
+        for (child, _) in self.children.drain() {
             let child = match core.get_state(child) {
                 Some(state) => state,
                 None => continue,
@@ -238,8 +470,23 @@ impl Bootstrap {
 impl State<CrustData> for Bootstrap {
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, timer_id: u8) {
         if timer_id == self.bs_timer.timer_id {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
+            self.bs_timeout = None;
+            let timed_out = self.children.values().map(|contact| BootstrapFailure {
+                contact: *contact,
+                reason: BootstrapFailureReason::Timeout,
+            });
+            self.failures.extend(timed_out);
+            self.terminate_children(core, poll);
+            self.connect_deferred(core, poll);
+            return self.finish(core, poll);
+        }
+        if timer_id == RETRY_TIMER_ID {
+            self.retry_timeout = None;
+            if let Err(e) = self.start_round(core, poll) {
+                debug!("Failed to start bootstrap round: {:?}", e);
+                self.finish(core, poll);
+            }
+            return;
         }
 
         let rx = unwrap!(self.sd_meta.take()).rx;
@@ -249,12 +496,11 @@ impl State<CrustData> for Bootstrap {
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        self.terminate_children(core, poll);
-        if let Some(sd_meta) = self.sd_meta.take() {
-            let _ = core.cancel_timeout(&sd_meta.timeout);
+        self.end_round(core, poll);
+        if let Some(retry_timeout) = self.retry_timeout.take() {
+            let _ = core.cancel_timeout(&retry_timeout);
         }
         let _ = core.remove_state(self.token);
-        let _ = core.cancel_timeout(&self.bs_timeout);
     }
 
     fn as_any(&mut self) -> &mut Any {
@@ -274,6 +520,10 @@ pub fn cache_peer_info(core: &mut EventLoopCore, poll: &Poll, peer_info: PeerInf
         debug!("Connecting to hard coded peer - it won't be cached.");
         return;
     }
+    if user_data.bans.is_contact_banned(&peer_info) {
+        debug!("Peer {:?} is banned - it won't be cached.", peer_info);
+        return;
+    }
 
     let expired_peers = user_data.bootstrap_cache.put(peer_info);
     user_data.bootstrap_cache.try_commit();
@@ -299,8 +549,10 @@ fn seek_peers(
         let (obs, rx) = mpsc::channel();
         state.register_observer(obs);
         state.seek_peers()?;
+        let timeouts = &core.user_data().config.cfg.timeouts;
+        let sd_timeout = Duration::from_millis(timeouts.service_discovery_timeout_ms);
         let timeout = core.set_timeout(
-            Duration::from_secs(SERVICE_DISCOVERY_TIMEOUT_SEC),
+            sd_timeout,
             CoreTimer::new(token, SERVICE_DISCOVERY_TIMER_ID),
         );
 
@@ -314,7 +566,7 @@ fn seek_peers(
 fn bootstrap_peers(
     cached_peers: Vec<PeerInfo>,
     config: &Config,
-    blacklist: HashSet<SocketAddr>,
+    blacklist: &HashSet<SocketAddr>,
 ) -> Vec<PeerInfo> {
     let mut peers = cached_peers;
     let mut hard_coded = config.hard_coded_contacts.clone();
@@ -326,6 +578,20 @@ fn bootstrap_peers(
     peers
 }
 
+/// Peers in the same range are likely run by the same party. That's /16 for IPv4 and /32 for IPv6.
+fn ip_range(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], 0, 0))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], 0, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
 /// Appends peers received from `ServiceDiscMeta` to the peers list
 fn receive_peers_into(peers: &mut Vec<PeerInfo>, rx: Receiver<HashSet<PeerInfo>>) {
     let our_peers: HashSet<&PeerInfo> = HashSet::from_iter(peers.iter());
@@ -405,7 +671,7 @@ mod tests {
             config.hard_coded_contacts = vec![peer1];
             let cached_peers = vec![peer2];
 
-            let peers = bootstrap_peers(cached_peers, &config, Default::default());
+            let peers = bootstrap_peers(cached_peers, &config, &Default::default());
 
             assert_eq!(peers.len(), 2);
             assert!(peers.contains(&peer1));
@@ -422,13 +688,37 @@ mod tests {
             let mut blacklisted = HashSet::new();
             let _ = blacklisted.insert(ipv4_addr(1, 2, 3, 4, 4000));
 
-            let peers = bootstrap_peers(cached_peers, &config, blacklisted);
+            let peers = bootstrap_peers(cached_peers, &config, &blacklisted);
 
             assert_eq!(peers.len(), 1);
             assert!(peers.contains(&peer2));
         }
     }
 
+    mod ip_range {
+        use super::*;
+
+        #[test]
+        fn it_groups_ipv4_addresses_by_16_bit_prefix() {
+            let ip1 = ipv4_addr(1, 2, 3, 4, 4000).ip();
+            let ip2 = ipv4_addr(1, 2, 200, 5, 5000).ip();
+            let ip3 = ipv4_addr(1, 3, 3, 4, 4000).ip();
+
+            assert_eq!(ip_range(ip1), ip_range(ip2));
+            assert_ne!(ip_range(ip1), ip_range(ip3));
+        }
+
+        #[test]
+        fn it_groups_ipv6_addresses_by_32_bit_prefix() {
+            let ip1 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1));
+            let ip2 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 2, 0, 0, 0, 0, 1));
+            let ip3 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb9, 1, 0, 0, 0, 0, 1));
+
+            assert_eq!(ip_range(ip1), ip_range(ip2));
+            assert_ne!(ip_range(ip1), ip_range(ip3));
+        }
+    }
+
     mod receive_peers_into {
         use super::*;
 
@@ -535,7 +825,7 @@ mod tests {
                         &mut core,
                         &poll,
                         Token(2),
-                        Err((peer_info, None)),
+                        Err((peer_info, BootstrapFailureReason::Timeout)),
                     );
 
                     let cached_peers = core.user_data().bootstrap_cache.snapshot();
@@ -577,7 +867,10 @@ mod tests {
                         &mut core,
                         &poll,
                         Token(2),
-                        Err((peer_info, Some(BootstrapDenyReason::InvalidNameHash))),
+                        Err((
+                            peer_info,
+                            BootstrapFailureReason::Denied(BootstrapDenyReason::InvalidNameHash),
+                        )),
                     );
 
                     let state = core.get_state(token);
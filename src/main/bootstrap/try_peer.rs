@@ -7,12 +7,15 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{BootstrapDenyReason, BootstrapperRole, Message, NameHash, PeerInfo, State};
-use crate::main::{CrustData, EventLoopCore};
+use crate::common::{
+    set_session_key, BootstrapperRole, Features, HandshakeAuth, HandshakeNonce, Message, NameHash,
+    PeerInfo, ProtocolInfo, Socket, State, Transport,
+};
+use crate::main::{BootstrapFailureReason, CrustData, EventLoopCore};
 use crate::PeerId;
 use mio::{Poll, PollOpt, Ready, Token};
-use safe_crypto::{SecretEncryptKey, SharedSecretKey};
-use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
+use safe_crypto::{gen_encrypt_keypair, SecretEncryptKey, SharedSecretKey};
+use socket_collection::{DecryptContext, EncryptContext, Priority, SocketError};
 use std::any::Any;
 use std::cell::RefCell;
 use std::mem;
@@ -23,7 +26,7 @@ pub type Finish = Box<
         &mut EventLoopCore,
         &Poll,
         Token,
-        Result<(TcpSock, PeerInfo, PeerId), (PeerInfo, Option<BootstrapDenyReason>)>,
+        Result<(Socket, PeerInfo, PeerId, Features), (PeerInfo, BootstrapFailureReason)>,
     ),
 >;
 
@@ -31,13 +34,24 @@ pub type Finish = Box<
 pub struct TryPeer {
     token: Token,
     peer: PeerInfo,
-    socket: TcpSock,
+    socket: Socket,
     request: Option<(Message, Priority)>,
     finish: Finish,
+    name_hash: NameHash,
+    our_uid: PeerId,
+    our_role: BootstrapperRole,
     shared_key: SharedSecretKey,
+    /// Nonce the peer has to sign to prove it owns the signing key in its `PeerId`.
+    nonce: HandshakeNonce,
+    /// Our ephemeral secret key. Once the peer sends its ephemeral public key, the session key
+    /// is derived from both.
+    session_sk: SecretEncryptKey,
+    /// Transports to try next if the peer can't be reached over the current one.
+    fallback: Vec<Transport>,
 }
 
 impl TryPeer {
+    /// Tries `transports` in order until the peer is reached over one of them.
     pub fn start(
         core: &mut EventLoopCore,
         poll: &Poll,
@@ -46,28 +60,45 @@ impl TryPeer {
         name_hash: NameHash,
         our_role: BootstrapperRole,
         our_sk: &SecretEncryptKey,
+        transports: Vec<Transport>,
         finish: Finish,
     ) -> crate::Res<Token> {
-        let mut socket = TcpSock::connect(&peer.addr)?;
-        socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(peer.pub_key))?;
         let shared_key = our_sk.shared_secret(&peer.pub_key);
-        socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone()))?;
         let token = core.get_new_token();
-
-        poll.register(
-            &socket,
+        let mut transports = transports.into_iter();
+        let mut socket = open_socket(
+            core,
+            poll,
             token,
-            Ready::writable() | Ready::readable(),
-            PollOpt::edge(),
-        )?;
+            transports.next().unwrap_or(Transport::Tcp),
+            &peer,
+            &shared_key,
+        );
+        while socket.is_err() {
+            match transports.next() {
+                Some(transport) => {
+                    socket = open_socket(core, poll, token, transport, &peer, &shared_key)
+                }
+                None => break,
+            }
+        }
+        let socket = socket?;
+        let (request, nonce, session_sk) =
+            bootstrap_request(core, &peer, our_uid, name_hash, &our_role)?;
 
         let state = TryPeer {
             token,
             peer,
             socket,
-            request: Some((Message::BootstrapRequest(our_uid, name_hash, our_role), 0)),
+            request: Some((request, 0)),
             finish,
+            name_hash,
+            our_uid,
+            our_role,
             shared_key,
+            nonce,
+            session_sk,
+            fallback: transports.collect(),
         };
 
         let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
@@ -75,36 +106,99 @@ impl TryPeer {
         Ok(token)
     }
 
+    /// Starts over on the next transport. Returns `false` if there's none left to try.
+    fn try_next_transport(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        while !self.fallback.is_empty() {
+            let transport = self.fallback.remove(0);
+            let _ = poll.deregister(&self.socket);
+            let res = open_socket(
+                core,
+                poll,
+                self.token,
+                transport,
+                &self.peer,
+                &self.shared_key,
+            )
+            .and_then(|socket| {
+                let (request, nonce, session_sk) = bootstrap_request(
+                    core,
+                    &self.peer,
+                    self.our_uid,
+                    self.name_hash,
+                    &self.our_role,
+                )?;
+                self.socket = socket;
+                self.request = Some((request, 0));
+                self.nonce = nonce;
+                self.session_sk = session_sk;
+                Ok(())
+            });
+            match res {
+                Ok(()) => return true,
+                Err(e) => debug!(
+                    "Failed to reach {:?} over {:?}: {}",
+                    self.peer, transport, e
+                ),
+            }
+        }
+        false
+    }
+
     fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, msg: Option<(Message, Priority)>) {
-        if self.socket.write(msg).is_err() {
-            self.handle_error(core, poll, None);
+        if let Err(e) = self.socket.write(msg) {
+            self.handle_error(core, poll, BootstrapFailureReason::Io(e.to_string()));
         }
     }
 
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         match self.socket.read::<Message>() {
-            Ok(Some(Message::BootstrapGranted(peer_uid))) => {
+            Ok(Some(Message::BootstrapGranted(peer_uid, their_protocol, auth))) => {
+                if peer_uid.pub_enc_key != self.peer.pub_key {
+                    debug!("{:?} responded as {:?}", self.peer, peer_uid);
+                    return self.handle_error(core, poll, BootstrapFailureReason::InvalidKey);
+                }
+                if auth.nonce != self.nonce
+                    || !auth.verify(&peer_uid, &self.name_hash, &self.our_uid.pub_enc_key)
+                {
+                    debug!("{:?} failed to prove it owns {:?}", self.peer, peer_uid);
+                    return self.handle_error(core, poll, BootstrapFailureReason::InvalidSignature);
+                }
+                if !their_protocol.is_compatible() {
+                    debug!("{:?} speaks protocol {:?}", self.peer, their_protocol);
+                    let reason =
+                        BootstrapFailureReason::IncompatibleVersion(their_protocol.version);
+                    return self.handle_error(core, poll, reason);
+                }
                 let _ = core.remove_state(self.token);
                 let token = self.token;
 
                 let mut socket = mem::replace(&mut self.socket, Default::default());
-                match socket.set_encrypt_ctx(EncryptContext::authenticated(self.shared_key.clone()))
-                {
-                    Ok(_) => {
-                        let data = (socket, self.peer, peer_uid);
+                let session_key = self.session_sk.shared_secret(&auth.session_pk);
+                match set_session_key(&mut socket, session_key) {
+                    Ok(()) => {
+                        let features = their_protocol.negotiated_features();
+                        let data = (socket, self.peer, peer_uid, features);
                         (*self.finish)(core, poll, token, Ok(data));
                     }
                     Err(e) => {
-                        debug!("Failed to set socket encrypt context: {}", e);
-                        self.handle_error(core, poll, None);
+                        debug!("Failed to set socket session key: {}", e);
+                        self.fail(core, poll, BootstrapFailureReason::Io(e.to_string()));
                     }
                 }
             }
             Ok(Some(Message::BootstrapDenied(reason))) => {
-                self.handle_error(core, poll, Some(reason))
+                self.handle_error(core, poll, BootstrapFailureReason::Denied(reason))
             }
             Ok(None) => (),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll, None),
+            Ok(Some(msg)) => {
+                debug!("Unexpected bootstrap response: {:?}", msg);
+                self.handle_error(core, poll, BootstrapFailureReason::UnexpectedMessage)
+            }
+            // The response was encrypted with a different key than the one we have for the peer.
+            Err(SocketError::Crypto(_)) => {
+                self.handle_error(core, poll, BootstrapFailureReason::InvalidKey)
+            }
+            Err(e) => self.handle_error(core, poll, BootstrapFailureReason::Io(e.to_string())),
         }
     }
 
@@ -112,8 +206,23 @@ impl TryPeer {
         &mut self,
         core: &mut EventLoopCore,
         poll: &Poll,
-        reason: Option<BootstrapDenyReason>,
+        reason: BootstrapFailureReason,
     ) {
+        if let BootstrapFailureReason::Io(ref e) = reason {
+            debug!(
+                "Failed to bootstrap off {:?} over {:?}: {}",
+                self.peer,
+                self.socket.transport(),
+                e
+            );
+            if self.try_next_transport(core, poll) {
+                return;
+            }
+        }
+        self.fail(core, poll, reason);
+    }
+
+    fn fail(&mut self, core: &mut EventLoopCore, poll: &Poll, reason: BootstrapFailureReason) {
         self.terminate(core, poll);
         (*self.finish)(core, poll, self.token, Err((self.peer, reason)));
     }
@@ -136,7 +245,8 @@ impl State<CrustData> for TryPeer {
             "Considering the following event to indicate disrupted connection: {:?}",
             kind
         );
-        self.handle_error(core, poll, None);
+        let reason = BootstrapFailureReason::Io(format!("Connection disrupted: {:?}", kind));
+        self.handle_error(core, poll, reason);
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
@@ -148,3 +258,51 @@ impl State<CrustData> for TryPeer {
         self
     }
 }
+
+fn open_socket(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    token: Token,
+    transport: Transport,
+    peer: &PeerInfo,
+    shared_key: &SharedSecretKey,
+) -> crate::Res<Socket> {
+    let endpoint = core.user_data().udp_endpoint(&peer.addr);
+    let mut socket = Socket::connect(core, poll, transport, &peer.addr, endpoint)?;
+    socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(peer.pub_key))?;
+    socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone()))?;
+    poll.register(
+        &socket,
+        token,
+        Ready::writable() | Ready::readable(),
+        PollOpt::edge(),
+    )?;
+    Ok(socket)
+}
+
+/// Builds the request along with the nonce the peer has to sign and our ephemeral secret key.
+fn bootstrap_request(
+    core: &EventLoopCore,
+    peer: &PeerInfo,
+    our_uid: PeerId,
+    name_hash: NameHash,
+    our_role: &BootstrapperRole,
+) -> crate::Res<(Message, HandshakeNonce, SecretEncryptKey)> {
+    let (session_pk, session_sk) = gen_encrypt_keypair();
+    let auth = HandshakeAuth::new(
+        &core.user_data().our_sign_sk,
+        &name_hash,
+        &our_uid.pub_enc_key,
+        &peer.pub_key,
+        session_pk,
+    )?;
+    let nonce = auth.nonce;
+    let request = Message::BootstrapRequest(
+        our_uid,
+        name_hash,
+        our_role.clone(),
+        ProtocolInfo::ours(),
+        auth,
+    );
+    Ok((request, nonce, session_sk))
+}
@@ -7,9 +7,14 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::PeerInfo;
-use crate::main::BootstrapCacheConfig;
+use crate::common::{CrustUser, PeerInfo, Transport};
+use crate::main::{
+    BanConfig, BootstrapCacheConfig, BootstrapRetryConfig, ConnectionLimitsConfig,
+    HandshakeLimitsConfig, IpRange, PeerExchangeConfig, ReconnectConfig, RekeyConfig, RelayConfig,
+    Timeouts,
+};
 use config_file_handler::{self, FileHandler};
+use safe_crypto::PublicEncryptKey;
 use std::collections::HashSet;
 use std::ffi::OsString;
 use std::net::IpAddr;
@@ -43,15 +48,72 @@ pub struct Config {
     pub service_discovery_listener_port: Option<u16>,
     /// Bootstrap cache specific settings.
     pub bootstrap_cache: BootstrapCacheConfig,
-    /// Whitelisted nodes who are allowed to bootstrap off us or to connect to us
-    pub whitelisted_node_ips: Option<HashSet<IpAddr>>,
-    /// Whitelisted clients who are allowed to bootstrap off us
-    pub whitelisted_client_ips: Option<HashSet<IpAddr>>,
+    /// Whitelisted nodes who are allowed to bootstrap off us or to connect to us. Entries are
+    /// addresses or CIDR ranges, e.g. `10.0.0.0/8`.
+    pub whitelisted_node_ips: Option<HashSet<IpRange>>,
+    /// Whitelisted clients who are allowed to bootstrap off us. Entries are addresses or CIDR
+    /// ranges.
+    pub whitelisted_client_ips: Option<HashSet<IpRange>>,
+    /// Peers allowed regardless of the IP whitelists, identified by their public encryption key.
+    #[serde(default)]
+    pub whitelisted_keys: HashSet<PublicEncryptKey>,
+    /// Addresses and CIDR ranges we never accept or connect to, even if they are whitelisted.
+    #[serde(default)]
+    pub denied_ips: HashSet<IpRange>,
     /// Network ID
     ///
     /// This is a mechanism to prevent nodes from different decentralized
     /// networks to connect to each other (issue #209)
     pub network_name: Option<String>,
+    /// Find out our external address via IGD and hard coded contacts when preparing connection
+    /// info, so that peers behind NATs can hole punch a connection to us.
+    #[serde(default)]
+    pub nat_traversal: bool,
+    /// Keep bootstrapping until connected to this many distinct peers, preferring peers in
+    /// different IP ranges. Defaults to 1.
+    #[serde(default)]
+    pub target_bootstrap_connections: Option<usize>,
+    /// Persistent bootstrapping, which retries failed rounds.
+    #[serde(default)]
+    pub bootstrap_retry: BootstrapRetryConfig,
+    /// Automatic reconnection to lost peers.
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    /// Largest message in bytes we are willing to send or receive. Messages larger than
+    /// 512 KiB are split into fragments and reassembled on the receiving side. Defaults to
+    /// 16 MiB.
+    #[serde(default)]
+    pub max_message_size: Option<usize>,
+    /// Forwarding traffic between peers which can't connect directly.
+    #[serde(default)]
+    pub relay: RelayConfig,
+    /// Peers and IP addresses banned at runtime.
+    #[serde(default)]
+    pub bans: BanConfig,
+    /// Sharing bootstrap contacts with connected nodes.
+    #[serde(default)]
+    pub peer_exchange: PeerExchangeConfig,
+    /// Periodic replacement of the session keys of our connections.
+    #[serde(default)]
+    pub rekey: RekeyConfig,
+    /// Limits on incoming connections which haven't completed the handshake yet.
+    #[serde(default)]
+    pub handshake_limits: HandshakeLimitsConfig,
+    /// Most connections we keep, by role of the peer.
+    #[serde(default)]
+    pub connection_limits: ConnectionLimitsConfig,
+    /// Emit `Event::PeerLatency` whenever the round trip time to a peer is measured.
+    #[serde(default)]
+    pub report_peer_latency: bool,
+    /// Heartbeat, handshake, connect and bootstrap timings.
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// Transports we listen on and connect over, in the order of preference. Connections to a
+    /// peer are attempted over each transport both support, bootstrap attempts fall back to the
+    /// next transport if the previous one fails. UDP connections use the port of the TCP
+    /// listener. Defaults to TCP only.
+    #[serde(default)]
+    pub transports: Option<Vec<Transport>>,
 }
 
 impl Default for Config {
@@ -65,15 +127,77 @@ impl Default for Config {
             bootstrap_cache: Default::default(),
             whitelisted_node_ips: None,
             whitelisted_client_ips: None,
+            whitelisted_keys: HashSet::new(),
+            denied_ips: HashSet::new(),
             network_name: None,
+            nat_traversal: false,
+            target_bootstrap_connections: None,
+            bootstrap_retry: Default::default(),
+            reconnect: Default::default(),
+            max_message_size: None,
+            relay: Default::default(),
+            bans: Default::default(),
+            peer_exchange: Default::default(),
+            rekey: Default::default(),
+            handshake_limits: Default::default(),
+            connection_limits: Default::default(),
+            report_peer_latency: false,
+            timeouts: Default::default(),
+            transports: None,
         }
     }
 }
 
+impl Config {
+    /// Configured transports, without duplicates. TCP if none are set.
+    pub fn transports(&self) -> Vec<Transport> {
+        let mut transports = Vec::new();
+        for transport in self.transports.iter().flatten() {
+            if !transports.contains(transport) {
+                transports.push(*transport);
+            }
+        }
+        if transports.is_empty() {
+            transports.push(Transport::Tcp);
+        }
+        transports
+    }
+
+    /// Returns true if `ip` is in `denied_ips`.
+    pub fn is_ip_denied(&self, ip: IpAddr) -> bool {
+        self.denied_ips.iter().any(|range| range.contains(ip))
+    }
+
+    /// Returns true if a peer with the given role and public key is allowed to connect from or
+    /// listen on `ip`. Denied addresses are never allowed, whitelisted keys always are otherwise.
+    /// Everyone else has to be in the whitelist for their role, if it's set.
+    pub fn is_whitelisted(
+        &self,
+        peer_kind: CrustUser,
+        ip: IpAddr,
+        pub_key: &PublicEncryptKey,
+    ) -> bool {
+        if self.is_ip_denied(ip) {
+            return false;
+        }
+        if self.whitelisted_keys.contains(pub_key) {
+            return true;
+        }
+        let whitelist = match peer_kind {
+            CrustUser::Node => &self.whitelisted_node_ips,
+            CrustUser::Client => &self.whitelisted_client_ips,
+        };
+        whitelist
+            .as_ref()
+            .map_or(true, |ranges| ranges.iter().any(|range| range.contains(ip)))
+    }
+}
+
 /// Reads the default crust config file.
 pub fn read_config_file() -> crate::Res<Config> {
     let file_handler = FileHandler::new(&get_file_name()?, false)?;
-    let cfg = file_handler.read_file()?;
+    let cfg: Config = file_handler.read_file()?;
+    cfg.timeouts.validate()?;
     Ok(cfg)
 }
 
@@ -116,10 +240,54 @@ fn get_file_name() -> crate::Res<OsString> {
 #[cfg(test)]
 mod tests {
     use super::Config;
+    use crate::common::{CrustUser, Transport};
+    use crate::main::IpRange;
+    use safe_crypto::gen_encrypt_keypair;
     use serde_json;
     use std::io::Read;
+    use std::net::IpAddr;
     use std::path::Path;
 
+    fn ip(s: &str) -> IpAddr {
+        unwrap!(s.parse())
+    }
+
+    #[test]
+    fn whitelists_match_ranges_and_keys() {
+        let (trusted_pk, _) = gen_encrypt_keypair();
+        let (other_pk, _) = gen_encrypt_keypair();
+        let mut config = Config::default();
+        let subnet: IpRange = unwrap!("10.0.0.0/8".parse());
+        config.whitelisted_node_ips = Some(vec![subnet].into_iter().collect());
+        config.whitelisted_client_ips = Some(Default::default());
+        let _ = config.whitelisted_keys.insert(trusted_pk);
+        let _ = config.denied_ips.insert(unwrap!("10.66.0.0/16".parse()));
+
+        assert!(config.is_whitelisted(CrustUser::Node, ip("10.1.2.3"), &other_pk));
+        assert!(!config.is_whitelisted(CrustUser::Node, ip("11.1.2.3"), &other_pk));
+        assert!(!config.is_whitelisted(CrustUser::Client, ip("10.1.2.3"), &other_pk));
+
+        assert!(config.is_whitelisted(CrustUser::Node, ip("11.1.2.3"), &trusted_pk));
+        assert!(config.is_whitelisted(CrustUser::Client, ip("11.1.2.3"), &trusted_pk));
+
+        // The deny list wins over both whitelists.
+        assert!(config.is_ip_denied(ip("10.66.1.1")));
+        assert!(!config.is_whitelisted(CrustUser::Node, ip("10.66.1.1"), &other_pk));
+        assert!(!config.is_whitelisted(CrustUser::Node, ip("10.66.1.1"), &trusted_pk));
+    }
+
+    #[test]
+    fn transports_default_to_tcp() {
+        let mut config = Config::default();
+        assert_eq!(config.transports(), vec![Transport::Tcp]);
+
+        config.transports = Some(vec![]);
+        assert_eq!(config.transports(), vec![Transport::Tcp]);
+
+        config.transports = Some(vec![Transport::Udp, Transport::Tcp, Transport::Udp]);
+        assert_eq!(config.transports(), vec![Transport::Udp, Transport::Tcp]);
+    }
+
     #[test]
     fn parse_sample_config_file() {
         let path = Path::new("installer/sample.config").to_path_buf();
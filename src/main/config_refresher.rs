@@ -7,8 +7,11 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{CoreTimer, CrustUser, State};
-use crate::main::{read_config_file, ActiveConnection, CrustData, EventLoopCore};
+use crate::common::{CoreTimer, State};
+use crate::main::reconnect;
+use crate::main::{
+    read_config_file, ActiveConnection, CrustData, EventLoopCore, RelayedConnection,
+};
 use mio::{Poll, Token};
 use mio_extras::timer::Timeout;
 use std::any::Any;
@@ -16,8 +19,6 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use std::time::Duration;
 
-const REFRESH_INTERVAL_SEC: u64 = 30;
-
 pub struct ConfigRefresher {
     token: Token,
     timer: CoreTimer,
@@ -29,7 +30,7 @@ impl ConfigRefresher {
         trace!("Entered state ConfigRefresher");
 
         let timer = CoreTimer::new(token, 0);
-        let timeout = core.set_timeout(Duration::from_secs(REFRESH_INTERVAL_SEC), timer);
+        let timeout = core.set_timeout(refresh_interval(core), timer);
 
         let state = Rc::new(RefCell::new(ConfigRefresher {
             token,
@@ -49,8 +50,6 @@ impl State<CrustData> for ConfigRefresher {
     }
 
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
-        self.timeout = core.set_timeout(Duration::from_secs(REFRESH_INTERVAL_SEC), self.timer);
-
         let config = match read_config_file() {
             Ok(cfg) => cfg,
             Err(e) => {
@@ -58,18 +57,24 @@ impl State<CrustData> for ConfigRefresher {
                     "Could not read Crust config (it's rescheduled to be read): {:?}",
                     e
                 );
+                self.timeout = core.set_timeout(refresh_interval(core), self.timer);
                 return;
             }
         };
+        self.timeout = core.set_timeout(
+            Duration::from_secs(config.timeouts.config_refresh_interval_sec),
+            self.timer,
+        );
 
-        let whitelisted_node_ips = config.whitelisted_node_ips.clone();
-        let whitelisted_client_ips = config.whitelisted_client_ips.clone();
+        let restricts_peers = config.whitelisted_node_ips.is_some()
+            || config.whitelisted_client_ips.is_some()
+            || !config.denied_ips.is_empty();
 
         if !core
             .user_data_mut()
             .config
             .check_for_refresh_and_reset_modified(config)
-            || (whitelisted_node_ips.is_none() && whitelisted_client_ips.is_none())
+            || !restricts_peers
         {
             return;
         }
@@ -79,16 +84,21 @@ impl State<CrustData> for ConfigRefresher {
              longer whitelisted"
         );
 
+        let config = &core.user_data().config.cfg;
         let peers_to_terminate: Vec<_> = core
             .user_data()
             .connections
-            .values()
-            .filter_map(|cid| {
+            .iter()
+            .filter_map(|(their_id, cid)| {
                 cid.active_connection
                     .and_then(|token| core.get_state(token))
                     .and_then(|peer| {
                         let should_drop = {
                             let mut state = peer.borrow_mut();
+                            // Relayed peers' IPs are unknown to us.
+                            if state.as_any().is::<RelayedConnection>() {
+                                return None;
+                            }
                             let ac = match state.as_any().downcast_mut::<ActiveConnection>() {
                                 Some(ac) => ac,
                                 None => {
@@ -106,18 +116,15 @@ impl State<CrustData> for ConfigRefresher {
                                     );
                                     true
                                 }
-                                Ok(s) => match ac.peer_kind() {
-                                    CrustUser::Node => whitelisted_node_ips
-                                        .as_ref()
-                                        .map_or(false, |ips| !ips.contains(&s.ip())),
-                                    CrustUser::Client => whitelisted_client_ips
-                                        .as_ref()
-                                        .map_or(false, |ips| !ips.contains(&s.ip())),
-                                },
+                                Ok(s) => !config.is_whitelisted(
+                                    ac.peer_kind(),
+                                    s.ip(),
+                                    &their_id.pub_enc_key,
+                                ),
                             }
                         };
                         if should_drop {
-                            Some(peer)
+                            Some((*their_id, peer))
                         } else {
                             None
                         }
@@ -125,7 +132,8 @@ impl State<CrustData> for ConfigRefresher {
             })
             .collect();
 
-        for peer in peers_to_terminate {
+        for (their_id, peer) in peers_to_terminate {
+            reconnect::forget_peer(core, poll, &their_id);
             peer.borrow_mut().terminate(core, poll);
         }
     }
@@ -134,3 +142,13 @@ impl State<CrustData> for ConfigRefresher {
         self
     }
 }
+
+fn refresh_interval(core: &EventLoopCore) -> Duration {
+    Duration::from_secs(
+        core.user_data()
+            .config
+            .cfg
+            .timeouts
+            .config_refresh_interval_sec,
+    )
+}
@@ -7,12 +7,15 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{Message, NameHash, State};
-use crate::main::{ConnectionId, CrustData, EventLoopCore};
+use crate::common::{
+    set_session_key, Features, HandshakeAuth, HandshakeNonce, Message, NameHash, ProtocolInfo,
+    Socket, State,
+};
+use crate::main::{ConnectAttemptError, ConnectionId, CrustData, EventLoopCore};
 use crate::PeerId;
 use mio::{Poll, PollOpt, Ready, Token};
-use safe_crypto::SharedSecretKey;
-use socket_collection::{EncryptContext, Priority, TcpSock};
+use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey};
+use socket_collection::Priority;
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
@@ -23,31 +26,49 @@ use std::rc::Rc;
 
 /// When connection messages are exchanged a callback is called with these parameters.
 /// A new mio `Token` is assigned to the given socket.
-pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, Option<TcpSock>)>;
+pub type Finish =
+    Box<FnMut(&mut EventLoopCore, &Poll, Token, Result<(Socket, Features), ConnectAttemptError>)>;
 
 /// Exchanges connect messages.
 pub struct ExchangeMsg {
     token: Token,
     expected_id: PeerId,
     expected_nh: NameHash,
-    socket: TcpSock,
+    socket: Socket,
     msg: Option<(Message, Priority)>,
-    shared_key: SharedSecretKey,
+    /// Hole punched connections have no listener on either side, hence both peers send
+    /// `ConnectRequest` and answer the other's request with `ConnectResponse`.
+    rendezvous: bool,
     finish: Finish,
+    our_id: PeerId,
+    /// Nonce the peer has to sign to prove it owns the signing key in its `PeerId`.
+    nonce: HandshakeNonce,
+    /// Our ephemeral key pair. Once the peer sends its ephemeral public key, the session key is
+    /// derived from both.
+    session_pk: PublicEncryptKey,
+    session_sk: SecretEncryptKey,
 }
 
 impl ExchangeMsg {
     pub fn start(
         core: &mut EventLoopCore,
         poll: &Poll,
-        socket: TcpSock,
+        socket: Socket,
         our_id: PeerId,
         expected_id: PeerId,
         name_hash: NameHash,
-        shared_key: SharedSecretKey,
         our_global_direct_listeners: HashSet<SocketAddr>,
+        rendezvous: bool,
         finish: Finish,
     ) -> crate::Res<Token> {
+        let (session_pk, session_sk) = gen_encrypt_keypair();
+        let auth = HandshakeAuth::new(
+            &core.user_data().our_sign_sk,
+            &name_hash,
+            &our_id.pub_enc_key,
+            &expected_id.pub_enc_key,
+            session_pk,
+        )?;
         let token = core.get_new_token();
 
         poll.register(
@@ -76,12 +97,22 @@ impl ExchangeMsg {
             expected_id,
             expected_nh: name_hash,
             socket,
+            nonce: auth.nonce,
             msg: Some((
-                Message::ConnectRequest(our_id, name_hash, our_global_direct_listeners),
+                Message::ConnectRequest(
+                    our_id,
+                    name_hash,
+                    our_global_direct_listeners,
+                    ProtocolInfo::ours(),
+                    auth,
+                ),
                 0,
             )),
-            shared_key,
+            rendezvous,
             finish,
+            our_id,
+            session_pk,
+            session_sk,
         };
 
         let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
@@ -90,39 +121,153 @@ impl ExchangeMsg {
     }
 
     fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, msg: Option<(Message, Priority)>) {
-        if self.socket.write(msg).is_err() {
-            self.handle_error(core, poll);
+        if let Err(e) = self.socket.write(msg) {
+            self.handle_error(core, poll, ConnectAttemptError::Io(e.to_string()));
         }
     }
 
     fn receive_response(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        match self.socket.read::<Message>() {
-            Ok(Some(Message::ConnectResponse(their_uid, name_hash))) => {
-                if their_uid != self.expected_id || name_hash != self.expected_nh {
-                    return self.handle_error(core, poll);
+        loop {
+            match self.socket.read::<Message>() {
+                Ok(Some(Message::ConnectResponse(their_uid, name_hash, their_protocol, auth))) => {
+                    if auth.nonce != self.nonce {
+                        // The responder has to sign the session we started.
+                        let error = ConnectAttemptError::InvalidSignature;
+                        return self.handle_error(core, poll, error);
+                    }
+                    return self.handle_response(
+                        core,
+                        poll,
+                        their_uid,
+                        name_hash,
+                        their_protocol,
+                        auth,
+                    );
                 }
-                let _ = core.remove_state(self.token);
-                let token = self.token;
-
-                let mut socket = mem::replace(&mut self.socket, Default::default());
-                match socket.set_encrypt_ctx(EncryptContext::authenticated(self.shared_key.clone()))
+                Ok(Some(Message::ConnectRequest(their_uid, name_hash, _, _, auth)))
+                    if self.rendezvous =>
                 {
-                    Ok(_) => (*self.finish)(core, poll, token, Some(socket)),
-                    Err(e) => {
-                        debug!("Failed to set socket encrypt context: {}", e);
-                        self.handle_error(core, poll);
+                    // The peer's response may have arrived right after its request.
+                    if !self.answer_request(core, poll, their_uid, name_hash, &auth) {
+                        return;
                     }
                 }
+                Ok(Some(Message::BootstrapDenied(reason))) => {
+                    return self.handle_error(core, poll, ConnectAttemptError::Denied(reason));
+                }
+                Ok(None) => return,
+                Ok(Some(msg)) => {
+                    debug!("Unexpected connect response: {:?}", msg);
+                    return self.handle_error(core, poll, ConnectAttemptError::UnexpectedMessage);
+                }
+                Err(e) => {
+                    let error = ConnectAttemptError::Io(e.to_string());
+                    return self.handle_error(core, poll, error);
+                }
+            }
+        }
+    }
+
+    /// Answers the request of a hole punched peer by signing its nonce, the same way a listener
+    /// does. Our own session starts once the peer answers our request likewise, so neither of
+    /// us accepts a replayed request. Returns false if the attempt failed.
+    fn answer_request(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_uid: PeerId,
+        name_hash: NameHash,
+        their_auth: &HandshakeAuth,
+    ) -> bool {
+        if let Err(error) = self.validate_peer(&their_uid, &name_hash, their_auth) {
+            self.handle_error(core, poll, error);
+            return false;
+        }
+        let auth = HandshakeAuth::sign(
+            &core.user_data().our_sign_sk,
+            &name_hash,
+            &self.our_id.pub_enc_key,
+            &their_uid.pub_enc_key,
+            self.session_pk,
+            their_auth.nonce,
+        );
+        let auth = match auth {
+            Ok(auth) => auth,
+            Err(e) => {
+                debug!("Failed to sign connect response: {:?}", e);
+                let error = ConnectAttemptError::Io(format!("{:?}", e));
+                self.handle_error(core, poll, error);
+                return false;
+            }
+        };
+        let msg = Message::ConnectResponse(self.our_id, name_hash, ProtocolInfo::ours(), auth);
+        // Our request has to go out first, unless it's been sent already.
+        let res = match self.msg.take() {
+            Some(req) => self.socket.write(Some(req)),
+            None => Ok(true),
+        };
+        if let Err(e) = res.and_then(|_| self.socket.write(Some((msg, 0)))) {
+            self.handle_error(core, poll, ConnectAttemptError::Io(e.to_string()));
+            return false;
+        }
+        true
+    }
+
+    fn validate_peer(
+        &self,
+        their_uid: &PeerId,
+        name_hash: &NameHash,
+        auth: &HandshakeAuth,
+    ) -> Result<(), ConnectAttemptError> {
+        if *their_uid != self.expected_id {
+            return Err(ConnectAttemptError::UnexpectedPeer(*their_uid));
+        }
+        if *name_hash != self.expected_nh {
+            return Err(ConnectAttemptError::NameHashMismatch);
+        }
+        if !auth.verify(their_uid, name_hash, &self.our_id.pub_enc_key) {
+            return Err(ConnectAttemptError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    fn handle_response(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_uid: PeerId,
+        name_hash: NameHash,
+        their_protocol: ProtocolInfo,
+        auth: HandshakeAuth,
+    ) {
+        if let Err(error) = self.validate_peer(&their_uid, &name_hash, &auth) {
+            return self.handle_error(core, poll, error);
+        }
+        if !their_protocol.is_compatible() {
+            let error = ConnectAttemptError::IncompatibleVersion(their_protocol.version);
+            return self.handle_error(core, poll, error);
+        }
+        let _ = core.remove_state(self.token);
+        let token = self.token;
+
+        let mut socket = mem::replace(&mut self.socket, Default::default());
+        let session_key = self.session_sk.shared_secret(&auth.session_pk);
+        match set_session_key(&mut socket, session_key) {
+            Ok(()) => {
+                let features = their_protocol.negotiated_features();
+                (*self.finish)(core, poll, token, Ok((socket, features)))
+            }
+            Err(e) => {
+                debug!("Failed to set socket session key: {}", e);
+                self.handle_error(core, poll, ConnectAttemptError::Io(e.to_string()));
             }
-            Ok(None) => (),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll),
         }
     }
 
-    fn handle_error(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+    fn handle_error(&mut self, core: &mut EventLoopCore, poll: &Poll, error: ConnectAttemptError) {
         self.terminate(core, poll);
         let token = self.token;
-        (*self.finish)(core, poll, token, None);
+        (*self.finish)(core, poll, token, Err(error));
     }
 }
 
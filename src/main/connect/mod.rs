@@ -10,25 +10,88 @@
 mod exchange_msg;
 
 use self::exchange_msg::ExchangeMsg;
-use crate::common::{CoreTimer, CrustUser, NameHash, PeerInfo, State};
+use crate::common::{
+    BootstrapDenyReason, CoreTimer, CrustUser, Features, NameHash, PeerInfo, Socket, State,
+    Transport, UdpEndpoint,
+};
 use crate::main::bootstrap;
+use crate::main::relay;
 use crate::main::{
     ActiveConnection, ConnectionCandidate, CrustData, CrustError, Event, EventLoopCore,
     PrivConnectionInfo, PubConnectionInfo,
 };
+use crate::nat::new_reusably_bound_tcp_socket;
 use crate::PeerId;
-use mio::{Poll, Token};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Poll, PollOpt, Ready, Registration, Token};
 use mio_extras::timer::Timeout;
+use net2::TcpBuilder;
 use safe_crypto::{SecretEncryptKey, SharedSecretKey};
 use socket_collection::{DecryptContext, EncryptContext, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::HashSet;
-use std::net::SocketAddr;
+use std::io::ErrorKind;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
 use std::rc::{Rc, Weak};
 use std::time::Duration;
 
-const TIMEOUT_SEC: u64 = 60;
+const HOLE_PUNCH_BACKLOG: i32 = 100;
+
+/// Reports whether connection has been established instead of emitting `ConnectFailure`.
+pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, bool)>;
+
+/// Why connecting to a peer failed, reported by `Event::ConnectFailure`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectFailureReason {
+    /// The peer's connection info has no addresses we could try.
+    InsufficientConnectionInfo,
+    /// None of the peer's direct or hole punch addresses is whitelisted, or all of them are
+    /// denied.
+    NotWhitelisted,
+    /// The peer is banned.
+    Banned,
+    /// `Timeouts::connect_timeout_sec` elapsed. Carries the attempts which failed before that.
+    Timeout(Vec<ConnectAttemptFailure>),
+    /// Every attempt failed.
+    AttemptsFailed(Vec<ConnectAttemptFailure>),
+    /// Connecting directly failed and so did connecting through the relays the peer advertised.
+    RelayFailed,
+}
+
+/// Failed attempt to connect to one of the peer's addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectAttemptFailure {
+    /// Address we tried. For hole punching, the address the remote peer connected from, if known.
+    pub addr: Option<SocketAddr>,
+    /// Why the attempt failed.
+    pub error: ConnectAttemptError,
+}
+
+/// Why a single connection attempt failed.
+///
+/// Peers silently drop connections on a network with a different name or from IP addresses they
+/// don't whitelist, which shows up as `Io`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectAttemptError {
+    /// Connecting, reading or writing failed.
+    Io(String),
+    /// The peer is on a network with a different name.
+    NameHashMismatch,
+    /// A different peer responded.
+    UnexpectedPeer(PeerId),
+    /// The peer refused our connection request.
+    Denied(BootstrapDenyReason),
+    /// The peer responded with something other than a handshake message.
+    UnexpectedMessage,
+    /// Another connection to the peer has been established meanwhile.
+    AlreadyConnected,
+    /// The peer speaks a protocol version we no longer support. Carries its version.
+    IncompatibleVersion(u32),
+    /// The peer's handshake signature didn't match the signing key in its `PeerId`.
+    InvalidSignature,
+}
 
 /// Atempts multiple connections to remote peer, but yields the first successful one.
 pub struct Connect {
@@ -41,6 +104,19 @@ pub struct Connect {
     children: HashSet<Token>,
     event_tx: crate::CrustEventSender,
     our_global_direct_listeners: HashSet<SocketAddr>,
+    /// Derived from the identity keys and only used for the handshake messages. Each connection
+    /// then switches to its own ephemeral session key.
+    shared_key: SharedSecretKey,
+    /// Accepts remote peer's hole punching attempts on our hole punch port.
+    hole_punch_listener: Option<TcpListener>,
+    /// Endpoint of our UDP hole punch socket, which accepts remote peer's UDP hole punching
+    /// attempts, and the registration telling when there's one to accept.
+    udp_hole_punch: Option<(Rc<RefCell<UdpEndpoint>>, Registration)>,
+    /// Nodes that might relay our traffic, if we fail to connect directly.
+    their_relays: Vec<PeerId>,
+    finish: Option<Finish>,
+    failures: Vec<ConnectAttemptFailure>,
+    timed_out: bool,
 }
 
 impl Connect {
@@ -53,90 +129,278 @@ impl Connect {
         event_tx: crate::CrustEventSender,
         our_sk: &SecretEncryptKey,
         our_global_direct_listeners: HashSet<SocketAddr>,
-    ) -> crate::Res<()> {
+        finish: Option<Finish>,
+    ) -> crate::Res<Token> {
         let their_id = their_ci.id;
         let their_direct = their_ci.for_direct;
-
-        if their_direct.is_empty() {
-            let _ = event_tx.send(Event::ConnectFailure(their_id));
+        let their_hole_punch = their_ci.for_hole_punch;
+        let their_relays = their_ci.relays;
+        // Peers which don't advertise transports only accept TCP.
+        let their_transports = if their_ci.transports.is_empty() {
+            vec![Transport::Tcp]
+        } else {
+            their_ci.transports
+        };
+        let transports: Vec<Transport> = our_ci
+            .transports
+            .into_iter()
+            .filter(|transport| their_transports.contains(transport))
+            .collect();
+        let hole_punch_socket = our_ci
+            .hole_punch_socket
+            .filter(|_| transports.contains(&Transport::Tcp) && !their_hole_punch.is_empty());
+        let their_udp_hole_punch = their_ci.for_udp_hole_punch;
+        let udp_hole_punch_socket = our_ci
+            .udp_hole_punch_socket
+            .filter(|_| transports.contains(&Transport::Udp) && !their_udp_hole_punch.is_empty());
+
+        if their_direct.is_empty() && hole_punch_socket.is_none() && udp_hole_punch_socket.is_none()
+        {
+            if finish.is_none() {
+                let reason = ConnectFailureReason::InsufficientConnectionInfo;
+                let _ = event_tx.send(Event::ConnectFailure(their_id, reason));
+            }
             return Err(CrustError::InsufficientConnectionInfo);
         }
 
         let token = core.get_new_token();
 
         let our_id = our_ci.id;
+        let their_pk = their_ci.id.pub_enc_key;
+        let shared_key = our_sk.shared_secret(&their_pk);
+        let timeout_sec = core.user_data().config.cfg.timeouts.connect_timeout_sec;
         let state = Rc::new(RefCell::new(Self {
             token,
-            timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC), CoreTimer::new(token, 0)),
+            timeout: core.set_timeout(Duration::from_secs(timeout_sec), CoreTimer::new(token, 0)),
             our_nh,
             our_id,
             their_id,
             self_weak: Weak::new(),
-            children: HashSet::with_capacity(their_direct.len()),
+            children: HashSet::with_capacity(their_direct.len() * transports.len()),
             event_tx,
             our_global_direct_listeners,
+            shared_key: shared_key.clone(),
+            hole_punch_listener: None,
+            udp_hole_punch: None,
+            their_relays,
+            finish,
+            failures: Vec::new(),
+            timed_out: false,
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
 
-        let their_pk = their_ci.id.pub_enc_key;
-        let sockets = their_direct
-            .into_iter()
-            .filter_map(|addr| {
-                let info = PeerInfo::new(addr, their_pk);
-                TcpSock::connect(&addr).map(|sock| (sock, info)).ok()
-            })
-            .collect::<Vec<_>>();
-
-        for (mut socket, peer_info) in sockets {
-            let shared_key = our_sk.shared_secret(&their_pk);
+        if let Some(socket) = hole_punch_socket {
+            if let Err(e) = state
+                .borrow_mut()
+                .hole_punch(core, poll, socket, their_hole_punch)
+            {
+                debug!("Failed to start TCP hole punching: {:?}", e);
+            }
+        }
+        if let Some(socket) = udp_hole_punch_socket {
+            if let Err(e) =
+                state
+                    .borrow_mut()
+                    .udp_hole_punch(core, poll, socket, their_udp_hole_punch)
+            {
+                debug!("Failed to start UDP hole punching: {:?}", e);
+            }
+        }
+
+        let attempts = their_direct
+            .iter()
+            .flat_map(|addr| transports.iter().map(move |transport| (*addr, *transport)));
+        for (addr, transport) in attempts {
+            let endpoint = core.user_data().udp_endpoint(&addr);
+            let mut socket = match Socket::connect(core, poll, transport, &addr, endpoint) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    state.borrow_mut().record_failure(Some(addr), e.to_string());
+                    continue;
+                }
+            };
             match (
                 socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(their_pk)),
                 socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone())),
             ) {
-                (Ok(_), Ok(_)) => state
-                    .borrow_mut()
-                    .exchange_msg(core, poll, socket, peer_info, shared_key),
-                res => warn!("Failed to set encrypt/decrypt context: {:?}", res),
+                (Ok(_), Ok(_)) => {
+                    let peer_info = PeerInfo::new(addr, their_pk);
+                    state
+                        .borrow_mut()
+                        .exchange_msg(core, poll, socket, Some(peer_info))
+                }
+                res => {
+                    warn!("Failed to set encrypt/decrypt context: {:?}", res);
+                    state
+                        .borrow_mut()
+                        .record_failure(Some(addr), format!("{:?}", res));
+                }
+            }
+        }
+
+        let _ = core.insert_state(token, state.clone());
+        // Only now that every attempt is launched, as each might have failed right away.
+        state.borrow_mut().maybe_terminate(core, poll);
+
+        Ok(token)
+    }
+
+    /// Performs TCP simultaneous open: dials all remote hole punch addresses from our hole punch
+    /// port and at the same time accepts remote peer's connections on that same port.
+    fn hole_punch(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        socket: TcpBuilder,
+        their_addrs: Vec<SocketAddr>,
+    ) -> crate::Res<()> {
+        let local_addr = socket.local_addr()?;
+        let listener = TcpListener::from_std(socket.listen(HOLE_PUNCH_BACKLOG)?)?;
+        poll.register(&listener, self.token, Ready::readable(), PollOpt::edge())?;
+        self.hole_punch_listener = Some(listener);
+
+        for addr in their_addrs {
+            let stream = new_reusably_bound_tcp_socket(&local_addr)
+                .and_then(|socket| socket.to_tcp_stream())
+                .and_then(|stream| TcpStream::connect_stream(stream, &addr));
+            match stream {
+                Ok(stream) => {
+                    let socket = Socket::Tcp(TcpSock::wrap(stream));
+                    self.rendezvous_exchange_msg(core, poll, socket)
+                }
+                Err(e) => {
+                    debug!("Failed to punch a hole to {}: {}", addr, e);
+                    self.record_failure(Some(addr), e.to_string());
+                }
             }
         }
 
-        let _ = core.insert_state(token, state);
+        Ok(())
+    }
+
+    /// Same as `hole_punch`, but over UDP. The endpoint accepts the remote peer's connections as
+    /// soon as they repeat the cookie it sent them, which works even while our own connection
+    /// attempts are still on the way.
+    fn udp_hole_punch(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        socket: UdpSocket,
+        their_addrs: Vec<SocketAddr>,
+    ) -> crate::Res<()> {
+        let socket = mio::net::UdpSocket::from_socket(socket)?;
+        let endpoint = UdpEndpoint::start(core, poll, socket)?;
+        let incoming = endpoint.borrow_mut().listen();
+        if let Err(e) = poll.register(&incoming, self.token, Ready::readable(), PollOpt::edge()) {
+            endpoint.borrow_mut().stop_listening(core, poll);
+            return Err(From::from(e));
+        }
+        self.udp_hole_punch = Some((endpoint.clone(), incoming));
+
+        for addr in their_addrs {
+            match Socket::connect(core, poll, Transport::Udp, &addr, Some(endpoint.clone())) {
+                Ok(socket) => self.rendezvous_exchange_msg(core, poll, socket),
+                Err(e) => {
+                    debug!("Failed to punch a UDP hole to {}: {}", addr, e);
+                    self.record_failure(Some(addr), e.to_string());
+                }
+            }
+        }
 
         Ok(())
     }
 
+    fn accept(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        loop {
+            let res = match self.hole_punch_listener {
+                Some(ref listener) => listener.accept(),
+                None => return,
+            };
+            match res {
+                Ok((socket, _)) => {
+                    let socket = Socket::Tcp(TcpSock::wrap(socket));
+                    self.rendezvous_exchange_msg(core, poll, socket)
+                }
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted =>
+                {
+                    return
+                }
+                Err(ref e) => {
+                    debug!("Failed to accept hole punched socket: {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn accept_udp(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        loop {
+            let stream = match self.udp_hole_punch {
+                Some((ref endpoint, _)) => endpoint.borrow_mut().accept(),
+                None => return,
+            };
+            match stream {
+                Some(stream) => self.rendezvous_exchange_msg(core, poll, Socket::Udp(stream)),
+                None => return,
+            }
+        }
+    }
+
+    /// Both peers know each other's keys, so hole punched sockets use authenticated encryption
+    /// right from the start.
+    fn rendezvous_exchange_msg(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        mut socket: Socket,
+    ) {
+        match (
+            socket.set_encrypt_ctx(EncryptContext::authenticated(self.shared_key.clone())),
+            socket.set_decrypt_ctx(DecryptContext::authenticated(self.shared_key.clone())),
+        ) {
+            (Ok(_), Ok(_)) => self.exchange_msg(core, poll, socket, None),
+            res => warn!("Failed to set encrypt/decrypt context: {:?}", res),
+        }
+    }
+
+    /// `peer_info` is remote peer's listener, `None` for hole punched sockets.
     fn exchange_msg(
         &mut self,
         core: &mut EventLoopCore,
         poll: &Poll,
-        socket: TcpSock,
-        peer_info: PeerInfo,
-        shared_key: SharedSecretKey,
+        socket: Socket,
+        peer_info: Option<PeerInfo>,
     ) {
+        let addr = peer_info
+            .map(|peer_info| peer_info.addr)
+            .or_else(|| socket.peer_addr().ok());
         let self_weak = self.self_weak.clone();
         let handler = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
             if let Some(self_rc) = self_weak.upgrade() {
                 self_rc
                     .borrow_mut()
-                    .handle_exchange_msg(core, poll, child, res, peer_info);
+                    .handle_exchange_msg(core, poll, child, res, peer_info, addr);
             }
         };
 
-        if let Ok(child) = ExchangeMsg::start(
+        match ExchangeMsg::start(
             core,
             poll,
             socket,
             self.our_id,
             self.their_id,
             self.our_nh,
-            shared_key,
             self.our_global_direct_listeners.clone(),
+            peer_info.is_none(),
             Box::new(handler),
         ) {
-            let _ = self.children.insert(child);
+            Ok(child) => {
+                let _ = self.children.insert(child);
+            }
+            Err(e) => self.record_failure(addr, e.to_string()),
         }
-        self.maybe_terminate(core, poll);
     }
 
     fn handle_exchange_msg(
@@ -144,34 +408,46 @@ impl Connect {
         core: &mut EventLoopCore,
         poll: &Poll,
         child: Token,
-        res: Option<TcpSock>,
-        peer_info: PeerInfo,
+        res: Result<(Socket, Features), ConnectAttemptError>,
+        peer_info: Option<PeerInfo>,
+        addr: Option<SocketAddr>,
     ) {
         let _ = self.children.remove(&child);
-        if let Some(socket) = res {
-            bootstrap::cache_peer_info(core, poll, peer_info);
-            let self_weak = self.self_weak.clone();
-            let handler = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
-                if let Some(self_rc) = self_weak.upgrade() {
-                    self_rc
-                        .borrow_mut()
-                        .handle_connection_candidate(core, poll, child, res);
+        match res {
+            Ok((socket, features)) => {
+                if let Some(peer_info) = peer_info {
+                    bootstrap::cache_peer_info(core, poll, peer_info);
+                }
+                let self_weak = self.self_weak.clone();
+                let handler = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
+                    if let Some(self_rc) = self_weak.upgrade() {
+                        self_rc
+                            .borrow_mut()
+                            .handle_connection_candidate(core, poll, child, res, addr, features);
+                    }
+                };
+
+                match ConnectionCandidate::start(
+                    core,
+                    poll,
+                    child,
+                    socket,
+                    self.our_id,
+                    self.their_id,
+                    Box::new(handler),
+                ) {
+                    Ok(child) => {
+                        let _ = self.children.insert(child);
+                    }
+                    Err(e) => self.record_failure(addr, e.to_string()),
                 }
-            };
-
-            if let Ok(child) = ConnectionCandidate::start(
-                core,
-                poll,
-                child,
-                socket,
-                self.our_id,
-                self.their_id,
-                Box::new(handler),
-            ) {
-                let _ = self.children.insert(child);
             }
-        } else {
-            self.remove_peer_from_cache(core, &peer_info);
+            Err(error) => {
+                if let Some(peer_info) = peer_info {
+                    self.remove_peer_from_cache(core, &peer_info);
+                }
+                self.failures.push(ConnectAttemptFailure { addr, error });
+            }
         }
         self.maybe_terminate(core, poll);
     }
@@ -181,25 +457,44 @@ impl Connect {
         core: &mut EventLoopCore,
         poll: &Poll,
         child: Token,
-        res: Option<TcpSock>,
+        res: Result<Socket, ConnectAttemptError>,
+        addr: Option<SocketAddr>,
+        features: Features,
     ) {
         let _ = self.children.remove(&child);
-        if let Some(socket) = res {
-            self.terminate(core, poll);
-            return ActiveConnection::start(
-                core,
-                poll,
-                child,
-                socket,
-                self.our_id,
-                self.their_id,
-                // Note; We connect only to Nodes
-                CrustUser::Node,
-                Event::ConnectSuccess(self.their_id),
-                self.event_tx.clone(),
-            );
-        }
-        self.maybe_terminate(core, poll);
+        let socket = match res {
+            Ok(socket) => socket,
+            Err(error) => {
+                self.failures.push(ConnectAttemptFailure { addr, error });
+                return self.maybe_terminate(core, poll);
+            }
+        };
+        let event = if self.finish.is_some() {
+            Event::Reconnected(self.their_id)
+        } else {
+            Event::ConnectSuccess(self.their_id)
+        };
+        self.terminate(core, poll);
+        ActiveConnection::start(
+            core,
+            poll,
+            child,
+            socket,
+            self.our_id,
+            self.their_id,
+            // Note; We connect only to Nodes
+            CrustUser::Node,
+            event,
+            self.event_tx.clone(),
+            features,
+        );
+    }
+
+    fn record_failure(&mut self, addr: Option<SocketAddr>, error: String) {
+        self.failures.push(ConnectAttemptFailure {
+            addr,
+            error: ConnectAttemptError::Io(error),
+        });
     }
 
     fn remove_peer_from_cache(&self, core: &mut EventLoopCore, peer_info: &PeerInfo) {
@@ -208,8 +503,11 @@ impl Connect {
         bootstrap_cache.try_commit();
     }
 
+    /// Terminates once all attempts have failed. While we hole punch, the remote peer might still
+    /// connect to our hole punch port, so then we wait for the timeout instead.
     fn maybe_terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        if self.children.is_empty() {
+        let hole_punching = self.hole_punch_listener.is_some() || self.udp_hole_punch.is_some();
+        if self.children.is_empty() && !hole_punching {
             self.terminate(core, poll);
         }
     }
@@ -227,19 +525,54 @@ impl Connect {
 }
 
 impl State<CrustData> for Connect {
+    fn ready(&mut self, core: &mut EventLoopCore, poll: &Poll, kind: Ready) {
+        if kind.is_readable() {
+            self.accept(core, poll);
+            self.accept_udp(core, poll);
+        }
+    }
+
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
         debug!("Connect to peer {:?} timed out", self.their_id);
+        self.timed_out = true;
         self.terminate(core, poll);
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         self.terminate_children(core, poll);
+        if let Some(listener) = self.hole_punch_listener.take() {
+            let _ = poll.deregister(&listener);
+        }
+        // Connections accepted or opened by the endpoint keep it alive.
+        if let Some((endpoint, incoming)) = self.udp_hole_punch.take() {
+            let _ = poll.deregister(&incoming);
+            endpoint.borrow_mut().stop_listening(core, poll);
+        }
 
         let _ = core.cancel_timeout(&self.timeout);
         let _ = core.remove_state(self.token);
 
-        if !core.user_data().connections.contains_key(&self.their_id) {
-            let _ = self.event_tx.send(Event::ConnectFailure(self.their_id));
+        if let Some(mut finish) = self.finish.take() {
+            // A connection which is still handshaking might fail yet.
+            let connected = core
+                .user_data()
+                .connections
+                .get(&self.their_id)
+                .map_or(false, |cid| cid.active_connection.is_some());
+            finish(core, poll, connected);
+        } else if !core.user_data().connections.contains_key(&self.their_id) {
+            let their_relays = mem::replace(&mut self.their_relays, Vec::new());
+            if !relay::connect(core, poll, self.their_id, their_relays) {
+                let failures = mem::replace(&mut self.failures, Vec::new());
+                let reason = if self.timed_out {
+                    ConnectFailureReason::Timeout(failures)
+                } else {
+                    ConnectFailureReason::AttemptsFailed(failures)
+                };
+                let _ = self
+                    .event_tx
+                    .send(Event::ConnectFailure(self.their_id, reason));
+            }
         }
     }
 
@@ -265,6 +598,13 @@ mod tests {
             let conn_info = PrivConnectionInfo {
                 id,
                 for_direct: vec![ipv4_addr(1, 2, 3, 4, 4000)],
+                for_hole_punch: vec![],
+                hole_punch_socket: None,
+                for_udp_hole_punch: vec![],
+                udp_hole_punch_socket: None,
+                mapping_methods: HashSet::new(),
+                relays: vec![],
+                transports: vec![Transport::Tcp],
             };
             (conn_info, sk)
         }
@@ -282,7 +622,7 @@ mod tests {
             let their_ci = their_ci.to_pub_connection_info();
 
             let (event_tx, _event_rx) = get_event_sender();
-            unwrap!(Connect::start(
+            let connect_state_token = unwrap!(Connect::start(
                 &mut core,
                 &poll,
                 our_ci,
@@ -291,9 +631,9 @@ mod tests {
                 event_tx,
                 &our_sk,
                 Default::default(),
+                None,
             ));
 
-            let connect_state_token = Token(0);
             let state = unwrap!(core.get_state(connect_state_token));
             let mut state = state.borrow_mut();
             let connect_state = unwrap!(state.as_any().downcast_mut::<Connect>());
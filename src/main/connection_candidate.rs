@@ -7,23 +7,24 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{Message, State};
-use crate::main::{ConnectionId, CrustData, EventLoopCore};
+use crate::common::{Message, Socket, State};
+use crate::main::connection_limits;
+use crate::main::{ConnectAttemptError, ConnectionId, CrustData, EventLoopCore};
 use crate::PeerId;
 use mio::{Poll, PollOpt, Ready, Token};
-use socket_collection::{Priority, TcpSock};
+use socket_collection::Priority;
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::mem;
 use std::rc::Rc;
 
-pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, Option<TcpSock>)>;
+pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, Result<Socket, ConnectAttemptError>)>;
 
 /// Exchanges `ConnectionChoose` message with remote peer and transitions to next state.
 pub struct ConnectionCandidate {
     token: Token,
-    socket: TcpSock,
+    socket: Socket,
     our_id: PeerId,
     their_id: PeerId,
     msg: Option<(Message, Priority)>,
@@ -35,7 +36,7 @@ impl ConnectionCandidate {
         core: &mut EventLoopCore,
         poll: &Poll,
         token: Token,
-        socket: TcpSock,
+        socket: Socket,
         our_id: PeerId,
         their_id: PeerId,
         finish: Finish,
@@ -67,7 +68,8 @@ impl ConnectionCandidate {
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         match self.socket.read::<Message>() {
             Ok(Some(Message::ChooseConnection)) => self.done(core, poll),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll),
+            Ok(Some(_)) => self.handle_error(core, poll, ConnectAttemptError::UnexpectedMessage),
+            Err(e) => self.handle_error(core, poll, ConnectAttemptError::Io(e.to_string())),
             Ok(None) => (),
         }
     }
@@ -81,20 +83,20 @@ impl ConnectionCandidate {
             _ => false,
         };
         if terminate {
-            return self.handle_error(core, poll);
+            return self.handle_error(core, poll, ConnectAttemptError::AlreadyConnected);
         }
 
         if self.our_id > self.their_id {
             match self.socket.write(msg) {
                 Ok(true) => self.done(core, poll),
                 Ok(false) => (),
-                Err(_) => self.handle_error(core, poll),
+                Err(e) => self.handle_error(core, poll, ConnectAttemptError::Io(e.to_string())),
             }
         } else if let Err(e) =
             poll.reregister(&self.socket, self.token, Ready::readable(), PollOpt::edge())
         {
             debug!("Error in re-registeration: {:?}", e);
-            self.handle_error(core, poll);
+            self.handle_error(core, poll, ConnectAttemptError::Io(e.to_string()));
         } else {
             self.read(core, poll)
         }
@@ -106,13 +108,13 @@ impl ConnectionCandidate {
         let socket = mem::replace(&mut self.socket, Default::default());
         let _ = poll.reregister(&socket, token, Ready::readable(), PollOpt::edge());
 
-        (*self.finish)(core, poll, token, Some(socket));
+        (*self.finish)(core, poll, token, Ok(socket));
     }
 
-    fn handle_error(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+    fn handle_error(&mut self, core: &mut EventLoopCore, poll: &Poll, error: ConnectAttemptError) {
         self.terminate(core, poll);
         let token = self.token;
-        (*self.finish)(core, poll, token, None);
+        (*self.finish)(core, poll, token, Err(error));
     }
 }
 
@@ -130,6 +132,7 @@ impl State<CrustData> for ConnectionCandidate {
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         let _ = core.remove_state(self.token);
         let _ = poll.deregister(&self.socket);
+        connection_limits::release(core, self.token);
 
         let connections = &mut core.user_data_mut().connections;
         if let Entry::Occupied(mut oe) = connections.entry(self.their_id) {
@@ -0,0 +1,149 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::CrustUser;
+use crate::main::service::peer_stats;
+use crate::main::EventLoopCore;
+use mio::{Poll, Token};
+use std::collections::HashSet;
+
+/// Connection count specific configurable settings. Connections in both directions count
+/// towards the limits, but only incoming connections are refused, with
+/// `BootstrapDenyReason::AtCapacity`. Incoming connections count from the moment they pass the
+/// check, while still handshaking.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ConnectionLimitsConfig {
+    /// Most connections to nodes and clients together. `None` means no limit.
+    pub max_connections: Option<usize>,
+    /// Most connections to nodes. `None` means no limit.
+    pub max_node_connections: Option<usize>,
+    /// Most connections to clients. `None` means no limit.
+    pub max_client_connections: Option<usize>,
+    /// Rather than refusing a new peer, drop the client we have exchanged user data with least
+    /// recently, if that makes room.
+    pub evict_idle_clients: bool,
+}
+
+/// Slot reserved for an incoming connection still handshaking. Reservations count towards the
+/// limits until they are released, so that peers handshaking at the same time can't exceed them.
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    role: CrustUser,
+    /// Client to drop once the connection has been granted.
+    evict: Option<Token>,
+}
+
+/// Reserves room for the connection handshaking on `token`, if there's room for another
+/// connection to a peer with the given role. If configured to, the most idle client is picked
+/// to make room, but only dropped by `admit`.
+pub fn reserve(core: &mut EventLoopCore, token: Token, role: CrustUser) -> bool {
+    match find_room(core, role) {
+        Some(evict) => {
+            let reservation = Reservation { role, evict };
+            let _ = core.user_data_mut().reservations.insert(token, reservation);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The connection on `token` has been granted, drops the client picked to make room for it.
+/// The reservation is kept until the connection is active.
+pub fn admit(core: &mut EventLoopCore, poll: &Poll, token: Token) {
+    let evict = match core.user_data_mut().reservations.get_mut(&token) {
+        Some(reservation) => reservation.evict.take(),
+        None => return,
+    };
+    if let Some(evict) = evict {
+        drop_client(core, poll, evict);
+    }
+}
+
+/// Releases the reservation once the connection is active or failed.
+pub fn release(core: &mut EventLoopCore, token: Token) {
+    let _ = core.user_data_mut().reservations.remove(&token);
+}
+
+/// Returns true if there's room for another connection to a peer with the given role. Drops
+/// the most idle client to make room, if configured to.
+pub fn make_room(core: &mut EventLoopCore, poll: &Poll, role: CrustUser) -> bool {
+    match find_room(core, role) {
+        Some(Some(evict)) => {
+            drop_client(core, poll, evict);
+            true
+        }
+        Some(None) => true,
+        None => false,
+    }
+}
+
+/// Returns `None` if there's no room for a peer with the given role, or the client to drop to
+/// make room, if any.
+fn find_room(core: &EventLoopCore, role: CrustUser) -> Option<Option<Token>> {
+    let config = &core.user_data().config.cfg.connection_limits;
+    let reservations = &core.user_data().reservations;
+    let connections: Vec<_> = core
+        .user_data()
+        .connections
+        .values()
+        .filter_map(|conn_id| conn_id.active_connection)
+        .filter(|token| !reservations.contains_key(token))
+        .filter_map(|token| peer_stats(core, token).map(|stats| (token, stats)))
+        .collect();
+    // Clients picked for eviction are as good as gone.
+    let evicting: HashSet<_> = reservations
+        .values()
+        .filter_map(|reservation| reservation.evict)
+        .collect();
+    let count = |of_role| {
+        let connected = connections
+            .iter()
+            .filter(|(token, stats)| stats.role == of_role && !evicting.contains(token))
+            .count();
+        let reserved = reservations
+            .values()
+            .filter(|reservation| reservation.role == of_role)
+            .count();
+        connected + reserved
+    };
+    let nodes = count(CrustUser::Node);
+    let clients = count(CrustUser::Client);
+
+    let role_full = match role {
+        CrustUser::Node => is_full(nodes, config.max_node_connections),
+        CrustUser::Client => is_full(clients, config.max_client_connections),
+    };
+    if !role_full && !is_full(nodes + clients, config.max_connections) {
+        return Some(None);
+    }
+    // Dropping a client doesn't help a node if it's the node limit that was hit.
+    if !config.evict_idle_clients || (role == CrustUser::Node && role_full) {
+        return None;
+    }
+
+    connections
+        .iter()
+        .filter(|(token, stats)| stats.role == CrustUser::Client && !evicting.contains(token))
+        .min_by_key(|(_, stats)| stats.last_data)
+        .map(|(token, _)| Some(*token))
+}
+
+fn drop_client(core: &mut EventLoopCore, poll: &Poll, token: Token) {
+    if let Some(state) = core.get_state(token) {
+        if let Some(stats) = peer_stats(core, token) {
+            debug!("Dropping idle client {:?} to make room", stats.id);
+        }
+        state.borrow_mut().terminate(core, poll);
+    }
+}
+
+fn is_full(count: usize, limit: Option<usize>) -> bool {
+    limit.map_or(false, |limit| count >= limit)
+}
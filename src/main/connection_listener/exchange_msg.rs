@@ -8,9 +8,10 @@
 // Software.
 
 use crate::common::{
-    ipv4_addr, BootstrapDenyReason, BootstrapperRole, CoreTimer, CrustUser, Message, NameHash,
-    PeerInfo, State,
+    set_session_key, unspecified_addr_like, BootstrapDenyReason, BootstrapperRole, CoreTimer,
+    CrustUser, Features, HandshakeAuth, Message, NameHash, PeerInfo, ProtocolInfo, Socket, State,
 };
+use crate::main::connection_limits;
 use crate::main::{
     read_config_file, ActiveConnection, Config, ConnectionCandidate, ConnectionId, CrustData,
     Event, EventLoopCore,
@@ -19,18 +20,18 @@ use crate::nat::{ip_addr_is_global, GetExtAddr};
 use crate::PeerId;
 use mio::{Poll, PollOpt, Ready, Token};
 use mio_extras::timer::Timeout;
-use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
-use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
+use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey};
+use socket_collection::{DecryptContext, EncryptContext, Priority};
 use std::any::Any;
 use std::cell::{RefCell, RefMut};
+use std::cmp;
 use std::collections::hash_map::Entry;
 use std::collections::HashSet;
 use std::mem;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::{Rc, Weak};
 use std::time::Duration;
 
-pub const EXCHANGE_MSG_TIMEOUT_SEC: u64 = 10 * 60;
 const CHECK_REACHABILITY_TIMEOUT_SEC: u64 = 3;
 
 /// Remote peer might send a huge list of external addresses to test for reachability. That
@@ -47,13 +48,26 @@ pub struct ExchangeMsg {
     name_hash: NameHash,
     next_state: NextState,
     our_uid: PeerId,
-    socket: TcpSock,
+    socket: Socket,
+    /// Address admitted by the handshake limiter. Released once the handshake is over.
+    peer_ip: Option<IpAddr>,
     timeout: Timeout,
+    /// Deadline for the whole handshake, which starts once the peer authenticated. Until then
+    /// the shorter pre-authentication deadline applies.
+    handshake_timeout: Duration,
+    authenticated: bool,
     reachability_children: HashSet<Token>,
     accept_bootstrap: bool,
     test_ext_reachability: bool,
     self_weak: Weak<RefCell<ExchangeMsg>>,
     our_sk: SecretEncryptKey,
+    /// Features both we and the remote peer support.
+    features: Features,
+    /// Verified handshake auth of the remote peer. We sign its session in our response.
+    their_auth: Option<HandshakeAuth>,
+    /// Our ephemeral key pair the session key is derived from.
+    session_pk: PublicEncryptKey,
+    session_sk: SecretEncryptKey,
 }
 
 impl ExchangeMsg {
@@ -65,7 +79,8 @@ impl ExchangeMsg {
         core: &mut EventLoopCore,
         poll: &Poll,
         timeout_sec: Option<u64>,
-        socket: TcpSock,
+        socket: Socket,
+        peer_ip: IpAddr,
         accept_bootstrap: bool,
         our_uid: PeerId,
         name_hash: NameHash,
@@ -78,10 +93,15 @@ impl ExchangeMsg {
         let kind = Ready::readable();
         poll.register(&socket, token, kind, PollOpt::edge())?;
 
+        let config = &core.user_data().config.cfg;
+        let handshake_timeout =
+            Duration::from_secs(timeout_sec.unwrap_or(config.timeouts.handshake_timeout_sec));
+        let pre_auth_timeout = Duration::from_secs(config.handshake_limits.pre_auth_timeout_sec);
         let timeout = core.set_timeout(
-            Duration::from_secs(timeout_sec.unwrap_or(EXCHANGE_MSG_TIMEOUT_SEC)),
+            cmp::min(pre_auth_timeout, handshake_timeout),
             CoreTimer::new(token, 0),
         );
+        let (session_pk, session_sk) = gen_encrypt_keypair();
 
         let state = Rc::new(RefCell::new(Self {
             token,
@@ -90,12 +110,19 @@ impl ExchangeMsg {
             next_state: NextState::None,
             our_uid,
             socket,
+            peer_ip: Some(peer_ip),
             timeout,
+            handshake_timeout,
+            authenticated: false,
             reachability_children: HashSet::with_capacity(4),
             accept_bootstrap,
             test_ext_reachability,
             self_weak: Default::default(),
             our_sk: our_sk.clone(),
+            features: Default::default(),
+            their_auth: None,
+            session_pk,
+            session_sk,
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
@@ -107,26 +134,56 @@ impl ExchangeMsg {
 
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         match self.socket.read::<Message>() {
-            Ok(Some(Message::BootstrapRequest(their_uid, name_hash, their_role))) => {
+            Ok(Some(Message::BootstrapRequest(
+                their_uid,
+                name_hash,
+                their_role,
+                their_protocol,
+                auth,
+            ))) => {
                 if !self.accept_bootstrap {
                     debug!("Bootstrapping off us is not allowed");
                     return self.terminate(core, poll);
                 }
 
-                match self.validate_peer_uid(their_uid) {
-                    Ok(their_uid) => {
-                        self.handle_bootstrap_req(core, poll, their_uid, name_hash, their_role)
-                    }
+                match self.validate_peer_uid(core, their_uid) {
+                    Ok(their_uid) => self.handle_bootstrap_req(
+                        core,
+                        poll,
+                        their_uid,
+                        name_hash,
+                        their_role,
+                        their_protocol,
+                        auth,
+                    ),
                     Err(()) => self.terminate(core, poll),
                 }
             }
-            Ok(Some(Message::ConnectRequest(their_uid, name_hash, their_addrs))) => {
-                match self.validate_peer_uid(their_uid) {
-                    Ok(their_uid) => {
-                        self.handle_connect(core, poll, their_uid, name_hash, their_addrs)
-                    }
-                    Err(()) => self.terminate(core, poll),
-                }
+            Ok(Some(Message::ConnectRequest(
+                their_uid,
+                name_hash,
+                their_addrs,
+                their_protocol,
+                auth,
+            ))) => match self.validate_peer_uid(core, their_uid) {
+                Ok(their_uid) => self.handle_connect(
+                    core,
+                    poll,
+                    their_uid,
+                    name_hash,
+                    their_addrs,
+                    their_protocol,
+                    auth,
+                ),
+                Err(()) => self.terminate(core, poll),
+            },
+            Ok(Some(Message::BootstrapRequestV1(their_uid, ..)))
+            | Ok(Some(Message::ConnectRequestV1(their_uid, ..))) => {
+                debug!(
+                    "{:?} speaks protocol version 1. Denying connection.",
+                    their_uid
+                );
+                self.deny_legacy_peer(core, poll, their_uid)
             }
             Ok(Some(Message::EchoAddrReq(their_pk))) => {
                 self.handle_echo_addr_req(core, poll, their_pk)
@@ -150,6 +207,8 @@ impl ExchangeMsg {
         their_uid: PeerId,
         name_hash: NameHash,
         their_role: BootstrapperRole,
+        their_protocol: ProtocolInfo,
+        auth: HandshakeAuth,
     ) {
         if !self.is_valid_name_hash(name_hash) {
             debug!("Rejecting Bootstrapper with an invalid name hash.");
@@ -168,9 +227,23 @@ impl ExchangeMsg {
             return self.terminate(core, poll);
         }
 
+        if !self.verify_auth(core, &their_uid, auth) {
+            debug!("Rejecting Bootstrapper with an invalid handshake signature.");
+            return self.deny(core, poll, BootstrapDenyReason::InvalidSignature);
+        }
+
+        if !self.negotiate_protocol(their_protocol) {
+            debug!("Rejecting Bootstrapper with protocol {:?}.", their_protocol);
+            return self.deny_incompatible_version(core, poll);
+        }
+
         self.try_update_crust_config(core);
 
-        if !self.is_peer_whitelisted((&their_role).into(), &core.user_data().config.cfg) {
+        if !self.is_peer_whitelisted(
+            (&their_role).into(),
+            &their_uid,
+            &core.user_data().config.cfg,
+        ) {
             debug!("Bootstrapper is not whitelisted. Denying bootstrap.");
             let reason = match their_role {
                 BootstrapperRole::Node(_) => BootstrapDenyReason::NodeNotWhitelisted,
@@ -179,6 +252,11 @@ impl ExchangeMsg {
             return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
         }
 
+        if !connection_limits::reserve(core, self.token, (&their_role).into()) {
+            debug!("No room for more connections. Denying bootstrap.");
+            return self.deny(core, poll, BootstrapDenyReason::AtCapacity);
+        }
+
         if let BootstrapperRole::Node(their_addrs) = their_role {
             if self.test_ext_reachability {
                 let on_check_reachability_result =
@@ -212,7 +290,12 @@ impl ExchangeMsg {
         }
     }
 
-    fn is_peer_whitelisted(&self, peer_kind: CrustUser, config: &Config) -> bool {
+    fn is_peer_whitelisted(
+        &self,
+        peer_kind: CrustUser,
+        their_uid: &PeerId,
+        config: &Config,
+    ) -> bool {
         let peer_ip = match self.socket.peer_addr() {
             Ok(s) => s.ip(),
             Err(e) => {
@@ -224,16 +307,7 @@ impl ExchangeMsg {
             }
         };
 
-        let res = match peer_kind {
-            CrustUser::Node => config
-                .whitelisted_node_ips
-                .as_ref()
-                .map_or(true, |ips| ips.contains(&peer_ip)),
-            CrustUser::Client => config
-                .whitelisted_client_ips
-                .as_ref()
-                .map_or(true, |ips| ips.contains(&peer_ip)),
-        };
+        let res = config.is_whitelisted(peer_kind, peer_ip, &their_uid.pub_enc_key);
 
         if !res {
             trace!("IP: {} is not whitelisted.", peer_ip);
@@ -271,11 +345,16 @@ impl ExchangeMsg {
         their_uid: PeerId,
         peer_kind: CrustUser,
     ) {
+        let auth = match self.sign_response(core, &their_uid) {
+            Some(auth) => auth,
+            None => return self.terminate(core, poll),
+        };
         self.enter_handshaking_mode(core, their_uid);
 
         let our_uid = self.our_uid;
         self.next_state = NextState::ActiveConnection(their_uid, peer_kind);
-        self.write(core, poll, Some((Message::BootstrapGranted(our_uid), 0)))
+        let msg = Message::BootstrapGranted(our_uid, ProtocolInfo::ours(), auth);
+        self.write(core, poll, Some((msg, 0)))
     }
 
     fn handle_connect(
@@ -285,6 +364,8 @@ impl ExchangeMsg {
         their_uid: PeerId,
         name_hash: NameHash,
         their_addrs: HashSet<SocketAddr>,
+        their_protocol: ProtocolInfo,
+        auth: HandshakeAuth,
     ) {
         if !self.is_valid_name_hash(name_hash) {
             debug!("Invalid name hash given. Denying connection.");
@@ -293,7 +374,7 @@ impl ExchangeMsg {
 
         self.try_update_crust_config(core);
 
-        if !self.is_peer_whitelisted(CrustUser::Node, &core.user_data().config.cfg) {
+        if !self.is_peer_whitelisted(CrustUser::Node, &their_uid, &core.user_data().config.cfg) {
             debug!("Connecting Node is not whitelisted. Denying connection.");
             return self.terminate(core, poll);
         }
@@ -303,6 +384,24 @@ impl ExchangeMsg {
             return self.terminate(core, poll);
         }
 
+        if !self.verify_auth(core, &their_uid, auth) {
+            debug!("Invalid handshake signature. Denying connection.");
+            return self.deny(core, poll, BootstrapDenyReason::InvalidSignature);
+        }
+
+        if !self.negotiate_protocol(their_protocol) {
+            debug!(
+                "Connecting Node speaks protocol {:?}. Denying connection.",
+                their_protocol
+            );
+            return self.deny_incompatible_version(core, poll);
+        }
+
+        if !connection_limits::reserve(core, self.token, CrustUser::Node) {
+            debug!("No room for more connections. Denying connection.");
+            return self.deny(core, poll, BootstrapDenyReason::AtCapacity);
+        }
+
         if self.test_ext_reachability {
             let on_check_reachability_result =
                 |mut state: RefMut<ExchangeMsg>,
@@ -350,9 +449,14 @@ impl ExchangeMsg {
 
     /// Sends response to incoming connection.
     fn send_connect_grant(&mut self, core: &mut EventLoopCore, poll: &Poll, their_uid: PeerId) {
+        let auth = match self.sign_response(core, &their_uid) {
+            Some(auth) => auth,
+            None => return self.terminate(core, poll),
+        };
         self.enter_handshaking_mode(core, their_uid);
         self.next_state = NextState::ConnectionCandidate(their_uid);
-        let msg = Message::ConnectResponse(self.our_uid, self.name_hash);
+        let msg =
+            Message::ConnectResponse(self.our_uid, self.name_hash, ProtocolInfo::ours(), auth);
         self.write(core, poll, Some((msg, 0)));
     }
 
@@ -374,6 +478,94 @@ impl ExchangeMsg {
         }
     }
 
+    /// Remembers the features we share with the remote peer. Returns false if we don't support
+    /// its protocol version.
+    fn negotiate_protocol(&mut self, their_protocol: ProtocolInfo) -> bool {
+        self.features = their_protocol.negotiated_features();
+        their_protocol.is_compatible()
+    }
+
+    fn deny_incompatible_version(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let reason = BootstrapDenyReason::IncompatibleVersion(ProtocolInfo::ours().version);
+        self.deny(core, poll, reason);
+    }
+
+    /// Peers of protocol version 1 expect the denial encrypted with the identity keys.
+    fn deny_legacy_peer(&mut self, core: &mut EventLoopCore, poll: &Poll, their_uid: PeerId) {
+        if !self.use_authed_encryption(their_uid.pub_enc_key) {
+            debug!("Failed to set authenticated encryption context.");
+            return self.terminate(core, poll);
+        }
+        self.deny_incompatible_version(core, poll);
+    }
+
+    fn deny(&mut self, core: &mut EventLoopCore, poll: &Poll, reason: BootstrapDenyReason) {
+        self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+    }
+
+    /// Checks the remote peer owns the signing key in its `PeerId` and remembers its session
+    /// for our response. Authenticated peers get the whole handshake timeout.
+    fn verify_auth(
+        &mut self,
+        core: &mut EventLoopCore,
+        their_uid: &PeerId,
+        auth: HandshakeAuth,
+    ) -> bool {
+        if !auth.verify(their_uid, &self.name_hash, &self.our_uid.pub_enc_key) {
+            return false;
+        }
+        self.their_auth = Some(auth);
+        if !self.authenticated {
+            self.authenticated = true;
+            let _ = core.cancel_timeout(&self.timeout);
+            self.timeout = core.set_timeout(self.handshake_timeout, CoreTimer::new(self.token, 0));
+        }
+        true
+    }
+
+    fn release_handshake_slot(&mut self, core: &mut EventLoopCore) {
+        if let Some(peer_ip) = self.peer_ip.take() {
+            core.user_data_mut().handshake_limiter.release(peer_ip);
+        }
+    }
+
+    /// Signs the remote peer's session together with our ephemeral key, proving we own the
+    /// signing key in our `PeerId`.
+    fn sign_response(&self, core: &EventLoopCore, their_uid: &PeerId) -> Option<HandshakeAuth> {
+        let nonce = self.their_auth.as_ref()?.nonce;
+        match HandshakeAuth::sign(
+            &core.user_data().our_sign_sk,
+            &self.name_hash,
+            &self.our_uid.pub_enc_key,
+            &their_uid.pub_enc_key,
+            self.session_pk,
+            nonce,
+        ) {
+            Ok(auth) => Some(auth),
+            Err(e) => {
+                debug!("Failed to sign handshake response: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Switches the socket from the identity keys to the session key. Our response was the last
+    /// message encrypted with the identity keys.
+    fn use_session_key(&mut self) -> bool {
+        let their_session_pk = match self.their_auth {
+            Some(ref auth) => auth.session_pk,
+            None => return false,
+        };
+        let session_key = self.session_sk.shared_secret(&their_session_pk);
+        match set_session_key(&mut self.socket, session_key) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Failed to set socket session key: {:?}", e);
+                false
+            }
+        }
+    }
+
     /// Set socket encrypt context to authenticated encryption.
     /// Returns false on failure.
     fn use_authed_encryption(&mut self, their_pk: PublicEncryptKey) -> bool {
@@ -419,7 +611,7 @@ impl ExchangeMsg {
             if let Ok(child) = GetExtAddr::<CrustData>::start(
                 core,
                 poll,
-                ipv4_addr(0, 0, 0, 0, 0),
+                unspecified_addr_like(&their_listener),
                 &PeerInfo::new(their_listener, their_uid.pub_enc_key),
                 self.our_uid.pub_enc_key,
                 &self.our_sk,
@@ -470,12 +662,18 @@ impl ExchangeMsg {
         self.name_hash == name_hash
     }
 
-    fn validate_peer_uid(&self, their_uid: PeerId) -> Result<PeerId, ()> {
+    fn validate_peer_uid(&self, core: &EventLoopCore, their_uid: PeerId) -> Result<PeerId, ()> {
         if self.our_uid == their_uid {
             debug!("Accepted connection from ourselves");
             return Err(());
         }
 
+        let their_ip = self.socket.peer_addr().ok().map(|addr| addr.ip());
+        if core.user_data().bans.is_peer_banned(&their_uid, their_ip) {
+            debug!("Rejecting handshake from banned peer {:?}", their_uid);
+            return Err(());
+        }
+
         Ok(their_uid)
     }
 
@@ -517,9 +715,17 @@ impl ExchangeMsg {
     fn done(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         let _ = core.remove_state(self.token);
         let _ = core.cancel_timeout(&self.timeout);
+        self.release_handshake_slot(core);
 
         let our_uid = self.our_uid;
         let event_tx = self.event_tx.clone();
+        let features = self.features;
+
+        if !self.use_session_key() {
+            return self.terminate(core, poll);
+        }
+        // The grant went out, only now make room for the peer.
+        connection_limits::admit(core, poll, self.token);
 
         match self.next_state {
             NextState::ActiveConnection(their_uid, peer_kind) => {
@@ -534,11 +740,13 @@ impl ExchangeMsg {
                     peer_kind,
                     Event::BootstrapAccept(their_uid, peer_kind),
                     event_tx,
+                    features,
                 );
+                connection_limits::release(core, self.token);
             }
             NextState::ConnectionCandidate(their_uid) => {
                 let handler = move |core: &mut EventLoopCore, poll: &Poll, token, res| {
-                    if let Some(socket) = res {
+                    if let Ok(socket) = res {
                         ActiveConnection::start(
                             core,
                             poll,
@@ -551,8 +759,10 @@ impl ExchangeMsg {
                             CrustUser::Node,
                             Event::ConnectSuccess(their_uid),
                             event_tx.clone(),
+                            features,
                         );
                     }
+                    connection_limits::release(core, token);
                 };
 
                 let socket = mem::replace(&mut self.socket, Default::default());
@@ -613,10 +823,19 @@ impl State<CrustData> for ExchangeMsg {
 
         let _ = core.cancel_timeout(&self.timeout);
         let _ = poll.deregister(&self.socket);
+        self.release_handshake_slot(core);
+        connection_limits::release(core, self.token);
     }
 
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
-        debug!("Exchange message timed out. Terminating direct connection request.");
+        if self.authenticated {
+            debug!("Exchange message timed out. Terminating direct connection request.");
+        } else {
+            debug!("Peer didn't authenticate in time. Terminating direct connection request.");
+            core.user_data_mut()
+                .handshake_limiter
+                .record_pre_auth_timeout();
+        }
         self.terminate(core, poll)
     }
 
@@ -10,20 +10,20 @@
 mod exchange_msg;
 
 use self::exchange_msg::ExchangeMsg;
-use crate::common::{NameHash, PeerInfo, State};
-use crate::main::{CrustData, Event, EventLoopCore};
-use crate::nat::ip_addr_is_global;
+use crate::common::{NameHash, PeerInfo, Socket, State, Transport, UdpListener};
+use crate::main::{BanTarget, CrustData, Event, EventLoopCore};
+use crate::nat::{ip_addr_is_global, ipv6_addr_is_link_local};
 use crate::nat::{MappedTcpSocket, MappingContext};
 use crate::PeerId;
-use mio::net::TcpListener;
+use mio::net::{TcpListener, UdpSocket};
 use mio::{Poll, PollOpt, Ready, Token};
-use net2::TcpBuilder;
+use net2::{TcpBuilder, UdpBuilder};
 use safe_crypto::SecretEncryptKey;
 use socket_collection::{DecryptContext, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
-use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::rc::Rc;
 use std::sync::Arc;
 
@@ -32,10 +32,16 @@ const LISTENER_BACKLOG: i32 = 100;
 /// Accepts connections and transitions each connection into `ExchangeMsg` state.
 /// Optionally will make `ExchangeMsg` to test for peer external reachability. This behavior
 /// is enabled by default.
+///
+/// Besides the IPv4 listener which is subject to NAT mapping, an IPv6 only listener is bound to
+/// the same port, if the host supports IPv6. If UDP is among the configured transports, UDP
+/// listeners are bound to the same port too.
 pub struct ConnectionListener {
     token: Token,
     event_tx: crate::CrustEventSender,
     listener: TcpListener,
+    listener_v6: Option<TcpListener>,
+    udp_listeners: Vec<UdpListener>,
     name_hash: NameHash,
     our_uid: PeerId,
     timeout_sec: Option<u64>,
@@ -61,11 +67,13 @@ impl ConnectionListener {
         let event_tx_0 = event_tx.clone();
         let our_sk2 = our_sk.clone();
         let our_pk = our_uid.pub_enc_key;
+        let our_ifv6s = mc.ifv6s().clone();
 
         let finish = move |core: &mut EventLoopCore,
                            poll: &Poll,
                            socket,
-                           mut mapped_addrs: Vec<SocketAddr>| {
+                           mut mapped_addrs: Vec<SocketAddr>,
+                           _mapping_methods| {
             let checker = |s: &SocketAddr| ip_addr_is_global(&s.ip()) && s.port() == port;
             if force_include_port && port != 0 && !mapped_addrs.iter().any(checker) {
                 let global_addrs: Vec<_> = mapped_addrs
@@ -88,6 +96,7 @@ impl ConnectionListener {
                 handshake_timeout_sec,
                 socket,
                 mapped_addrs,
+                &our_ifv6s,
                 our_uid,
                 name_hash,
                 token,
@@ -120,6 +129,7 @@ impl ConnectionListener {
         timeout_sec: Option<u64>,
         socket: TcpBuilder,
         mapped_addrs: Vec<SocketAddr>,
+        our_ifv6s: &[Ipv6Addr],
         our_uid: PeerId,
         name_hash: NameHash,
         token: Token,
@@ -132,16 +142,66 @@ impl ConnectionListener {
         let listener = TcpListener::from_std(listener)?;
         poll.register(&listener, token, Ready::readable(), PollOpt::edge())?;
 
+        let listener_v6 = match Self::bind_v6_listener(local_addr.port()) {
+            Ok(listener_v6) => {
+                poll.register(&listener_v6, token, Ready::readable(), PollOpt::edge())?;
+                Some(listener_v6)
+            }
+            Err(e) => {
+                info!("IPv6 listener not started, accepting IPv4 only: {}", e);
+                None
+            }
+        };
+
+        let mut udp_listeners = Vec::new();
+        if core
+            .user_data()
+            .config
+            .cfg
+            .transports()
+            .contains(&Transport::Udp)
+        {
+            let ips = [
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            ];
+            for ip in &ips {
+                let addr = SocketAddr::new(*ip, local_addr.port());
+                match Self::bind_udp_listener(core, poll, token, &addr) {
+                    Ok(udp_listener) => {
+                        let endpoint = Rc::downgrade(udp_listener.endpoint());
+                        core.user_data_mut().udp_endpoints.push(endpoint);
+                        udp_listeners.push(udp_listener);
+                    }
+                    Err(e) => info!("UDP listener on {} not started: {}", addr, e),
+                }
+            }
+        }
+
         core.user_data_mut().our_listeners.extend(
             mapped_addrs
                 .into_iter()
                 .map(|addr| PeerInfo::new(addr, our_uid.pub_enc_key)),
         );
+        if listener_v6.is_some() {
+            // Link-local addresses are useless without scope ID, so don't advertise them.
+            core.user_data_mut().our_listeners.extend(
+                our_ifv6s
+                    .iter()
+                    .filter(|ip| !ip.is_unspecified() && !ipv6_addr_is_link_local(**ip))
+                    .map(|ip| {
+                        let addr = SocketAddr::new(IpAddr::V6(*ip), local_addr.port());
+                        PeerInfo::new(addr, our_uid.pub_enc_key)
+                    }),
+            );
+        }
 
         let state = Self {
             token,
             event_tx: event_tx.clone(),
             listener,
+            listener_v6,
+            udp_listeners,
             name_hash,
             our_uid,
             timeout_sec,
@@ -156,32 +216,53 @@ impl ConnectionListener {
         Ok(())
     }
 
+    /// Binds IPv6 only socket, so that it doesn't conflict with IPv4 listener on the same port.
+    fn bind_v6_listener(port: u16) -> io::Result<TcpListener> {
+        let socket = TcpBuilder::new_v6()?;
+        let _ = socket.only_v6(true)?;
+        let _ = socket.reuse_address(true)?;
+        let _ = socket.bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))?;
+        TcpListener::from_std(socket.listen(LISTENER_BACKLOG)?)
+    }
+
+    /// Binds a UDP socket, IPv6 only for IPv6 addresses, and accepts connections on it.
+    fn bind_udp_listener(
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        token: Token,
+        addr: &SocketAddr,
+    ) -> io::Result<UdpListener> {
+        let socket = if addr.is_ipv4() {
+            UdpSocket::bind(addr)?
+        } else {
+            let socket = UdpBuilder::new_v6()?;
+            let _ = socket.only_v6(true)?;
+            UdpSocket::from_socket(socket.bind(addr)?)?
+        };
+        let listener = UdpListener::start(core, poll, socket)?;
+        if let Err(e) = poll.register(&listener, token, Ready::readable(), PollOpt::edge()) {
+            listener.close(core, poll);
+            return Err(e);
+        }
+        Ok(listener)
+    }
+
     fn accept(&self, core: &mut EventLoopCore, poll: &Poll) {
+        self.accept_from(&self.listener, core, poll);
+        if let Some(ref listener_v6) = self.listener_v6 {
+            self.accept_from(listener_v6, core, poll);
+        }
+        for udp_listener in &self.udp_listeners {
+            self.accept_udp_from(udp_listener, core, poll);
+        }
+    }
+
+    fn accept_from(&self, listener: &TcpListener, core: &mut EventLoopCore, poll: &Poll) {
         loop {
-            match self.listener.accept() {
-                Ok((socket, _)) => {
-                    let mut socket = TcpSock::wrap(socket);
-                    if let Err(e) = socket.set_decrypt_ctx(DecryptContext::anonymous_decrypt(
-                        self.our_uid.pub_enc_key,
-                        self.our_sk.clone(),
-                    )) {
-                        debug!("Failed to set decryption context: {}", e);
-                        continue;
-                    }
-                    if let Err(e) = ExchangeMsg::start(
-                        core,
-                        poll,
-                        self.timeout_sec,
-                        socket,
-                        self.accept_bootstrap,
-                        self.our_uid,
-                        self.name_hash,
-                        self.event_tx.clone(),
-                        &self.our_sk,
-                        self.test_ext_reachability,
-                    ) {
-                        debug!("Error accepting direct connection: {:?}", e);
-                    }
+            match listener.accept() {
+                Ok((socket, peer_addr)) => {
+                    let socket = Socket::Tcp(TcpSock::wrap(socket));
+                    self.handle_accepted(core, poll, socket, peer_addr)
                 }
                 Err(ref e)
                     if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted =>
@@ -195,6 +276,76 @@ impl ConnectionListener {
             }
         }
     }
+
+    fn accept_udp_from(&self, listener: &UdpListener, core: &mut EventLoopCore, poll: &Poll) {
+        loop {
+            match listener.accept() {
+                Ok((stream, peer_addr)) => {
+                    self.handle_accepted(core, poll, Socket::Udp(stream), peer_addr)
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) => {
+                    debug!("Failed to accept UDP connection: {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle_accepted(
+        &self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        mut socket: Socket,
+        peer_addr: SocketAddr,
+    ) {
+        if core
+            .user_data()
+            .bans
+            .is_banned(&BanTarget::Ip(peer_addr.ip()))
+        {
+            debug!("Rejecting connection from banned address {}", peer_addr);
+            return;
+        }
+        if core.user_data().config.cfg.is_ip_denied(peer_addr.ip()) {
+            debug!("Rejecting connection from denied address {}", peer_addr);
+            return;
+        }
+        let user_data = core.user_data_mut();
+        let limits = &user_data.config.cfg.handshake_limits;
+        if let Err(reason) = user_data.handshake_limiter.admit(peer_addr.ip(), limits) {
+            debug!("Rejecting connection from {}: {:?}", peer_addr, reason);
+            return;
+        }
+        if let Err(e) = socket.set_decrypt_ctx(DecryptContext::anonymous_decrypt(
+            self.our_uid.pub_enc_key,
+            self.our_sk.clone(),
+        )) {
+            debug!("Failed to set decryption context: {}", e);
+            core.user_data_mut()
+                .handshake_limiter
+                .release(peer_addr.ip());
+            return;
+        }
+        if let Err(e) = ExchangeMsg::start(
+            core,
+            poll,
+            self.timeout_sec,
+            socket,
+            peer_addr.ip(),
+            self.accept_bootstrap,
+            self.our_uid,
+            self.name_hash,
+            self.event_tx.clone(),
+            &self.our_sk,
+            self.test_ext_reachability,
+        ) {
+            debug!("Error accepting direct connection: {:?}", e);
+            core.user_data_mut()
+                .handshake_limiter
+                .release(peer_addr.ip());
+        }
+    }
 }
 
 impl State<CrustData> for ConnectionListener {
@@ -206,6 +357,14 @@ impl State<CrustData> for ConnectionListener {
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         let _ = poll.deregister(&self.listener);
+        if let Some(ref listener_v6) = self.listener_v6 {
+            let _ = poll.deregister(listener_v6);
+        }
+        for udp_listener in self.udp_listeners.drain(..) {
+            let _ = poll.deregister(&udp_listener);
+            udp_listener.close(core, poll);
+        }
+        core.user_data_mut().udp_endpoints.clear();
         let _ = core.remove_state(self.token);
     }
 
@@ -216,45 +375,58 @@ impl State<CrustData> for ConnectionListener {
 
 #[cfg(test)]
 mod tests {
-    use super::exchange_msg::EXCHANGE_MSG_TIMEOUT_SEC;
     use super::*;
     use crate::common::{
-        self, ipv4_addr, BootstrapperRole, CoreMessage, CrustUser, Message, NameHash, HASH_SIZE,
+        self, ipv4_addr, set_session_key, BootstrapDenyReason, BootstrapperRole, CoreMessage,
+        CrustUser, Features, HandshakeAuth, Message, NameHash, ProtocolInfo, HASH_SIZE,
     };
     use crate::main::bootstrap;
-    use crate::main::{Event, EventLoop};
+    use crate::main::{Event, EventLoop, HandshakeLimitsConfig, HandshakeStats, Timeouts};
     use crate::nat::MappingContext;
-    use crate::tests::rand_peer_id_and_enc_sk;
+    use crate::tests::rand_peer_id_and_keys;
     use maidsafe_utilities::event_sender::MaidSafeEventCategory;
     use mio::{Events, Token};
-    use safe_crypto::gen_encrypt_keypair;
+    use safe_crypto::{gen_encrypt_keypair, SecretSignKey};
     use socket_collection::{EncryptContext, SocketError};
     use std::io::Read;
     use std::net::SocketAddr as StdSocketAddr;
     use std::net::TcpStream;
     use std::sync::mpsc;
     use std::sync::Arc;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
-    // Make sure this is < EXCHANGE_MSG_TIMEOUT_SEC else blocking reader socket in this test will
-    // exit with an EAGAIN error (unless this is what is wanted).
+    // Make sure this is < `Timeouts::handshake_timeout_sec` else blocking reader socket in this
+    // test will exit with an EAGAIN error (unless this is what is wanted).
     const HANDSHAKE_TIMEOUT_SEC: u64 = 5;
     const LISTENER_TOKEN: usize = 0;
     const NAME_HASH: NameHash = [1; HASH_SIZE];
     const NAME_HASH_2: NameHash = [2; HASH_SIZE];
 
+    /// Remote peer connecting to the listener.
+    struct Peer {
+        uid: PeerId,
+        sk: SecretEncryptKey,
+        sign_sk: SecretSignKey,
+    }
+
+    fn rand_peer() -> Peer {
+        let (uid, sk, sign_sk) = rand_peer_id_and_keys();
+        Peer { uid, sk, sign_sk }
+    }
+
     struct Listener {
-        _el: EventLoop,
+        el: EventLoop,
         uid: PeerId,
         addr: SocketAddr,
         event_rx: mpsc::Receiver<Event>,
     }
 
     fn start_listener(accept_bootstrap: bool) -> Listener {
+        let (uid, our_sk, our_sign_sk) = rand_peer_id_and_keys();
         let el = unwrap!(common::spawn_event_loop(
             LISTENER_TOKEN + 1,
             Some("Connection Listener Test"),
-            || CrustData::new(bootstrap::Cache::new(Default::default())),
+            move || CrustData::new(bootstrap::Cache::new(Default::default()), our_sign_sk),
         ));
 
         let (event_tx, event_rx) = mpsc::channel();
@@ -262,7 +434,6 @@ mod tests {
             crate::CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, mpsc::channel().0);
 
         let mc = Arc::new(unwrap!(MappingContext::try_new(), "Could not get MC"));
-        let (uid, our_sk) = rand_peer_id_and_enc_sk();
         unwrap!(
             el.send(CoreMessage::new(move |core, poll| {
                 ConnectionListener::start(
@@ -304,8 +475,12 @@ mod tests {
                 listener.set_accept_bootstrap(accept_bootstrap);
                 listener.set_ext_reachability_test(false);
 
-                let listener_info =
-                    unwrap!(core.user_data_mut().our_listeners.iter().nth(0).cloned());
+                let listener_info = unwrap!(core
+                    .user_data_mut()
+                    .our_listeners
+                    .iter()
+                    .find(|listener| listener.addr.is_ipv4())
+                    .cloned());
                 unwrap!(tx.send(listener_info));
             })),
             "Could not send to tx"
@@ -313,13 +488,30 @@ mod tests {
         let listener_info = unwrap!(rx.recv());
 
         Listener {
-            _el: el,
+            el,
             uid,
             addr: listener_info.addr,
             event_rx,
         }
     }
 
+    fn set_handshake_limits(listener: &Listener, limits: HandshakeLimitsConfig) {
+        let (tx, rx) = mpsc::channel();
+        unwrap!(listener.el.send(CoreMessage::new(move |core, _| {
+            core.user_data_mut().config.cfg.handshake_limits = limits;
+            unwrap!(tx.send(()));
+        })));
+        unwrap!(rx.recv());
+    }
+
+    fn handshake_stats(listener: &Listener) -> HandshakeStats {
+        let (tx, rx) = mpsc::channel();
+        unwrap!(listener.el.send(CoreMessage::new(move |core, _| {
+            unwrap!(tx.send(core.user_data().handshake_limiter.stats()));
+        })));
+        unwrap!(rx.recv())
+    }
+
     fn connect_to_listener(listener: &Listener) -> TcpStream {
         let listener_addr = StdSocketAddr::new(listener.addr.ip(), listener.addr.port());
         let stream = unwrap!(
@@ -327,32 +519,60 @@ mod tests {
             "Could not connect to listener"
         );
         unwrap!(
-            stream.set_read_timeout(Some(Duration::from_secs(EXCHANGE_MSG_TIMEOUT_SEC + 1))),
+            stream.set_read_timeout(Some(Duration::from_secs(
+                Timeouts::default().handshake_timeout_sec + 1
+            ))),
             "Could not set read timeout."
         );
 
         stream
     }
 
-    fn bootstrap(
-        name_hash: NameHash,
-        our_uid: PeerId,
-        our_sk: &SecretEncryptKey,
-        listener: &Listener,
-    ) {
+    fn bootstrap(name_hash: NameHash, us: &Peer, listener: &Listener, protocol: ProtocolInfo) {
+        let our_uid = us.uid;
+        let (session_pk, _) = gen_encrypt_keypair();
+        let auth = unwrap!(HandshakeAuth::new(
+            &us.sign_sk,
+            &name_hash,
+            &our_uid.pub_enc_key,
+            &listener.uid.pub_enc_key,
+            session_pk,
+        ));
+        let nonce = auth.nonce;
+        let message =
+            Message::BootstrapRequest(our_uid, name_hash, BootstrapperRole::Client, protocol, auth);
+
+        match exchange_bootstrap_msg(us, listener, message) {
+            Message::BootstrapGranted(peer_uid, _, auth) => {
+                assert_eq!(peer_uid, listener.uid);
+                assert_eq!(auth.nonce, nonce);
+                assert!(auth.verify(&peer_uid, &name_hash, &our_uid.pub_enc_key));
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
+            Event::BootstrapAccept(peer_id, peer_kind) => {
+                assert_eq!(peer_id, our_uid);
+                assert_eq!(peer_kind, CrustUser::Client);
+            }
+            event => panic!("Unexpected event notification: {:?}", event),
+        }
+    }
+
+    /// Sends the bootstrap request to the listener and returns its response.
+    fn exchange_bootstrap_msg(us: &Peer, listener: &Listener, message: Message) -> Message {
         const SOCKET_TOKEN: Token = Token(0);
         let el = unwrap!(Poll::new());
 
         let mut sock = unwrap!(TcpSock::connect(&listener.addr));
         unwrap!(sock.set_encrypt_ctx(EncryptContext::anonymous_encrypt(listener.uid.pub_enc_key)));
-        let shared_key = our_sk.shared_secret(&listener.uid.pub_enc_key);
+        let shared_key = us.sk.shared_secret(&listener.uid.pub_enc_key);
         unwrap!(sock.set_decrypt_ctx(DecryptContext::authenticated(shared_key)));
         unwrap!(el.register(&sock, SOCKET_TOKEN, Ready::writable(), PollOpt::edge(),));
 
-        let message = Message::BootstrapRequest(our_uid, name_hash, BootstrapperRole::Client);
-
         let mut events = Events::with_capacity(16);
-        let msg = 'event_loop: loop {
+        'event_loop: loop {
             let _ = unwrap!(el.poll(&mut events, None));
             for ev in events.iter() {
                 match ev.token() {
@@ -375,38 +595,31 @@ mod tests {
                     _ => panic!("Unexpected event"),
                 }
             }
-        };
-
-        match msg {
-            Message::BootstrapGranted(peer_uid) => assert_eq!(peer_uid, listener.uid),
-            msg => panic!("Unexpected message: {:?}", msg),
-        }
-
-        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
-            Event::BootstrapAccept(peer_id, peer_kind) => {
-                assert_eq!(peer_id, our_uid);
-                assert_eq!(peer_kind, CrustUser::Client);
-            }
-            event => panic!("Unexpected event notification: {:?}", event),
         }
     }
 
-    fn connect(
-        name_hash: NameHash,
-        our_uid: PeerId,
-        our_sk: &SecretEncryptKey,
-        listener: &Listener,
-    ) {
+    fn connect(name_hash: NameHash, us: &Peer, listener: &Listener, protocol: ProtocolInfo) {
         const SOCKET_TOKEN: Token = Token(0);
+        let our_uid = us.uid;
         let el = unwrap!(Poll::new());
 
-        let mut sock = unwrap!(TcpSock::connect(&listener.addr));
+        let mut sock = Socket::Tcp(unwrap!(TcpSock::connect(&listener.addr)));
         unwrap!(sock.set_encrypt_ctx(EncryptContext::anonymous_encrypt(listener.uid.pub_enc_key)));
-        let shared_key = our_sk.shared_secret(&listener.uid.pub_enc_key);
-        unwrap!(sock.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone())));
+        let shared_key = us.sk.shared_secret(&listener.uid.pub_enc_key);
+        unwrap!(sock.set_decrypt_ctx(DecryptContext::authenticated(shared_key)));
         unwrap!(el.register(&sock, SOCKET_TOKEN, Ready::writable(), PollOpt::edge()));
 
-        let message = Message::ConnectRequest(our_uid, name_hash, Default::default());
+        let (session_pk, session_sk) = gen_encrypt_keypair();
+        let auth = unwrap!(HandshakeAuth::new(
+            &us.sign_sk,
+            &name_hash,
+            &our_uid.pub_enc_key,
+            &listener.uid.pub_enc_key,
+            session_pk,
+        ));
+        let nonce = auth.nonce;
+        let message =
+            Message::ConnectRequest(our_uid, name_hash, Default::default(), protocol, auth);
 
         let mut events = Events::with_capacity(16);
         'event_loop: loop {
@@ -427,13 +640,18 @@ mod tests {
                         if ev.readiness().is_readable() {
                             let msg: Message = unwrap!(unwrap!(sock.read()));
                             let their_uid = match msg {
-                                Message::ConnectResponse(peer_uid, peer_hash) => {
+                                Message::ConnectResponse(peer_uid, peer_hash, _, auth) => {
                                     assert_eq!(peer_uid, listener.uid);
                                     assert_eq!(peer_hash, NAME_HASH);
-
-                                    unwrap!(sock.set_encrypt_ctx(EncryptContext::authenticated(
-                                        shared_key
-                                    )));
+                                    assert_eq!(auth.nonce, nonce);
+                                    assert!(auth.verify(
+                                        &peer_uid,
+                                        &peer_hash,
+                                        &our_uid.pub_enc_key
+                                    ));
+
+                                    let session_key = session_sk.shared_secret(&auth.session_pk);
+                                    unwrap!(set_session_key(&mut sock, session_key));
                                     peer_uid
                                 }
                                 msg => panic!("Unexpected message: {:?}", msg),
@@ -460,63 +678,129 @@ mod tests {
     #[test]
     fn bootstrap_with_correct_parameters() {
         let listener = start_listener(true);
-        let (uid, sk) = rand_peer_id_and_enc_sk();
-        bootstrap(NAME_HASH, uid, &sk, &listener);
+        bootstrap(NAME_HASH, &rand_peer(), &listener, ProtocolInfo::ours());
     }
 
     #[test]
     #[should_panic]
     fn bootstrap_when_bootstrapping_is_disabled() {
         let listener = start_listener(false);
-        let (uid, sk) = rand_peer_id_and_enc_sk();
-        bootstrap(NAME_HASH, uid, &sk, &listener);
+        bootstrap(NAME_HASH, &rand_peer(), &listener, ProtocolInfo::ours());
     }
 
     #[test]
     fn connect_with_correct_parameters() {
         let listener = start_listener(false);
-        let (uid, our_sk) = rand_peer_id_and_enc_sk();
-        connect(NAME_HASH, uid, &our_sk, &listener);
+        connect(NAME_HASH, &rand_peer(), &listener, ProtocolInfo::ours());
     }
 
     #[test]
     #[should_panic]
     fn connect_to_self() {
         let listener = start_listener(true);
-        let (_uid, our_sk) = rand_peer_id_and_enc_sk();
-        connect(NAME_HASH, listener.uid, &our_sk, &listener);
+        let us = Peer {
+            uid: listener.uid,
+            ..rand_peer()
+        };
+        connect(NAME_HASH, &us, &listener, ProtocolInfo::ours());
     }
 
     #[test]
     #[should_panic]
     fn bootstrap_with_invalid_version_hash() {
         let listener = start_listener(true);
-        let (uid, sk) = rand_peer_id_and_enc_sk();
-        bootstrap(NAME_HASH_2, uid, &sk, &listener);
+        bootstrap(NAME_HASH_2, &rand_peer(), &listener, ProtocolInfo::ours());
     }
 
     #[test]
     #[should_panic]
     fn connect_with_invalid_version_hash() {
         let listener = start_listener(true);
-        let (uid, our_sk) = rand_peer_id_and_enc_sk();
-        connect(NAME_HASH_2, uid, &our_sk, &listener);
+        connect(NAME_HASH_2, &rand_peer(), &listener, ProtocolInfo::ours());
+    }
+
+    #[test]
+    #[should_panic(expected = "IncompatibleVersion")]
+    fn bootstrap_with_incompatible_protocol_version() {
+        let listener = start_listener(true);
+        let protocol = ProtocolInfo {
+            version: 0,
+            features: Features::supported(),
+        };
+        bootstrap(NAME_HASH, &rand_peer(), &listener, protocol);
+    }
+
+    #[test]
+    fn bootstrap_with_protocol_version_1() {
+        let listener = start_listener(true);
+        let us = rand_peer();
+        let protocol = ProtocolInfo {
+            version: 1,
+            features: Features::supported(),
+        };
+        let message =
+            Message::BootstrapRequestV1(us.uid, NAME_HASH, BootstrapperRole::Client, protocol);
+        match exchange_bootstrap_msg(&us, &listener, message) {
+            Message::BootstrapDenied(BootstrapDenyReason::IncompatibleVersion(version)) => {
+                assert_eq!(version, ProtocolInfo::ours().version)
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "IncompatibleVersion")]
+    fn connect_with_incompatible_protocol_version() {
+        let listener = start_listener(false);
+        let protocol = ProtocolInfo {
+            version: 0,
+            features: Features::supported(),
+        };
+        connect(NAME_HASH, &rand_peer(), &listener, protocol);
+    }
+
+    #[test]
+    #[should_panic(expected = "InvalidSignature")]
+    fn bootstrap_with_someone_elses_sign_key() {
+        let listener = start_listener(true);
+        let us = Peer {
+            sign_sk: rand_peer().sign_sk,
+            ..rand_peer()
+        };
+        bootstrap(NAME_HASH, &us, &listener, ProtocolInfo::ours());
+    }
+
+    #[test]
+    #[should_panic(expected = "InvalidSignature")]
+    fn connect_with_someone_elses_sign_key() {
+        let listener = start_listener(false);
+        let us = Peer {
+            sign_sk: rand_peer().sign_sk,
+            ..rand_peer()
+        };
+        connect(NAME_HASH, &us, &listener, ProtocolInfo::ours());
     }
 
     #[test]
     #[should_panic]
     fn bootstrap_with_invalid_pub_key() {
         let listener = start_listener(true);
-        let (_uid, sk) = rand_peer_id_and_enc_sk();
-        bootstrap(NAME_HASH, listener.uid, &sk, &listener);
+        let us = Peer {
+            uid: listener.uid,
+            ..rand_peer()
+        };
+        bootstrap(NAME_HASH, &us, &listener, ProtocolInfo::ours());
     }
 
     #[test]
     #[should_panic]
     fn connect_with_invalid_pub_key() {
         let listener = start_listener(true);
-        let (_uid, our_sk) = rand_peer_id_and_enc_sk();
-        connect(NAME_HASH, listener.uid, &our_sk, &listener);
+        let us = Peer {
+            uid: listener.uid,
+            ..rand_peer()
+        };
+        connect(NAME_HASH, &us, &listener, ProtocolInfo::ours());
     }
 
     #[test]
@@ -574,6 +858,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn unauthenticated_connection_is_dropped_before_handshake_timeout() {
+        let listener = start_listener(true);
+        set_handshake_limits(
+            &listener,
+            HandshakeLimitsConfig {
+                pre_auth_timeout_sec: 1,
+                ..Default::default()
+            },
+        );
+
+        let mut us = connect_to_listener(&listener);
+        let started_at = Instant::now();
+        let mut buf = [0; 512];
+        assert_eq!(
+            0,
+            unwrap!(us.read(&mut buf), "read should have returned EOF (0)")
+        );
+        assert!(started_at.elapsed() < Duration::from_secs(HANDSHAKE_TIMEOUT_SEC));
+
+        let stats = handshake_stats(&listener);
+        assert_eq!(stats.pre_auth_timeouts, 1);
+        assert_eq!(stats.pending, 0);
+    }
+
+    #[test]
+    fn connections_over_per_ip_limit_are_dropped() {
+        let listener = start_listener(true);
+        set_handshake_limits(
+            &listener,
+            HandshakeLimitsConfig {
+                max_pending_per_ip: 1,
+                ..Default::default()
+            },
+        );
+
+        let _pending = connect_to_listener(&listener);
+        let mut rejected = connect_to_listener(&listener);
+        let started_at = Instant::now();
+        let mut buf = [0; 512];
+        assert_eq!(
+            0,
+            unwrap!(rejected.read(&mut buf), "read should have returned EOF (0)")
+        );
+        assert!(started_at.elapsed() < Duration::from_secs(HANDSHAKE_TIMEOUT_SEC));
+
+        let stats = handshake_stats(&listener);
+        assert_eq!(stats.too_many_pending_from_ip, 1);
+        assert_eq!(stats.pending, 1);
+    }
+
     #[test]
     fn stun_service() {
         // TODO(povilas): use GetExtAddr for this test.
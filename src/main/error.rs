@@ -95,11 +95,36 @@ quick_error! {
             cause(e)
             from()
         }
+        /// Connection attempt failed.
+        ConnectFailed {
+            description("Failed to connect to peer")
+            display("Failed to connect to peer")
+        }
+        /// Bootstrapping failed.
+        BootstrapFailed {
+            description("Failed to bootstrap")
+            display("Failed to bootstrap")
+        }
+        /// A tracked message won't be delivered.
+        DeliveryFailed {
+            description("Message delivery failed")
+            display("Message wasn't delivered to the peer")
+        }
+        /// Service stopped before the request completed.
+        RequestCancelled {
+            description("Request cancelled")
+            display("Service stopped before the request completed")
+        }
         /// Crypto error.
         Crypto(e: safe_crypto::Error) {
             display("Crypto error: {}", e)
             cause(e)
             from()
         }
+        /// Config values are inconsistent.
+        InvalidConfig(reason: &'static str) {
+            description("Invalid config")
+            display("Invalid config: {}", reason)
+        }
     }
 }
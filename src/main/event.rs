@@ -7,11 +7,12 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use super::ConnectionInfoResult;
+use super::{BootstrapFailure, ConnectFailureReason, ConnectionInfoResult, MsgId};
 
 use crate::common::CrustUser;
 use crate::PeerId;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 /// Enum representing different events that will be sent over the asynchronous channel to the user
 /// of this module.
@@ -21,8 +22,12 @@ pub enum Event {
     BootstrapAccept(PeerId, CrustUser),
     /// Invoked when we bootstrap to a new peer.
     BootstrapConnect(PeerId, SocketAddr),
-    /// Invoked when we failed to connect to all bootstrap contacts.
-    BootstrapFailed,
+    /// Invoked when we failed to connect to all bootstrap contacts. Carries the contacts tried in
+    /// the last round and why each of them failed.
+    BootstrapFailed(Vec<BootstrapFailure>),
+    /// Invoked in persistent bootstrap mode when a bootstrap round failed. Carries the number of
+    /// failed rounds so far and the delay before the next round.
+    BootstrapRetry(u32, Duration),
     /// Invoked when we are ready to listen for incomming connection. Contains
     /// the listening port.
     ListenerStarted(u16),
@@ -32,12 +37,32 @@ pub enum Event {
     ConnectionInfoPrepared(ConnectionInfoResult),
     /// Invoked when connection to a new peer has been established.
     ConnectSuccess(PeerId),
+    /// Invoked when connection to a new peer has been established through a relay node, because
+    /// direct connection failed. Contains the peer and the relay IDs. Such connection is used
+    /// like any other, but it's lost together with the connection to the relay.
+    RelayConnectSuccess(PeerId, PeerId),
     /// Invoked when connection to a new peer has failed.
-    ConnectFailure(PeerId),
+    ConnectFailure(PeerId, ConnectFailureReason),
     /// Invoked when a peer disconnects or can no longer be contacted.
     LostPeer(PeerId),
+    /// Invoked when we start another attempt to reconnect to a lost peer. Contains the attempt
+    /// number starting from 1.
+    Reconnecting(PeerId, u32),
+    /// Invoked when connection to a lost peer has been reestablished.
+    Reconnected(PeerId),
     /// Invoked when a new message is received. Passes the message.
     NewMessage(PeerId, CrustUser, Vec<u8>),
-    /// Invoked when trying to sending a too large data.
+    /// Invoked when a message sent with `Service::send_tracked` has been written to the socket.
+    /// Messages to relayed peers are reported once handed to the connection to the relay.
+    MessageSent(PeerId, MsgId),
+    /// Invoked when the peer acknowledged receipt of a message sent with `Service::send_tracked`.
+    MessageDelivered(PeerId, MsgId),
+    /// Invoked when messages sent with `Service::send_tracked` won't be delivered, because the
+    /// connection to the peer was lost before it acknowledged them, or a message was too large.
+    MessageFailed(PeerId, Vec<MsgId>),
+    /// Invoked when trying to send data larger than `Config::max_message_size`.
     WriteMsgSizeProhibitive(PeerId, Vec<u8>),
+    /// Smoothed round trip time to the peer, invoked on every heartbeat answered by the peer if
+    /// `Config::report_peer_latency` is set. Relayed connections don't measure it.
+    PeerLatency(PeerId, Duration),
 }
@@ -0,0 +1,310 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::Message;
+use crate::main::{EventLoopCore, MsgId};
+use socket_collection::Priority;
+use std::cmp;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Messages larger than this are split into fragments of this size.
+pub const MAX_FRAGMENT_SIZE: usize = 512 * 1024;
+/// Used when `Config::max_message_size` is not set.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// Fragments carry a serialised `Data` or `TrackedData` message, which is slightly larger than
+/// the user data in it.
+pub const MAX_MESSAGE_OVERHEAD: usize = 64;
+/// Limits the number of incomplete incoming messages. The sender has at most one message in
+/// flight per priority it uses, which are few in practice, although `Priority` has 256 values.
+/// Memory is bounded by the size of their data, see `Reassembler::add`.
+const MAX_PARTIAL_MESSAGES: usize = 16;
+
+/// The largest message we're willing to send or reassemble.
+pub fn max_message_size(core: &EventLoopCore) -> usize {
+    core.user_data()
+        .config
+        .cfg
+        .max_message_size
+        .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+}
+
+/// Splits large messages into fragments which are handed to the socket one at a time, so that
+/// heartbeats and smaller messages can be sent in between.
+#[derive(Default)]
+pub struct Fragmenter {
+    next_msg_id: u64,
+    /// Lower value means higher priority, hence the first entry is sent first.
+    queue: BTreeMap<Priority, VecDeque<OutgoingMessage>>,
+}
+
+struct OutgoingMessage {
+    msg_id: u64,
+    data: Vec<u8>,
+    next_index: u32,
+    count: u32,
+    tracked: Option<MsgId>,
+}
+
+impl Fragmenter {
+    /// `tracked` is the ID of a message sent with `Service::send_tracked`.
+    pub fn push(&mut self, data: Vec<u8>, priority: Priority, tracked: Option<MsgId>) {
+        let count = (data.len() + MAX_FRAGMENT_SIZE - 1) / MAX_FRAGMENT_SIZE;
+        let msg = OutgoingMessage {
+            msg_id: self.next_msg_id,
+            data,
+            next_index: 0,
+            count: count as u32,
+            tracked,
+        };
+        self.next_msg_id = self.next_msg_id.wrapping_add(1);
+        self.queue
+            .entry(priority)
+            .or_insert_with(VecDeque::new)
+            .push_back(msg);
+    }
+
+    /// Next fragment of the most important message. A more important message preempts the one
+    /// which is being sent. The last fragment of a tracked message comes with its ID.
+    pub fn next_fragment(&mut self) -> Option<(Message, Priority, Option<MsgId>)> {
+        let priority = *self.queue.keys().next()?;
+        let (fragment, tracked, queue_empty) = {
+            let queue = self.queue.get_mut(&priority)?;
+            let (fragment, tracked, msg_done) = {
+                let msg = queue.front_mut()?;
+                let start = msg.next_index as usize * MAX_FRAGMENT_SIZE;
+                let end = cmp::min(start + MAX_FRAGMENT_SIZE, msg.data.len());
+                let fragment = Message::DataFragment(
+                    msg.msg_id,
+                    msg.next_index,
+                    msg.count,
+                    msg.data[start..end].to_vec(),
+                );
+                msg.next_index += 1;
+                let msg_done = msg.next_index == msg.count;
+                let tracked = if msg_done { msg.tracked } else { None };
+                (fragment, tracked, msg_done)
+            };
+            if msg_done {
+                let _ = queue.pop_front();
+            }
+            (fragment, tracked, queue.is_empty())
+        };
+        if queue_empty {
+            let _ = self.queue.remove(&priority);
+        }
+        Some((fragment, priority, tracked))
+    }
+
+    /// Size of the data which is yet to be handed to the socket.
+    pub fn queued_bytes(&self) -> usize {
+        self.queue
+            .values()
+            .flat_map(|queue| queue.iter())
+            .map(|msg| {
+                let sent = msg.next_index as usize * MAX_FRAGMENT_SIZE;
+                msg.data.len() - cmp::min(sent, msg.data.len())
+            })
+            .sum()
+    }
+}
+
+/// Collects fragments of incoming messages.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<u64, PartialMessage>,
+    /// Size of the data of all partial messages.
+    bytes: usize,
+}
+
+struct PartialMessage {
+    next_index: u32,
+    count: u32,
+    data: Vec<u8>,
+}
+
+impl Reassembler {
+    /// Returns the whole message, once its last fragment arrives. Messages with fragments out of
+    /// order are dropped, as are those which would make all partial messages together larger
+    /// than `max_size`.
+    pub fn add(
+        &mut self,
+        msg_id: u64,
+        index: u32,
+        count: u32,
+        fragment: Vec<u8>,
+        max_size: usize,
+    ) -> Option<Vec<u8>> {
+        if index == 0 && !self.partial.contains_key(&msg_id) {
+            if self.partial.len() >= MAX_PARTIAL_MESSAGES {
+                debug!("Too many partial messages, dropping message {}", msg_id);
+                return None;
+            }
+            let _ = self.partial.insert(
+                msg_id,
+                PartialMessage {
+                    next_index: 0,
+                    count,
+                    data: Vec::new(),
+                },
+            );
+        }
+
+        let mut oe = match self.partial.entry(msg_id) {
+            Entry::Occupied(oe) => oe,
+            Entry::Vacant(_) => {
+                debug!("Dropping fragment {} of unknown message {}", index, msg_id);
+                return None;
+            }
+        };
+        {
+            let msg = oe.get_mut();
+            if msg.next_index != index
+                || msg.count != count
+                || self.bytes + fragment.len() > max_size
+            {
+                debug!("Dropping invalid or too large message {}", msg_id);
+                self.bytes -= oe.remove().data.len();
+                return None;
+            }
+            self.bytes += fragment.len();
+            msg.data.extend_from_slice(&fragment);
+            msg.next_index += 1;
+            if msg.next_index < msg.count {
+                return None;
+            }
+        }
+        let data = oe.remove().data;
+        self.bytes -= data.len();
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reassemble(fragmenter: &mut Fragmenter, reassembler: &mut Reassembler) -> Vec<Vec<u8>> {
+        let mut msgs = Vec::new();
+        while let Some((fragment, _, _)) = fragmenter.next_fragment() {
+            match fragment {
+                Message::DataFragment(msg_id, index, count, data) => {
+                    if let Some(msg) = reassembler.add(msg_id, index, count, data, 10_000_000) {
+                        msgs.push(msg);
+                    }
+                }
+                msg => panic!("Unexpected message: {:?}", msg),
+            }
+        }
+        msgs
+    }
+
+    #[test]
+    fn fragments_are_reassembled() {
+        let data: Vec<u8> = (0..MAX_FRAGMENT_SIZE * 2 + 100).map(|i| i as u8).collect();
+        let mut fragmenter = Fragmenter::default();
+        let mut reassembler = Reassembler::default();
+
+        fragmenter.push(data.clone(), 3, None);
+        let msgs = reassemble(&mut fragmenter, &mut reassembler);
+
+        assert_eq!(msgs, vec![data]);
+    }
+
+    #[test]
+    fn more_important_message_preempts_the_one_being_sent() {
+        let mut fragmenter = Fragmenter::default();
+        fragmenter.push(vec![1; MAX_FRAGMENT_SIZE * 2], 5, None);
+
+        match unwrap!(fragmenter.next_fragment()) {
+            (Message::DataFragment(_, 0, 2, _), 5, None) => (),
+            fragment => panic!("Unexpected fragment: {:?}", fragment),
+        }
+        fragmenter.push(vec![2; MAX_FRAGMENT_SIZE + 1], 0, None);
+        match unwrap!(fragmenter.next_fragment()) {
+            (Message::DataFragment(_, 0, 2, _), 0, None) => (),
+            fragment => panic!("Unexpected fragment: {:?}", fragment),
+        }
+        match unwrap!(fragmenter.next_fragment()) {
+            (Message::DataFragment(_, 1, 2, data), 0, None) => assert_eq!(data, vec![2]),
+            fragment => panic!("Unexpected fragment: {:?}", fragment),
+        }
+        match unwrap!(fragmenter.next_fragment()) {
+            (Message::DataFragment(_, 1, 2, _), 5, None) => (),
+            fragment => panic!("Unexpected fragment: {:?}", fragment),
+        }
+        assert!(fragmenter.next_fragment().is_none());
+    }
+
+    #[test]
+    fn queued_bytes_shrink_as_fragments_are_taken() {
+        let mut fragmenter = Fragmenter::default();
+        fragmenter.push(vec![1; MAX_FRAGMENT_SIZE + 10], 0, None);
+        fragmenter.push(vec![2; MAX_FRAGMENT_SIZE * 2], 1, None);
+        assert_eq!(fragmenter.queued_bytes(), MAX_FRAGMENT_SIZE * 3 + 10);
+
+        let _ = fragmenter.next_fragment();
+        assert_eq!(fragmenter.queued_bytes(), MAX_FRAGMENT_SIZE * 2 + 10);
+        let _ = fragmenter.next_fragment();
+        assert_eq!(fragmenter.queued_bytes(), MAX_FRAGMENT_SIZE * 2);
+    }
+
+    #[test]
+    fn last_fragment_of_tracked_message_comes_with_its_id() {
+        let mut fragmenter = Fragmenter::default();
+        fragmenter.push(vec![1; MAX_FRAGMENT_SIZE * 2], 0, Some(7));
+
+        match unwrap!(fragmenter.next_fragment()) {
+            (Message::DataFragment(_, 0, 2, _), 0, None) => (),
+            fragment => panic!("Unexpected fragment: {:?}", fragment),
+        }
+        match unwrap!(fragmenter.next_fragment()) {
+            (Message::DataFragment(_, 1, 2, _), 0, Some(7)) => (),
+            fragment => panic!("Unexpected fragment: {:?}", fragment),
+        }
+    }
+
+    #[test]
+    fn too_large_message_is_dropped() {
+        let mut reassembler = Reassembler::default();
+
+        assert!(reassembler.add(0, 0, 3, vec![0; 10], 25).is_none());
+        assert!(reassembler.add(0, 1, 3, vec![0; 10], 25).is_none());
+        assert!(reassembler.add(0, 2, 3, vec![0; 10], 25).is_none());
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn partial_messages_together_are_bounded() {
+        let mut reassembler = Reassembler::default();
+
+        assert!(reassembler.add(0, 0, 3, vec![0; 10], 25).is_none());
+        assert!(reassembler.add(1, 0, 3, vec![0; 10], 25).is_none());
+        assert!(reassembler.add(0, 1, 3, vec![0; 10], 25).is_none());
+        assert_eq!(reassembler.partial.len(), 1);
+        assert_eq!(reassembler.bytes, 10);
+
+        assert!(reassembler.add(1, 1, 3, vec![0; 10], 25).is_none());
+        assert_eq!(
+            reassembler.add(1, 2, 3, vec![1; 5], 25),
+            Some([vec![0; 20], vec![1; 5]].concat())
+        );
+        assert_eq!(reassembler.bytes, 0);
+    }
+
+    #[test]
+    fn out_of_order_fragment_drops_message() {
+        let mut reassembler = Reassembler::default();
+
+        assert!(reassembler.add(0, 0, 3, vec![0; 10], 100).is_none());
+        assert!(reassembler.add(0, 2, 3, vec![0; 10], 100).is_none());
+        assert!(reassembler.add(0, 1, 3, vec![0; 10], 100).is_none());
+        assert!(reassembler.partial.is_empty());
+    }
+}
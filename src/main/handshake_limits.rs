@@ -0,0 +1,218 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Every connection accepted by the listener holds a socket, a decrypt context and a timer until
+//! its handshake is over. These limits keep a single host, or a flood of hosts, from exhausting
+//! our memory and file descriptors with connections that never complete the handshake.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Incoming handshake specific configurable settings.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HandshakeLimitsConfig {
+    /// Handshakes of incoming connections under way at the same time, from all addresses.
+    pub max_pending: usize,
+    /// Handshakes of incoming connections under way at the same time from a single IP address.
+    pub max_pending_per_ip: usize,
+    /// Connections accepted per second on average.
+    pub accept_rate_per_sec: u32,
+    /// Connections accepted in a burst, on top of `accept_rate_per_sec`.
+    pub accept_burst: u32,
+    /// Drop incoming connections which don't send a correctly signed request within this many
+    /// seconds. Authenticated peers get the whole `Timeouts::handshake_timeout_sec`.
+    pub pre_auth_timeout_sec: u64,
+}
+
+impl Default for HandshakeLimitsConfig {
+    fn default() -> HandshakeLimitsConfig {
+        HandshakeLimitsConfig {
+            max_pending: 512,
+            max_pending_per_ip: 16,
+            accept_rate_per_sec: 100,
+            accept_burst: 200,
+            pre_auth_timeout_sec: 10,
+        }
+    }
+}
+
+/// Counters of incoming connections the listener turned away, returned by
+/// `Service::handshake_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeStats {
+    /// Handshakes currently under way.
+    pub pending: usize,
+    /// Connections accepted faster than `accept_rate_per_sec` allows.
+    pub rate_limited: u64,
+    /// Connections rejected because `max_pending` handshakes were under way.
+    pub too_many_pending: u64,
+    /// Connections rejected because `max_pending_per_ip` handshakes from the same address were
+    /// under way.
+    pub too_many_pending_from_ip: u64,
+    /// Connections dropped for not authenticating within `pre_auth_timeout_sec`.
+    pub pre_auth_timeouts: u64,
+}
+
+/// Why an incoming connection wasn't admitted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    RateLimited,
+    TooManyPending,
+    TooManyPendingFromIp,
+}
+
+/// Keeps track of incoming connections still handshaking.
+#[derive(Default)]
+pub struct HandshakeLimiter {
+    pending: HashMap<IpAddr, usize>,
+    /// Accepts left in the token bucket and when it was last refilled.
+    tokens: f64,
+    refilled_at: Option<Instant>,
+    stats: HandshakeStats,
+}
+
+impl HandshakeLimiter {
+    /// Admits a connection from `ip` unless it exceeds a limit. Admitted connections have to be
+    /// released once their handshake is over, successfully or not.
+    pub fn admit(&mut self, ip: IpAddr, config: &HandshakeLimitsConfig) -> Result<(), Rejection> {
+        let res = self.check(ip, config);
+        match res {
+            Ok(()) => {
+                *self.pending.entry(ip).or_insert(0) += 1;
+                self.stats.pending += 1;
+            }
+            Err(Rejection::RateLimited) => self.stats.rate_limited += 1,
+            Err(Rejection::TooManyPending) => self.stats.too_many_pending += 1,
+            Err(Rejection::TooManyPendingFromIp) => self.stats.too_many_pending_from_ip += 1,
+        }
+        res
+    }
+
+    pub fn release(&mut self, ip: IpAddr) {
+        let remove = match self.pending.get_mut(&ip) {
+            Some(count) => {
+                *count -= 1;
+                self.stats.pending -= 1;
+                *count == 0
+            }
+            None => return,
+        };
+        if remove {
+            let _ = self.pending.remove(&ip);
+        }
+    }
+
+    pub fn record_pre_auth_timeout(&mut self) {
+        self.stats.pre_auth_timeouts += 1;
+    }
+
+    pub fn stats(&self) -> HandshakeStats {
+        self.stats
+    }
+
+    fn check(&mut self, ip: IpAddr, config: &HandshakeLimitsConfig) -> Result<(), Rejection> {
+        if !self.take_token(config) {
+            return Err(Rejection::RateLimited);
+        }
+        if self.stats.pending >= config.max_pending {
+            return Err(Rejection::TooManyPending);
+        }
+        if self.pending.get(&ip).map_or(0, |count| *count) >= config.max_pending_per_ip {
+            return Err(Rejection::TooManyPendingFromIp);
+        }
+        Ok(())
+    }
+
+    fn take_token(&mut self, config: &HandshakeLimitsConfig) -> bool {
+        let now = Instant::now();
+        let burst = f64::from(cmp::max(config.accept_burst, 1));
+        self.tokens = match self.refilled_at {
+            Some(refilled_at) => {
+                let elapsed = now - refilled_at;
+                let elapsed =
+                    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+                (self.tokens + elapsed * f64::from(config.accept_rate_per_sec)).min(burst)
+            }
+            None => burst,
+        };
+        self.refilled_at = Some(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn pending_handshakes_are_limited_per_ip_and_in_total() {
+        let config = HandshakeLimitsConfig {
+            max_pending: 3,
+            max_pending_per_ip: 2,
+            ..Default::default()
+        };
+        let mut limiter = HandshakeLimiter::default();
+
+        assert_eq!(limiter.admit(ip(1), &config), Ok(()));
+        assert_eq!(limiter.admit(ip(1), &config), Ok(()));
+        assert_eq!(
+            limiter.admit(ip(1), &config),
+            Err(Rejection::TooManyPendingFromIp)
+        );
+        assert_eq!(limiter.admit(ip(2), &config), Ok(()));
+        assert_eq!(
+            limiter.admit(ip(3), &config),
+            Err(Rejection::TooManyPending)
+        );
+
+        limiter.release(ip(1));
+        assert_eq!(limiter.admit(ip(3), &config), Ok(()));
+
+        let stats = limiter.stats();
+        assert_eq!(stats.pending, 3);
+        assert_eq!(stats.too_many_pending, 1);
+        assert_eq!(stats.too_many_pending_from_ip, 1);
+    }
+
+    #[test]
+    fn accepts_beyond_burst_are_rate_limited() {
+        let config = HandshakeLimitsConfig {
+            accept_rate_per_sec: 0,
+            accept_burst: 2,
+            ..Default::default()
+        };
+        let mut limiter = HandshakeLimiter::default();
+
+        assert_eq!(limiter.admit(ip(1), &config), Ok(()));
+        assert_eq!(limiter.admit(ip(2), &config), Ok(()));
+        assert_eq!(limiter.admit(ip(3), &config), Err(Rejection::RateLimited));
+        assert_eq!(limiter.stats().rate_limited, 1);
+    }
+
+    #[test]
+    fn releasing_unknown_ip_is_ignored() {
+        let mut limiter = HandshakeLimiter::default();
+        limiter.release(ip(1));
+        assert_eq!(limiter.stats().pending, 0);
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::main::CrustError;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Range of IPv4 or IPv6 addresses in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+/// A plain address stands for a range of just that address. IPv4 ranges also match IPv4 mapped
+/// IPv6 addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    /// First address of the range, i.e. with the bits after the prefix cleared.
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// Range of the addresses sharing the first `prefix_len` bits with `addr`. Fails if the
+    /// prefix is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> crate::Res<Self> {
+        let addr = match addr {
+            IpAddr::V4(addr) if prefix_len <= 32 => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & v4_mask(prefix_len)))
+            }
+            IpAddr::V6(addr) if prefix_len <= 128 => {
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & v6_mask(prefix_len)))
+            }
+            _ => {
+                return Err(CrustError::InvalidConfig(
+                    "IP range prefix is longer than the address",
+                ))
+            }
+        };
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// Returns true if `ip` is in this range.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, unmap(ip)) {
+            (IpAddr::V4(first), IpAddr::V4(ip)) => {
+                u32::from(ip) & v4_mask(self.prefix_len) == u32::from(first)
+            }
+            (IpAddr::V6(first), IpAddr::V6(ip)) => {
+                u128::from(ip) & v6_mask(self.prefix_len) == u128::from(first)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(addr: IpAddr) -> Self {
+        let prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Self { addr, prefix_len }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = CrustError;
+
+    fn from_str(s: &str) -> crate::Res<Self> {
+        let invalid = || CrustError::InvalidConfig("invalid IP range");
+        let mut parts = s.splitn(2, '/');
+        let addr = parts
+            .next()
+            .and_then(|addr| addr.parse::<IpAddr>().ok())
+            .ok_or_else(invalid)?;
+        match parts.next() {
+            Some(prefix_len) => Self::new(addr, prefix_len.parse().map_err(|_| invalid())?),
+            None => Ok(Self::from(addr)),
+        }
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for IpRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("invalid IP range: {}", s)))
+    }
+}
+
+fn v4_mask(prefix_len: u8) -> u32 {
+    if prefix_len == 0 {
+        0
+    } else {
+        !0 << (32 - prefix_len)
+    }
+}
+
+fn v6_mask(prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        !0 << (128 - prefix_len)
+    }
+}
+
+/// Converts IPv4 mapped IPv6 addresses, as seen on dual stack sockets, to IPv4.
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if segments[..5] == [0; 5] && segments[5] == 0xffff {
+                let octets = ip.octets();
+                IpAddr::V4(Ipv4Addr::new(
+                    octets[12], octets[13], octets[14], octets[15],
+                ))
+            } else {
+                IpAddr::V6(ip)
+            }
+        }
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn ip(s: &str) -> IpAddr {
+        unwrap!(s.parse())
+    }
+
+    fn range(s: &str) -> IpRange {
+        unwrap!(s.parse())
+    }
+
+    #[test]
+    fn ipv4_range_contains_addresses_with_same_prefix() {
+        let subnet = range("10.1.0.0/16");
+        assert!(subnet.contains(ip("10.1.0.1")));
+        assert!(subnet.contains(ip("10.1.255.255")));
+        assert!(!subnet.contains(ip("10.2.0.1")));
+        assert!(!subnet.contains(ip("2001:db8::1")));
+        assert!(subnet.contains(ip("::ffff:10.1.2.3")));
+
+        assert!(range("0.0.0.0/0").contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn ipv6_range_contains_addresses_with_same_prefix() {
+        let subnet = range("2001:db8::/32");
+        assert!(subnet.contains(ip("2001:db8:1::1")));
+        assert!(!subnet.contains(ip("2001:db9::1")));
+        assert!(!subnet.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn plain_address_is_a_single_address_range() {
+        let single = range("8.8.4.4");
+        assert_eq!(single, range("8.8.4.4/32"));
+        assert!(single.contains(ip("8.8.4.4")));
+        assert!(!single.contains(ip("8.8.4.5")));
+    }
+
+    #[test]
+    fn host_bits_are_ignored() {
+        assert_eq!(range("10.1.2.3/8"), range("10.0.0.0/8"));
+        assert_eq!(range("10.1.2.3/8").to_string(), "10.0.0.0/8");
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("2001:db8::/129".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn serialises_as_string() {
+        let json = unwrap!(serde_json::to_string(&range("192.168.0.0/24")));
+        assert_eq!(json, "\"192.168.0.0/24\"");
+        let parsed: IpRange = unwrap!(serde_json::from_str(&json));
+        assert_eq!(parsed, range("192.168.0.0/24"));
+        assert!(serde_json::from_str::<IpRange>("\"192.168.0.0/99\"").is_err());
+    }
+}
@@ -7,33 +7,61 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-pub use self::active_connection::{ActiveConnection, INACTIVITY_TIMEOUT_MS};
+pub use self::active_connection::ActiveConnection;
+pub use self::ban::{BanConfig, BanTarget};
 #[cfg(test)]
 pub use self::bootstrap::Cache as BootstrapCache;
-pub use self::bootstrap::{Bootstrap, CacheConfig as BootstrapCacheConfig};
+pub use self::bootstrap::{
+    Bootstrap, BootstrapFailure, BootstrapFailureReason, CacheConfig as BootstrapCacheConfig,
+    RetryConfig as BootstrapRetryConfig,
+};
 pub use self::config_handler::Config;
 pub use self::config_refresher::ConfigRefresher;
-pub use self::connect::Connect;
+pub use self::connect::{
+    Connect, ConnectAttemptError, ConnectAttemptFailure, ConnectFailureReason,
+};
 pub use self::connection_candidate::ConnectionCandidate;
+pub use self::connection_limits::ConnectionLimitsConfig;
 pub use self::connection_listener::ConnectionListener;
 pub use self::error::CrustError;
 pub use self::event::Event;
+pub use self::handshake_limits::{HandshakeLimitsConfig, HandshakeStats};
+pub use self::ip_range::IpRange;
+pub use self::peer_exchange::{PeerExchange, PeerExchangeConfig};
+pub use self::peer_stats::{PeerStats, TrafficStats};
+pub use self::reconnect::{ReconnectConfig, ReconnectSupervisor};
+pub use self::rekey::RekeyConfig;
+pub use self::relay::{Relay, RelayConfig, RelayedConnection};
 pub use self::service::Service;
+#[cfg(feature = "async-api")]
+pub use self::service::{AsyncService, EventStream};
+pub use self::timeouts::Timeouts;
 pub use self::types::{
     ConfigWrapper, ConnectionId, ConnectionInfoResult, CrustData, EventLoop, EventLoopCore,
-    EventToken, GetGlobalListenerAddrs, PrivConnectionInfo, PubConnectionInfo,
+    EventToken, GetGlobalListenerAddrs, MsgId, PrivConnectionInfo, PubConnectionInfo,
 };
 
 mod active_connection;
+mod ban;
 mod bootstrap;
 mod config_handler;
 mod config_refresher;
 mod connect;
 mod connection_candidate;
+mod connection_limits;
 mod connection_listener;
 mod error;
 mod event;
+mod fragmentation;
+mod handshake_limits;
+mod ip_range;
+mod peer_exchange;
+mod peer_stats;
+mod reconnect;
+mod rekey;
+mod relay;
 mod service;
+mod timeouts;
 mod types;
 
 pub use self::config_handler::read_config_file;
@@ -43,8 +71,8 @@ use safe_crypto::{PublicEncryptKey, PublicSignKey};
 /// Used to identify unique peers.
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct PeerId {
-    /// Public signing key is not used by Crust itself, but it is passed to Crust users when
-    /// new peers are found.
+    /// Public signing key. During the handshake Crust checks that peers own the matching secret
+    /// key, so users can trust it when new peers are found.
     pub pub_sign_key: PublicSignKey,
     /// Crust uses this field to actually identify different peers.
     pub pub_enc_key: PublicEncryptKey,
@@ -0,0 +1,435 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Connected nodes periodically share samples of the contacts they know with each other, which
+//! keeps the bootstrap cache populated without seeding it by hand.
+
+use crate::common::{CoreMessage, CoreTimer, CrustUser, Features, Message, PeerInfo, State};
+use crate::main::{bootstrap, relay};
+use crate::main::{ActiveConnection, CrustData, EventLoopCore, EventToken};
+use crate::PeerId;
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use rand;
+use rand::seq::SliceRandom;
+use safe_crypto::PublicEncryptKey;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How often we look for connected nodes which are due to be asked for contacts.
+const TICK_INTERVAL_SEC: u64 = 5;
+/// Requests from a peer which asked us less than this long ago are ignored.
+const MIN_RESPONSE_INTERVAL_SEC: u64 = 30;
+
+/// Peer exchange specific configurable settings.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PeerExchangeConfig {
+    /// Ask connected nodes for contacts and answer their requests. Peers with this disabled
+    /// neither ask nor answer.
+    pub enabled: bool,
+    /// Ask each connected node for contacts this often, in seconds. Nodes are first asked shortly
+    /// after we connect to them.
+    pub request_interval_sec: u64,
+    /// Largest number of contacts we send in a single response and accept from one.
+    pub max_peers_per_response: usize,
+    /// Largest number of contacts learned from a single peer that our bootstrap cache holds at a
+    /// time.
+    pub max_peers_per_origin: usize,
+}
+
+impl Default for PeerExchangeConfig {
+    fn default() -> PeerExchangeConfig {
+        PeerExchangeConfig {
+            enabled: false,
+            request_interval_sec: 10 * 60,
+            max_peers_per_response: 16,
+            max_peers_per_origin: 32,
+        }
+    }
+}
+
+/// Requests contacts from connected nodes and answers their requests.
+pub struct PeerExchange {
+    token: Token,
+    our_id: PeerId,
+    timer: CoreTimer,
+    timeout: Timeout,
+    /// When we last asked each connected node.
+    last_request: HashMap<PeerId, Instant>,
+    /// Nodes we're awaiting a response from. Responses from other peers are ignored.
+    pending: HashSet<PeerId>,
+    /// When we last answered each peer.
+    last_response: HashMap<PeerId, Instant>,
+    /// Cached contacts learned through peer exchange and the peers who shared them.
+    origins: HashMap<PeerInfo, PeerId>,
+}
+
+impl PeerExchange {
+    pub fn start(core: &mut EventLoopCore, token: Token, our_id: PeerId) -> crate::Res<()> {
+        let timer = CoreTimer::new(token, 0);
+        let timeout = core.set_timeout(Duration::from_secs(TICK_INTERVAL_SEC), timer);
+        let state = Rc::new(RefCell::new(Self {
+            token,
+            our_id,
+            timer,
+            timeout,
+            last_request: HashMap::new(),
+            pending: HashSet::new(),
+            last_response: HashMap::new(),
+            origins: HashMap::new(),
+        }));
+        let _ = core.insert_state(token, state);
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        from: PeerId,
+        msg: Message,
+    ) {
+        match msg {
+            Message::PeerExchangeRequest => self.handle_request(core, poll, from),
+            Message::PeerExchangeResponse(peers) => self.handle_response(core, poll, from, peers),
+            msg => debug!("Unexpected peer exchange message: {:?}", msg),
+        }
+    }
+
+    /// Asks the connected nodes we haven't asked for `request_interval_sec`.
+    fn request_peers(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let nodes = connected_nodes(core);
+        self.last_request
+            .retain(|their_id, _| nodes.contains(their_id));
+        self.pending.retain(|their_id| nodes.contains(their_id));
+        {
+            let connections = &core.user_data().connections;
+            self.last_response
+                .retain(|their_id, _| connections.contains_key(their_id));
+        }
+
+        let interval = {
+            let config = &core.user_data().config.cfg.peer_exchange;
+            if !config.enabled {
+                return;
+            }
+            Duration::from_secs(config.request_interval_sec)
+        };
+        for their_id in nodes {
+            let due = self
+                .last_request
+                .get(&their_id)
+                .map_or(true, |asked_at| asked_at.elapsed() >= interval);
+            if due && relay::send_direct(core, poll, &their_id, Message::PeerExchangeRequest, 0) {
+                let _ = self.last_request.insert(their_id, Instant::now());
+                let _ = self.pending.insert(their_id);
+            }
+        }
+    }
+
+    fn handle_request(&mut self, core: &mut EventLoopCore, poll: &Poll, from: PeerId) {
+        if !core.user_data().config.cfg.peer_exchange.enabled {
+            return;
+        }
+        let min_interval = Duration::from_secs(MIN_RESPONSE_INTERVAL_SEC);
+        if let Some(answered_at) = self.last_response.get(&from) {
+            if answered_at.elapsed() < min_interval {
+                debug!(
+                    "Ignoring peer exchange request from {:?}: too frequent",
+                    from
+                );
+                return;
+            }
+        }
+
+        let peers = self.sample(core, &from);
+        let msg = Message::PeerExchangeResponse(peers);
+        if relay::send_direct(core, poll, &from, msg, 0) {
+            let _ = self.last_response.insert(from, Instant::now());
+        }
+    }
+
+    /// Our listeners followed by a random sample of the bootstrap cache, leaving out the
+    /// requester itself.
+    fn sample(&self, core: &EventLoopCore, requester: &PeerId) -> Vec<PeerInfo> {
+        let user_data = core.user_data();
+        let mut cached = user_data.bootstrap_cache.snapshot();
+        cached.shuffle(&mut rand::thread_rng());
+
+        let mut peers: Vec<PeerInfo> = user_data.our_listeners.iter().cloned().collect();
+        peers.extend(cached);
+        peers.retain(|peer| {
+            peer.pub_key != requester.pub_enc_key && !user_data.bans.is_contact_banned(peer)
+        });
+        peers.truncate(user_data.config.cfg.peer_exchange.max_peers_per_response);
+        peers
+    }
+
+    fn handle_response(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        from: PeerId,
+        peers: Vec<PeerInfo>,
+    ) {
+        if !self.pending.remove(&from) {
+            debug!(
+                "Ignoring unsolicited peer exchange response from {:?}",
+                from
+            );
+            return;
+        }
+
+        let cached: HashSet<PublicEncryptKey> = core
+            .user_data()
+            .bootstrap_cache
+            .snapshot()
+            .iter()
+            .map(|peer| peer.pub_key)
+            .collect();
+        self.origins
+            .retain(|peer, _| cached.contains(&peer.pub_key));
+        let mut shared = self
+            .origins
+            .values()
+            .filter(|origin| **origin == from)
+            .count();
+
+        let config = core.user_data().config.cfg.clone();
+        for peer in peers
+            .into_iter()
+            .take(config.peer_exchange.max_peers_per_response)
+        {
+            if shared >= config.peer_exchange.max_peers_per_origin {
+                debug!("Enough contacts from {:?} already", from);
+                break;
+            }
+            let skip = peer.pub_key == self.our_id.pub_enc_key
+                || cached.contains(&peer.pub_key)
+                || config.hard_coded_contacts.contains(&peer)
+                || core.user_data().bans.is_contact_banned(&peer);
+            if skip {
+                continue;
+            }
+            bootstrap::cache_peer_info(core, poll, peer);
+            let _ = self.origins.insert(peer, from);
+            shared += 1;
+        }
+    }
+
+    fn take_contacts_from(&mut self, origin: &PeerId) -> Vec<PeerInfo> {
+        let shared: Vec<PeerInfo> = self
+            .origins
+            .iter()
+            .filter(|&(_, shared_by)| shared_by == origin)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &shared {
+            let _ = self.origins.remove(peer);
+        }
+        shared
+    }
+}
+
+impl State<CrustData> for PeerExchange {
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        self.timeout = core.set_timeout(Duration::from_secs(TICK_INTERVAL_SEC), self.timer);
+        self.request_peers(core, poll);
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Handles peer exchange messages received from a directly connected peer. Processing is
+/// deferred, because the response goes out over the receiving connection, which is still
+/// borrowed.
+pub fn handle_message(core: &mut EventLoopCore, from: PeerId, msg: Message) {
+    let _ = core.sender().send(CoreMessage::new(move |core, poll| {
+        if let Some(state) = core.get_state(EventToken::PeerExchange.into()) {
+            let mut state = state.borrow_mut();
+            if let Some(peer_exchange) = state.as_any().downcast_mut::<PeerExchange>() {
+                peer_exchange.handle_message(core, poll, from, msg);
+            }
+        }
+    }));
+}
+
+/// Removes the contacts the given peer shared with us from the bootstrap cache.
+pub fn drop_contacts_from(core: &mut EventLoopCore, origin: &PeerId) {
+    let shared = match core.get_state(EventToken::PeerExchange.into()) {
+        Some(state) => {
+            let mut state = state.borrow_mut();
+            match state.as_any().downcast_mut::<PeerExchange>() {
+                Some(peer_exchange) => peer_exchange.take_contacts_from(origin),
+                None => return,
+            }
+        }
+        None => return,
+    };
+    if shared.is_empty() {
+        return;
+    }
+    let bootstrap_cache = &mut core.user_data_mut().bootstrap_cache;
+    for peer in &shared {
+        bootstrap_cache.remove(peer);
+    }
+    bootstrap_cache.try_commit();
+}
+
+/// Nodes we're directly connected to which support peer exchange.
+fn connected_nodes(core: &EventLoopCore) -> HashSet<PeerId> {
+    core.user_data()
+        .connections
+        .iter()
+        .filter(|&(_, cid)| {
+            cid.active_connection
+                .and_then(|token| core.get_state(token))
+                .map_or(false, |state| {
+                    let mut state = state.borrow_mut();
+                    match state.as_any().downcast_mut::<ActiveConnection>() {
+                        Some(conn) => {
+                            conn.peer_kind() == CrustUser::Node
+                                && conn.features().contains(Features::PEER_EXCHANGE)
+                        }
+                        None => false,
+                    }
+                })
+        })
+        .map(|(their_id, _)| *their_id)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+    use crate::main::ban::{self, BanTarget};
+    use crate::tests::utils::{
+        peer_info_with_rand_key, rand_peer_id_and_enc_sk, test_bootstrap_cache, test_core,
+    };
+
+    fn start_peer_exchange(core: &mut EventLoopCore) {
+        let (our_id, _) = rand_peer_id_and_enc_sk();
+        unwrap!(PeerExchange::start(
+            core,
+            EventToken::PeerExchange.into(),
+            our_id
+        ));
+    }
+
+    fn with_peer_exchange<F: FnOnce(&mut PeerExchange, &mut EventLoopCore)>(
+        core: &mut EventLoopCore,
+        f: F,
+    ) {
+        let state = unwrap!(core.get_state(EventToken::PeerExchange.into()));
+        let mut state = state.borrow_mut();
+        f(unwrap!(state.as_any().downcast_mut::<PeerExchange>()), core);
+    }
+
+    fn rand_peers(count: u8) -> Vec<PeerInfo> {
+        (0..count)
+            .map(|i| peer_info_with_rand_key(ipv4_addr(1, 2, 3, i, 4000)))
+            .collect()
+    }
+
+    #[test]
+    fn unsolicited_responses_are_ignored() {
+        let mut core = test_core(test_bootstrap_cache());
+        let poll = unwrap!(Poll::new());
+        start_peer_exchange(&mut core);
+        let (their_id, _) = rand_peer_id_and_enc_sk();
+
+        with_peer_exchange(&mut core, |peer_exchange, core| {
+            peer_exchange.handle_response(core, &poll, their_id, rand_peers(3));
+        });
+
+        assert!(core.user_data().bootstrap_cache.snapshot().is_empty());
+    }
+
+    #[test]
+    fn contacts_per_origin_are_limited() {
+        let mut core = test_core(test_bootstrap_cache());
+        let poll = unwrap!(Poll::new());
+        core.user_data_mut()
+            .config
+            .cfg
+            .peer_exchange
+            .max_peers_per_origin = 2;
+        start_peer_exchange(&mut core);
+        let (their_id, _) = rand_peer_id_and_enc_sk();
+
+        with_peer_exchange(&mut core, |peer_exchange, core| {
+            let _ = peer_exchange.pending.insert(their_id);
+            peer_exchange.handle_response(core, &poll, their_id, rand_peers(5));
+            let _ = peer_exchange.pending.insert(their_id);
+            peer_exchange.handle_response(core, &poll, their_id, rand_peers(5));
+        });
+
+        assert_eq!(core.user_data().bootstrap_cache.snapshot().len(), 2);
+    }
+
+    #[test]
+    fn sample_leaves_out_requester_and_is_limited() {
+        let mut core = test_core(test_bootstrap_cache());
+        core.user_data_mut()
+            .config
+            .cfg
+            .peer_exchange
+            .max_peers_per_response = 3;
+        let (requester, _) = rand_peer_id_and_enc_sk();
+        let requester_contact = PeerInfo::new(ipv4_addr(1, 2, 3, 4, 4000), requester.pub_enc_key);
+        let _ = core.user_data_mut().bootstrap_cache.put(requester_contact);
+        for peer in rand_peers(5) {
+            let _ = core.user_data_mut().bootstrap_cache.put(peer);
+        }
+        start_peer_exchange(&mut core);
+
+        with_peer_exchange(&mut core, |peer_exchange, core| {
+            let peers = peer_exchange.sample(core, &requester);
+            assert_eq!(peers.len(), 3);
+            assert!(!peers.contains(&requester_contact));
+        });
+    }
+
+    #[test]
+    fn banning_origin_drops_contacts_it_shared() {
+        let mut core = test_core(test_bootstrap_cache());
+        let poll = unwrap!(Poll::new());
+        start_peer_exchange(&mut core);
+        let (their_id, _) = rand_peer_id_and_enc_sk();
+        let other = peer_info_with_rand_key(ipv4_addr(1, 2, 4, 1, 4000));
+        let _ = core.user_data_mut().bootstrap_cache.put(other);
+
+        with_peer_exchange(&mut core, |peer_exchange, core| {
+            let _ = peer_exchange.pending.insert(their_id);
+            peer_exchange.handle_response(core, &poll, their_id, rand_peers(3));
+        });
+        assert_eq!(core.user_data().bootstrap_cache.snapshot().len(), 4);
+
+        ban::ban(
+            &mut core,
+            &poll,
+            BanTarget::Peer(their_id),
+            Duration::from_secs(60),
+        );
+
+        assert_eq!(core.user_data().bootstrap_cache.snapshot(), vec![other]);
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CrustUser, RttEstimator, Transport};
+use crate::PeerId;
+use socket_collection::Priority;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+/// Number of user messages and their total size in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    /// Number of messages.
+    pub messages: u64,
+    /// Total size of the messages, not counting protocol overhead.
+    pub bytes: u64,
+}
+
+/// Statistics of the connection to a peer, returned by `Service::peer_stats`.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    /// The peer ID.
+    pub id: PeerId,
+    /// Whether the peer is a node or a client.
+    pub role: CrustUser,
+    /// Remote address of the connection. `None` for relayed connections.
+    pub remote_addr: Option<SocketAddr>,
+    /// Local address of the connection. `None` for relayed connections.
+    pub local_addr: Option<SocketAddr>,
+    /// Transport of the connection. `None` for relayed connections.
+    pub transport: Option<Transport>,
+    /// The node forwarding our traffic, if the connection is relayed.
+    pub relay: Option<PeerId>,
+    /// When the connection was established.
+    pub connected_since: SystemTime,
+    /// Messages handed to the connection, by priority.
+    pub sent: BTreeMap<Priority, TrafficStats>,
+    /// Messages received from the peer. Priority is only known to the sender.
+    pub received: TrafficStats,
+    /// Size of the large messages which are yet to be fragmented and handed to the socket.
+    pub queued_bytes: usize,
+    /// Last time we sent anything to the peer, including heartbeats.
+    pub last_sent: SystemTime,
+    /// Last time we received anything from the peer, including heartbeats.
+    pub last_received: SystemTime,
+    /// Last time we sent user data to or received user data from the peer.
+    pub last_data: SystemTime,
+    /// Smoothed round trip time, measured by heartbeats. `None` until the peer answers the first
+    /// heartbeat and for relayed connections.
+    pub rtt: Option<Duration>,
+    /// Round trip time variation, i.e. jitter.
+    pub rtt_var: Option<Duration>,
+    /// How many times the session key was replaced since the connection was established.
+    pub rekeys: u64,
+}
+
+/// Keeps the statistics of a connection up to date.
+pub struct StatsRecorder {
+    stats: PeerStats,
+    rtt: RttEstimator,
+}
+
+impl StatsRecorder {
+    pub fn new(
+        id: PeerId,
+        role: CrustUser,
+        remote_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+        transport: Option<Transport>,
+        relay: Option<PeerId>,
+    ) -> Self {
+        let now = SystemTime::now();
+        Self {
+            stats: PeerStats {
+                id,
+                role,
+                remote_addr,
+                local_addr,
+                transport,
+                relay,
+                connected_since: now,
+                sent: BTreeMap::new(),
+                received: TrafficStats::default(),
+                queued_bytes: 0,
+                last_sent: now,
+                last_received: now,
+                last_data: now,
+                rtt: None,
+                rtt_var: None,
+                rekeys: 0,
+            },
+            rtt: RttEstimator::default(),
+        }
+    }
+
+    /// Counts a user message handed to the connection.
+    pub fn record_sent(&mut self, priority: Priority, bytes: usize) {
+        let sent = self
+            .stats
+            .sent
+            .entry(priority)
+            .or_insert_with(TrafficStats::default);
+        sent.messages += 1;
+        sent.bytes += bytes as u64;
+        self.stats.last_data = SystemTime::now();
+    }
+
+    /// Counts a user message received from the peer.
+    pub fn record_received(&mut self, bytes: usize) {
+        self.stats.received.messages += 1;
+        self.stats.received.bytes += bytes as u64;
+        self.stats.last_data = SystemTime::now();
+    }
+
+    /// Called whenever anything, including heartbeats, is written to the connection.
+    pub fn touch_sent(&mut self) {
+        self.stats.last_sent = SystemTime::now();
+    }
+
+    /// Called whenever anything, including heartbeats, is read from the connection.
+    pub fn touch_received(&mut self) {
+        self.stats.last_received = SystemTime::now();
+    }
+
+    /// Counts a replacement of the session key.
+    pub fn record_rekey(&mut self) {
+        self.stats.rekeys += 1;
+    }
+
+    /// Updates the round trip time estimate and returns the smoothed value.
+    pub fn record_rtt(&mut self, sample: Duration) -> Duration {
+        self.rtt.update(sample)
+    }
+
+    pub fn snapshot(&self, queued_bytes: usize) -> PeerStats {
+        PeerStats {
+            queued_bytes,
+            rtt: self.rtt.srtt,
+            rtt_var: self.rtt.srtt.map(|_| self.rtt.rttvar),
+            ..self.stats.clone()
+        }
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{
+    Backoff, BootstrapperRole, CoreMessage, CoreTimer, CrustUser, Features, NameHash, PeerInfo,
+    Socket, State, Transport,
+};
+use crate::main::bootstrap::TryPeer;
+use crate::main::service::our_global_listener_addrs;
+use crate::main::{
+    ActiveConnection, BootstrapFailureReason, Connect, ConnectionId, CrustData, Event,
+    EventLoopCore, EventToken, PrivConnectionInfo, PubConnectionInfo,
+};
+use crate::PeerId;
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use safe_crypto::SecretEncryptKey;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// How often we check whether another handshake with the peer is done, before attempting again.
+const HANDSHAKE_RECHECK_MS: u64 = 1000;
+
+type BootstrapResult =
+    Result<(Socket, PeerInfo, PeerId, Features), (PeerInfo, BootstrapFailureReason)>;
+
+/// Automatic reconnection specific configurable settings.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    /// Reconnect to peers we have connected or bootstrapped to, when connection to them is lost.
+    pub enabled: bool,
+    /// Give up after this many failed attempts. Zero means retry forever.
+    pub max_attempts: u32,
+    /// Delay before the first attempt in milliseconds. It doubles after every failed attempt.
+    pub initial_backoff_ms: u64,
+    /// Upper bound of the delay between attempts in milliseconds.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> ReconnectConfig {
+        ReconnectConfig {
+            enabled: false,
+            max_attempts: 10,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 5 * 60 * 1000,
+        }
+    }
+}
+
+/// Information needed to reestablish connection with a lost peer.
+#[derive(Debug, Clone)]
+pub enum ReconnectTarget {
+    /// Peer we connected to via `Service::connect`, over the given transports.
+    Connect(PubConnectionInfo, Vec<Transport>),
+    /// Peer we bootstrapped off with the given role.
+    Bootstrap(PeerInfo, CrustUser),
+}
+
+/// Remembers how we reached our peers and starts reconnection attempts when they are lost.
+pub struct ReconnectSupervisor {
+    token: Token,
+    our_uid: PeerId,
+    name_hash: NameHash,
+    our_sk: SecretEncryptKey,
+    event_tx: crate::CrustEventSender,
+    targets: HashMap<PeerId, ReconnectTarget>,
+    /// Tokens of ongoing `Reconnect` states.
+    attempts: HashMap<PeerId, Token>,
+}
+
+impl ReconnectSupervisor {
+    pub fn start(
+        core: &mut EventLoopCore,
+        token: Token,
+        our_uid: PeerId,
+        name_hash: NameHash,
+        our_sk: SecretEncryptKey,
+        event_tx: crate::CrustEventSender,
+    ) -> crate::Res<()> {
+        let state = Rc::new(RefCell::new(Self {
+            token,
+            our_uid,
+            name_hash,
+            our_sk,
+            event_tx,
+            targets: Default::default(),
+            attempts: Default::default(),
+        }));
+        let _ = core.insert_state(token, state);
+        Ok(())
+    }
+
+    fn peer_lost(&mut self, core: &mut EventLoopCore, their_id: PeerId) {
+        let config = core.user_data().config.cfg.reconnect.clone();
+        if !config.enabled || self.attempts.contains_key(&their_id) {
+            return;
+        }
+        let target = match self.targets.get(&their_id) {
+            Some(target) => target.clone(),
+            None => return,
+        };
+
+        trace!("Scheduling reconnection to {:?}", their_id);
+        let token = Reconnect::start(
+            core,
+            self.token,
+            their_id,
+            target,
+            &config,
+            self.our_uid,
+            self.name_hash,
+            self.our_sk.clone(),
+            self.event_tx.clone(),
+        );
+        let _ = self.attempts.insert(their_id, token);
+    }
+
+    fn forget(&mut self, core: &mut EventLoopCore, poll: &Poll, their_id: &PeerId) {
+        let _ = self.targets.remove(their_id);
+        if let Some(token) = self.attempts.remove(their_id) {
+            if let Some(state) = core.get_state(token) {
+                state.borrow_mut().terminate(core, poll);
+            }
+        }
+    }
+
+    fn attempt_finished(&mut self, their_id: &PeerId, connected: bool) {
+        let _ = self.attempts.remove(their_id);
+        if !connected {
+            let _ = self.targets.remove(their_id);
+        }
+    }
+}
+
+impl State<CrustData> for ReconnectSupervisor {
+    fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        for (_, token) in self.attempts.drain() {
+            if let Some(state) = core.get_state(token) {
+                state.borrow_mut().terminate(core, poll);
+            }
+        }
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Remembers how to reach the given peer, should the connection with it be lost.
+pub fn remember_peer(core: &mut EventLoopCore, their_id: PeerId, target: ReconnectTarget) {
+    if let Some(state) = core.get_state(EventToken::Reconnect.into()) {
+        let mut state = state.borrow_mut();
+        if let Some(supervisor) = state.as_any().downcast_mut::<ReconnectSupervisor>() {
+            let _ = supervisor.targets.insert(their_id, target);
+        }
+    }
+}
+
+/// Stops reconnecting to the given peer, e.g. when user disconnected it explicitly.
+pub fn forget_peer(core: &mut EventLoopCore, poll: &Poll, their_id: &PeerId) {
+    if let Some(state) = core.get_state(EventToken::Reconnect.into()) {
+        let mut state = state.borrow_mut();
+        if let Some(supervisor) = state.as_any().downcast_mut::<ReconnectSupervisor>() {
+            supervisor.forget(core, poll, their_id);
+        }
+    }
+}
+
+/// Starts reconnecting to the given peer, if reconnection is enabled and we know how to reach it.
+pub fn peer_lost(core: &mut EventLoopCore, their_id: PeerId) {
+    if let Some(state) = core.get_state(EventToken::Reconnect.into()) {
+        let mut state = state.borrow_mut();
+        if let Some(supervisor) = state.as_any().downcast_mut::<ReconnectSupervisor>() {
+            supervisor.peer_lost(core, their_id);
+        }
+    }
+}
+
+/// Reconnects to a single lost peer, waiting longer after every failed attempt.
+struct Reconnect {
+    token: Token,
+    supervisor: Token,
+    their_id: PeerId,
+    target: ReconnectTarget,
+    our_uid: PeerId,
+    name_hash: NameHash,
+    our_sk: SecretEncryptKey,
+    event_tx: crate::CrustEventSender,
+    backoff: Backoff,
+    max_attempts: u32,
+    timeout: Option<Timeout>,
+    child: Option<Token>,
+    /// Result of the bootstrap attempt, waiting to be handled outside of the callback. The socket
+    /// can't be sent over the event loop channel, so it's kept here.
+    bootstrapped: Rc<Cell<Option<(Token, BootstrapResult)>>>,
+}
+
+impl Reconnect {
+    fn start(
+        core: &mut EventLoopCore,
+        supervisor: Token,
+        their_id: PeerId,
+        target: ReconnectTarget,
+        config: &ReconnectConfig,
+        our_uid: PeerId,
+        name_hash: NameHash,
+        our_sk: SecretEncryptKey,
+        event_tx: crate::CrustEventSender,
+    ) -> Token {
+        let token = core.get_new_token();
+        let mut backoff = Backoff::new(config.initial_backoff_ms, config.max_backoff_ms);
+        let timeout = core.set_timeout(backoff.next_delay(), CoreTimer::new(token, 0));
+
+        let state = Rc::new(RefCell::new(Self {
+            token,
+            supervisor,
+            their_id,
+            target,
+            our_uid,
+            name_hash,
+            our_sk,
+            event_tx,
+            backoff,
+            max_attempts: config.max_attempts,
+            timeout: Some(timeout),
+            child: None,
+            bootstrapped: Rc::new(Cell::new(None)),
+        }));
+        let _ = core.insert_state(token, state);
+
+        token
+    }
+
+    fn attempt(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.timeout = None;
+
+        match core.user_data().connections.get(&self.their_id) {
+            Some(&ConnectionId {
+                active_connection: Some(_),
+                ..
+            }) => {
+                trace!("{:?} is connected again, stop reconnecting", self.their_id);
+                return self.finish(core, poll, true);
+            }
+            Some(_) => {
+                trace!("Already handshaking with {:?}, retry later", self.their_id);
+                return self.recheck(core);
+            }
+            None => (),
+        }
+
+        let attempt = self.backoff.attempt();
+        debug!("Reconnecting to {:?}, attempt {}", self.their_id, attempt);
+        let _ = self
+            .event_tx
+            .send(Event::Reconnecting(self.their_id, attempt));
+
+        let res = match self.target.clone() {
+            ReconnectTarget::Connect(their_ci, transports) => {
+                self.connect(core, poll, their_ci, transports)
+            }
+            ReconnectTarget::Bootstrap(peer_info, our_role) => {
+                self.bootstrap(core, poll, peer_info, our_role)
+            }
+        };
+        match res {
+            Ok(child) => self.child = Some(child),
+            Err(e) => {
+                debug!("Failed to reconnect to {:?}: {:?}", self.their_id, e);
+                self.handle_result(core, poll, false);
+            }
+        }
+    }
+
+    fn connect(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_ci: PubConnectionInfo,
+        transports: Vec<Transport>,
+    ) -> crate::Res<Token> {
+        let our_ci = PrivConnectionInfo {
+            id: self.our_uid,
+            for_direct: core
+                .user_data()
+                .our_listeners
+                .iter()
+                .map(|peer| peer.addr)
+                .collect(),
+            for_hole_punch: vec![],
+            hole_punch_socket: None,
+            for_udp_hole_punch: vec![],
+            udp_hole_punch_socket: None,
+            mapping_methods: HashSet::new(),
+            relays: vec![],
+            transports,
+        };
+
+        // `Connect` may report the result while we're still borrowed, hence defer it.
+        let token = self.token;
+        let finish = move |core: &mut EventLoopCore, _poll: &Poll, connected| {
+            let _ = core.sender().send(CoreMessage::new(move |core, poll| {
+                if let Some(state) = core.get_state(token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(reconnect) = state.as_any().downcast_mut::<Reconnect>() {
+                        reconnect.handle_result(core, poll, connected);
+                    }
+                }
+            }));
+        };
+
+        Connect::start(
+            core,
+            poll,
+            our_ci,
+            their_ci,
+            self.name_hash,
+            self.event_tx.clone(),
+            &self.our_sk,
+            our_global_listener_addrs(core),
+            Some(Box::new(finish)),
+        )
+    }
+
+    fn bootstrap(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        peer_info: PeerInfo,
+        our_role: CrustUser,
+    ) -> crate::Res<Token> {
+        let our_role = match our_role {
+            CrustUser::Node => BootstrapperRole::Node(our_global_listener_addrs(core)),
+            CrustUser::Client => BootstrapperRole::Client,
+        };
+
+        // `TryPeer` may report the result while we're still borrowed, hence defer it.
+        let token = self.token;
+        let bootstrapped = self.bootstrapped.clone();
+        let finish = move |core: &mut EventLoopCore, _poll: &Poll, child, res| {
+            bootstrapped.set(Some((child, res)));
+            let _ = core.sender().send(CoreMessage::new(move |core, poll| {
+                if let Some(state) = core.get_state(token) {
+                    let mut state = state.borrow_mut();
+                    if let Some(reconnect) = state.as_any().downcast_mut::<Reconnect>() {
+                        if let Some((child, res)) = reconnect.bootstrapped.take() {
+                            reconnect.handle_bootstrap(core, poll, child, res);
+                        }
+                    }
+                }
+            }));
+        };
+
+        let transports = core.user_data().config.cfg.transports();
+        TryPeer::start(
+            core,
+            poll,
+            peer_info,
+            self.our_uid,
+            self.name_hash,
+            our_role,
+            &self.our_sk,
+            transports,
+            Box::new(finish),
+        )
+    }
+
+    fn handle_bootstrap(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        child: Token,
+        res: BootstrapResult,
+    ) {
+        self.child = None;
+        match res {
+            Ok((socket, _, peer_id, features)) => {
+                if peer_id != self.their_id {
+                    debug!(
+                        "Expected to reconnect to {:?}, but {:?} responded",
+                        self.their_id, peer_id
+                    );
+                    let _ = poll.deregister(&socket);
+                    return self.handle_result(core, poll, false);
+                }
+                match core.user_data().connections.get(&peer_id) {
+                    Some(&ConnectionId {
+                        active_connection: Some(_),
+                        ..
+                    }) => {
+                        let _ = poll.deregister(&socket);
+                        return self.handle_result(core, poll, true);
+                    }
+                    // Another handshake with the peer is under way, wait for it to complete.
+                    Some(_) => {
+                        let _ = poll.deregister(&socket);
+                        return self.recheck(core);
+                    }
+                    None => (),
+                }
+                ActiveConnection::start(
+                    core,
+                    poll,
+                    child,
+                    socket,
+                    self.our_uid,
+                    peer_id,
+                    CrustUser::Node,
+                    Event::Reconnected(peer_id),
+                    self.event_tx.clone(),
+                    features,
+                );
+                self.handle_result(core, poll, true);
+            }
+            Err((_, reason)) => {
+                debug!("Failed to reconnect to {:?}: {:?}", self.their_id, reason);
+                self.handle_result(core, poll, false);
+            }
+        }
+    }
+
+    fn handle_result(&mut self, core: &mut EventLoopCore, poll: &Poll, connected: bool) {
+        self.child = None;
+        if connected {
+            return self.finish(core, poll, true);
+        }
+        if self.max_attempts != 0 && self.backoff.attempt() >= self.max_attempts {
+            info!(
+                "Giving up reconnecting to {:?} after {} attempts",
+                self.their_id,
+                self.backoff.attempt()
+            );
+            return self.finish(core, poll, false);
+        }
+        self.schedule(core);
+    }
+
+    fn schedule(&mut self, core: &mut EventLoopCore) {
+        let delay = self.backoff.next_delay();
+        self.timeout = Some(core.set_timeout(delay, CoreTimer::new(self.token, 0)));
+    }
+
+    /// Checks again after a short while, without using up an attempt.
+    fn recheck(&mut self, core: &mut EventLoopCore) {
+        let delay = Duration::from_millis(HANDSHAKE_RECHECK_MS);
+        self.timeout = Some(core.set_timeout(delay, CoreTimer::new(self.token, 0)));
+    }
+
+    fn finish(&mut self, core: &mut EventLoopCore, poll: &Poll, connected: bool) {
+        self.terminate(core, poll);
+
+        if let Some(state) = core.get_state(self.supervisor) {
+            let mut state = state.borrow_mut();
+            if let Some(supervisor) = state.as_any().downcast_mut::<ReconnectSupervisor>() {
+                supervisor.attempt_finished(&self.their_id, connected);
+            }
+        }
+    }
+}
+
+impl State<CrustData> for Reconnect {
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        self.attempt(core, poll);
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if let Some(timeout) = self.timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if let Some(child) = self.child.take() {
+            if let Some(state) = core.get_state(child) {
+                state.borrow_mut().terminate(core, poll);
+            }
+        }
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
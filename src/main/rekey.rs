@@ -0,0 +1,275 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Active connections periodically replace the session key agreed during the handshake, so that
+//! no single key encrypts too much traffic.
+//!
+//! The initiator sends `Rekey` with a new ephemeral key and the peer answers with `RekeyAck`
+//! carrying its own. Both then derive the new session key. The responder encrypts everything
+//! after its `RekeyAck` with the new key, the initiator everything after `RekeyDone`. Each side
+//! switches its decrypt context as soon as it reads the other side's marker.
+
+use crate::common::{CoreTimer, Features, Message};
+use crate::main::EventLoopCore;
+use mio::Token;
+use mio_extras::timer::Timeout;
+use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey, SharedSecretKey};
+use std::mem;
+use std::time::Duration;
+
+/// Session rekeying specific configurable settings.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RekeyConfig {
+    /// Start replacing the session keys of our connections. Peers with this disabled still
+    /// answer rekey requests.
+    pub enabled: bool,
+    /// Replace the session key once this many bytes of user data were sent and received with it.
+    pub max_bytes: u64,
+    /// Replace the session key at least this often, in seconds.
+    pub interval_sec: u64,
+}
+
+impl Default for RekeyConfig {
+    fn default() -> RekeyConfig {
+        RekeyConfig {
+            enabled: true,
+            max_bytes: 1024 * 1024 * 1024,
+            interval_sec: 60 * 60,
+        }
+    }
+}
+
+/// Switch of our encrypt context to a new session key. The peer switches its decrypt context
+/// when it reads `marker`, so the marker has to be the last message encrypted with the old key.
+/// The socket sends queued messages by priority rather than in order, hence the marker is only
+/// queued once everything before it has been flushed, and the new key is only installed once the
+/// marker has been flushed too. Messages sent in the meantime are held back.
+pub struct KeySwitch {
+    /// `None` once handed to the socket.
+    pub marker: Option<Message>,
+    pub key: SharedSecretKey,
+}
+
+/// Keeps track of when to replace the session key and of the rekey under way.
+pub struct Rekey {
+    /// Whether we start rekeys ourselves.
+    enabled: bool,
+    max_bytes: u64,
+    interval: Duration,
+    timer: CoreTimer,
+    timeout: Option<Timeout>,
+    /// User data sent and received with the current key.
+    bytes: u64,
+    phase: Phase,
+}
+
+enum Phase {
+    Idle,
+    /// We sent `Rekey` and wait for the peer's key.
+    Initiated(SecretEncryptKey),
+    /// We sent `RekeyAck` with the given key and wait for `RekeyDone`, until when the peer still
+    /// encrypts with the old key.
+    Responded(SharedSecretKey),
+}
+
+impl Rekey {
+    /// Settings are taken from the current config, later changes don't affect this connection.
+    /// Peers which don't support rekeying are never asked to.
+    pub fn new(core: &mut EventLoopCore, state_id: Token, features: Features) -> Self {
+        let config = core.user_data().config.cfg.rekey.clone();
+        let enabled = config.enabled && features.contains(Features::REKEY);
+        let interval = Duration::from_secs(config.interval_sec);
+        let timer = CoreTimer::new(state_id, 2);
+        let timeout = if enabled {
+            Some(core.set_timeout(interval, timer))
+        } else {
+            None
+        };
+
+        Self {
+            enabled,
+            max_bytes: config.max_bytes,
+            interval,
+            timer,
+            timeout,
+            bytes: 0,
+            phase: Phase::Idle,
+        }
+    }
+
+    pub fn is_timer(&self, timer_id: u8) -> bool {
+        self.timer.timer_id == timer_id
+    }
+
+    /// Counts user data sent or received. Returns true if it's time for a rekey.
+    pub fn record(&mut self, bytes: usize) -> bool {
+        self.bytes += bytes as u64;
+        self.is_due()
+    }
+
+    /// Starts a rekey, unless one is under way already. Returns the message to send the peer.
+    pub fn start(&mut self) -> Option<Message> {
+        if !self.enabled {
+            return None;
+        }
+        match self.phase {
+            Phase::Idle => (),
+            _ => return None,
+        }
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        self.phase = Phase::Initiated(our_sk);
+        self.bytes = 0;
+        Some(Message::Rekey(our_pk))
+    }
+
+    /// Answers the peer's `Rekey`. Returns our `RekeyAck` and the new key, which we encrypt with
+    /// after the ack. `we_win` tells whether we carry on as the initiator if both of us started a
+    /// rekey at the same time.
+    pub fn handle_request(
+        &mut self,
+        we_win: bool,
+        their_pk: &PublicEncryptKey,
+    ) -> Option<(Message, SharedSecretKey)> {
+        match self.phase {
+            Phase::Idle => (),
+            Phase::Initiated(_) if !we_win => (),
+            Phase::Initiated(_) => return None,
+            Phase::Responded(_) => {
+                debug!("Rekey requested while still answering the previous one");
+                return None;
+            }
+        }
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let key = our_sk.shared_secret(their_pk);
+        self.phase = Phase::Responded(key.clone());
+        self.bytes = 0;
+        Some((Message::RekeyAck(our_pk), key))
+    }
+
+    /// Returns the new key if we're waiting for the peer's `RekeyAck`.
+    pub fn handle_ack(&mut self, their_pk: &PublicEncryptKey) -> Option<SharedSecretKey> {
+        match mem::replace(&mut self.phase, Phase::Idle) {
+            Phase::Initiated(our_sk) => Some(our_sk.shared_secret(their_pk)),
+            phase => {
+                debug!("Unexpected rekey ack");
+                self.phase = phase;
+                None
+            }
+        }
+    }
+
+    /// Returns the new key if we're waiting for the peer's `RekeyDone`.
+    pub fn handle_done(&mut self) -> Option<SharedSecretKey> {
+        match mem::replace(&mut self.phase, Phase::Idle) {
+            Phase::Responded(key) => Some(key),
+            phase => {
+                debug!("Unexpected rekey done");
+                self.phase = phase;
+                None
+            }
+        }
+    }
+
+    /// Restarts the timer once a rekey is done. Returns true if enough traffic went through
+    /// meanwhile to start another one right away.
+    pub fn finish(&mut self, core: &mut EventLoopCore) -> bool {
+        if let Some(timeout) = self.timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if self.enabled {
+            self.timeout = Some(core.set_timeout(self.interval, self.timer));
+        }
+        self.is_due()
+    }
+
+    pub fn terminate(&mut self, core: &mut EventLoopCore) {
+        if let Some(timeout) = self.timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        self.enabled && self.bytes >= self.max_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::utils::{test_bootstrap_cache, test_core};
+
+    fn new_rekey(core: &mut EventLoopCore) -> Rekey {
+        Rekey::new(core, Token(0), Features::supported())
+    }
+
+    fn rekey_pk(msg: Option<Message>) -> PublicEncryptKey {
+        match msg {
+            Some(Message::Rekey(pk)) | Some(Message::RekeyAck(pk)) => pk,
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn both_peers_derive_the_same_key() {
+        let mut core = test_core(test_bootstrap_cache());
+        let mut initiator = new_rekey(&mut core);
+        let mut responder = new_rekey(&mut core);
+
+        let initiator_pk = rekey_pk(initiator.start());
+        let (ack, responder_key) = unwrap!(responder.handle_request(false, &initiator_pk));
+        let initiator_key = unwrap!(initiator.handle_ack(&rekey_pk(Some(ack))));
+        assert!(responder.handle_done().is_some());
+
+        let encrypted = unwrap!(initiator_key.encrypt(&b"after rekey".to_vec()));
+        let decrypted: Vec<u8> = unwrap!(responder_key.decrypt(&encrypted));
+        assert_eq!(decrypted, b"after rekey".to_vec());
+
+        // Both are ready for the next rekey.
+        assert!(initiator.start().is_some());
+        assert!(responder.start().is_some());
+    }
+
+    #[test]
+    fn simultaneous_rekeys_are_resolved_by_peer_id() {
+        let mut core = test_core(test_bootstrap_cache());
+        let mut winner = new_rekey(&mut core);
+        let mut loser = new_rekey(&mut core);
+
+        let winner_pk = rekey_pk(winner.start());
+        let loser_pk = rekey_pk(loser.start());
+
+        assert!(winner.handle_request(true, &loser_pk).is_none());
+        let (ack, _) = unwrap!(loser.handle_request(false, &winner_pk));
+        assert!(winner.handle_ack(&rekey_pk(Some(ack))).is_some());
+        assert!(loser.handle_done().is_some());
+    }
+
+    #[test]
+    fn rekey_is_due_after_configured_byte_count() {
+        let mut core = test_core(test_bootstrap_cache());
+        core.user_data_mut().config.cfg.rekey.max_bytes = 100;
+        let mut rekey = new_rekey(&mut core);
+
+        assert!(!rekey.record(60));
+        assert!(rekey.record(60));
+        assert!(rekey.start().is_some());
+        // Traffic during the rekey counts towards the next one.
+        assert!(!rekey.record(60));
+        assert!(rekey.start().is_none());
+    }
+
+    #[test]
+    fn peers_without_rekey_support_are_never_asked() {
+        let mut core = test_core(test_bootstrap_cache());
+        let mut rekey = Rekey::new(&mut core, Token(0), Features::HEARTBEAT_PING);
+        assert!(rekey.start().is_none());
+        assert!(!rekey.record(1024 * 1024 * 1024));
+    }
+}
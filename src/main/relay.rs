@@ -0,0 +1,1258 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! When two peers fail to connect directly, e.g. both of them are behind symmetric NATs, they
+//! can still talk through a node both of them are directly connected to. The relay node only
+//! forwards frames. The peers authenticate each other with a signed handshake and encrypt their
+//! session with a key derived from ephemeral keys, so the relay can neither read, forge nor
+//! replay their messages.
+
+use crate::common::{
+    CoreMessage, CoreTimer, CrustUser, HandshakeAuth, HandshakeNonce, Message, NameHash, State,
+};
+use crate::main::active_connection::{Heartbeat, HeartbeatAction};
+use crate::main::ban;
+use crate::main::connection_limits;
+use crate::main::fragmentation::{
+    self, Fragmenter, Reassembler, MAX_FRAGMENT_SIZE, MAX_MESSAGE_OVERHEAD,
+};
+use crate::main::peer_stats::StatsRecorder;
+use crate::main::{
+    ActiveConnection, ConnectFailureReason, ConnectionId, CrustData, Event, EventLoopCore,
+    EventToken, MsgId, PeerStats,
+};
+use crate::PeerId;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use safe_crypto::{gen_encrypt_keypair, SecretEncryptKey, SharedSecretKey};
+use socket_collection::Priority;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Limits the number of relays we advertise and ask to forward our connection request.
+const MAX_RELAY_CANDIDATES: usize = 8;
+
+/// Relay specific configurable settings.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RelayConfig {
+    /// Forward traffic between our peers which failed to connect to each other directly.
+    /// This only makes sense for publicly reachable nodes.
+    pub enabled: bool,
+}
+
+/// Handshake of a relayed connection, encrypted with the key derived from the peers' identity
+/// keys. Each side signs the requester's nonce together with its ephemeral key.
+#[derive(Debug, Serialize, Deserialize)]
+enum RelayedHandshake {
+    ConnectRequest(NameHash, HandshakeAuth),
+    ConnectResponse(HandshakeAuth),
+}
+
+/// Messages exchanged by relayed peers once the handshake is done. They are encrypted with the
+/// session key, so the relay can't read or forge them.
+#[derive(Debug, Serialize, Deserialize)]
+enum RelayedMessage {
+    Heartbeat,
+    Data(Vec<u8>),
+    Disconnect,
+    TrackedData(MsgId, Vec<u8>),
+    Ack(MsgId),
+    /// Part of a serialised `Data` or `TrackedData` message larger than `MAX_FRAGMENT_SIZE`.
+    DataFragment(u64, u32, u32, Vec<u8>),
+}
+
+/// Forwards traffic for our peers and establishes relayed connections for us.
+pub struct Relay {
+    token: Token,
+    our_id: PeerId,
+    name_hash: NameHash,
+    our_sk: SecretEncryptKey,
+    event_tx: crate::CrustEventSender,
+    /// Tokens of ongoing `RelayConnect` states.
+    attempts: HashMap<PeerId, Token>,
+    /// Requests we responded to, waiting for the requester to prove it has the session key.
+    accepting: HashMap<PeerId, Accepting>,
+}
+
+/// Relayed connection request we accepted. The connection is established only once the first
+/// message encrypted with the session key arrives, so requests replayed by the relay come to
+/// nothing.
+struct Accepting {
+    relay_id: PeerId,
+    session_key: SharedSecretKey,
+    started_at: Instant,
+}
+
+impl Relay {
+    pub fn start(
+        core: &mut EventLoopCore,
+        token: Token,
+        our_id: PeerId,
+        name_hash: NameHash,
+        our_sk: SecretEncryptKey,
+        event_tx: crate::CrustEventSender,
+    ) -> crate::Res<()> {
+        let state = Rc::new(RefCell::new(Self {
+            token,
+            our_id,
+            name_hash,
+            our_sk,
+            event_tx,
+            attempts: Default::default(),
+            accepting: Default::default(),
+        }));
+        let _ = core.insert_state(token, state);
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_id: PeerId,
+        relays: Vec<PeerId>,
+    ) -> bool {
+        if self.attempts.contains_key(&their_id) {
+            return true;
+        }
+
+        let (session_pk, session_sk) = gen_encrypt_keypair();
+        let auth = match HandshakeAuth::new(
+            &core.user_data().our_sign_sk,
+            &self.name_hash,
+            &self.our_id.pub_enc_key,
+            &their_id.pub_enc_key,
+            session_pk,
+        ) {
+            Ok(auth) => auth,
+            Err(e) => {
+                debug!("Failed to sign relayed connect request: {:?}", e);
+                return false;
+            }
+        };
+        let nonce = auth.nonce;
+        let shared_key = self.our_sk.shared_secret(&their_id.pub_enc_key);
+        let request =
+            match shared_key.encrypt(&RelayedHandshake::ConnectRequest(self.name_hash, auth)) {
+                Ok(request) => request,
+                Err(e) => {
+                    debug!("Failed to encrypt relayed connect request: {}", e);
+                    return false;
+                }
+            };
+
+        let our_id = self.our_id;
+        let relays: HashSet<_> = relays
+            .into_iter()
+            .filter(|relay_id| *relay_id != their_id && *relay_id != our_id)
+            .take(MAX_RELAY_CANDIDATES)
+            .filter(|relay_id| {
+                let msg = Message::RelayRequest(their_id, request.clone());
+                send_direct(core, poll, relay_id, msg, 0)
+            })
+            .collect();
+        if relays.is_empty() {
+            return false;
+        }
+
+        debug!(
+            "Connecting to {:?} through relays {:?}",
+            their_id,
+            relays.iter().collect::<Vec<_>>()
+        );
+        let token = RelayConnect::start(
+            core,
+            their_id,
+            relays,
+            nonce,
+            session_sk,
+            self.event_tx.clone(),
+        );
+        let _ = self.attempts.insert(their_id, token);
+        true
+    }
+
+    fn handle_message(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        from: PeerId,
+        msg: Message,
+    ) {
+        match msg {
+            Message::RelayRequest(target, payload) => {
+                self.forward(core, poll, from, target, payload)
+            }
+            Message::Relayed(source, payload) => {
+                self.handle_relayed(core, poll, from, source, &payload)
+            }
+            Message::RelayFailed(target) => self.handle_relay_failed(core, poll, from, target),
+            msg => debug!("{:?} - Unexpected relay message: {:?}", self.our_id, msg),
+        }
+    }
+
+    /// Called when we act as a relay.
+    fn forward(
+        &self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        source: PeerId,
+        target: PeerId,
+        payload: Vec<u8>,
+    ) {
+        let forwarded = core.user_data().config.cfg.relay.enabled
+            && source != target
+            && is_allowed(core, &source)
+            && is_allowed(core, &target)
+            && send_direct(core, poll, &target, Message::Relayed(source, payload), 0);
+        if !forwarded {
+            trace!("Can't relay from {:?} to {:?}", source, target);
+            let _ = send_direct(core, poll, &source, Message::RelayFailed(target), 0);
+        }
+    }
+
+    fn handle_relayed(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        relay_id: PeerId,
+        source: PeerId,
+        payload: &[u8],
+    ) {
+        if let Some(state) = active_state(core, &source) {
+            let mut state = state.borrow_mut();
+            if let Some(conn) = state.as_any().downcast_mut::<RelayedConnection>() {
+                if conn.relay_id == relay_id {
+                    conn.handle_payload(core, poll, payload);
+                }
+            }
+            // Otherwise we're directly connected to the source already.
+            return;
+        }
+
+        self.expire_accepting(core);
+        let confirmed = match self.accepting.get(&source) {
+            Some(accepting) => {
+                accepting.relay_id == relay_id
+                    && Session::new(accepting.session_key.clone())
+                        .open(payload)
+                        .is_some()
+            }
+            None => false,
+        };
+        if confirmed {
+            if let Some(accepting) = self.accepting.remove(&source) {
+                return self.handle_confirmed(core, poll, source, accepting, payload);
+            }
+        }
+
+        let shared_key = self.our_sk.shared_secret(&source.pub_enc_key);
+        match shared_key.decrypt(payload) {
+            Ok(RelayedHandshake::ConnectRequest(name_hash, auth)) => self.handle_connect_request(
+                core,
+                poll,
+                relay_id,
+                source,
+                name_hash,
+                auth,
+                &shared_key,
+            ),
+            Ok(RelayedHandshake::ConnectResponse(auth)) => {
+                self.handle_connect_response(core, poll, relay_id, source, auth)
+            }
+            Err(e) => debug!("Failed to decrypt relayed message from {:?}: {}", source, e),
+        }
+    }
+
+    fn handle_connect_request(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        relay_id: PeerId,
+        source: PeerId,
+        name_hash: NameHash,
+        auth: HandshakeAuth,
+        shared_key: &SharedSecretKey,
+    ) {
+        if name_hash != self.name_hash {
+            debug!(
+                "Relayed connect request from {:?} with invalid name hash",
+                source
+            );
+            return;
+        }
+        if !auth.verify(&source, &self.name_hash, &self.our_id.pub_enc_key) {
+            debug!(
+                "{:?} failed to prove it owns its relayed connect request",
+                source
+            );
+            return;
+        }
+        if !is_admitted(core, &relay_id, &source) {
+            debug!(
+                "Refusing relayed connection from {:?} via {:?}",
+                source, relay_id
+            );
+            return;
+        }
+        // When both of us fell back to a relay, the request of the peer with the greater ID wins.
+        if let Some(&token) = self.attempts.get(&source) {
+            if self.our_id > source {
+                return;
+            }
+            let _ = self.attempts.remove(&source);
+            terminate_state(core, poll, token);
+        }
+
+        let (session_pk, session_sk) = gen_encrypt_keypair();
+        let our_auth = match HandshakeAuth::sign(
+            &core.user_data().our_sign_sk,
+            &self.name_hash,
+            &self.our_id.pub_enc_key,
+            &source.pub_enc_key,
+            session_pk,
+            auth.nonce,
+        ) {
+            Ok(our_auth) => our_auth,
+            Err(e) => {
+                debug!("Failed to sign relayed connect response: {:?}", e);
+                return;
+            }
+        };
+        let response = match shared_key.encrypt(&RelayedHandshake::ConnectResponse(our_auth)) {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("Failed to encrypt relayed connect response: {}", e);
+                return;
+            }
+        };
+        let msg = Message::RelayRequest(source, response);
+        if send_direct(core, poll, &relay_id, msg, 0) {
+            let accepting = Accepting {
+                relay_id,
+                session_key: session_sk.shared_secret(&auth.session_pk),
+                started_at: Instant::now(),
+            };
+            let _ = self.accepting.insert(source, accepting);
+        }
+    }
+
+    fn handle_connect_response(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        relay_id: PeerId,
+        source: PeerId,
+        auth: HandshakeAuth,
+    ) {
+        let token = match self.attempts.get(&source) {
+            Some(&token) => token,
+            None => return,
+        };
+        let session_key = {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.borrow_mut();
+            let attempt = match state.as_any().downcast_mut::<RelayConnect>() {
+                Some(attempt) => attempt,
+                None => return,
+            };
+            if auth.nonce != attempt.nonce
+                || !auth.verify(&source, &self.name_hash, &self.our_id.pub_enc_key)
+            {
+                debug!(
+                    "{:?} failed to prove it owns its relayed connect response",
+                    source
+                );
+                return;
+            }
+            attempt.session_sk.shared_secret(&auth.session_pk)
+        };
+
+        let _ = self.attempts.remove(&source);
+        terminate_state(core, poll, token);
+        if !is_admitted(core, &relay_id, &source) {
+            debug!(
+                "Refusing relayed connection to {:?} via {:?}",
+                source, relay_id
+            );
+            let reason = ConnectFailureReason::RelayFailed;
+            let _ = self.event_tx.send(Event::ConnectFailure(source, reason));
+            return;
+        }
+        // A direct connection might have been established meanwhile. The peer then drops the
+        // session once it expires.
+        let conn = match RelayedConnection::start(
+            core,
+            relay_id,
+            self.our_id,
+            source,
+            session_key,
+            self.event_tx.clone(),
+        ) {
+            Some(conn) => conn,
+            None => return,
+        };
+        // Proves to the peer that we have the session key.
+        let _ = conn
+            .borrow_mut()
+            .write(core, poll, &RelayedMessage::Heartbeat, 0);
+    }
+
+    /// The requester sent its first message encrypted with the session key.
+    fn handle_confirmed(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        source: PeerId,
+        accepting: Accepting,
+        payload: &[u8],
+    ) {
+        if is_connected(core, &source) {
+            debug!(
+                "Already connected to {:?}, refusing relayed connection",
+                source
+            );
+            return refuse(core, poll, source, accepting);
+        }
+        if !connection_limits::make_room(core, poll, CrustUser::Node) {
+            debug!("No room for relayed connection from {:?}", source);
+            return refuse(core, poll, source, accepting);
+        }
+        let conn = match RelayedConnection::start(
+            core,
+            accepting.relay_id,
+            self.our_id,
+            source,
+            accepting.session_key.clone(),
+            self.event_tx.clone(),
+        ) {
+            Some(conn) => conn,
+            None => return refuse(core, poll, source, accepting),
+        };
+        conn.borrow_mut().handle_payload(core, poll, payload);
+    }
+
+    fn expire_accepting(&mut self, core: &EventLoopCore) {
+        let timeout_sec = core
+            .user_data()
+            .config
+            .cfg
+            .timeouts
+            .relay_connect_timeout_sec;
+        let timeout = Duration::from_secs(timeout_sec);
+        self.accepting
+            .retain(|_, accepting| accepting.started_at.elapsed() < timeout);
+    }
+
+    fn handle_relay_failed(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        relay_id: PeerId,
+        target: PeerId,
+    ) {
+        if self.attempts.contains_key(&target) {
+            return self.drop_relay_from_attempt(core, poll, relay_id, target);
+        }
+
+        if let Some(state) = active_state(core, &target) {
+            let mut state = state.borrow_mut();
+            if let Some(conn) = state.as_any().downcast_mut::<RelayedConnection>() {
+                if conn.relay_id == relay_id {
+                    debug!("Relay {:?} can't reach {:?} anymore", relay_id, target);
+                    conn.terminate(core, poll);
+                }
+            }
+        }
+    }
+
+    /// Fails the connection attempt when there are no more relays to try.
+    fn drop_relay_from_attempt(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        relay_id: PeerId,
+        their_id: PeerId,
+    ) {
+        let token = match self.attempts.get(&their_id) {
+            Some(&token) => token,
+            None => return,
+        };
+        let exhausted = match core.get_state(token) {
+            Some(state) => {
+                let mut state = state.borrow_mut();
+                match state.as_any().downcast_mut::<RelayConnect>() {
+                    Some(attempt) => {
+                        let _ = attempt.relays.remove(&relay_id);
+                        attempt.relays.is_empty()
+                    }
+                    None => true,
+                }
+            }
+            None => true,
+        };
+        if exhausted {
+            let _ = self.attempts.remove(&their_id);
+            terminate_state(core, poll, token);
+            let reason = ConnectFailureReason::RelayFailed;
+            let _ = self.event_tx.send(Event::ConnectFailure(their_id, reason));
+        }
+    }
+
+    fn relay_lost(&mut self, core: &mut EventLoopCore, poll: &Poll, relay_id: PeerId) {
+        self.accepting
+            .retain(|_, accepting| accepting.relay_id != relay_id);
+        let their_ids: Vec<_> = self.attempts.keys().cloned().collect();
+        for their_id in their_ids {
+            self.drop_relay_from_attempt(core, poll, relay_id, their_id);
+        }
+
+        let relayed: Vec<_> = core
+            .user_data()
+            .connections
+            .values()
+            .filter_map(|cid| cid.active_connection)
+            .filter_map(|token| core.get_state(token))
+            .filter(|state| {
+                match state
+                    .borrow_mut()
+                    .as_any()
+                    .downcast_mut::<RelayedConnection>()
+                {
+                    Some(conn) => conn.relay_id == relay_id,
+                    None => false,
+                }
+            })
+            .collect();
+        for state in relayed {
+            state.borrow_mut().terminate(core, poll);
+        }
+    }
+}
+
+impl State<CrustData> for Relay {
+    fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        for (_, token) in self.attempts.drain() {
+            terminate_state(core, poll, token);
+        }
+        self.accepting.clear();
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Asks the given relays to forward our connection request to the peer. Returns `false`, if none
+/// of them is directly connected to us.
+pub fn connect(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    their_id: PeerId,
+    relays: Vec<PeerId>,
+) -> bool {
+    if let Some(state) = core.get_state(EventToken::Relay.into()) {
+        let mut state = state.borrow_mut();
+        if let Some(relay) = state.as_any().downcast_mut::<Relay>() {
+            return relay.connect(core, poll, their_id, relays);
+        }
+    }
+    false
+}
+
+/// Handles relay protocol messages received from a directly connected peer. Processing is
+/// deferred, because it involves other connections while the receiving one is still borrowed.
+pub fn handle_message(core: &mut EventLoopCore, from: PeerId, msg: Message) {
+    let _ = core.sender().send(CoreMessage::new(move |core, poll| {
+        if let Some(state) = core.get_state(EventToken::Relay.into()) {
+            let mut state = state.borrow_mut();
+            if let Some(relay) = state.as_any().downcast_mut::<Relay>() {
+                relay.handle_message(core, poll, from, msg);
+            }
+        }
+    }));
+}
+
+/// Drops connections relayed through the given peer, since they can't be reached anymore.
+pub fn relay_lost(core: &mut EventLoopCore, relay_id: PeerId) {
+    let _ = core.sender().send(CoreMessage::new(move |core, poll| {
+        if let Some(state) = core.get_state(EventToken::Relay.into()) {
+            let mut state = state.borrow_mut();
+            if let Some(relay) = state.as_any().downcast_mut::<Relay>() {
+                relay.relay_lost(core, poll, relay_id);
+            }
+        }
+    }));
+}
+
+/// Directly connected nodes which could forward traffic to us, should some peer fail to connect
+/// to us directly.
+pub fn relay_candidates(core: &EventLoopCore) -> Vec<PeerId> {
+    core.user_data()
+        .connections
+        .iter()
+        .filter(|&(_, cid)| {
+            cid.active_connection
+                .and_then(|token| core.get_state(token))
+                .map_or(false, |state| {
+                    let mut state = state.borrow_mut();
+                    match state.as_any().downcast_mut::<ActiveConnection>() {
+                        Some(conn) => conn.peer_kind() == CrustUser::Node,
+                        None => false,
+                    }
+                })
+        })
+        .map(|(their_id, _)| *their_id)
+        .take(MAX_RELAY_CANDIDATES)
+        .collect()
+}
+
+/// Checks bans and the IP deny list for a peer we forward traffic of.
+fn is_allowed(core: &EventLoopCore, their_id: &PeerId) -> bool {
+    let user_data = core.user_data();
+    let ip = user_data
+        .connections
+        .get(their_id)
+        .and_then(|conn_id| ban::connection_ip(core, conn_id));
+    !user_data.bans.is_peer_banned(their_id, ip)
+        && !ip.map_or(false, |ip| user_data.config.cfg.is_ip_denied(ip))
+}
+
+/// Applies bans and the whitelist to a peer whose traffic comes through the given relay. The
+/// peer's own address is unknown, so unless its key is whitelisted it's judged by the address of
+/// the relay.
+fn is_admitted(core: &EventLoopCore, relay_id: &PeerId, their_id: &PeerId) -> bool {
+    let user_data = core.user_data();
+    let relay_ip = match user_data
+        .connections
+        .get(relay_id)
+        .and_then(|conn_id| ban::connection_ip(core, conn_id))
+    {
+        Some(ip) => ip,
+        None => return false,
+    };
+    !user_data.bans.is_peer_banned(their_id, None)
+        && !user_data.bans.is_peer_banned(relay_id, Some(relay_ip))
+        && user_data
+            .config
+            .cfg
+            .is_whitelisted(CrustUser::Node, relay_ip, &their_id.pub_enc_key)
+}
+
+fn is_connected(core: &EventLoopCore, their_id: &PeerId) -> bool {
+    core.user_data()
+        .connections
+        .get(their_id)
+        .map_or(false, |conn_id| conn_id.active_connection.is_some())
+}
+
+/// Tells the requester, which already considers itself connected, that we dropped the session.
+fn refuse(core: &mut EventLoopCore, poll: &Poll, source: PeerId, accepting: Accepting) {
+    let mut session = Session::new(accepting.session_key);
+    if let Ok(msg) = session.seal(&RelayedMessage::Disconnect) {
+        let msg = Message::RelayRequest(source, msg);
+        let _ = send_direct(core, poll, &accepting.relay_id, msg, 0);
+    }
+}
+
+fn active_state(core: &EventLoopCore, their_id: &PeerId) -> Option<Rc<RefCell<State<CrustData>>>> {
+    match core.user_data().connections.get(their_id) {
+        Some(&ConnectionId {
+            active_connection: Some(token),
+            ..
+        }) => core.get_state(token),
+        _ => None,
+    }
+}
+
+/// Sends the message over a direct connection. Returns `false`, if there's no such connection.
+pub fn send_direct(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    their_id: &PeerId,
+    msg: Message,
+    priority: Priority,
+) -> bool {
+    let state = match active_state(core, their_id) {
+        Some(state) => state,
+        None => return false,
+    };
+    let mut state = state.borrow_mut();
+    match state.as_any().downcast_mut::<ActiveConnection>() {
+        Some(conn) => {
+            conn.send(core, poll, msg, priority);
+            true
+        }
+        None => false,
+    }
+}
+
+fn terminate_state(core: &mut EventLoopCore, poll: &Poll, token: Token) {
+    if let Some(state) = core.get_state(token) {
+        state.borrow_mut().terminate(core, poll);
+    }
+}
+
+/// Waits for the remote peer to accept our connection request sent through relays.
+struct RelayConnect {
+    token: Token,
+    their_id: PeerId,
+    /// Relays that still might deliver our request.
+    relays: HashSet<PeerId>,
+    /// Nonce the peer has to sign in its response.
+    nonce: HandshakeNonce,
+    /// Our ephemeral secret key the session key is derived from.
+    session_sk: SecretEncryptKey,
+    timeout: Timeout,
+    event_tx: crate::CrustEventSender,
+}
+
+impl RelayConnect {
+    fn start(
+        core: &mut EventLoopCore,
+        their_id: PeerId,
+        relays: HashSet<PeerId>,
+        nonce: HandshakeNonce,
+        session_sk: SecretEncryptKey,
+        event_tx: crate::CrustEventSender,
+    ) -> Token {
+        let token = core.get_new_token();
+        let timeout_sec = core
+            .user_data()
+            .config
+            .cfg
+            .timeouts
+            .relay_connect_timeout_sec;
+        let timeout = core.set_timeout(Duration::from_secs(timeout_sec), CoreTimer::new(token, 0));
+
+        core.user_data_mut()
+            .connections
+            .entry(their_id)
+            .or_insert(ConnectionId {
+                active_connection: None,
+                currently_handshaking: 0,
+            })
+            .currently_handshaking += 1;
+
+        let state = Rc::new(RefCell::new(Self {
+            token,
+            their_id,
+            relays,
+            nonce,
+            session_sk,
+            timeout,
+            event_tx,
+        }));
+        let _ = core.insert_state(token, state);
+        token
+    }
+}
+
+impl State<CrustData> for RelayConnect {
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        debug!("Relayed connect to {:?} timed out", self.their_id);
+        self.terminate(core, poll);
+
+        if let Some(state) = core.get_state(EventToken::Relay.into()) {
+            let mut state = state.borrow_mut();
+            if let Some(relay) = state.as_any().downcast_mut::<Relay>() {
+                let _ = relay.attempts.remove(&self.their_id);
+            }
+        }
+        let reason = ConnectFailureReason::RelayFailed;
+        let _ = self
+            .event_tx
+            .send(Event::ConnectFailure(self.their_id, reason));
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+
+        let connections = &mut core.user_data_mut().connections;
+        if let Entry::Occupied(mut oe) = connections.entry(self.their_id) {
+            oe.get_mut().currently_handshaking -= 1;
+            if oe.get().currently_handshaking == 0 && oe.get().active_connection.is_none() {
+                let _ = oe.remove();
+            }
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Connection to a peer whose traffic is forwarded by a relay node we are directly connected to.
+/// It's lost together with the connection to the relay.
+pub struct RelayedConnection {
+    token: Token,
+    relay_id: PeerId,
+    our_id: PeerId,
+    their_id: PeerId,
+    session: Session,
+    event_tx: crate::CrustEventSender,
+    heartbeat: Heartbeat,
+    /// Tracked messages the peer hasn't acknowledged yet.
+    unacked: BTreeSet<MsgId>,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+    stats: StatsRecorder,
+}
+
+impl RelayedConnection {
+    /// Returns `None` if we are connected to the peer already, e.g. directly.
+    fn start(
+        core: &mut EventLoopCore,
+        relay_id: PeerId,
+        our_id: PeerId,
+        their_id: PeerId,
+        session_key: SharedSecretKey,
+        event_tx: crate::CrustEventSender,
+    ) -> Option<Rc<RefCell<Self>>> {
+        if is_connected(core, &their_id) {
+            debug!(
+                "Already connected to {:?}, dropping relayed connection via {:?}",
+                their_id, relay_id
+            );
+            return None;
+        }
+        trace!(
+            "Entered state RelayedConnection: {:?} -> {:?} via {:?}",
+            our_id,
+            their_id,
+            relay_id
+        );
+
+        let token = core.get_new_token();
+        let heartbeat = Heartbeat::new(core, token);
+        let state = Rc::new(RefCell::new(Self {
+            token,
+            relay_id,
+            our_id,
+            their_id,
+            session: Session::new(session_key),
+            event_tx: event_tx.clone(),
+            heartbeat,
+            unacked: BTreeSet::new(),
+            fragmenter: Fragmenter::default(),
+            reassembler: Reassembler::default(),
+            stats: StatsRecorder::new(their_id, CrustUser::Node, None, None, None, Some(relay_id)),
+        }));
+        let _ = core.insert_state(token, state.clone());
+
+        // Relayed connections never count as handshaking, whatever count the entry has belongs
+        // to direct attempts still in progress.
+        core.user_data_mut()
+            .connections
+            .entry(their_id)
+            .or_insert(ConnectionId {
+                active_connection: None,
+                currently_handshaking: 0,
+            })
+            .active_connection = Some(token);
+
+        let _ = event_tx.send(Event::RelayConnectSuccess(their_id, relay_id));
+        Some(state)
+    }
+
+    fn handle_payload(&mut self, core: &mut EventLoopCore, poll: &Poll, payload: &[u8]) {
+        let msg = match self.session.open(payload) {
+            Some(msg) => msg,
+            None => {
+                debug!(
+                    "{:?} - Dropping invalid or replayed relayed message",
+                    self.our_id
+                );
+                return;
+            }
+        };
+        self.handle_message(core, poll, msg);
+    }
+
+    fn handle_message(&mut self, core: &mut EventLoopCore, poll: &Poll, msg: RelayedMessage) {
+        match msg {
+            RelayedMessage::Data(data) => {
+                self.stats.record_received(data.len());
+                let _ = self
+                    .event_tx
+                    .send(Event::NewMessage(self.their_id, CrustUser::Node, data));
+                self.reset_receive_heartbeat(core, poll);
+            }
+            RelayedMessage::TrackedData(msg_id, data) => {
+                let _ = self.write(core, poll, &RelayedMessage::Ack(msg_id), 0);
+                self.stats.record_received(data.len());
+                let _ = self
+                    .event_tx
+                    .send(Event::NewMessage(self.their_id, CrustUser::Node, data));
+                self.reset_receive_heartbeat(core, poll);
+            }
+            RelayedMessage::Ack(msg_id) => {
+                if self.unacked.remove(&msg_id) {
+                    let _ = self
+                        .event_tx
+                        .send(Event::MessageDelivered(self.their_id, msg_id));
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            RelayedMessage::DataFragment(msg_id, index, count, fragment) => {
+                let max_size = fragmentation::max_message_size(core) + MAX_MESSAGE_OVERHEAD;
+                if let Some(data) = self
+                    .reassembler
+                    .add(msg_id, index, count, fragment, max_size)
+                {
+                    match deserialise(&data) {
+                        Ok(msg @ RelayedMessage::Data(..))
+                        | Ok(msg @ RelayedMessage::TrackedData(..)) => {
+                            self.handle_message(core, poll, msg)
+                        }
+                        Ok(msg) => debug!(
+                            "{:?} - Unexpected fragmented message: {:?}",
+                            self.our_id, msg
+                        ),
+                        Err(e) => debug!(
+                            "{:?} - Failed to deserialise fragmented message: {:?}",
+                            self.our_id, e
+                        ),
+                    }
+                }
+                self.reset_receive_heartbeat(core, poll);
+            }
+            RelayedMessage::Disconnect => self.terminate(core, poll),
+            RelayedMessage::Heartbeat => self.reset_receive_heartbeat(core, poll),
+        }
+    }
+
+    /// Sends user data, which the peer acknowledges.
+    pub fn write_tracked(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        data: Vec<u8>,
+        priority: Priority,
+        msg_id: MsgId,
+    ) {
+        self.write_data(core, poll, data, priority, Some(msg_id));
+    }
+
+    /// Messages larger than `MAX_FRAGMENT_SIZE` are split into fragments. All of them are handed
+    /// to the connection to the relay at once, which sends them in the order of their priority.
+    fn write_data(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        data: Vec<u8>,
+        priority: Priority,
+        tracked: Option<MsgId>,
+    ) {
+        if data.len() > fragmentation::max_message_size(core) {
+            let _ = self
+                .event_tx
+                .send(Event::WriteMsgSizeProhibitive(self.their_id, data));
+            if let Some(msg_id) = tracked {
+                let _ = self
+                    .event_tx
+                    .send(Event::MessageFailed(self.their_id, vec![msg_id]));
+            }
+            return;
+        }
+
+        self.stats.record_sent(priority, data.len());
+        let fragmented = data.len() > MAX_FRAGMENT_SIZE;
+        let msg = match tracked {
+            Some(msg_id) => {
+                let _ = self.unacked.insert(msg_id);
+                RelayedMessage::TrackedData(msg_id, data)
+            }
+            None => RelayedMessage::Data(data),
+        };
+        if fragmented {
+            match serialise(&msg) {
+                Ok(data) => self.fragmenter.push(data, priority, tracked),
+                Err(e) => {
+                    debug!("{:?} - Failed to serialise message: {:?}", self.our_id, e);
+                    return self.terminate(core, poll);
+                }
+            }
+            while let Some((fragment, priority, _)) = self.fragmenter.next_fragment() {
+                let fragment = match fragment {
+                    Message::DataFragment(msg_id, index, count, data) => {
+                        RelayedMessage::DataFragment(msg_id, index, count, data)
+                    }
+                    msg => {
+                        debug!("{:?} - Unexpected fragment: {:?}", self.our_id, msg);
+                        continue;
+                    }
+                };
+                if !self.write(core, poll, &fragment, priority) {
+                    return;
+                }
+            }
+        } else if !self.write(core, poll, &msg, priority) {
+            return;
+        }
+
+        // Had we lost the relay, the message would have been reported failed already.
+        if let Some(msg_id) = tracked {
+            let _ = self
+                .event_tx
+                .send(Event::MessageSent(self.their_id, msg_id));
+        }
+    }
+
+    pub fn stats(&self) -> PeerStats {
+        self.stats.snapshot(0)
+    }
+
+    fn send(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        msg: &RelayedMessage,
+        priority: Priority,
+    ) -> bool {
+        match self.session.seal(msg) {
+            Ok(payload) => {
+                let msg = Message::RelayRequest(self.their_id, payload);
+                send_direct(core, poll, &self.relay_id, msg, priority)
+            }
+            Err(e) => {
+                debug!(
+                    "{:?} - Failed to encrypt relayed message: {}",
+                    self.our_id, e
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns `false` if the connection was terminated.
+    fn write(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        msg: &RelayedMessage,
+        priority: Priority,
+    ) -> bool {
+        if !self.send(core, poll, msg, priority) {
+            debug!("{:?} - Lost relay {:?}", self.our_id, self.relay_id);
+            self.terminate(core, poll);
+            return false;
+        }
+        self.stats.touch_sent();
+        if let Err(e) = self.heartbeat.reset_send(core) {
+            debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
+            self.terminate(core, poll);
+            return false;
+        }
+        true
+    }
+
+    fn reset_receive_heartbeat(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.stats.touch_received();
+        if let Err(e) = self.heartbeat.reset_receive(core) {
+            debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
+            self.terminate(core, poll);
+        }
+    }
+}
+
+impl State<CrustData> for RelayedConnection {
+    fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, data: Vec<u8>, priority: Priority) {
+        self.write_data(core, poll, data, priority, None);
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.heartbeat.terminate(core);
+        let _ = core.remove_state(self.token);
+        let _ = self.send(core, poll, &RelayedMessage::Disconnect, 0);
+
+        let connections = &mut core.user_data_mut().connections;
+        if let Entry::Occupied(mut oe) = connections.entry(self.their_id) {
+            if oe.get().active_connection == Some(self.token) {
+                oe.get_mut().active_connection = None;
+            }
+            if oe.get().currently_handshaking == 0 && oe.get().active_connection.is_none() {
+                let _ = oe.remove();
+            }
+        }
+
+        if !self.unacked.is_empty() {
+            let failed = mem::replace(&mut self.unacked, BTreeSet::new());
+            let _ = self.event_tx.send(Event::MessageFailed(
+                self.their_id,
+                failed.into_iter().collect(),
+            ));
+        }
+        let _ = self.event_tx.send(Event::LostPeer(self.their_id));
+    }
+
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, timer_id: u8) {
+        match self.heartbeat.timeout(core, timer_id) {
+            HeartbeatAction::Send => {
+                let _ = self.write(core, poll, &RelayedMessage::Heartbeat, 0);
+            }
+            HeartbeatAction::Terminate => {
+                debug!(
+                    "Dropping relayed connection to {:?} due to peer inactivity",
+                    self.their_id
+                );
+                self.terminate(core, poll);
+            }
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Encrypts the messages of a relayed connection with the session key. Each message carries a
+/// counter, so that the relay can't replay them.
+struct Session {
+    key: SharedSecretKey,
+    next_sent: u64,
+    next_received: u64,
+}
+
+impl Session {
+    fn new(key: SharedSecretKey) -> Self {
+        Session {
+            key,
+            next_sent: 0,
+            next_received: 0,
+        }
+    }
+
+    fn seal(&mut self, msg: &RelayedMessage) -> Result<Vec<u8>, safe_crypto::Error> {
+        let payload = self.key.encrypt(&(self.next_sent, msg))?;
+        self.next_sent += 1;
+        Ok(payload)
+    }
+
+    /// Returns `None` if the payload can't be decrypted or is older than the last one opened.
+    fn open(&mut self, payload: &[u8]) -> Option<RelayedMessage> {
+        let (counter, msg): (u64, RelayedMessage) = self.key.decrypt(payload).ok()?;
+        if counter < self.next_received {
+            return None;
+        }
+        self.next_received = counter + 1;
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_pair() -> (Session, Session) {
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let (their_pk, their_sk) = gen_encrypt_keypair();
+        (
+            Session::new(our_sk.shared_secret(&their_pk)),
+            Session::new(their_sk.shared_secret(&our_pk)),
+        )
+    }
+
+    #[test]
+    fn relayed_messages_are_readable_only_by_the_peers() {
+        let (mut ours, mut theirs) = session_pair();
+        let (_, relay_sk) = gen_encrypt_keypair();
+        let (their_pk, _) = gen_encrypt_keypair();
+        let mut relays = Session::new(relay_sk.shared_secret(&their_pk));
+
+        let payload = unwrap!(ours.seal(&RelayedMessage::Data(vec![1, 2, 3])));
+
+        assert!(relays.open(&payload).is_none());
+        match theirs.open(&payload) {
+            Some(RelayedMessage::Data(data)) => assert_eq!(data, vec![1, 2, 3]),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn replayed_relayed_messages_are_dropped() {
+        let (mut ours, mut theirs) = session_pair();
+
+        let first = unwrap!(ours.seal(&RelayedMessage::Heartbeat));
+        let second = unwrap!(ours.seal(&RelayedMessage::Data(vec![1])));
+
+        assert!(theirs.open(&first).is_some());
+        assert!(theirs.open(&second).is_some());
+        assert!(theirs.open(&first).is_none());
+        assert!(theirs.open(&second).is_none());
+    }
+
+    mod relayed_connection {
+        use super::*;
+        use crate::tests::utils::{
+            get_event_sender, rand_peer_id_and_enc_sk, test_bootstrap_cache, test_core,
+        };
+
+        fn start(
+            core: &mut EventLoopCore,
+            their_id: PeerId,
+        ) -> Option<Rc<RefCell<RelayedConnection>>> {
+            let (relay_id, _) = rand_peer_id_and_enc_sk();
+            let (our_id, _) = rand_peer_id_and_enc_sk();
+            let (event_tx, _event_rx) = get_event_sender();
+            let (session, _) = session_pair();
+            RelayedConnection::start(core, relay_id, our_id, their_id, session.key, event_tx)
+        }
+
+        #[test]
+        fn leaves_handshake_count_of_direct_attempts_alone() {
+            let mut core = test_core(test_bootstrap_cache());
+            let (their_id, _) = rand_peer_id_and_enc_sk();
+            let _ = core.user_data_mut().connections.insert(
+                their_id,
+                ConnectionId {
+                    active_connection: None,
+                    currently_handshaking: 1,
+                },
+            );
+
+            let conn = unwrap!(start(&mut core, their_id));
+
+            let conn_id = unwrap!(core.user_data().connections.get(&their_id));
+            assert_eq!(conn_id.currently_handshaking, 1);
+            assert_eq!(conn_id.active_connection, Some(conn.borrow().token));
+        }
+
+        #[test]
+        fn is_refused_while_connected_directly() {
+            let mut core = test_core(test_bootstrap_cache());
+            let (their_id, _) = rand_peer_id_and_enc_sk();
+            let direct = Token(9999);
+            let _ = core.user_data_mut().connections.insert(
+                their_id,
+                ConnectionId {
+                    active_connection: Some(direct),
+                    currently_handshaking: 0,
+                },
+            );
+
+            assert!(start(&mut core, their_id).is_none());
+
+            let conn_id = unwrap!(core.user_data().connections.get(&their_id));
+            assert_eq!(conn_id.active_connection, Some(direct));
+        }
+    }
+}
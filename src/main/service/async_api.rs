@@ -0,0 +1,324 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::{write_tracked, Service};
+use crate::common::CrustUser;
+use crate::main::config_handler::{self, Config};
+use crate::main::{CrustError, Event, MsgId, PeerId, PrivConnectionInfo, PubConnectionInfo};
+use futures::channel::{mpsc as futures_mpsc, oneshot};
+use futures::Stream;
+use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
+use safe_crypto::{SecretEncryptKey, SecretSignKey};
+use socket_collection::Priority;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+
+/// Futures based facade over [`Service`], available with the `async-api` feature.
+///
+/// `connect`, `send` and `bootstrap` return futures which resolve when the operation completes,
+/// instead of blocking the calling thread or leaving the caller to match the outcome in the
+/// event channel. Events are delivered through the [`EventStream`] returned by the
+/// constructors.
+///
+/// Methods which have no async counterpart are reachable through [`service`] and
+/// [`service_mut`].
+///
+/// [`Service`]: struct.Service.html
+/// [`EventStream`]: struct.EventStream.html
+/// [`service`]: struct.AsyncService.html#method.service
+/// [`service_mut`]: struct.AsyncService.html#method.service_mut
+pub struct AsyncService {
+    service: Service,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl AsyncService {
+    /// Constructs a service reading the config file from the default location. See
+    /// `Service::try_new`.
+    pub fn try_new(
+        our_uid: PeerId,
+        our_sk: SecretEncryptKey,
+        our_sign_sk: SecretSignKey,
+    ) -> crate::Res<(Self, EventStream)> {
+        Self::with_config(
+            config_handler::read_config_file()?,
+            our_uid,
+            our_sk,
+            our_sign_sk,
+        )
+    }
+
+    /// Constructs a service with the given config. See `Service::with_config`.
+    pub fn with_config(
+        config: Config,
+        our_uid: PeerId,
+        our_sk: SecretEncryptKey,
+        our_sign_sk: SecretSignKey,
+    ) -> crate::Res<(Self, EventStream)> {
+        let (event_tx, event_rx) = mpsc::channel();
+        let (category_tx, category_rx) = mpsc::channel();
+        let event_tx = MaidSafeObserver::new(event_tx, MaidSafeEventCategory::Crust, category_tx);
+        let (stream_tx, stream_rx) = futures_mpsc::unbounded();
+        let pending = Arc::new(Mutex::new(Pending::default()));
+
+        let service = Service::with_config(event_tx, config, our_uid, our_sk, our_sign_sk)?;
+
+        // Exits when the service is dropped, which drops all event senders. The observer sends
+        // the event before its category, so the event is there once the category is received.
+        let pending_clone = pending.clone();
+        let _ = thread::Builder::new()
+            .name("CrustEventDispatcher".to_string())
+            .spawn(move || {
+                for _ in category_rx.iter() {
+                    let event = match event_rx.try_recv() {
+                        Ok(event) => event,
+                        Err(_) => continue,
+                    };
+                    let event = unwrap!(pending_clone.lock()).resolve(event);
+                    let _ = stream_tx.unbounded_send(event);
+                }
+            })?;
+
+        Ok((
+            AsyncService { service, pending },
+            EventStream { rx: stream_rx },
+        ))
+    }
+
+    /// The underlying service.
+    pub fn service(&self) -> &Service {
+        &self.service
+    }
+
+    /// The underlying service, e.g. to start listening.
+    pub fn service_mut(&mut self) -> &mut Service {
+        &mut self.service
+    }
+
+    /// Generates connection info. Unlike `Service::prepare_connection_info`, the result is not
+    /// emitted as an event.
+    pub async fn prepare_connection_info(&self) -> crate::Res<PrivConnectionInfo> {
+        let (tx, rx) = oneshot::channel();
+        self.service.prepare_connection_info_then(move |result| {
+            let _ = tx.send(result);
+        })?;
+        rx.await.map_err(|_| CrustError::RequestCancelled)?
+    }
+
+    /// Connects to a peer, see `Service::connect`. Resolves once the connection, either direct or
+    /// relayed, succeeds or fails. If we're already connected to the peer, resolves right away.
+    pub async fn connect(
+        &self,
+        our_ci: PrivConnectionInfo,
+        their_ci: PubConnectionInfo,
+    ) -> crate::Res<()> {
+        let their_id = their_ci.id;
+        let (tx, rx) = oneshot::channel();
+        // Register before connecting, so that the outcome can't slip by.
+        unwrap!(self.pending.lock())
+            .connects
+            .entry(their_id)
+            .or_insert_with(Vec::new)
+            .push(tx);
+
+        let pending = self.pending.clone();
+        let res = self
+            .service
+            .connect_then(our_ci, their_ci, move |connected| {
+                // Otherwise the connection attempt in progress resolves us.
+                if connected {
+                    unwrap!(pending.lock()).resolve_connect(&their_id, || Ok(()));
+                }
+            });
+        if let Err(e) = res {
+            let _ = unwrap!(self.pending.lock()).connects.remove(&their_id);
+            return Err(e);
+        }
+
+        rx.await.map_err(|_| CrustError::RequestCancelled)?
+    }
+
+    /// Starts bootstrapping, see `Service::start_bootstrap`. Resolves with the ID of the peer we
+    /// bootstrapped off, or fails if bootstrapping does. If bootstrapping is in progress already,
+    /// resolves with its outcome.
+    pub async fn bootstrap(
+        &mut self,
+        blacklist: HashSet<SocketAddr>,
+        crust_user: CrustUser,
+    ) -> crate::Res<PeerId> {
+        let (tx, rx) = oneshot::channel();
+        unwrap!(self.pending.lock()).bootstraps.push(tx);
+        if let Err(e) = self.service.start_bootstrap(blacklist, crust_user) {
+            let _ = unwrap!(self.pending.lock()).bootstraps.pop();
+            return Err(e);
+        }
+        rx.await.map_err(|_| CrustError::RequestCancelled)?
+    }
+
+    /// Sends data to a peer, tracked like `Service::send_tracked`. Resolves once the peer
+    /// acknowledges the data, or fails with `CrustError::DeliveryFailed` if the message is
+    /// reported by `Event::MessageFailed`.
+    pub async fn send(&self, peer_id: &PeerId, msg: Vec<u8>, priority: Priority) -> crate::Res<()> {
+        let peer_id = *peer_id;
+        let (tx, rx) = oneshot::channel();
+        let pending = self.pending.clone();
+        self.service.post(move |core, poll| {
+            // Hold the lock while writing, so that the dispatcher can't pass the outcome by before
+            // the request is registered.
+            let mut pending = unwrap!(pending.lock());
+            match write_tracked(core, poll, &peer_id, msg, priority) {
+                Ok(msg_id) => {
+                    let _ = pending.sends.insert((peer_id, msg_id), tx);
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                }
+            }
+        })?;
+        rx.await.map_err(|_| CrustError::RequestCancelled)?
+    }
+}
+
+/// A stream of all events emitted by an [`AsyncService`]. It ends when the service is dropped.
+///
+/// [`AsyncService`]: struct.AsyncService.html
+pub struct EventStream {
+    rx: futures_mpsc::UnboundedReceiver<Event>,
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+/// Requests waiting for an event.
+#[derive(Default)]
+struct Pending {
+    connects: HashMap<PeerId, Vec<oneshot::Sender<crate::Res<()>>>>,
+    bootstraps: Vec<oneshot::Sender<crate::Res<PeerId>>>,
+    sends: HashMap<(PeerId, MsgId), oneshot::Sender<crate::Res<()>>>,
+}
+
+impl Pending {
+    /// Completes the requests waiting for the given event. Returns the event so that it can be
+    /// passed on to the stream.
+    fn resolve(&mut self, event: Event) -> Event {
+        match event {
+            Event::ConnectSuccess(id) | Event::RelayConnectSuccess(id, _) => {
+                self.resolve_connect(&id, || Ok(()))
+            }
+            Event::ConnectFailure(id, _) => {
+                self.resolve_connect(&id, || Err(CrustError::ConnectFailed))
+            }
+            Event::BootstrapConnect(id, _) => {
+                for tx in self.bootstraps.drain(..) {
+                    let _ = tx.send(Ok(id));
+                }
+            }
+            Event::BootstrapFailed(_) => {
+                for tx in self.bootstraps.drain(..) {
+                    let _ = tx.send(Err(CrustError::BootstrapFailed));
+                }
+            }
+            Event::MessageDelivered(id, msg_id) => {
+                if let Some(tx) = self.sends.remove(&(id, msg_id)) {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            Event::MessageFailed(id, ref msg_ids) => {
+                for msg_id in msg_ids {
+                    if let Some(tx) = self.sends.remove(&(id, *msg_id)) {
+                        let _ = tx.send(Err(CrustError::DeliveryFailed));
+                    }
+                }
+            }
+            _ => (),
+        }
+        event
+    }
+
+    fn resolve_connect<F>(&mut self, their_id: &PeerId, result: F)
+    where
+        F: Fn() -> crate::Res<()>,
+    {
+        for tx in self.connects.remove(their_id).unwrap_or_default() {
+            let _ = tx.send(result());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{gen_config, rand_peer_id_and_enc_sk, rand_peer_id_and_keys};
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    fn async_service() -> (AsyncService, EventStream) {
+        let (our_uid, our_sk, our_sign_sk) = rand_peer_id_and_keys();
+        unwrap!(AsyncService::with_config(
+            gen_config(),
+            our_uid,
+            our_sk,
+            our_sign_sk
+        ))
+    }
+
+    #[test]
+    fn connect_and_send_resolve() {
+        let (mut service1, mut events1) = async_service();
+        let (service2, _events2) = async_service();
+
+        unwrap!(service1.service_mut().start_listening_tcp());
+        match block_on(events1.next()) {
+            Some(Event::ListenerStarted(_)) => (),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        unwrap!(service1.service().set_ext_reachability_test(false));
+
+        let ci1 = unwrap!(block_on(service1.prepare_connection_info()));
+        let ci2 = unwrap!(block_on(service2.prepare_connection_info()));
+        let pub_ci1 = ci1.to_pub_connection_info();
+        let id1 = service1.service().id();
+        let id2 = service2.service().id();
+
+        unwrap!(block_on(service2.connect(ci2, pub_ci1)));
+
+        unwrap!(block_on(service2.send(&id1, vec![1, 2, 3], 0)));
+        loop {
+            match block_on(events1.next()) {
+                Some(Event::NewMessage(id, _, data)) => {
+                    assert_eq!(id, id2);
+                    assert_eq!(data, vec![1, 2, 3]);
+                    break;
+                }
+                Some(_) => (),
+                None => panic!("Event stream ended"),
+            }
+        }
+    }
+
+    #[test]
+    fn send_to_unknown_peer_fails() {
+        let (service, _events) = async_service();
+        let (peer_id, _) = rand_peer_id_and_enc_sk();
+
+        match block_on(service.send(&peer_id, vec![1], 0)) {
+            Err(CrustError::PeerNotFound) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
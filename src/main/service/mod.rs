@@ -8,27 +8,43 @@
 // Software.
 
 use crate::common::{
-    self, BootstrapperRole, CoreMessage, CrustUser, NameHash, PeerInfo, HASH_SIZE,
+    self, BootstrapperRole, CoreMessage, CrustUser, NameHash, PeerInfo, Transport, HASH_SIZE,
 };
+use crate::main::ban::{self, BanList};
 use crate::main::bootstrap;
 use crate::main::config_handler::{self, Config};
+use crate::main::reconnect::{self, ReconnectTarget};
+use crate::main::relay;
 use crate::main::{
-    ActiveConnection, Bootstrap, ConfigRefresher, ConfigWrapper, Connect, ConnectionId,
-    ConnectionInfoResult, ConnectionListener, CrustData, CrustError, Event, EventLoop,
-    EventLoopCore, EventToken, PeerId, PrivConnectionInfo, PubConnectionInfo,
+    ActiveConnection, BanTarget, Bootstrap, ConfigRefresher, ConfigWrapper, Connect,
+    ConnectFailureReason, ConnectionId, ConnectionInfoResult, ConnectionListener, CrustData,
+    CrustError, Event, EventLoop, EventLoopCore, EventToken, HandshakeStats, MsgId, PeerExchange,
+    PeerId, PeerStats, PrivConnectionInfo, PubConnectionInfo, ReconnectSupervisor, Relay,
+    RelayedConnection,
+};
+use crate::nat::{
+    ip_addr_is_global, new_reusably_bound_tcp_socket, MappedTcpSocket, MappedUdpSocket,
+    MappingContext,
 };
-use crate::nat::{ip_addr_is_global, MappedTcpSocket, MappingContext};
 use crate::service_discovery::ServiceDiscovery;
-use mio::Poll;
-use safe_crypto::{self, PublicEncryptKey, SecretEncryptKey};
+use mio::{Poll, Token};
+use net2::TcpBuilder;
+use safe_crypto::{self, PublicEncryptKey, SecretEncryptKey, SecretSignKey};
 use socket_collection::Priority;
+use std::cell::{Cell, RefCell};
 use std::collections::HashSet;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::rc::Rc;
 use std::sync::{mpsc, Arc};
+use std::time::Duration;
 
-const SERVICE_DISCOVERY_DEFAULT_PORT: u16 = 5484;
+#[cfg(feature = "async-api")]
+mod async_api;
 
-const DISABLE_NAT: bool = true;
+#[cfg(feature = "async-api")]
+pub use self::async_api::{AsyncService, EventStream};
+
+const SERVICE_DISCOVERY_DEFAULT_PORT: u16 = 5484;
 
 /// A structure representing all the Crust services. This is the main object through which crust is
 /// used.
@@ -98,16 +114,20 @@ impl Service {
     /// - `our_uid` is peer ID which contains public encryption key as well.
     /// - `our_sk` is secret encryption key. This key with the combination of remote peer's public
     ///    key is used to encrypt traffic between two peers.
+    /// - `our_sign_sk` is secret signing key matching `our_uid.pub_sign_key`. We sign handshakes
+    ///    with it, so that peers can trust our whole `PeerId`.
     pub fn try_new(
         event_tx: crate::CrustEventSender,
         our_uid: PeerId,
         our_sk: SecretEncryptKey,
+        our_sign_sk: SecretSignKey,
     ) -> crate::Res<Self> {
         Service::with_config(
             event_tx,
             config_handler::read_config_file()?,
             our_uid,
             our_sk,
+            our_sign_sk,
         )
     }
 
@@ -119,8 +139,10 @@ impl Service {
         config: Config,
         our_uid: PeerId,
         our_sk: SecretEncryptKey,
+        our_sign_sk: SecretSignKey,
     ) -> crate::Res<Self> {
         safe_crypto::init()?;
+        config.timeouts.validate()?;
 
         let name_hash = name_hash(&config.network_name);
 
@@ -134,7 +156,10 @@ impl Service {
             move || {
                 let mut cache = bootstrap::Cache::new(bootstrap_cache_cfg);
                 cache.read_file();
-                let mut user_data = CrustData::new(cache);
+                let mut user_data = CrustData::new(cache, our_sign_sk);
+                user_data.nat_traversal = config.nat_traversal;
+                user_data.bans = BanList::new(config.bans.clone());
+                user_data.bans.read_file();
                 user_data.config = ConfigWrapper::new(config);
                 user_data
             },
@@ -151,6 +176,9 @@ impl Service {
         };
         service.start_config_refresher()?;
         service.start_bootstrap_cache_validator()?;
+        service.start_reconnect_supervisor()?;
+        service.start_relay()?;
+        service.start_peer_exchange()?;
 
         Ok(service)
     }
@@ -332,7 +360,6 @@ impl Service {
     /// Check if we have peers on LAN
     pub fn has_peers_on_lan(&self) -> bool {
         use std::thread;
-        use std::time::Duration;
 
         let (obs, rx) = mpsc::channel();
         let _ = self.post(move |core, _| {
@@ -357,6 +384,15 @@ impl Service {
         rx.try_recv().is_ok()
     }
 
+    /// Enables/disables NAT traversal when preparing connection info. When enabled, we ask our
+    /// router via IGD and hard coded contacts for our external address, so that peers outside
+    /// of our local network can hole punch a connection to us. Overrides `Config::nat_traversal`.
+    pub fn set_nat_traversal(&self, enable: bool) -> crate::Res<()> {
+        self.post(move |core, _| {
+            core.user_data_mut().nat_traversal = enable;
+        })
+    }
+
     /// Start the bootstrapping procedure. It will auto terminate after indicating success or
     /// failure via the event channel.
     pub fn start_bootstrap(
@@ -388,7 +424,7 @@ impl Service {
                     &our_sk,
                 ) {
                     error!("Could not bootstrap: {:?}", e);
-                    let _ = event_tx.send(Event::BootstrapFailed);
+                    let _ = event_tx.send(Event::BootstrapFailed(Vec::new()));
                 }
             }
         })
@@ -453,8 +489,36 @@ impl Service {
     pub fn connect(
         &self,
         our_ci: PrivConnectionInfo,
-        mut their_ci: PubConnectionInfo,
+        their_ci: PubConnectionInfo,
+    ) -> crate::Res<()> {
+        self.connect_then(our_ci, their_ci, |_| ())
+    }
+
+    /// Same as `connect`, but only attempts the given transports instead of those in `our_ci`.
+    /// The choice also applies to reconnecting. To have the peer connect over the same
+    /// transports, set `PrivConnectionInfo::transports` before sharing the connection info.
+    pub fn connect_over(
+        &self,
+        mut our_ci: PrivConnectionInfo,
+        their_ci: PubConnectionInfo,
+        transports: &[Transport],
     ) -> crate::Res<()> {
+        our_ci.transports = transports.to_vec();
+        self.connect(our_ci, their_ci)
+    }
+
+    /// Same as `connect`, but if no connection attempt is started because we're already connected
+    /// or connecting to the peer, `skipped` is called on the event loop with a flag telling
+    /// whether we're connected.
+    fn connect_then<F>(
+        &self,
+        our_ci: PrivConnectionInfo,
+        mut their_ci: PubConnectionInfo,
+        skipped: F,
+    ) -> crate::Res<()>
+    where
+        F: FnOnce(bool) + Send + 'static,
+    {
         if their_ci.id == self.our_uid {
             debug!(
                 "Requested connect to {:?}, which is our peer ID",
@@ -468,23 +532,51 @@ impl Service {
         let our_sk = self.our_sk.clone();
 
         self.post(move |core, poll| {
-            if let Some(ref whitelisted_node_ips) = core.user_data().config.cfg.whitelisted_node_ips
             {
-                let their_direct = their_ci
-                    .for_direct
-                    .drain(..)
-                    .filter(|s| whitelisted_node_ips.contains(&s.ip()))
-                    .collect();
-                their_ci.for_direct = their_direct;
+                let config = &core.user_data().config.cfg;
+                let their_pk = their_ci.id.pub_enc_key;
+                let had_addrs =
+                    !their_ci.for_direct.is_empty() || !their_ci.for_hole_punch.is_empty();
+                let is_whitelisted = |addr: &SocketAddr| {
+                    config.is_whitelisted(CrustUser::Node, addr.ip(), &their_pk)
+                };
+                their_ci.for_direct.retain(&is_whitelisted);
+                their_ci.for_hole_punch.retain(&is_whitelisted);
+                if had_addrs && their_ci.for_direct.is_empty() && their_ci.for_hole_punch.is_empty()
+                {
+                    debug!("None of {:?}'s addresses is whitelisted", their_ci.id);
+                    let reason = ConnectFailureReason::NotWhitelisted;
+                    let _ = event_tx.send(Event::ConnectFailure(their_ci.id, reason));
+                    return;
+                }
+            }
+
+            {
+                let bans = &core.user_data().bans;
+                if bans.is_peer_banned(&their_ci.id, None) {
+                    debug!("Refusing to connect to banned peer {:?}", their_ci.id);
+                    let reason = ConnectFailureReason::Banned;
+                    let _ = event_tx.send(Event::ConnectFailure(their_ci.id, reason));
+                    return;
+                }
+                let is_allowed = |addr: &SocketAddr| !bans.is_banned(&BanTarget::Ip(addr.ip()));
+                their_ci.for_direct.retain(&is_allowed);
+                their_ci.for_hole_punch.retain(&is_allowed);
             }
 
-            if core.user_data().connections.contains_key(&their_ci.id) {
+            if let Some(conn_id) = core.user_data().connections.get(&their_ci.id) {
                 debug!(
                     "Already connected OR already in process of connecting to {:?}",
                     their_ci.id
                 );
+                skipped(conn_id.active_connection.is_some());
                 return;
             }
+            reconnect::remember_peer(
+                core,
+                their_ci.id,
+                ReconnectTarget::Connect(their_ci.clone(), our_ci.transports.clone()),
+            );
             let _ = Connect::start(
                 core,
                 poll,
@@ -494,18 +586,38 @@ impl Service {
                 event_tx,
                 &our_sk,
                 our_global_listener_addrs(core),
+                None,
             );
         })?;
 
         Ok(())
     }
 
+    /// Bans the peer or IP address for the given duration. Connections to it are dropped right
+    /// away, its handshakes are rejected and it's excluded from bootstrapping. The ban list is
+    /// written to disk if `BanConfig::persist` is set.
+    pub fn ban_peer<T: Into<BanTarget>>(&self, target: T, duration: Duration) -> crate::Res<()> {
+        let target = target.into();
+        self.post(move |core, poll| ban::ban(core, poll, target, duration))
+    }
+
+    /// Lifts the ban and returns whether the peer or IP address was banned.
+    pub fn unban_peer<T: Into<BanTarget>>(&self, target: T) -> crate::Res<bool> {
+        let target = target.into();
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let _ = tx.send(core.user_data_mut().bans.unban(&target));
+        })?;
+        Ok(rx.recv()?)
+    }
+
     /// Disconnect from the given peer and returns whether there was a connection at all.
     pub fn disconnect(&self, peer_uid: &PeerId) -> bool {
         let peer_uid = *peer_uid;
         let (tx, rx) = mpsc::channel();
 
         let _ = self.post(move |core, poll| {
+            reconnect::forget_peer(core, poll, &peer_uid);
             if let Some(&ConnectionId {
                 active_connection: Some(token),
                 ..
@@ -525,10 +637,29 @@ impl Service {
 
     /// Send data to a peer.
     pub fn send(&self, peer_uid: &PeerId, msg: Vec<u8>, priority: Priority) -> crate::Res<()> {
-        let peer_uid = *peer_uid;
         let (tx, rx) = mpsc::channel();
+        self.send_then(peer_uid, msg, priority, move |res| {
+            let _ = tx.send(res);
+        })?;
+        rx.recv()
+            .map_err(CrustError::ChannelRecv)
+            .and_then(|res| res)
+    }
 
-        let res = self.post(move |core, poll| {
+    /// Same as `send`, but instead of blocking, `done` is called on the event loop once the data
+    /// is queued to the peer connection.
+    fn send_then<F>(
+        &self,
+        peer_uid: &PeerId,
+        msg: Vec<u8>,
+        priority: Priority,
+        done: F,
+    ) -> crate::Res<()>
+    where
+        F: FnOnce(crate::Res<()>) + Send + 'static,
+    {
+        let peer_uid = *peer_uid;
+        self.post(move |core, poll| {
             if let Some(&ConnectionId {
                 active_connection: Some(token),
                 ..
@@ -537,85 +668,43 @@ impl Service {
                 if let Some(state) = core.get_state(token) {
                     state.borrow_mut().write(core, poll, msg, priority);
                 }
-                let _ = tx.send(Ok(()));
+                done(Ok(()));
             } else {
-                let _ = tx.send(Err(CrustError::PeerNotFound));
+                done(Err(CrustError::PeerNotFound));
             }
-        });
-        res.and_then(|_| {
-            rx.recv()
-                .map_err(CrustError::ChannelRecv)
-                .and_then(|res| res)
         })
     }
 
+    /// Send data to a peer and track its delivery. Returns the message ID which is then reported
+    /// by `Event::MessageSent` once the data is written to the socket and by
+    /// `Event::MessageDelivered` once the peer acknowledges it. If the connection is lost before
+    /// that, the ID is reported by `Event::MessageFailed`.
+    pub fn send_tracked(
+        &self,
+        peer_uid: &PeerId,
+        msg: Vec<u8>,
+        priority: Priority,
+    ) -> crate::Res<MsgId> {
+        let peer_uid = *peer_uid;
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, poll| {
+            let _ = tx.send(write_tracked(core, poll, &peer_uid, msg, priority));
+        })?;
+        rx.recv()?
+    }
+
     /// Generate connection info. The connection info is returned via the `ConnectionInfoPrepared`
     /// event on the event channel. Calling this method is the first step of connecting to another
     /// peer, see `Service::connect` for more info.
     // TODO: immediate return in case of sender.send() returned with NotificationError
     pub fn prepare_connection_info(&self, result_token: u32) {
-        let our_sk = self.our_sk.clone();
-        let our_uid = self.our_uid;
         let event_tx = self.event_tx.clone();
-
-        let post_res = if DISABLE_NAT {
-            self.post(move |core, _poll| {
-                let our_listeners = core
-                    .user_data()
-                    .our_listeners
-                    .iter()
-                    .map(|peer| peer.addr)
-                    .collect();
-                let event = Event::ConnectionInfoPrepared(ConnectionInfoResult {
-                    result_token,
-                    result: Ok(PrivConnectionInfo {
-                        id: our_uid,
-                        for_direct: our_listeners,
-                    }),
-                });
-                let _ = event_tx.send(event);
-            })
-        } else {
-            let mc = self.mc.clone();
-            self.post(move |core, poll| {
-                let our_listeners = core
-                    .user_data()
-                    .our_listeners
-                    .iter()
-                    .map(|peer| peer.addr)
-                    .collect();
-                let event_tx_clone = event_tx.clone();
-                match MappedTcpSocket::start(
-                    core,
-                    poll,
-                    0,
-                    &mc,
-                    our_uid.pub_enc_key,
-                    &our_sk,
-                    move |_, _, _socket, _addrs| {
-                        let event_tx = event_tx_clone;
-                        let event = Event::ConnectionInfoPrepared(ConnectionInfoResult {
-                            result_token,
-                            result: Ok(PrivConnectionInfo {
-                                id: our_uid,
-                                for_direct: our_listeners,
-                            }),
-                        });
-                        let _ = event_tx.send(event);
-                    },
-                ) {
-                    Ok(()) => (),
-                    Err(e) => {
-                        debug!("Error mapping tcp socket: {}", e);
-                        let _ =
-                            event_tx.send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
-                                result_token,
-                                result: Err(From::from(e)),
-                            }));
-                    }
-                };
-            })
-        };
+        let post_res = self.prepare_connection_info_then(move |result| {
+            let _ = event_tx.send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
+                result_token,
+                result,
+            }));
+        });
         if let Err(e) = post_res {
             let _ = self
                 .event_tx
@@ -626,6 +715,130 @@ impl Service {
         }
     }
 
+    /// Same as `prepare_connection_info`, but the result is handed to `done` on the event loop
+    /// instead of being sent as an event.
+    fn prepare_connection_info_then<F>(&self, done: F) -> crate::Res<()>
+    where
+        F: FnOnce(crate::Res<PrivConnectionInfo>) + Send + 'static,
+    {
+        let our_sk = self.our_sk.clone();
+        let our_uid = self.our_uid;
+
+        let mc = self.mc.clone();
+        self.post(move |core, poll| {
+            let our_listeners: Vec<_> = core
+                .user_data()
+                .our_listeners
+                .iter()
+                .map(|peer| peer.addr)
+                .collect();
+            let relays = relay::relay_candidates(core);
+            let transports = core.user_data().our_transports();
+
+            // UDP hole punching works without a UDP listener, so it only depends on the config.
+            let map_udp = core
+                .user_data()
+                .config
+                .cfg
+                .transports()
+                .contains(&Transport::Udp);
+
+            if !core.user_data().nat_traversal {
+                let result = local_hole_punch_socket(&mc).and_then(|(socket, addrs)| {
+                    let mut transports = transports;
+                    let (udp_socket, udp_addrs) = if map_udp {
+                        let (udp_socket, udp_addrs) = local_udp_hole_punch_socket(&mc)?;
+                        add_transport(&mut transports, Transport::Udp);
+                        (Some(udp_socket), udp_addrs)
+                    } else {
+                        (None, Vec::new())
+                    };
+                    Ok(PrivConnectionInfo {
+                        id: our_uid,
+                        for_direct: our_listeners,
+                        for_hole_punch: addrs,
+                        hole_punch_socket: Some(socket),
+                        for_udp_hole_punch: udp_addrs,
+                        udp_hole_punch_socket: udp_socket,
+                        mapping_methods: HashSet::new(),
+                        relays,
+                        transports,
+                    })
+                });
+                done(result);
+                return;
+            }
+
+            // Mapping can fail either right away or asynchronously, whichever happens gets `done`.
+            // TCP and UDP sockets are mapped at the same time, the info is done once both are.
+            let done = Rc::new(Cell::new(Some(done)));
+            let mapping = Rc::new(RefCell::new(Mapping {
+                pending: if map_udp { 2 } else { 1 },
+                info: Some(PrivConnectionInfo {
+                    id: our_uid,
+                    for_direct: our_listeners,
+                    for_hole_punch: Vec::new(),
+                    hole_punch_socket: None,
+                    for_udp_hole_punch: Vec::new(),
+                    udp_hole_punch_socket: None,
+                    mapping_methods: HashSet::new(),
+                    relays,
+                    transports,
+                }),
+            }));
+            let (done_clone, mapping_clone) = (done.clone(), mapping.clone());
+            match MappedTcpSocket::start(
+                core,
+                poll,
+                0,
+                &mc,
+                our_uid.pub_enc_key,
+                &our_sk,
+                move |_, _, socket, addrs, mapping_methods| {
+                    if let Some(ref mut info) = mapping_clone.borrow_mut().info {
+                        info.for_hole_punch = addrs;
+                        info.hole_punch_socket = Some(socket);
+                        info.mapping_methods.extend(mapping_methods);
+                    }
+                    mapping_done(&mapping_clone, &done_clone);
+                },
+            ) {
+                Ok(()) => (),
+                Err(e) => {
+                    debug!("Error mapping tcp socket: {}", e);
+                    if let Some(done) = done.take() {
+                        done(Err(From::from(e)));
+                    }
+                    return;
+                }
+            };
+
+            if !map_udp {
+                return;
+            }
+            let (done_clone, mapping_clone) = (done.clone(), mapping.clone());
+            if let Err(e) = MappedUdpSocket::start(
+                core,
+                poll,
+                0,
+                &mc,
+                move |_, _, socket, addrs, mapping_methods| {
+                    if let Some(ref mut info) = mapping_clone.borrow_mut().info {
+                        info.for_udp_hole_punch = addrs;
+                        info.udp_hole_punch_socket = Some(socket);
+                        info.mapping_methods.extend(mapping_methods);
+                        add_transport(&mut info.transports, Transport::Udp);
+                    }
+                    mapping_done(&mapping_clone, &done_clone);
+                },
+            ) {
+                // TCP hole punching still works.
+                debug!("Error mapping udp socket: {}", e);
+                mapping_done(&mapping, &done);
+            }
+        })
+    }
+
     /// Check if we are connected to the given peer
     pub fn is_connected(&self, peer_uid: &PeerId) -> bool {
         let peer_uid = *peer_uid;
@@ -645,6 +858,48 @@ impl Service {
         rx.recv().unwrap_or(false)
     }
 
+    /// Returns statistics of the connection to the given peer.
+    pub fn peer_stats(&self, peer_uid: &PeerId) -> crate::Res<PeerStats> {
+        let peer_uid = *peer_uid;
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let stats = core
+                .user_data()
+                .connections
+                .get(&peer_uid)
+                .and_then(|conn_id| conn_id.active_connection)
+                .and_then(|token| peer_stats(core, token));
+            let _ = tx.send(stats);
+        })?;
+        rx.recv()?.ok_or(CrustError::PeerNotFound)
+    }
+
+    /// Returns statistics of the connections to all peers we're connected to.
+    pub fn connected_peers(&self) -> crate::Res<Vec<PeerStats>> {
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let stats = core
+                .user_data()
+                .connections
+                .values()
+                .filter_map(|conn_id| conn_id.active_connection)
+                .filter_map(|token| peer_stats(core, token))
+                .collect();
+            let _ = tx.send(stats);
+        })?;
+        Ok(rx.recv()?)
+    }
+
+    /// Returns counters of incoming connections our listener turned away before or during the
+    /// handshake.
+    pub fn handshake_stats(&self) -> crate::Res<HandshakeStats> {
+        let (tx, rx) = mpsc::channel();
+        self.post(move |core, _| {
+            let _ = tx.send(core.user_data().handshake_limiter.stats());
+        })?;
+        Ok(rx.recv()?)
+    }
+
     /// Returns our ID.
     pub fn id(&self) -> PeerId {
         self.our_uid
@@ -701,9 +956,61 @@ impl Service {
         })?;
         rx.recv()?
     }
+
+    /// Starts a state that reconnects to lost peers, if enabled in config.
+    fn start_reconnect_supervisor(&self) -> crate::Res<()> {
+        let (tx, rx) = mpsc::channel();
+        let our_uid = self.our_uid;
+        let name_hash = self.name_hash;
+        let our_sk = self.our_sk.clone();
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, _poll| {
+            let _ = tx.send(ReconnectSupervisor::start(
+                core,
+                EventToken::Reconnect.into(),
+                our_uid,
+                name_hash,
+                our_sk,
+                event_tx,
+            ));
+        })?;
+        rx.recv()?
+    }
+
+    fn start_relay(&self) -> crate::Res<()> {
+        let (tx, rx) = mpsc::channel();
+        let our_uid = self.our_uid;
+        let name_hash = self.name_hash;
+        let our_sk = self.our_sk.clone();
+        let event_tx = self.event_tx.clone();
+        self.post(move |core, _poll| {
+            let _ = tx.send(Relay::start(
+                core,
+                EventToken::Relay.into(),
+                our_uid,
+                name_hash,
+                our_sk,
+                event_tx,
+            ));
+        })?;
+        rx.recv()?
+    }
+
+    fn start_peer_exchange(&self) -> crate::Res<()> {
+        let (tx, rx) = mpsc::channel();
+        let our_uid = self.our_uid;
+        self.post(move |core, _poll| {
+            let _ = tx.send(PeerExchange::start(
+                core,
+                EventToken::PeerExchange.into(),
+                our_uid,
+            ));
+        })?;
+        rx.recv()?
+    }
 }
 
-fn our_global_listener_addrs(core: &EventLoopCore) -> HashSet<SocketAddr> {
+pub fn our_global_listener_addrs(core: &EventLoopCore) -> HashSet<SocketAddr> {
     core.user_data()
         .our_listeners
         .iter()
@@ -712,6 +1019,105 @@ fn our_global_listener_addrs(core: &EventLoopCore) -> HashSet<SocketAddr> {
         .collect()
 }
 
+fn write_tracked(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    peer_uid: &PeerId,
+    msg: Vec<u8>,
+    priority: Priority,
+) -> crate::Res<MsgId> {
+    let state = match core.user_data().connections.get(peer_uid) {
+        Some(&ConnectionId {
+            active_connection: Some(token),
+            ..
+        }) => core.get_state(token).ok_or(CrustError::PeerNotFound)?,
+        _ => return Err(CrustError::PeerNotFound),
+    };
+    let msg_id = core.user_data().next_msg_id;
+    core.user_data_mut().next_msg_id = msg_id.wrapping_add(1);
+
+    let mut state = state.borrow_mut();
+    if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection>() {
+        active_connection.write_tracked(core, poll, msg, priority, msg_id);
+    } else if let Some(relayed_connection) = state.as_any().downcast_mut::<RelayedConnection>() {
+        relayed_connection.write_tracked(core, poll, msg, priority, msg_id);
+    } else {
+        return Err(CrustError::PeerNotFound);
+    }
+    Ok(msg_id)
+}
+
+/// Statistics of a direct or relayed connection.
+pub fn peer_stats(core: &EventLoopCore, token: Token) -> Option<PeerStats> {
+    let state = core.get_state(token)?;
+    let mut state = state.borrow_mut();
+    if let Some(active_connection) = state.as_any().downcast_mut::<ActiveConnection>() {
+        Some(active_connection.stats())
+    } else if let Some(relayed_connection) = state.as_any().downcast_mut::<RelayedConnection>() {
+        Some(relayed_connection.stats())
+    } else {
+        None
+    }
+}
+
+/// Binds a socket for TCP hole punching without asking IGD or peers for our external address.
+/// Such socket is only reachable by peers on the same local network.
+fn local_hole_punch_socket(mc: &MappingContext) -> crate::Res<(TcpBuilder, Vec<SocketAddr>)> {
+    let socket = new_reusably_bound_tcp_socket(&common::ipv4_addr(0, 0, 0, 0, 0))?;
+    let port = socket.local_addr()?.port();
+    let addrs = mc
+        .ifv4s()
+        .iter()
+        .map(|&(ip, _)| SocketAddr::new(IpAddr::V4(ip), port))
+        .collect();
+    Ok((socket, addrs))
+}
+
+/// Binds a socket for UDP hole punching without asking IGD or peers for our external address.
+fn local_udp_hole_punch_socket(mc: &MappingContext) -> crate::Res<(UdpSocket, Vec<SocketAddr>)> {
+    let socket = UdpSocket::bind(common::ipv4_addr(0, 0, 0, 0, 0))?;
+    socket.set_nonblocking(true)?;
+    let port = socket.local_addr()?.port();
+    let addrs = mc
+        .ifv4s()
+        .iter()
+        .map(|&(ip, _)| SocketAddr::new(IpAddr::V4(ip), port))
+        .collect();
+    Ok((socket, addrs))
+}
+
+/// Adds `transport` to those we advertise, e.g. because we are able to hole punch over it even
+/// though we don't listen on it.
+fn add_transport(transports: &mut Vec<Transport>, transport: Transport) {
+    if !transports.contains(&transport) {
+        transports.push(transport);
+    }
+}
+
+/// Connection info waiting for its hole punch sockets to be mapped.
+struct Mapping {
+    pending: usize,
+    info: Option<PrivConnectionInfo>,
+}
+
+/// Hands the connection info to `done` once the last of its sockets is mapped.
+fn mapping_done<F>(mapping: &RefCell<Mapping>, done: &Cell<Option<F>>)
+where
+    F: FnOnce(crate::Res<PrivConnectionInfo>),
+{
+    let info = {
+        let mut mapping = mapping.borrow_mut();
+        mapping.pending -= 1;
+        if mapping.pending > 0 {
+            return;
+        }
+        mapping.info.take()
+    };
+    if let (Some(info), Some(done)) = (info, done.take()) {
+        done(Ok(info));
+    }
+}
+
 /// Returns a hash of the network name.
 fn name_hash(network_name: &Option<String>) -> NameHash {
     trace!("Network name: {:?}", network_name);
@@ -726,7 +1132,7 @@ mod tests {
     use super::*;
     use crate::common::CrustUser;
     use crate::main::{Event, PrivConnectionInfo, PubConnectionInfo};
-    use crate::tests::{get_event_sender, rand_peer_id_and_enc_sk, timebomb};
+    use crate::tests::{gen_config, get_event_sender, rand_peer_id_and_keys, timebomb};
     use crate::CrustError;
     use maidsafe_utilities;
     use maidsafe_utilities::thread::Joiner;
@@ -742,8 +1148,8 @@ mod tests {
     fn connect_self() {
         timebomb(Duration::from_secs(30), || {
             let (event_tx, event_rx) = get_event_sender();
-            let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-            let mut service = unwrap!(Service::try_new(event_tx, peer_id, peer_sk));
+            let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+            let mut service = unwrap!(Service::try_new(event_tx, peer_id, peer_sk, peer_sign_sk));
 
             unwrap!(service.start_listening_tcp());
             expect_event!(event_rx, Event::ListenerStarted(_));
@@ -763,20 +1169,42 @@ mod tests {
         })
     }
 
+    #[test]
+    fn prepare_connection_info_without_nat_traversal() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx, event_rx) = get_event_sender();
+            let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+            let service = unwrap!(Service::try_new(event_tx, peer_id, peer_sk, peer_sign_sk));
+            unwrap!(service.set_nat_traversal(false));
+
+            service.prepare_connection_info(0);
+
+            let priv_info = expect_event!(event_rx,
+                                          Event::ConnectionInfoPrepared(result) => {
+                unwrap!(result.result)
+            });
+            assert!(priv_info.mapping_methods.is_empty());
+            assert!(priv_info.hole_punch_socket.is_some());
+            assert!(!priv_info.for_hole_punch.is_empty());
+        })
+    }
+
     #[test]
     fn direct_connect_two_peers() {
         timebomb(Duration::from_secs(30), || {
             let (event_tx_0, event_rx_0) = get_event_sender();
-            let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-            let mut service_0 = unwrap!(Service::try_new(event_tx_0, peer_id, peer_sk));
+            let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+            let mut service_0 =
+                unwrap!(Service::try_new(event_tx_0, peer_id, peer_sk, peer_sign_sk));
 
             unwrap!(service_0.start_listening_tcp());
             expect_event!(event_rx_0, Event::ListenerStarted(_));
             unwrap!(service_0.set_ext_reachability_test(false));
 
             let (event_tx_1, event_rx_1) = get_event_sender();
-            let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-            let mut service_1 = unwrap!(Service::try_new(event_tx_1, peer_id, peer_sk));
+            let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+            let mut service_1 =
+                unwrap!(Service::try_new(event_tx_1, peer_id, peer_sk, peer_sign_sk));
 
             unwrap!(service_1.start_listening_tcp());
             expect_event!(event_rx_1, Event::ListenerStarted(_));
@@ -788,17 +1216,16 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn rendezvous_connect_two_peers() {
         unwrap!(maidsafe_utilities::log::init(true));
         timebomb(Duration::from_secs(30), || {
             let (event_tx_0, event_rx_0) = get_event_sender();
-            let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-            let service_0 = unwrap!(Service::try_new(event_tx_0, peer_id, peer_sk));
+            let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+            let service_0 = unwrap!(Service::try_new(event_tx_0, peer_id, peer_sk, peer_sign_sk));
 
             let (event_tx_1, event_rx_1) = get_event_sender();
-            let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-            let service_1 = unwrap!(Service::try_new(event_tx_1, peer_id, peer_sk));
+            let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+            let service_1 = unwrap!(Service::try_new(event_tx_1, peer_id, peer_sk, peer_sign_sk));
 
             connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
             debug!("Exchanging messages ...");
@@ -807,6 +1234,47 @@ mod tests {
         thread::sleep(Duration::from_secs(1));
     }
 
+    #[test]
+    fn rendezvous_connect_two_peers_over_udp() {
+        timebomb(Duration::from_secs(30), || {
+            let udp_service = || {
+                let mut config = gen_config();
+                config.transports = Some(vec![Transport::Tcp, Transport::Udp]);
+                let (event_tx, event_rx) = get_event_sender();
+                let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+                let service = unwrap!(Service::with_config(
+                    event_tx,
+                    config,
+                    peer_id,
+                    peer_sk,
+                    peer_sign_sk
+                ));
+                unwrap!(service.set_nat_traversal(false));
+                (service, event_rx)
+            };
+            let (mut service_0, event_rx_0) = udp_service();
+            let (mut service_1, event_rx_1) = udp_service();
+
+            let priv_info_0 = prepare_connection_info(&mut service_0, &event_rx_0);
+            let priv_info_1 = prepare_connection_info(&mut service_1, &event_rx_1);
+            assert!(priv_info_0.udp_hole_punch_socket.is_some());
+            assert!(!priv_info_0.for_udp_hole_punch.is_empty());
+            let pub_info_0 = priv_info_0.to_pub_connection_info();
+            let pub_info_1 = priv_info_1.to_pub_connection_info();
+
+            // Neither peer listens, so UDP hole punching is the only way.
+            unwrap!(service_0.connect_over(priv_info_0, pub_info_1, &[Transport::Udp]));
+            unwrap!(service_1.connect_over(priv_info_1, pub_info_0, &[Transport::Udp]));
+
+            expect_event!(event_rx_0, Event::ConnectSuccess(id) => assert_eq!(id, service_1.id()));
+            expect_event!(event_rx_1, Event::ConnectSuccess(id) => assert_eq!(id, service_0.id()));
+            let stats = unwrap!(service_0.peer_stats(&service_1.id()));
+            assert_eq!(stats.transport, Some(Transport::Udp));
+
+            exchange_messages(&service_0, &event_rx_0, &service_1, &event_rx_1);
+        })
+    }
+
     fn connect(
         service_0: &Service,
         event_rx_0: &Receiver<Event>,
@@ -903,9 +1371,14 @@ mod tests {
             fn new_with_sender(index: usize) -> (Self, mpsc::Sender<PubConnectionInfo>) {
                 let (event_sender, event_rx) = get_event_sender();
                 let config = unwrap!(crate::main::config_handler::read_config_file());
-                let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-                let mut service =
-                    unwrap!(Service::with_config(event_sender, config, peer_id, peer_sk));
+                let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+                let mut service = unwrap!(Service::with_config(
+                    event_sender,
+                    config,
+                    peer_id,
+                    peer_sk,
+                    peer_sign_sk
+                ));
                 // Start listener so that the test works without hole punching.
                 assert!(service.start_listening_tcp().is_ok());
                 match unwrap!(event_rx.recv()) {
@@ -0,0 +1,118 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::main::CrustError;
+
+#[cfg(not(test))]
+const INACTIVITY_TIMEOUT_MS: u64 = 120_000;
+#[cfg(not(test))]
+const HEARTBEAT_PERIOD_MS: u64 = 20_000;
+
+#[cfg(test)]
+const INACTIVITY_TIMEOUT_MS: u64 = 900;
+#[cfg(test)]
+const HEARTBEAT_PERIOD_MS: u64 = 300;
+
+/// Timing specific configurable settings. Changes picked up when the config file is reread apply
+/// to connections and attempts started afterwards.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Timeouts {
+    /// Drop connections we haven't heard from for this long, in milliseconds.
+    pub inactivity_timeout_ms: u64,
+    /// Send heartbeats this often, in milliseconds. Must be shorter than
+    /// `inactivity_timeout_ms`.
+    pub heartbeat_period_ms: u64,
+    /// Give up connecting to a peer after this many seconds.
+    pub connect_timeout_sec: u64,
+    /// Give up connecting to a peer through relays after this many seconds.
+    pub relay_connect_timeout_sec: u64,
+    /// Give up bootstrapping after this many seconds.
+    pub bootstrap_timeout_sec: u64,
+    /// Wait this long for peers on the local network to answer service discovery before
+    /// bootstrapping off the other contacts, in milliseconds.
+    pub service_discovery_timeout_ms: u64,
+    /// Drop incoming connections which don't complete the handshake within this many seconds.
+    pub handshake_timeout_sec: u64,
+    /// Reread the config file this often, in seconds.
+    pub config_refresh_interval_sec: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            inactivity_timeout_ms: INACTIVITY_TIMEOUT_MS,
+            heartbeat_period_ms: HEARTBEAT_PERIOD_MS,
+            connect_timeout_sec: 60,
+            relay_connect_timeout_sec: 30,
+            bootstrap_timeout_sec: 10,
+            service_discovery_timeout_ms: 1000,
+            handshake_timeout_sec: 10 * 60,
+            config_refresh_interval_sec: 30,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Rejects zero timeouts and heartbeats which wouldn't keep connections alive.
+    pub fn validate(&self) -> crate::Res<()> {
+        let all_set = [
+            self.inactivity_timeout_ms,
+            self.heartbeat_period_ms,
+            self.connect_timeout_sec,
+            self.relay_connect_timeout_sec,
+            self.bootstrap_timeout_sec,
+            self.service_discovery_timeout_ms,
+            self.handshake_timeout_sec,
+            self.config_refresh_interval_sec,
+        ]
+        .iter()
+        .all(|timeout| *timeout > 0);
+        if !all_set {
+            return Err(CrustError::InvalidConfig(
+                "timeouts must be greater than zero",
+            ));
+        }
+        if self.heartbeat_period_ms >= self.inactivity_timeout_ms {
+            return Err(CrustError::InvalidConfig(
+                "heartbeat period must be shorter than inactivity timeout",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_timeouts_are_rejected() {
+        unwrap!(Timeouts::default().validate());
+
+        let timeouts = Timeouts {
+            connect_timeout_sec: 0,
+            ..Default::default()
+        };
+        assert!(timeouts.validate().is_err());
+
+        let timeouts = Timeouts {
+            relay_connect_timeout_sec: 0,
+            ..Default::default()
+        };
+        assert!(timeouts.validate().is_err());
+
+        let timeouts = Timeouts {
+            heartbeat_period_ms: 1000,
+            inactivity_timeout_ms: 1000,
+            ..Default::default()
+        };
+        assert!(timeouts.validate().is_err());
+    }
+}
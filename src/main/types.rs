@@ -7,13 +7,21 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{self, Core, PeerInfo};
+use crate::common::{self, Core, PeerInfo, Transport, UdpEndpoint};
+use crate::main::ban::BanList;
 use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::connection_limits::Reservation;
+use crate::main::handshake_limits::HandshakeLimiter;
 use crate::main::Config;
+use crate::nat::MappingMethod;
 use crate::PeerId;
 use mio::Token;
+use net2::TcpBuilder;
+use safe_crypto::SecretSignKey;
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
-use std::net::SocketAddr;
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::{Rc, Weak};
 
 // ========================================================================================
 //                                     ConnectionId
@@ -27,6 +35,12 @@ pub struct ConnectionId {
     pub currently_handshaking: usize,
 }
 
+// ========================================================================================
+//                                         MsgId
+// ========================================================================================
+/// Identifies a message sent with `Service::send_tracked` in the delivery events.
+pub type MsgId = u64;
+
 // ========================================================================================
 //                                   ConnectionInfoResult
 // ========================================================================================
@@ -49,6 +63,28 @@ pub struct PrivConnectionInfo {
     pub id: PeerId,
     #[doc(hidden)]
     pub for_direct: Vec<SocketAddr>,
+    #[doc(hidden)]
+    pub for_hole_punch: Vec<SocketAddr>,
+    /// Reusably bound socket whose port is mapped to `for_hole_punch` addresses.
+    #[doc(hidden)]
+    pub hole_punch_socket: Option<TcpBuilder>,
+    #[doc(hidden)]
+    pub for_udp_hole_punch: Vec<SocketAddr>,
+    /// UDP socket whose port is mapped to `for_udp_hole_punch` addresses. Only prepared if UDP
+    /// is enabled in the config.
+    #[doc(hidden)]
+    pub udp_hole_punch_socket: Option<UdpSocket>,
+    /// NAT traversal methods which succeeded to find our external address. Empty if NAT
+    /// traversal is disabled or neither method worked.
+    pub mapping_methods: HashSet<MappingMethod>,
+    /// Nodes we are directly connected to, which could relay traffic between us and the peer,
+    /// should the direct connection fail.
+    #[doc(hidden)]
+    pub relays: Vec<PeerId>,
+    /// Transports to connect to the peer over, initially those enabled in the config. Only
+    /// transports the peer supports as well are attempted. `Service::connect_over` picks them
+    /// for a single connection.
+    pub transports: Vec<Transport>,
 }
 
 impl PrivConnectionInfo {
@@ -57,6 +93,10 @@ impl PrivConnectionInfo {
     pub fn to_pub_connection_info(&self) -> PubConnectionInfo {
         PubConnectionInfo {
             for_direct: self.for_direct.clone(),
+            for_hole_punch: self.for_hole_punch.clone(),
+            for_udp_hole_punch: self.for_udp_hole_punch.clone(),
+            relays: self.relays.clone(),
+            transports: self.transports.clone(),
             id: self.id,
         }
     }
@@ -72,6 +112,19 @@ pub struct PubConnectionInfo {
     pub id: PeerId,
     #[doc(hidden)]
     pub for_direct: Vec<SocketAddr>,
+    #[doc(hidden)]
+    #[serde(default)]
+    pub for_hole_punch: Vec<SocketAddr>,
+    #[doc(hidden)]
+    #[serde(default)]
+    pub for_udp_hole_punch: Vec<SocketAddr>,
+    #[doc(hidden)]
+    #[serde(default)]
+    pub relays: Vec<PeerId>,
+    /// Empty for peers which only support TCP.
+    #[doc(hidden)]
+    #[serde(default)]
+    pub transports: Vec<Transport>,
 }
 
 impl PubConnectionInfo {
@@ -133,17 +186,62 @@ pub struct CrustData {
     /// Either established or in progress connections.
     pub connections: HashMap<PeerId, ConnectionId>,
     pub config: ConfigWrapper,
+    /// Whether to map our hole punch sockets via IGD and peer STUNs.
+    pub nat_traversal: bool,
+    /// ID of the next message sent with `Service::send_tracked`.
+    pub next_msg_id: MsgId,
+    /// Peers and IP addresses banned with `Service::ban_peer`.
+    pub bans: BanList,
+    /// Incoming connections still handshaking and those turned away.
+    pub handshake_limiter: HandshakeLimiter,
+    /// Room reserved for incoming connections under `ConnectionLimitsConfig`, by the token of
+    /// their handshake.
+    pub reservations: HashMap<Token, Reservation>,
+    /// Signs our handshakes, proving we own the signing key in our `PeerId`.
+    pub our_sign_sk: SecretSignKey,
+    /// UDP endpoints of our connection listener. Connections over UDP are opened from them, so
+    /// that they share the port we advertise.
+    pub udp_endpoints: Vec<Weak<RefCell<UdpEndpoint>>>,
 }
 
 impl CrustData {
-    pub fn new(bootstrap_cache: BootstrapCache) -> Self {
+    pub fn new(bootstrap_cache: BootstrapCache, our_sign_sk: SecretSignKey) -> Self {
         Self {
             bootstrap_cache,
             our_listeners: Default::default(),
             connections: Default::default(),
             config: Default::default(),
+            nat_traversal: false,
+            next_msg_id: 0,
+            bans: Default::default(),
+            handshake_limiter: Default::default(),
+            reservations: Default::default(),
+            our_sign_sk,
+            udp_endpoints: Vec::new(),
         }
     }
+
+    /// Transports enabled in the config which we are able to accept connections over.
+    pub fn our_transports(&self) -> Vec<Transport> {
+        let listening_udp = self
+            .udp_endpoints
+            .iter()
+            .any(|endpoint| endpoint.upgrade().is_some());
+        self.config
+            .cfg
+            .transports()
+            .into_iter()
+            .filter(|transport| *transport != Transport::Udp || listening_udp)
+            .collect()
+    }
+
+    /// Our listener's UDP endpoint of the same IP version as `addr`, if any.
+    pub fn udp_endpoint(&self, addr: &SocketAddr) -> Option<Rc<RefCell<UdpEndpoint>>> {
+        self.udp_endpoints
+            .iter()
+            .filter_map(|endpoint| endpoint.upgrade())
+            .find(|endpoint| endpoint.borrow().local_addr().is_ipv4() == addr.is_ipv4())
+    }
 }
 
 impl GetGlobalListenerAddrs for CrustData {
@@ -173,6 +271,12 @@ pub enum EventToken {
     ConfigRefresher,
     /// Bootstrap cache validator token.
     BootstrapCacheValidator,
+    /// Reconnect supervisor token.
+    Reconnect,
+    /// Relay token.
+    Relay,
+    /// Peer exchange token.
+    PeerExchange,
     /// Up from this value you can use tokens for arbitrary events.
     Unreserved,
 }
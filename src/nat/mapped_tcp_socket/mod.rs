@@ -28,6 +28,15 @@ mod get_ext_addr;
 
 const TIMEOUT_SEC: u64 = 3;
 
+/// A way to find out how the world sees our socket's address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MappingMethod {
+    /// Port mapping via Internet Gateway Device protocol on our router.
+    Igd,
+    /// External address echoed back by another Crust peer.
+    PeerStun,
+}
+
 /// A state which represents the in-progress mapping of a tcp socket.
 pub struct MappedTcpSocket<F, T> {
     token: Token,
@@ -35,6 +44,7 @@ pub struct MappedTcpSocket<F, T> {
     igd_children: usize,
     stun_children: HashSet<Token>,
     mapped_addrs: Vec<SocketAddr>,
+    mapping_methods: HashSet<MappingMethod>,
     timeout: Timeout,
     finish: Option<F>,
     phantom: PhantomData<T>,
@@ -42,7 +52,7 @@ pub struct MappedTcpSocket<F, T> {
 
 impl<F, T: 'static> MappedTcpSocket<F, T>
 where
-    F: FnOnce(&mut Core<T>, &Poll, TcpBuilder, Vec<SocketAddr>) + Any,
+    F: FnOnce(&mut Core<T>, &Poll, TcpBuilder, Vec<SocketAddr>, HashSet<MappingMethod>) + Any,
 {
     /// Start mapping a tcp socket
     pub fn start(
@@ -56,7 +66,7 @@ where
     ) -> Result<(), NatError> {
         let token = core.get_new_token();
 
-        // TODO(Spandan) Ipv6 is not supported in Listener so dealing only with ipv4 right now
+        // NAT mappings only concern IPv4, IPv6 listeners are bound separately.
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
 
         let socket = util::new_reusably_bound_tcp_socket(&addr)?;
@@ -108,13 +118,14 @@ where
             igd_children,
             stun_children: HashSet::with_capacity(mc.peer_stuns().len()),
             mapped_addrs,
+            mapping_methods: HashSet::new(),
             timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC), CoreTimer::new(token, 0)),
             finish: Some(finish),
             phantom: PhantomData,
         }));
 
-        // Ask Stuns
-        for stun in mc.peer_stuns() {
+        // Ask Stuns. Our socket can only reach the ones of the same address family.
+        for stun in mc.peer_stuns().iter().filter(|stun| stun.addr.is_ipv4()) {
             let self_weak = Rc::downgrade(&state);
             let handler = move |core: &mut Core<T>, poll: &Poll, child_token, res| {
                 if let Some(self_rc) = self_weak.upgrade() {
@@ -158,6 +169,7 @@ where
         let _ = self.stun_children.remove(&child);
         if let Ok(our_ext_addr) = res {
             self.mapped_addrs.push(our_ext_addr);
+            let _ = self.mapping_methods.insert(MappingMethod::PeerStun);
         }
         if self.stun_children.is_empty() && self.igd_children == 0 {
             self.terminate(core, poll);
@@ -167,6 +179,7 @@ where
     fn handle_igd_resp(&mut self, core: &mut Core<T>, poll: &Poll, our_ext_addr: SocketAddr) {
         self.igd_children -= 1;
         self.mapped_addrs.push(our_ext_addr);
+        let _ = self.mapping_methods.insert(MappingMethod::Igd);
         if self.stun_children.is_empty() && self.igd_children == 0 {
             self.terminate(core, poll);
         }
@@ -186,7 +199,7 @@ where
 
 impl<F, T: 'static> State<T> for MappedTcpSocket<F, T>
 where
-    F: FnOnce(&mut Core<T>, &Poll, TcpBuilder, Vec<SocketAddr>) + Any,
+    F: FnOnce(&mut Core<T>, &Poll, TcpBuilder, Vec<SocketAddr>, HashSet<MappingMethod>) + Any,
 {
     fn timeout(&mut self, core: &mut Core<T>, poll: &Poll, _: u8) {
         self.terminate(core, poll)
@@ -199,7 +212,8 @@ where
 
         let socket = unwrap!(self.socket.take());
         let mapped_addrs = self.mapped_addrs.drain(..).collect();
-        (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs);
+        let mapping_methods = self.mapping_methods.drain().collect();
+        (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs, mapping_methods);
     }
 
     fn as_any(&mut self) -> &mut Any {
@@ -0,0 +1,255 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{Core, CoreMessage, CoreTimer, State, UdpPacket};
+use crate::nat::{MappingContext, MappingMethod, NatError};
+use igd::PortMappingProtocol;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use maidsafe_utilities::thread;
+use mio::{Poll, PollOpt, Ready, Token};
+use mio_extras::timer::Timeout;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::rc::Rc;
+use std::time::Duration;
+
+const TIMEOUT_SEC: u64 = 3;
+/// Echo requests and responses may get lost, so the requests are repeated this often.
+const ECHO_INTERVAL_MS: u64 = 500;
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+const TIMEOUT_TIMER_ID: u8 = 0;
+const ECHO_TIMER_ID: u8 = 1;
+
+/// A state which represents the in-progress mapping of a UDP socket. Peer STUNs are asked for our
+/// external address by echo requests to their UDP listeners, which share the port with their TCP
+/// listeners.
+pub struct MappedUdpSocket<F, T> {
+    token: Token,
+    socket: Option<UdpSocket>,
+    /// Clone of `socket` registered on the event loop to receive the echoes.
+    receiver: mio::net::UdpSocket,
+    echo_id: u32,
+    /// Peer STUNs which didn't answer yet.
+    stuns: HashSet<SocketAddr>,
+    igd_children: usize,
+    mapped_addrs: Vec<SocketAddr>,
+    mapping_methods: HashSet<MappingMethod>,
+    timeout: Timeout,
+    echo_timeout: Option<Timeout>,
+    finish: Option<F>,
+    phantom: PhantomData<T>,
+}
+
+impl<F, T: 'static> MappedUdpSocket<F, T>
+where
+    F: FnOnce(&mut Core<T>, &Poll, UdpSocket, Vec<SocketAddr>, HashSet<MappingMethod>) + Any,
+{
+    /// Start mapping a UDP socket
+    pub fn start(
+        core: &mut Core<T>,
+        poll: &Poll,
+        port: u16,
+        mc: &MappingContext,
+        finish: F,
+    ) -> Result<(), NatError> {
+        let token = core.get_new_token();
+
+        // NAT mappings only concern IPv4.
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        let addr = socket.local_addr()?;
+        let receiver = mio::net::UdpSocket::from_socket(socket.try_clone()?)?;
+        poll.register(&receiver, token, Ready::readable(), PollOpt::edge())?;
+
+        // Ask IGD
+        let mut igd_children = 0;
+        for &(ref ip, ref gateway) in mc.ifv4s() {
+            let gateway = match *gateway {
+                Some(ref gateway) => gateway.clone(),
+                None => continue,
+            };
+            let tx = core.sender().clone();
+            let addr_igd = SocketAddrV4::new(*ip, addr.port());
+            let _ = thread::named("IGD-Address-Mapping", move || {
+                let res =
+                    gateway.get_any_address(PortMappingProtocol::UDP, addr_igd, 0, "MaidSafeNat");
+                let ext_addr = match res {
+                    Ok(ext_addr) => ext_addr,
+                    Err(_) => return,
+                };
+                let _ = tx.send(CoreMessage::new(move |core, poll| {
+                    let state = match core.get_state(token) {
+                        Some(state) => state,
+                        None => return,
+                    };
+
+                    let mut state = state.borrow_mut();
+                    let mapping_udp_sock =
+                        match state.as_any().downcast_mut::<MappedUdpSocket<F, T>>() {
+                            Some(mapping_sock) => mapping_sock,
+                            None => return,
+                        };
+                    mapping_udp_sock.handle_igd_resp(core, poll, SocketAddr::V4(ext_addr));
+                }));
+            });
+            igd_children += 1;
+        }
+
+        let mapped_addrs = mc
+            .ifv4s()
+            .iter()
+            .map(|&(ip, _)| SocketAddr::new(IpAddr::V4(ip), addr.port()))
+            .collect();
+
+        // Ask Stuns. Our socket can only reach the ones of the same address family.
+        let stuns = mc
+            .peer_stuns()
+            .iter()
+            .map(|stun| stun.addr)
+            .filter(|addr| addr.is_ipv4())
+            .collect();
+
+        let mut state = Self {
+            token,
+            socket: Some(socket),
+            receiver,
+            echo_id: rand::random(),
+            stuns,
+            igd_children,
+            mapped_addrs,
+            mapping_methods: HashSet::new(),
+            timeout: core.set_timeout(
+                Duration::from_secs(TIMEOUT_SEC),
+                CoreTimer::new(token, TIMEOUT_TIMER_ID),
+            ),
+            echo_timeout: None,
+            finish: Some(finish),
+            phantom: PhantomData,
+        };
+
+        if state.stuns.is_empty() && state.igd_children == 0 {
+            state.terminate(core, poll);
+            return Ok(());
+        }
+
+        state.send_echoes(core);
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(())
+    }
+
+    /// Asks the peer STUNs which didn't answer yet for our address.
+    fn send_echoes(&mut self, core: &mut Core<T>) {
+        if self.stuns.is_empty() {
+            return;
+        }
+        let request = match serialise(&UdpPacket::echo(self.echo_id)) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Failed to serialise echo request: {:?}", e);
+                return;
+            }
+        };
+        for stun in &self.stuns {
+            if let Err(e) = self.receiver.send_to(&request, stun) {
+                debug!("Failed to send echo request to {}: {}", stun, e);
+            }
+        }
+        self.echo_timeout = Some(core.set_timeout(
+            Duration::from_millis(ECHO_INTERVAL_MS),
+            CoreTimer::new(self.token, ECHO_TIMER_ID),
+        ));
+    }
+
+    fn handle_stun_resp(&mut self, stun: SocketAddr, our_ext_addr: SocketAddr) {
+        if self.stuns.remove(&stun) {
+            self.mapped_addrs.push(our_ext_addr);
+            let _ = self.mapping_methods.insert(MappingMethod::PeerStun);
+        }
+    }
+
+    fn handle_igd_resp(&mut self, core: &mut Core<T>, poll: &Poll, our_ext_addr: SocketAddr) {
+        self.igd_children -= 1;
+        self.mapped_addrs.push(our_ext_addr);
+        let _ = self.mapping_methods.insert(MappingMethod::Igd);
+        if self.stuns.is_empty() && self.igd_children == 0 {
+            self.terminate(core, poll);
+        }
+    }
+}
+
+impl<F, T: 'static> State<T> for MappedUdpSocket<F, T>
+where
+    F: FnOnce(&mut Core<T>, &Poll, UdpSocket, Vec<SocketAddr>, HashSet<MappingMethod>) + Any,
+{
+    fn ready(&mut self, core: &mut Core<T>, poll: &Poll, kind: Ready) {
+        if !kind.is_readable() {
+            return;
+        }
+
+        let mut buf = [0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, addr) = match self.receiver.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                // Some platforms report ICMP port unreachable this way, other datagrams may follow.
+                Err(ref e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    debug!("Failed to receive echo response: {}", e);
+                    break;
+                }
+            };
+            match deserialise::<UdpPacket>(&buf[..len]) {
+                Ok(UdpPacket::EchoResp {
+                    id,
+                    addr: our_ext_addr,
+                }) if id == self.echo_id => self.handle_stun_resp(addr, our_ext_addr),
+                _ => trace!("Unexpected UDP packet from {}", addr),
+            }
+        }
+
+        if self.stuns.is_empty() && self.igd_children == 0 {
+            self.terminate(core, poll);
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core<T>, poll: &Poll, timer_id: u8) {
+        if timer_id == ECHO_TIMER_ID {
+            self.echo_timeout = None;
+            self.send_echoes(core);
+        } else {
+            self.terminate(core, poll)
+        }
+    }
+
+    fn terminate(&mut self, core: &mut Core<T>, poll: &Poll) {
+        let _ = core.remove_state(self.token);
+        let _ = core.cancel_timeout(&self.timeout);
+        if let Some(echo_timeout) = self.echo_timeout.take() {
+            let _ = core.cancel_timeout(&echo_timeout);
+        }
+        let _ = poll.deregister(&self.receiver);
+
+        let socket = unwrap!(self.socket.take());
+        let mapped_addrs = self.mapped_addrs.drain(..).collect();
+        let mapping_methods = self.mapping_methods.drain().collect();
+        (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs, mapping_methods);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
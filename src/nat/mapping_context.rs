@@ -71,6 +71,11 @@ impl MappingContext {
         &self.our_ifv4s
     }
 
+    /// Get v6 interfaces
+    pub fn ifv6s(&self) -> &Vec<Ipv6Addr> {
+        &self.our_ifv6s
+    }
+
     /// Iterate over the known servers
     pub fn peer_stuns(&self) -> &Vec<PeerInfo> {
         &self.peer_stuns
@@ -8,11 +8,13 @@
 // Software.
 
 pub use self::error::NatError;
-pub use self::mapped_tcp_socket::{GetExtAddr, GetExtAddrFinish, MappedTcpSocket};
+pub use self::mapped_tcp_socket::{GetExtAddr, GetExtAddrFinish, MappedTcpSocket, MappingMethod};
+pub use self::mapped_udp_socket::MappedUdpSocket;
 pub use self::mapping_context::MappingContext;
-pub use self::util::ip_addr_is_global;
+pub use self::util::{ip_addr_is_global, ipv6_addr_is_link_local, new_reusably_bound_tcp_socket};
 
 mod error;
 mod mapped_tcp_socket;
+mod mapped_udp_socket;
 mod mapping_context;
 mod util;
@@ -55,7 +55,79 @@ pub fn ipv4_addr_is_global(ipv4: Ipv4Addr) -> bool {
 }
 
 /// A replacement for `Ipv6Addr::is_global` while we wait for that to enter stable.
+/// Like with IPv4, multicast addresses are never considered global, since we can't connect to
+/// them.
 pub fn ipv6_addr_is_global(ipv6: Ipv6Addr) -> bool {
-    // TODO(canndrew): This function is incomplete and may return false-positives.
-    !(ipv6.is_loopback() || ipv6.is_unspecified())
+    if let Some(ipv4) = ipv4_mapped(ipv6) {
+        return ipv4_addr_is_global(ipv4);
+    }
+
+    let segments = ipv6.segments();
+    let is_unique_local = segments[0] & 0xfe00 == 0xfc00;
+    let is_site_local = segments[0] & 0xffc0 == 0xfec0;
+    let is_documentation = segments[0] == 0x2001 && segments[1] == 0xdb8;
+
+    !(ipv6.is_loopback()
+        || ipv6.is_unspecified()
+        || ipv6.is_multicast()
+        || ipv6_addr_is_link_local(ipv6)
+        || is_unique_local
+        || is_site_local
+        || is_documentation)
+}
+
+/// Link-local addresses (fe80::/10) are only usable together with the interface scope ID, hence
+/// should not be shared with other peers.
+pub fn ipv6_addr_is_link_local(ipv6: Ipv6Addr) -> bool {
+    ipv6.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Returns the embedded IPv4 address of `::ffff:a.b.c.d`.
+fn ipv4_mapped(ipv6: Ipv6Addr) -> Option<Ipv4Addr> {
+    match ipv6.segments() {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo] => Some(Ipv4Addr::new(
+            (hi >> 8) as u8,
+            hi as u8,
+            (lo >> 8) as u8,
+            lo as u8,
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv6(addr: &str) -> Ipv6Addr {
+        unwrap!(addr.parse())
+    }
+
+    #[test]
+    fn ipv6_global_addresses() {
+        assert!(ipv6_addr_is_global(ipv6("2a00:1450:4009:81f::200e")));
+        assert!(ipv6_addr_is_global(ipv6("2001:4860:4860::8888")));
+        assert!(ipv6_addr_is_global(ipv6("::ffff:8.8.8.8")));
+    }
+
+    #[test]
+    fn ipv6_non_global_addresses() {
+        let addrs = [
+            "::",
+            "::1",
+            "fc00::1",
+            "fd12:3456:789a::1",
+            "fe80::1",
+            "febf::1",
+            "fec0::1",
+            "2001:db8::1",
+            "ff02::1",
+            "ff0e::1",
+            "::ffff:192.168.0.1",
+            "::ffff:127.0.0.1",
+        ];
+        for addr in &addrs {
+            assert!(!ipv6_addr_is_global(ipv6(addr)), "{} is not global", addr);
+        }
+    }
 }
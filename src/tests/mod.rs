@@ -11,11 +11,15 @@
 pub mod utils;
 
 pub use self::utils::{
-    gen_config, get_event_sender, rand_peer_id_and_enc_sk, test_service, timebomb,
+    gen_config, get_event_sender, rand_peer_id_and_enc_sk, rand_peer_id_and_keys, test_service,
+    timebomb,
 };
 
-use crate::common::{CrustUser, PeerInfo};
-use crate::main::{Config, Event, Service};
+use crate::common::{BootstrapDenyReason, CrustUser, PeerInfo, Transport};
+use crate::main::{
+    BootstrapFailure, BootstrapFailureReason, Config, ConnectAttemptError, ConnectFailureReason,
+    ConnectionLimitsConfig, CrustError, Event, Service, TrafficStats,
+};
 use crate::PeerId;
 use hamcrest2::prelude::*;
 use mio;
@@ -44,6 +48,7 @@ fn gen_service_discovery_port() -> u16 {
 
 mod connect {
     use super::*;
+    use std::net::{IpAddr, Ipv6Addr};
 
     #[test]
     fn successfully_connected_peer_contacts_are_cached() {
@@ -122,6 +127,43 @@ mod connect {
         });
     }
 
+    #[test]
+    fn connects_over_ipv6_loopback() {
+        let (mut service1, event_rx1) = test_service();
+        let (service2, event_rx2) = test_service();
+
+        unwrap!(service1.start_listening_tcp());
+        expect_event!(event_rx1, Event::ListenerStarted(_port) => ());
+        unwrap!(service1.set_ext_reachability_test(false));
+        let uid1 = service1.id();
+
+        let token = rand::random();
+        service1.prepare_connection_info(token);
+        let ci1 = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+            assert_eq!(res.result_token, token);
+            unwrap!(res.result)
+        });
+
+        let token = rand::random();
+        service2.prepare_connection_info(token);
+        let ci2 = expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => {
+            assert_eq!(res.result_token, token);
+            unwrap!(res.result)
+        });
+
+        let mut pub_ci1 = ci1.to_pub_connection_info();
+        pub_ci1
+            .for_direct
+            .retain(|addr| addr.ip() == IpAddr::V6(Ipv6Addr::LOCALHOST));
+        pub_ci1.for_hole_punch.clear();
+        assert!(!pub_ci1.for_direct.is_empty());
+
+        unwrap!(service2.connect(ci2, pub_ci1));
+        expect_event!(event_rx2, Event::ConnectSuccess(id) => {
+            assert_eq!(id, uid1);
+        });
+    }
+
     #[test]
     fn when_external_reachability_is_enabled_fails_to_connect_on_localhost() {
         let (mut service1, event_rx1) = test_service();
@@ -151,8 +193,105 @@ mod connect {
         let pub_ci1 = unwrap!(ci_rx1.recv());
 
         unwrap!(service2.connect(ci2, pub_ci1));
-        expect_event!(event_rx2, Event::ConnectFailure(id) => {
+        expect_event!(event_rx2, Event::ConnectFailure(id, reason) => {
             assert_eq!(id, uid1);
+            let failures = match reason {
+                ConnectFailureReason::AttemptsFailed(failures) => failures,
+                reason => panic!("Unexpected failure reason: {:?}", reason),
+            };
+            let denied = ConnectAttemptError::Denied(BootstrapDenyReason::FailedExternalReachability);
+            assert!(failures.iter().any(|failure| failure.error == denied));
+        });
+    }
+
+    #[test]
+    fn falls_back_to_relay_when_direct_connection_fails() {
+        use std::net::TcpListener;
+
+        let (event_tx, relay_rx) = get_event_sender();
+        let mut config = gen_config();
+        config.relay.enabled = true;
+        let (relay_id, relay_sk, relay_sign_sk) = rand_peer_id_and_keys();
+        let mut relay = unwrap!(Service::with_config(
+            event_tx,
+            config,
+            relay_id,
+            relay_sk,
+            relay_sign_sk
+        ));
+        unwrap!(relay.start_listening_tcp());
+        let port = expect_event!(relay_rx, Event::ListenerStarted(port) => port);
+        unwrap!(relay.set_accept_bootstrap(true));
+        unwrap!(relay.set_ext_reachability_test(false));
+
+        let bootstrap_off_relay = || {
+            let mut config = gen_config();
+            config.hard_coded_contacts = vec![localhost_contact_info(port, relay.pub_key())];
+            let (event_tx, event_rx) = get_event_sender();
+            let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+            let mut service = unwrap!(Service::with_config(
+                event_tx,
+                config,
+                peer_id,
+                peer_sk,
+                peer_sign_sk
+            ));
+            unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Node));
+            expect_event!(event_rx, Event::BootstrapConnect(id, _) => assert_eq!(id, relay_id));
+            expect_event!(relay_rx, Event::BootstrapAccept(id, CrustUser::Node) => {
+                assert_eq!(id, peer_id)
+            });
+            (service, event_rx)
+        };
+        let (service1, event_rx1) = bootstrap_off_relay();
+        let (service2, event_rx2) = bootstrap_off_relay();
+
+        // Nobody listens on this port, so direct connections are refused.
+        let refused_addr = unwrap!(unwrap!(TcpListener::bind("127.0.0.1:0")).local_addr());
+
+        let token = rand::random();
+        service1.prepare_connection_info(token);
+        let ci1 = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+            unwrap!(res.result)
+        });
+        assert_eq!(ci1.relays, vec![relay_id]);
+        let mut pub_ci1 = ci1.to_pub_connection_info();
+        pub_ci1.for_direct = vec![refused_addr];
+        pub_ci1.for_hole_punch.clear();
+
+        let token = rand::random();
+        service2.prepare_connection_info(token);
+        let ci2 = expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => {
+            unwrap!(res.result)
+        });
+        let mut pub_ci2 = ci2.to_pub_connection_info();
+        pub_ci2.for_direct = vec![refused_addr];
+        pub_ci2.for_hole_punch.clear();
+
+        unwrap!(service1.connect(ci1, pub_ci2));
+        unwrap!(service2.connect(ci2, pub_ci1));
+        expect_event!(event_rx1, Event::RelayConnectSuccess(id, relay) => {
+            assert_eq!(id, service2.id());
+            assert_eq!(relay, relay_id);
+        });
+        expect_event!(event_rx2, Event::RelayConnectSuccess(id, relay) => {
+            assert_eq!(id, service1.id());
+            assert_eq!(relay, relay_id);
+        });
+
+        let msg = b"hello through relay".to_vec();
+        unwrap!(service1.send(&service2.id(), msg.clone(), 0));
+        expect_event!(event_rx2, Event::NewMessage(id, CrustUser::Node, data) => {
+            assert_eq!(id, service1.id());
+            assert_eq!(data, msg);
+        });
+
+        // Larger than a fragment, so it's split up on the way.
+        let msg = vec![7; 2 * 1024 * 1024 + 1];
+        unwrap!(service2.send(&service1.id(), msg.clone(), 0));
+        expect_event!(event_rx1, Event::NewMessage(id, CrustUser::Node, data) => {
+            assert_eq!(id, service2.id());
+            assert_eq!(data, msg);
         });
     }
 }
@@ -169,8 +308,14 @@ fn bootstrap_two_services_and_exchange_messages() {
     config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
 
     let (event_tx1, event_rx1) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
@@ -198,6 +343,428 @@ fn bootstrap_two_services_and_exchange_messages() {
     });
 }
 
+#[test]
+fn large_messages_are_fragmented_and_reassembled() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    config1.max_message_size = Some(4 * 1024 * 1024);
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    expect_event!(event_rx0, Event::BootstrapAccept(_, CrustUser::Client));
+
+    let large_msg: Vec<u8> = (0..3 * 1024 * 1024).map(|_| rand::random()).collect();
+    unwrap!(service1.send(&peer_id0, large_msg.clone(), 1));
+    let small_msg = b"sent after the large one".to_vec();
+    unwrap!(service1.send(&peer_id0, small_msg.clone(), 0));
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        expect_event!(event_rx0, Event::NewMessage(_, CrustUser::Client, data) => {
+            received.push(data)
+        });
+    }
+    assert!(received.contains(&large_msg));
+    assert!(received.contains(&small_msg));
+
+    let too_large_msg = vec![0; 5 * 1024 * 1024];
+    unwrap!(service1.send(&peer_id0, too_large_msg, 0));
+    expect_event!(event_rx1, Event::WriteMsgSizeProhibitive(peer_id, _) => {
+        assert_eq!(peer_id, peer_id0)
+    });
+}
+
+#[test]
+fn bootstrap_and_exchange_messages_over_udp() {
+    let mut config0 = gen_config();
+    config0.transports = Some(vec![Transport::Udp]);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service0 = unwrap!(Service::with_config(
+        event_tx0,
+        config0,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    config1.transports = Some(vec![Transport::Udp]);
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, CrustUser::Client) => {
+        peer_id
+    });
+
+    // Takes many packets, so it's retransmitted and reordered if the socket buffers overflow.
+    let large_msg: Vec<u8> = (0..512 * 1024).map(|_| rand::random()).collect();
+    unwrap!(service1.send(&peer_id0, large_msg.clone(), 1));
+    expect_event!(event_rx0, Event::NewMessage(_, CrustUser::Client, data) => {
+        assert_eq!(data, large_msg)
+    });
+    unwrap!(service0.send(&peer_id1, b"reply".to_vec(), 0));
+    expect_event!(event_rx1, Event::NewMessage(_, CrustUser::Node, data) => {
+        assert_eq!(data, b"reply".to_vec())
+    });
+
+    let stats1 = unwrap!(service1.peer_stats(&peer_id0));
+    assert_eq!(stats1.transport, Some(Transport::Udp));
+    assert_eq!(stats1.remote_addr.map(|addr| addr.port()), Some(port0));
+}
+
+#[test]
+fn tracked_messages_are_reported_sent_and_delivered() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    expect_event!(event_rx0, Event::BootstrapAccept(_, CrustUser::Client));
+
+    let small_msg = vec![1, 2, 3];
+    let large_msg = vec![4; 2 * 1024 * 1024];
+    let msg_id1 = unwrap!(service1.send_tracked(&peer_id0, small_msg.clone(), 0));
+    let msg_id2 = unwrap!(service1.send_tracked(&peer_id0, large_msg.clone(), 1));
+    assert_ne!(msg_id1, msg_id2);
+
+    for msg in &[small_msg, large_msg] {
+        expect_event!(event_rx0, Event::NewMessage(_, CrustUser::Client, data) => {
+            assert_eq!(data, *msg)
+        });
+    }
+
+    // Sent and delivered events of the two messages may interleave.
+    let mut sent = Vec::new();
+    let mut delivered = Vec::new();
+    while delivered.len() < 2 {
+        match unwrap!(event_rx1.recv_timeout(Duration::from_secs(30))) {
+            Event::MessageSent(peer_id, id) => {
+                assert_eq!(peer_id, peer_id0);
+                sent.push(id);
+            }
+            Event::MessageDelivered(peer_id, id) => {
+                assert_eq!(peer_id, peer_id0);
+                assert!(sent.contains(&id));
+                delivered.push(id);
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+    delivered.sort();
+    assert_eq!(delivered, vec![msg_id1, msg_id2]);
+}
+
+#[test]
+fn peer_stats_count_messages_by_priority() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, CrustUser::Client) => {
+        peer_id
+    });
+
+    unwrap!(service1.send(&peer_id0, vec![1; 10], 0));
+    unwrap!(service1.send(&peer_id0, vec![2; 20], 0));
+    unwrap!(service1.send(&peer_id0, vec![3; 30], 2));
+    for _ in 0..3 {
+        expect_event!(event_rx0, Event::NewMessage(..));
+    }
+
+    let stats1 = unwrap!(service1.peer_stats(&peer_id0));
+    assert_eq!(stats1.id, peer_id0);
+    assert_eq!(stats1.role, CrustUser::Node);
+    assert_eq!(stats1.remote_addr.map(|addr| addr.port()), Some(port0));
+    assert!(stats1.relay.is_none());
+    assert_eq!(
+        stats1.sent.get(&0),
+        Some(&TrafficStats {
+            messages: 2,
+            bytes: 30,
+        })
+    );
+    assert_eq!(
+        stats1.sent.get(&2),
+        Some(&TrafficStats {
+            messages: 1,
+            bytes: 30,
+        })
+    );
+    assert_eq!(stats1.received, TrafficStats::default());
+
+    let stats0 = unwrap!(service0.connected_peers());
+    assert_eq!(stats0.len(), 1);
+    assert_eq!(stats0[0].id, peer_id1);
+    assert_eq!(stats0[0].role, CrustUser::Client);
+    assert_eq!(stats0[0].local_addr.map(|addr| addr.port()), Some(port0));
+    assert_eq!(
+        stats0[0].received,
+        TrafficStats {
+            messages: 3,
+            bytes: 60,
+        }
+    );
+
+    let (unknown_id, _) = rand_peer_id_and_enc_sk();
+    match service1.peer_stats(&unknown_id) {
+        Err(CrustError::PeerNotFound) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn session_keys_are_replaced_without_losing_messages() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    config1.rekey.max_bytes = 16 * 1024;
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+
+    // Each round crosses the rekey threshold, so messages are queued while keys are switched.
+    for round in 0..8u8 {
+        let mut sent = Vec::new();
+        for i in 0..8u8 {
+            let mut data = vec![round; 4 * 1024];
+            data[0] = i;
+            unwrap!(service0.send(&peer_id1, data.clone(), i % 2));
+            unwrap!(service1.send(&peer_id0, data.clone(), i % 2));
+            sent.push(data);
+        }
+        // Large enough to be fragmented.
+        let large = vec![round; 600 * 1024];
+        unwrap!(service1.send(&peer_id0, large.clone(), 1));
+
+        let mut received0 = Vec::new();
+        for _ in 0..sent.len() + 1 {
+            received0.push(expect_event!(event_rx0, Event::NewMessage(_, _, data) => data));
+        }
+        let mut received1 = Vec::new();
+        for _ in 0..sent.len() {
+            received1.push(expect_event!(event_rx1, Event::NewMessage(_, _, data) => data));
+        }
+
+        received1.sort();
+        assert_eq!(received1, sent);
+        sent.push(large);
+        sent.sort();
+        received0.sort();
+        assert_eq!(received0, sent);
+    }
+
+    let mut rekeys = 0;
+    for _ in 0..100 {
+        rekeys = unwrap!(service1.peer_stats(&peer_id0)).rekeys;
+        if rekeys >= 3 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(rekeys >= 3, "Only {} rekeys", rekeys);
+}
+
+#[test]
+fn bootstrap_connects_to_target_number_of_peers() {
+    fn bootstrap_node() -> (Service, mpsc::Receiver<Event>, PeerInfo) {
+        let (mut service, event_rx) = test_service();
+        unwrap!(service.start_listening_tcp());
+        let port = expect_event!(event_rx, Event::ListenerStarted(port) => port);
+        unwrap!(service.set_accept_bootstrap(true));
+        let contact = localhost_contact_info(port, service.pub_key());
+        (service, event_rx, contact)
+    }
+
+    let (_service0, _event_rx0, contact0) = bootstrap_node();
+    let (_service1, _event_rx1, contact1) = bootstrap_node();
+    let (_service2, _event_rx2, contact2) = bootstrap_node();
+    let contacts = vec![contact0, contact1, contact2];
+
+    let mut config = gen_config();
+    config.hard_coded_contacts = contacts;
+    config.target_bootstrap_connections = Some(2);
+
+    let (event_tx, event_rx) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+    unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id1 = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id2 = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _) => peer_id);
+    assert_ne!(peer_id1, peer_id2);
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(unwrap!(service.connected_peers()).len(), 2);
+    if let Ok(event) = event_rx.try_recv() {
+        panic!("Unexpected event: {:?}", event);
+    }
+}
+
+#[test]
+fn heartbeats_measure_peer_latency() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    config1.report_peer_latency = true;
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let rtt = expect_event!(event_rx1, Event::PeerLatency(peer_id, rtt) => {
+        assert_eq!(peer_id, peer_id0);
+        rtt
+    });
+    assert!(rtt < Duration::from_secs(1));
+
+    let stats = unwrap!(service1.peer_stats(&peer_id0));
+    assert!(stats.rtt.is_some());
+    assert!(stats.rtt_var.is_some());
+}
+
+#[test]
+fn banned_peer_is_disconnected_and_cannot_bootstrap_until_unbanned() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (peer_id1, peer_sk1, peer_sign_sk1) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id1,
+        peer_sk1,
+        peer_sign_sk1
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(..));
+    expect_event!(event_rx0, Event::BootstrapAccept(_, CrustUser::Client));
+
+    unwrap!(service0.ban_peer(peer_id1, Duration::from_secs(60)));
+    expect_event!(event_rx0, Event::LostPeer(peer_id) => assert_eq!(peer_id, peer_id1));
+    expect_event!(event_rx1, Event::LostPeer(_));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapFailed(..));
+
+    assert!(unwrap!(service0.unban_peer(peer_id1)));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(..));
+}
+
 // Note: if this test fails, make sure that a firewall on your system allows UDP broadcasts
 #[test]
 fn bootstrap_two_services_using_service_discovery() {
@@ -206,15 +773,27 @@ fn bootstrap_two_services_using_service_discovery() {
     config0.service_discovery_listener_port = Some(service0_discovery_port);
 
     let (event_tx0, event_rx0) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service0 = unwrap!(Service::with_config(
+        event_tx0,
+        config0,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     let (event_tx1, event_rx1) = get_event_sender();
     let mut config1 = gen_config();
     config1.service_discovery_listener_port = Some(gen_service_discovery_port());
     config1.service_discovery_port = Some(service0_discovery_port);
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     unwrap!(service1.start_listening_tcp());
     let _ = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
@@ -241,12 +820,13 @@ fn bootstrap_with_multiple_contact_endpoints() {
     use std::net::TcpListener;
 
     let (event_tx0, event_rx0) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
     let mut service0 = unwrap!(Service::with_config(
         event_tx0,
         Config::default(),
         peer_id,
-        peer_sk
+        peer_sk,
+        peer_sign_sk
     ));
     unwrap!(service0.start_listening_tcp());
     let port = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
@@ -260,8 +840,14 @@ fn bootstrap_with_multiple_contact_endpoints() {
     config1.hard_coded_contacts = vec![invalid_address, valid_address];
 
     let (event_tx1, event_rx1) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
     unwrap!(service1.start_listening_tcp());
@@ -278,8 +864,14 @@ fn bootstrap_with_multiple_contact_endpoints() {
 fn bootstrap_with_skipped_external_reachability_test() {
     let config = Config::default();
     let (event_tx0, event_rx0) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service0 = unwrap!(Service::with_config(event_tx0, config, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service0 = unwrap!(Service::with_config(
+        event_tx0,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
     unwrap!(service0.start_listening_tcp());
     let port = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
     unwrap!(service0.set_accept_bootstrap(true));
@@ -289,8 +881,14 @@ fn bootstrap_with_skipped_external_reachability_test() {
     config1.hard_coded_contacts = vec![localhost_contact_info(port, service0.pub_key())];
 
     let (event_tx1, event_rx1) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Node));
 
     let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
@@ -305,12 +903,13 @@ fn bootstrap_with_blacklist() {
     use std::net::TcpListener;
 
     let (event_tx0, event_rx0) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
     let mut service0 = unwrap!(Service::with_config(
         event_tx0,
         Config::default(),
         peer_id,
-        peer_sk
+        peer_sk,
+        peer_sign_sk
     ));
     unwrap!(service0.start_listening_tcp());
     let port = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
@@ -327,8 +926,14 @@ fn bootstrap_with_blacklist() {
     config1.hard_coded_contacts = vec![blacklisted_address, valid_address];
 
     let (event_tx1, event_rx1) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
     let mut blacklist = HashSet::new();
     let _ = blacklist.insert(blacklisted_address.addr);
     unwrap!(service1.start_bootstrap(blacklist, CrustUser::Client));
@@ -361,14 +966,20 @@ fn bootstrap_fails_only_blacklisted_contact() {
     let mut config = gen_config();
     config.hard_coded_contacts = vec![blacklisted_address];
     let (event_tx, event_rx) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service = unwrap!(Service::with_config(event_tx, config, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     let mut blacklist = HashSet::new();
     let _ = blacklist.insert(blacklisted_address.addr);
     unwrap!(service.start_bootstrap(blacklist, CrustUser::Client));
 
-    expect_event!(event_rx, Event::BootstrapFailed);
+    expect_event!(event_rx, Event::BootstrapFailed(failures) => assert!(failures.is_empty()));
 
     let blacklisted_listener = unwrap!(mio::net::TcpListener::from_std(blacklisted_listener));
     thread::sleep(Duration::from_secs(5));
@@ -380,11 +991,17 @@ fn bootstrap_fails_only_blacklisted_contact() {
 fn bootstrap_fails_if_there_are_no_contacts() {
     let config = gen_config();
     let (event_tx, event_rx) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service = unwrap!(Service::with_config(event_tx, config, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx, Event::BootstrapFailed);
+    expect_event!(event_rx, Event::BootstrapFailed(failures) => assert!(failures.is_empty()));
 }
 
 #[test]
@@ -399,19 +1016,246 @@ fn bootstrap_timeouts_if_there_are_only_invalid_contacts() {
     config.hard_coded_contacts = vec![address];
 
     let (event_tx, event_rx) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service = unwrap!(Service::with_config(event_tx, config, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx, Event::BootstrapFailed(failures) => {
+        let expected = BootstrapFailure {
+            contact: address,
+            reason: BootstrapFailureReason::Timeout,
+        };
+        assert_eq!(failures, vec![expected]);
+    });
+}
+
+#[test]
+fn bootstrap_failure_reports_network_name_mismatch() {
+    let mut config0 = gen_config();
+    config0.network_name = Some("network-0".to_owned());
+    let (event_tx0, event_rx0) = get_event_sender();
+    let (peer_id0, peer_sk0, peer_sign_sk0) = rand_peer_id_and_keys();
+    let mut service0 = unwrap!(Service::with_config(
+        event_tx0,
+        config0,
+        peer_id0,
+        peer_sk0,
+        peer_sign_sk0
+    ));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+    let contact = localhost_contact_info(port0, service0.pub_key());
+
+    let mut config1 = gen_config();
+    config1.network_name = Some("network-1".to_owned());
+    config1.hard_coded_contacts = vec![contact];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (peer_id1, peer_sk1, peer_sign_sk1) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id1,
+        peer_sk1,
+        peer_sign_sk1
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapFailed(failures) => {
+        let expected = BootstrapFailure {
+            contact,
+            reason: BootstrapFailureReason::Denied(BootstrapDenyReason::InvalidNameHash),
+        };
+        assert_eq!(failures, vec![expected]);
+    });
+}
+
+fn limited_bootstrap_node(
+    limits: ConnectionLimitsConfig,
+) -> (Service, mpsc::Receiver<Event>, PeerInfo) {
+    let mut config = gen_config();
+    config.connection_limits = limits;
+    let (event_tx, event_rx) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+    unwrap!(service.start_listening_tcp());
+    let port = expect_event!(event_rx, Event::ListenerStarted(port) => port);
+    unwrap!(service.set_accept_bootstrap(true));
+    let contact = localhost_contact_info(port, service.pub_key());
+    (service, event_rx, contact)
+}
+
+fn bootstrap_client(contact: PeerInfo) -> (Service, mpsc::Receiver<Event>) {
+    let mut config = gen_config();
+    config.hard_coded_contacts = vec![contact];
+    let (event_tx, event_rx) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+    unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
+    (service, event_rx)
+}
+
+#[test]
+fn bootstrap_is_denied_when_at_capacity() {
+    let (_service0, event_rx0, contact) = limited_bootstrap_node(ConnectionLimitsConfig {
+        max_client_connections: Some(1),
+        ..Default::default()
+    });
+
+    let (_service1, event_rx1) = bootstrap_client(contact);
+    expect_event!(event_rx1, Event::BootstrapConnect(..));
+    expect_event!(event_rx0, Event::BootstrapAccept(_, CrustUser::Client));
+
+    let (_service2, event_rx2) = bootstrap_client(contact);
+    expect_event!(event_rx2, Event::BootstrapFailed(failures) => {
+        let expected = BootstrapFailure {
+            contact,
+            reason: BootstrapFailureReason::Denied(BootstrapDenyReason::AtCapacity),
+        };
+        assert_eq!(failures, vec![expected]);
+    });
+}
+
+#[test]
+fn idle_client_is_evicted_to_make_room() {
+    let (_service0, event_rx0, contact) = limited_bootstrap_node(ConnectionLimitsConfig {
+        max_connections: Some(2),
+        evict_idle_clients: true,
+        ..Default::default()
+    });
+
+    let (_service1, event_rx1) = bootstrap_client(contact);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    expect_event!(event_rx0, Event::BootstrapAccept(_, CrustUser::Client));
+
+    let (service2, event_rx2) = bootstrap_client(contact);
+    expect_event!(event_rx2, Event::BootstrapConnect(..));
+    expect_event!(event_rx0, Event::BootstrapAccept(_, CrustUser::Client));
+
+    // The first client is the one which exchanged user data least recently.
+    thread::sleep(Duration::from_millis(10));
+    unwrap!(service2.send(&peer_id0, vec![1, 2, 3], 0));
+    expect_event!(event_rx0, Event::NewMessage(..));
+
+    let (_service3, event_rx3) = bootstrap_client(contact);
+    expect_event!(event_rx3, Event::BootstrapConnect(..));
+    expect_event!(event_rx1, Event::LostPeer(peer_id) => assert_eq!(peer_id, peer_id0));
+}
+
+#[test]
+fn persistent_bootstrap_retries_until_contact_is_up() {
+    use std::net::TcpListener;
+
+    let port0 = unwrap!(unwrap!(TcpListener::bind("127.0.0.1:0")).local_addr()).port();
+    let mut config0 = gen_config();
+    config0.tcp_acceptor_port = Some(port0);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service0 = unwrap!(Service::with_config(
+        event_tx0,
+        config0,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    config1.bootstrap_retry.enabled = true;
+    config1.bootstrap_retry.initial_backoff_ms = 100;
+    config1.bootstrap_retry.max_backoff_ms = 200;
+    let (event_tx1, event_rx1) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapRetry(1, _));
+    expect_event!(event_rx1, Event::BootstrapRetry(2, _));
+
+    unwrap!(service0.start_listening_tcp());
+    expect_event!(event_rx0, Event::ListenerStarted(_));
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    loop {
+        match unwrap!(event_rx1.recv_timeout(Duration::from_secs(30))) {
+            Event::BootstrapRetry(..) => (),
+            Event::BootstrapConnect(peer_id, _) => {
+                assert_eq!(peer_id, service0.id());
+                break;
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+}
+
+#[test]
+fn persistent_bootstrap_can_be_stopped() {
+    let mut config = gen_config();
+    config.bootstrap_retry.enabled = true;
+    config.bootstrap_retry.initial_backoff_ms = 100;
+    config.bootstrap_retry.max_backoff_ms = 100;
+    let (event_tx, event_rx) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx, Event::BootstrapFailed);
+    expect_event!(event_rx, Event::BootstrapRetry(1, _));
+    unwrap!(service.stop_bootstrap());
+
+    thread::sleep(Duration::from_millis(500));
+    while let Ok(event) = event_rx.try_recv() {
+        match event {
+            // Retries scheduled before the bootstrap was stopped.
+            Event::BootstrapRetry(..) => (),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+    thread::sleep(Duration::from_millis(500));
+    assert!(event_rx.try_recv().is_err());
 }
 
 #[test]
 fn drop_disconnects() {
     let config_0 = gen_config();
     let (event_tx_0, event_rx_0) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service_0 = unwrap!(Service::with_config(event_tx_0, config_0, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service_0 = unwrap!(Service::with_config(
+        event_tx_0,
+        config_0,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     unwrap!(service_0.start_listening_tcp());
     let port = expect_event!(event_rx_0, Event::ListenerStarted(port) => port);
@@ -421,8 +1265,14 @@ fn drop_disconnects() {
     config_1.hard_coded_contacts = vec![localhost_contact_info(port, service_0.pub_key())];
 
     let (event_tx_1, event_rx_1) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service_1 = unwrap!(Service::with_config(event_tx_1, config_1, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service_1 = unwrap!(Service::with_config(
+        event_tx_1,
+        config_1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     unwrap!(service_1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
@@ -436,15 +1286,71 @@ fn drop_disconnects() {
     });
 }
 
+#[test]
+fn reconnect_to_lost_bootstrap_peer() {
+    let config_0 = gen_config();
+    let (event_tx_0, event_rx_0) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service_0 = unwrap!(Service::with_config(
+        event_tx_0,
+        config_0,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service_0.start_listening_tcp());
+    let port = expect_event!(event_rx_0, Event::ListenerStarted(port) => port);
+    unwrap!(service_0.set_accept_bootstrap(true));
+
+    let mut config_1 = gen_config();
+    config_1.hard_coded_contacts = vec![localhost_contact_info(port, service_0.pub_key())];
+    config_1.reconnect.enabled = true;
+    config_1.reconnect.initial_backoff_ms = 100;
+
+    let (event_tx_1, event_rx_1) = get_event_sender();
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service_1 = unwrap!(Service::with_config(
+        event_tx_1,
+        config_1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
+
+    unwrap!(service_1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id_0 = expect_event!(event_rx_1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id_1 = expect_event!(event_rx_0, Event::BootstrapAccept(peer_id, _) => peer_id);
+
+    assert!(service_0.disconnect(&peer_id_1));
+    expect_event!(event_rx_1, Event::LostPeer(peer_id) => {
+        assert_eq!(peer_id, peer_id_0)
+    });
+    expect_event!(event_rx_1, Event::Reconnecting(peer_id, attempt) => {
+        assert_eq!(peer_id, peer_id_0);
+        assert_eq!(attempt, 1);
+    });
+    expect_event!(event_rx_1, Event::Reconnected(peer_id) => {
+        assert_eq!(peer_id, peer_id_0)
+    });
+    expect_event!(event_rx_0, Event::LostPeer(_peer_id));
+    expect_event!(event_rx_0, Event::BootstrapAccept(peer_id, _) => {
+        assert_eq!(peer_id, peer_id_1)
+    });
+}
+
 // This module implements a simulated crust peer which accepts incomming
 // connections but then does nothing. It's purpose is to test that we detect
 // and handle non-responsive peers correctly.
 mod broken_peer {
     use super::*;
-    use crate::common::{Core, Message, State};
+    use crate::common::{
+        set_session_key, Core, HandshakeAuth, Message, ProtocolInfo, Socket, State,
+    };
     use mio::net::TcpListener;
     use mio::{Poll, PollOpt, Ready, Token};
-    use safe_crypto::SecretEncryptKey;
+    use safe_crypto::{gen_encrypt_keypair, SecretEncryptKey, SecretSignKey};
     use socket_collection::{DecryptContext, EncryptContext, TcpSock};
     use std::any::Any;
     use std::cell::RefCell;
@@ -455,6 +1361,7 @@ mod broken_peer {
         token: Token,
         our_id: PeerId,
         our_sk: SecretEncryptKey,
+        our_sign_sk: SecretSignKey,
     }
 
     impl Listen {
@@ -464,6 +1371,7 @@ mod broken_peer {
             listener: TcpListener,
             our_id: PeerId,
             our_sk: SecretEncryptKey,
+            our_sign_sk: SecretSignKey,
         ) {
             let token = core.get_new_token();
 
@@ -474,6 +1382,7 @@ mod broken_peer {
                 token,
                 our_id,
                 our_sk,
+                our_sign_sk,
             };
             let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
         }
@@ -484,12 +1393,20 @@ mod broken_peer {
             let (socket, _) = unwrap!(self.listener.accept());
             unwrap!(poll.deregister(&self.listener));
 
-            let mut socket = TcpSock::wrap(socket);
+            let mut socket = Socket::Tcp(TcpSock::wrap(socket));
             unwrap!(socket.set_decrypt_ctx(DecryptContext::anonymous_decrypt(
                 self.our_id.pub_enc_key,
                 self.our_sk.clone()
             )));
-            Connection::start(core, poll, self.token, socket, self.our_id, &self.our_sk);
+            Connection::start(
+                core,
+                poll,
+                self.token,
+                socket,
+                self.our_id,
+                &self.our_sk,
+                &self.our_sign_sk,
+            );
         }
 
         fn as_any(&mut self) -> &mut Any {
@@ -498,10 +1415,11 @@ mod broken_peer {
     }
 
     struct Connection {
-        socket: TcpSock,
+        socket: Socket,
         token: Token,
         our_id: PeerId,
         our_sk: SecretEncryptKey,
+        our_sign_sk: SecretSignKey,
     }
 
     impl Connection {
@@ -509,9 +1427,10 @@ mod broken_peer {
             core: &mut Core<()>,
             poll: &Poll,
             token: Token,
-            socket: TcpSock,
+            socket: Socket,
             our_id: PeerId,
             our_sk: &SecretEncryptKey,
+            our_sign_sk: &SecretSignKey,
         ) {
             unwrap!(poll.register(&socket, token, Ready::readable(), PollOpt::edge()));
 
@@ -520,6 +1439,7 @@ mod broken_peer {
                 token,
                 our_id,
                 our_sk: our_sk.clone(),
+                our_sign_sk: our_sign_sk.clone(),
             };
             let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
         }
@@ -529,14 +1449,25 @@ mod broken_peer {
         fn ready(&mut self, core: &mut Core<()>, poll: &Poll, kind: Ready) {
             if kind.is_readable() {
                 match self.socket.read::<Message>() {
-                    Ok(Some(Message::BootstrapRequest(their_id, _, _))) => {
+                    Ok(Some(Message::BootstrapRequest(their_id, name_hash, _, _, auth))) => {
                         let shared_key = self.our_sk.shared_secret(&their_id.pub_enc_key);
                         unwrap!(self
                             .socket
                             .set_encrypt_ctx(EncryptContext::authenticated(shared_key)));
-                        let _ = unwrap!(self
-                            .socket
-                            .write(Some((Message::BootstrapGranted(self.our_id), 0))));
+                        let (session_pk, session_sk) = gen_encrypt_keypair();
+                        let our_auth = unwrap!(HandshakeAuth::sign(
+                            &self.our_sign_sk,
+                            &name_hash,
+                            &self.our_id.pub_enc_key,
+                            &their_id.pub_enc_key,
+                            session_pk,
+                            auth.nonce,
+                        ));
+                        let msg =
+                            Message::BootstrapGranted(self.our_id, ProtocolInfo::ours(), our_auth);
+                        let _ = unwrap!(self.socket.write(Some((msg, 0))));
+                        let session_key = session_sk.shared_secret(&auth.session_pk);
+                        unwrap!(set_session_key(&mut self.socket, session_key));
                     }
                     Ok(Some(_)) | Ok(None) => (),
                     Err(_) => self.terminate(core, poll),
@@ -570,11 +1501,18 @@ fn drop_peer_when_no_message_received_within_inactivity_period() {
 
     let bind_addr = unwrap!(SocketAddr::from_str("127.0.0.1:0"), "Could not parse addr");
     let listener = unwrap!(TcpListener::bind(&bind_addr), "Could not bind listener");
-    let (listener_id, listener_sk) = rand_peer_id_and_enc_sk();
+    let (listener_id, listener_sk, listener_sign_sk) = rand_peer_id_and_keys();
     let address = PeerInfo::new(unwrap!(listener.local_addr()), listener_id.pub_enc_key);
 
     unwrap!(el.send(CoreMessage::new(move |core, poll| {
-        broken_peer::Listen::start(core, poll, listener, listener_id, listener_sk)
+        broken_peer::Listen::start(
+            core,
+            poll,
+            listener,
+            listener_id,
+            listener_sk,
+            listener_sign_sk,
+        )
     })));
 
     // Spin up normal service that will connect to the above guy.
@@ -582,8 +1520,14 @@ fn drop_peer_when_no_message_received_within_inactivity_period() {
     config.hard_coded_contacts = vec![address];
 
     let (event_tx, event_rx) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service = unwrap!(Service::with_config(event_tx, config, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
     let peer_id = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _) => peer_id);
@@ -596,14 +1540,20 @@ fn drop_peer_when_no_message_received_within_inactivity_period() {
 
 #[test]
 fn do_not_drop_peer_even_when_no_data_messages_are_exchanged_within_inactivity_period() {
-    use crate::main::INACTIVITY_TIMEOUT_MS;
+    use crate::main::Timeouts;
     use std::thread;
     use std::time::Duration;
 
     let config0 = gen_config();
     let (event_tx0, event_rx0) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service0 = unwrap!(Service::with_config(
+        event_tx0,
+        config0,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     unwrap!(service0.start_listening_tcp());
     let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
@@ -613,14 +1563,22 @@ fn do_not_drop_peer_even_when_no_data_messages_are_exchanged_within_inactivity_p
     config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
 
     let (event_tx1, event_rx1) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config1,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
 
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
     expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _));
     expect_event!(event_rx0, Event::BootstrapAccept(_peer_id, _));
 
-    thread::sleep(Duration::from_millis(2 * INACTIVITY_TIMEOUT_MS));
+    thread::sleep(Duration::from_millis(
+        2 * Timeouts::default().inactivity_timeout_ms,
+    ));
 
     if let Ok(Event::LostPeer(..)) = event_rx0.try_recv() {
         panic!("peer lost unexpectedly");
@@ -17,7 +17,7 @@ use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
 use mio_extras::channel::channel;
 use mio_extras::timer;
 use rand;
-use safe_crypto::{gen_encrypt_keypair, gen_sign_keypair, SecretEncryptKey};
+use safe_crypto::{gen_encrypt_keypair, gen_sign_keypair, SecretEncryptKey, SecretSignKey};
 use std::env;
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -45,13 +45,19 @@ macro_rules! expect_event {
 
 /// Generates random peer id.
 pub fn rand_peer_id_and_enc_sk() -> (PeerId, SecretEncryptKey) {
+    let (id, enc_sk, _sign_sk) = rand_peer_id_and_keys();
+    (id, enc_sk)
+}
+
+/// Generates random peer id together with its secret encryption and signing keys.
+pub fn rand_peer_id_and_keys() -> (PeerId, SecretEncryptKey, SecretSignKey) {
     let (enc_pk, enc_sk) = gen_encrypt_keypair();
-    let (sign_pk, _sign_sk) = gen_sign_keypair();
+    let (sign_pk, sign_sk) = gen_sign_keypair();
     let id = PeerId {
         pub_sign_key: sign_pk,
         pub_enc_key: enc_pk,
     };
-    (id, enc_sk)
+    (id, enc_sk, sign_sk)
 }
 
 pub fn get_event_sender() -> (crate::CrustEventSender, Receiver<Event>) {
@@ -106,7 +112,8 @@ pub fn bootstrap_cache_tmp_file() -> PathBuf {
 pub fn test_core(bootstrap_cache: BootstrapCache) -> EventLoopCore {
     let (event_tx, _event_rx) = channel();
     let timer = timer::Builder::default().build();
-    EventLoopCore::new_for_tests(0, event_tx, timer, CrustData::new(bootstrap_cache))
+    let (_, sign_sk) = gen_sign_keypair();
+    EventLoopCore::new_for_tests(0, event_tx, timer, CrustData::new(bootstrap_cache, sign_sk))
 }
 
 /// Bootstrap cache on tmp directory with unique file name.
@@ -129,7 +136,13 @@ pub fn peer_info_with_rand_key(addr: SocketAddr) -> PeerInfo {
 pub fn test_service() -> (Service, Receiver<Event>) {
     let config = gen_config();
     let (event_tx, event_rx) = get_event_sender();
-    let (peer_id, peer_sk) = rand_peer_id_and_enc_sk();
-    let service = unwrap!(Service::with_config(event_tx, config, peer_id, peer_sk));
+    let (peer_id, peer_sk, peer_sign_sk) = rand_peer_id_and_keys();
+    let service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        peer_id,
+        peer_sk,
+        peer_sign_sk
+    ));
     (service, event_rx)
 }